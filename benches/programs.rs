@@ -0,0 +1,22 @@
+//! Criterion benchmarks over the Lox programs in `benches/programs`.
+//!
+//!     cargo bench --bench programs
+
+use criterion::{criterion_group, criterion_main, Criterion};
+use rlox::vm::bench_interpret;
+
+const PROGRAMS: &[(&str, &str)] = &[
+    ("fib", include_str!("programs/fib.lox")),
+    ("strings", include_str!("programs/strings.lox")),
+    ("loop", include_str!("programs/loop.lox")),
+    ("fields", include_str!("programs/fields.lox")),
+];
+
+fn programs(c: &mut Criterion) {
+    for (name, source) in PROGRAMS {
+        c.bench_function(name, |b| b.iter(|| bench_interpret(source).unwrap()));
+    }
+}
+
+criterion_group!(benches, programs);
+criterion_main!(benches);
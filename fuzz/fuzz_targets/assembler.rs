@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::asm::assemble;
+
+fuzz_target!(|source: &str| {
+    let _ = assemble(source);
+});
@@ -0,0 +1,23 @@
+#![no_main]
+
+use std::rc::Rc;
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{
+    compiler::compile,
+    config::{Config, Limits, PrintOutput},
+};
+
+fn config() -> Config {
+    Config {
+        compiler_error: PrintOutput::Null,
+        vm_error: PrintOutput::Null,
+        print_output: PrintOutput::Null,
+        limits: Limits::untrusted(),
+        ..Default::default()
+    }
+}
+
+fuzz_target!(|source: &str| {
+    let _ = compile(Rc::from(source), config());
+});
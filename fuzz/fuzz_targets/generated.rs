@@ -0,0 +1,27 @@
+#![no_main]
+
+use std::rc::Rc;
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{
+    compiler::compile,
+    config::{Config, PrintOutput},
+    testing::{run_with_fuel, Generator},
+    vm::{InterpretResult, VM},
+};
+
+fuzz_target!(|seed: u64| {
+    let source = Generator::new(seed).program();
+    let config = Config {
+        print_output: PrintOutput::Null,
+        ..Default::default()
+    };
+    let program = compile(Rc::from(source.as_str()), Config::default())
+        .expect("generated program compiles");
+    let mut vm = VM::load(&program, config);
+    assert_eq!(
+        run_with_fuel(&mut vm, 1_000_000),
+        Some(InterpretResult::OK),
+        "{source}"
+    );
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::serialize::deserialize;
+
+fuzz_target!(|bytes: &[u8]| {
+    let _ = deserialize(bytes);
+});
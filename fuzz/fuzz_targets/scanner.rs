@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rlox::scanner::{scan_all, TokenType};
+
+fuzz_target!(|source: &str| {
+    let tokens = scan_all(source);
+    assert_eq!(tokens.last().map(|t| t.typ), Some(TokenType::EOF));
+});
@@ -0,0 +1,87 @@
+#![no_main]
+
+use std::rc::Rc;
+
+use libfuzzer_sys::fuzz_target;
+use rlox::{
+    compiler::compile,
+    config::{Config, Limits, PrintOutput},
+    scanner::TokenType,
+};
+
+fn lexeme(typ: TokenType) -> &'static str {
+    use TokenType::*;
+    match typ {
+        LeftParen => "(",
+        RightParen => ")",
+        LeftBrace => "{",
+        RightBrace => "}",
+        LeftBracket => "[",
+        RightBracket => "]",
+        Comma => ",",
+        Dot => ".",
+        Ellipsis => "...",
+        Minus => "-",
+        Plus => "+",
+        PlusEqual => "+=",
+        SemiColon => ";",
+        Slash => "/",
+        Star => "*",
+        Percent => "%",
+        TildeSlash => "~/",
+        QuestionQuestion => "??",
+        QuestionDot => "?.",
+        Bang => "!",
+        BangEqual => "!=",
+        Equal => "=",
+        EqualEqual => "==",
+        Greater => ">",
+        GreaterEqual => ">=",
+        Less => "<",
+        LessEqual => "<=",
+        Identifier => "a",
+        String => "\"s\"",
+        Number => "1",
+        And => "and",
+        Assert => "assert",
+        Break => "break",
+        Class => "class",
+        Const => "const",
+        Continue => "continue",
+        Else => "else",
+        False => "false",
+        For => "for",
+        Fun => "fun",
+        If => "if",
+        Import => "import",
+        In => "in",
+        Is => "is",
+        Nil => "nil",
+        Or => "or",
+        Print => "print",
+        Return => "return",
+        Super => "super",
+        This => "this",
+        Throw => "throw",
+        True => "true",
+        Var => "var",
+        While => "while",
+        Error => "@",
+        EOF => "",
+    }
+}
+
+fn config() -> Config {
+    Config {
+        compiler_error: PrintOutput::Null,
+        vm_error: PrintOutput::Null,
+        print_output: PrintOutput::Null,
+        limits: Limits::untrusted(),
+        ..Default::default()
+    }
+}
+
+fuzz_target!(|tokens: Vec<TokenType>| {
+    let source: Vec<_> = tokens.into_iter().map(lexeme).collect();
+    let _ = compile(Rc::from(source.join(" ")), config());
+});
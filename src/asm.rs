@@ -0,0 +1,619 @@
+use std::{collections::HashMap, error::Error, fmt, fmt::Write};
+
+use crate::{
+    chunk::{Chunk, OpCode, Operand},
+    memory::{Function, FunctionId, Memory},
+    value::Value,
+    vm::InstructionPointer,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct AssembleError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
+
+impl Error for AssembleError {}
+
+fn parse_op_code(name: &str) -> Option<OpCode> {
+    (0..=u8::MAX)
+        .filter_map(|b| OpCode::try_from(b).ok())
+        .find(|op_code| format!("{op_code:?}") == name)
+}
+
+enum Section {
+    None,
+    Constants,
+    Code,
+}
+
+enum ConstantSource {
+    Value(Value),
+    Function(usize, String),
+}
+
+struct PendingFunction {
+    name: String,
+    arity: usize,
+    min_arity: usize,
+    variadic: bool,
+    constants: Vec<ConstantSource>,
+    code: Vec<(usize, usize, OpCode, Option<String>)>,
+    labels: HashMap<String, usize>,
+}
+
+impl PendingFunction {
+    fn next_offset(&self) -> usize {
+        self.code
+            .last()
+            .map(|(_, offset, op_code, _)| offset + 1 + op_code.operand().size())
+            .unwrap_or(0)
+    }
+}
+
+pub fn assemble(source: &str) -> Result<Memory, AssembleError> {
+    let mut memory = Memory::new();
+    let mut functions: Vec<PendingFunction> = Vec::new();
+    let mut section = Section::None;
+    let mut line_override = None;
+
+    for (i, raw) in source.lines().enumerate() {
+        let number = i + 1;
+        let err = |message: String| AssembleError {
+            line: number,
+            message,
+        };
+
+        let line = strip_comment(raw).trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let mut words = line.splitn(2, char::is_whitespace);
+        let head = words.next().unwrap_or_default();
+        let rest = words.next().unwrap_or_default().trim();
+
+        match head {
+            ".function" => {
+                let mut parts = rest.split_whitespace();
+                let name = parts
+                    .next()
+                    .ok_or_else(|| err("Expect function name".into()))?;
+                let arity = match parts.next() {
+                    Some(arity) => arity
+                        .parse()
+                        .map_err(|_| err(format!("Invalid arity '{arity}'")))?,
+                    None => 0,
+                };
+                let min_arity = match parts.next() {
+                    Some(min) => min
+                        .parse()
+                        .map_err(|_| err(format!("Invalid arity '{min}'")))?,
+                    None => arity,
+                };
+                let variadic = match parts.next() {
+                    Some("rest") => true,
+                    Some(other) => return Err(err(format!("Invalid arity '{other}'"))),
+                    None => false,
+                };
+                functions.push(PendingFunction {
+                    name: name.into(),
+                    arity,
+                    min_arity,
+                    variadic,
+                    constants: Vec::new(),
+                    code: Vec::new(),
+                    labels: HashMap::new(),
+                });
+                section = Section::None;
+                line_override = None;
+                continue;
+            }
+            ".constants" => {
+                section = Section::Constants;
+                continue;
+            }
+            ".code" => {
+                section = Section::Code;
+                continue;
+            }
+            ".end" => {
+                section = Section::None;
+                continue;
+            }
+            ".line" => {
+                let line = rest
+                    .parse()
+                    .map_err(|_| err(format!("Invalid line '{rest}'")))?;
+                line_override = Some(line);
+                continue;
+            }
+            _ => (),
+        }
+
+        let function = functions
+            .last_mut()
+            .ok_or_else(|| err("Expect '.function' before code".into()))?;
+
+        match section {
+            Section::None => return Err(err("Expect '.constants' or '.code'".into())),
+            Section::Constants => {
+                let constant = parse_constant(head, rest, &mut memory)
+                    .map(ConstantSource::Value)
+                    .or_else(|| {
+                        (head == "function").then(|| ConstantSource::Function(number, rest.into()))
+                    })
+                    .ok_or_else(|| err(format!("Invalid constant '{line}'")))?;
+                function.constants.push(constant);
+            }
+            Section::Code => {
+                if let Some(label) = line.strip_suffix(':') {
+                    let offset = function.next_offset();
+                    function.labels.insert(label.into(), offset);
+                    continue;
+                }
+
+                let op_code =
+                    parse_op_code(head).ok_or_else(|| err(format!("Unknown opcode '{head}'")))?;
+                let offset = function.next_offset();
+                let arg = (!rest.is_empty()).then(|| rest.to_owned());
+                let line = line_override.unwrap_or(number);
+                function.code.push((line, offset, op_code, arg));
+            }
+        }
+    }
+
+    if functions.is_empty() {
+        return Err(AssembleError {
+            line: 1,
+            message: "Expect at least one '.function'".into(),
+        });
+    }
+
+    let ids: Vec<FunctionId> = functions
+        .iter()
+        .map(|f| memory.new_function(&f.name))
+        .collect();
+    let by_name: HashMap<&str, FunctionId> = functions
+        .iter()
+        .zip(ids.iter())
+        .map(|(f, id)| (f.name.as_str(), *id))
+        .collect();
+
+    for (function, id) in functions.iter().zip(ids.iter()) {
+        let mut chunk = Chunk::new();
+
+        for constant in function.constants.iter() {
+            let value = match constant {
+                ConstantSource::Value(value) => *value,
+                ConstantSource::Function(line, name) => {
+                    let id = by_name.get(name.as_str()).ok_or_else(|| AssembleError {
+                        line: *line,
+                        message: format!("Unknown function '{name}'"),
+                    })?;
+                    Value::Function(*id)
+                }
+            };
+            chunk.push_constant(value);
+        }
+
+        for (line, offset, op_code, arg) in function.code.iter() {
+            let err = |message: String| AssembleError {
+                line: *line,
+                message,
+            };
+            chunk.write_opcode(*op_code, *line);
+
+            let kind = op_code.operand();
+            let arg = match (kind, arg) {
+                (Operand::None, None) => continue,
+                (Operand::None, Some(_)) => {
+                    return Err(err(format!("{op_code:?} takes no operand")));
+                }
+                (_, None) => return Err(err(format!("{op_code:?} expects an operand"))),
+                (_, Some(arg)) => arg,
+            };
+
+            match kind {
+                Operand::Byte | Operand::Constant => {
+                    let byte: u8 = arg
+                        .parse()
+                        .map_err(|_| err(format!("Invalid operand '{arg}'")))?;
+                    if kind == Operand::Constant && byte as usize >= chunk.constants().len() {
+                        return Err(err(format!("Unknown constant {byte}")));
+                    }
+                    chunk.write(byte, *line);
+                }
+                Operand::Short => {
+                    let short: u16 = arg
+                        .parse()
+                        .map_err(|_| err(format!("Invalid operand '{arg}'")))?;
+                    chunk.write((short >> 8) as u8, *line);
+                    chunk.write((short & 0xFF) as u8, *line);
+                }
+                Operand::ConstantLong => {
+                    let index: usize = arg
+                        .parse()
+                        .map_err(|_| err(format!("Invalid operand '{arg}'")))?;
+                    if index >= chunk.constants().len() {
+                        return Err(err(format!("Unknown constant {index}")));
+                    }
+                    chunk.write((index >> 16) as u8, *line);
+                    chunk.write((index >> 8) as u8, *line);
+                    chunk.write(index as u8, *line);
+                }
+                Operand::Invoke | Operand::Locals | Operand::LocalConstant => {
+                    let bytes = arg
+                        .split_whitespace()
+                        .map(|arg| arg.parse::<u8>())
+                        .collect::<Result<Vec<_>, _>>();
+                    let [first, second] = bytes.as_deref().unwrap_or(&[]) else {
+                        return Err(err(format!("Invalid operands '{arg}'")));
+                    };
+                    let constant = match kind {
+                        Operand::Invoke => Some(first),
+                        Operand::LocalConstant => Some(second),
+                        _ => None,
+                    };
+                    if let Some(constant) =
+                        constant.filter(|c| **c as usize >= chunk.constants().len())
+                    {
+                        return Err(err(format!("Unknown constant {constant}")));
+                    }
+                    chunk.write(*first, *line);
+                    chunk.write(*second, *line);
+                }
+                Operand::InvokeLong => {
+                    let parsed = arg.split_once(' ').and_then(|(index, count)| {
+                        Some((
+                            index.parse::<usize>().ok()?,
+                            count.trim().parse::<u8>().ok()?,
+                        ))
+                    });
+                    let Some((index, count)) = parsed else {
+                        return Err(err(format!("Invalid operands '{arg}'")));
+                    };
+                    if index >= chunk.constants().len() {
+                        return Err(err(format!("Unknown constant {index}")));
+                    }
+                    chunk.write((index >> 16) as u8, *line);
+                    chunk.write((index >> 8) as u8, *line);
+                    chunk.write(index as u8, *line);
+                    chunk.write(count, *line);
+                }
+                Operand::Jump | Operand::Loop => {
+                    let target = *function
+                        .labels
+                        .get(arg.as_str())
+                        .ok_or_else(|| err(format!("Unknown label '{arg}'")))?;
+                    let after = offset + 3;
+                    let jump = if kind == Operand::Jump {
+                        target.checked_sub(after)
+                    } else {
+                        after.checked_sub(target)
+                    };
+                    let jump = jump
+                        .filter(|j| *j <= u16::MAX as usize)
+                        .ok_or_else(|| err(format!("Label '{arg}' is out of range")))?;
+                    chunk.write((jump >> 8) as u8, *line);
+                    chunk.write((jump & 0xFF) as u8, *line);
+                }
+                Operand::None => unreachable!(),
+            }
+        }
+
+        let f = memory.function_mut(*id);
+        f.arity = function.arity;
+        f.min_arity = function.min_arity;
+        f.variadic = function.variadic;
+        f.chunk = chunk;
+    }
+
+    Ok(memory)
+}
+
+fn strip_comment(line: &str) -> &str {
+    let mut in_string = false;
+    let mut escaped = false;
+    for (i, c) in line.char_indices() {
+        match c {
+            _ if escaped => escaped = false,
+            '\\' if in_string => escaped = true,
+            '"' => in_string = !in_string,
+            ';' if !in_string => return &line[..i],
+            _ => (),
+        }
+    }
+    line
+}
+
+fn parse_constant(kind: &str, rest: &str, memory: &mut Memory) -> Option<Value> {
+    let value = match kind {
+        "nil" => Value::Nil,
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        "number" => Value::Number(rest.parse().ok()?),
+        "int" => Value::Int(rest.parse().ok()?),
+        "string" => Value::String(memory.string_intern(&parse_string(rest)?)),
+        "name" => Value::StringId(memory.string_id(&parse_string(rest)?)),
+        _ => return None,
+    };
+    Some(value)
+}
+
+fn parse_string(s: &str) -> Option<String> {
+    let inner = s.strip_prefix('"')?.strip_suffix('"')?;
+    let mut result = String::with_capacity(inner.len());
+    let mut chars = inner.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            result.push(c);
+            continue;
+        }
+        match chars.next()? {
+            'n' => result.push('\n'),
+            't' => result.push('\t'),
+            'r' => result.push('\r'),
+            '0' => result.push('\0'),
+            c => result.push(c),
+        }
+    }
+    Some(result)
+}
+
+pub fn write_program(memory: &Memory, output: &mut impl Write) {
+    for (i, function) in memory.functions().enumerate() {
+        if i > 0 {
+            writeln!(output).unwrap();
+        }
+        let name = memory.get_string(function.name);
+        write_function(name, function, memory, output);
+    }
+}
+
+fn write_function(name: &str, function: &Function, memory: &Memory, output: &mut impl Write) {
+    let (arity, min_arity, chunk) = (function.arity, function.min_arity, &function.chunk);
+    match (min_arity == arity, function.variadic) {
+        (true, false) => writeln!(output, ".function {name} {arity}").unwrap(),
+        (false, false) => writeln!(output, ".function {name} {arity} {min_arity}").unwrap(),
+        (_, true) => writeln!(output, ".function {name} {arity} {min_arity} rest").unwrap(),
+    }
+
+    writeln!(output, ".constants").unwrap();
+    for (i, constant) in chunk.constants().iter().enumerate() {
+        write!(output, "    ; {i}\n    ").unwrap();
+        match constant {
+            Value::Nil => writeln!(output, "nil"),
+            Value::Bool(b) => writeln!(output, "{b}"),
+            Value::Number(n) => writeln!(output, "number {n}"),
+            Value::Int(i) => writeln!(output, "int {i}"),
+            Value::String(s) => writeln!(output, "string {s:?}"),
+            Value::StringId(id) => writeln!(output, "name {:?}", memory.get_string(*id)),
+            Value::Function(id) => {
+                let name = memory.get_string(memory.function(*id).name);
+                writeln!(output, "function {name}")
+            }
+            Value::Closure(_)
+            | Value::NativeFunction(_)
+            | Value::Class(_)
+            | Value::Instance(_)
+            | Value::BoundMethod(_)
+            | Value::List(_)
+            | Value::Tuple(_)
+            | Value::Module(_)
+            | Value::Type(_)
+            | Value::Foreign(_) => {
+                writeln!(output, "nil")
+            }
+        }
+        .unwrap();
+    }
+
+    let mut instructions = Vec::new();
+    let mut targets = Vec::new();
+    let mut offset = 0;
+    while offset < chunk.code.len() {
+        let Ok(op_code) = OpCode::try_from(chunk.code[offset]) else {
+            break;
+        };
+        let kind = op_code.operand();
+        let size = kind.size();
+        let arg = match kind {
+            Operand::None => None,
+            Operand::Byte
+            | Operand::Constant
+            | Operand::Invoke
+            | Operand::Locals
+            | Operand::LocalConstant => Some(chunk.code[offset + 1] as usize),
+            Operand::Short => {
+                Some(((chunk.code[offset + 1] as usize) << 8) | chunk.code[offset + 2] as usize)
+            }
+            Operand::ConstantLong | Operand::InvokeLong => {
+                Some(chunk.constant_long(InstructionPointer(offset + 1)).0)
+            }
+            Operand::Jump | Operand::Loop => {
+                let jump =
+                    ((chunk.code[offset + 1] as usize) << 8) | chunk.code[offset + 2] as usize;
+                let target = if kind == Operand::Jump {
+                    offset + 3 + jump
+                } else {
+                    offset + 3 - jump
+                };
+                targets.push(target);
+                Some(target)
+            }
+        };
+        instructions.push((offset, op_code, kind, arg));
+        offset += 1 + size;
+    }
+
+    writeln!(output, ".code").unwrap();
+    let mut line = None;
+    for (offset, op_code, kind, arg) in instructions {
+        if targets.contains(&offset) {
+            writeln!(output, "L{offset:0>4}:").unwrap();
+        }
+        let current = chunk.line(InstructionPointer(offset));
+        if line != Some(current) {
+            writeln!(output, "    .line {current}").unwrap();
+            line = Some(current);
+        }
+        match (kind, arg) {
+            (Operand::Jump | Operand::Loop, Some(target)) => {
+                writeln!(output, "    {op_code:?} L{target:0>4}").unwrap()
+            }
+            (Operand::Invoke | Operand::Locals | Operand::LocalConstant, Some(arg)) => {
+                let second = chunk.code[offset + 2];
+                writeln!(output, "    {op_code:?} {arg} {second}").unwrap()
+            }
+            (Operand::InvokeLong, Some(arg)) => {
+                let count = chunk.code[offset + 4];
+                writeln!(output, "    {op_code:?} {arg} {count}").unwrap()
+            }
+            (_, Some(arg)) => writeln!(output, "    {op_code:?} {arg}").unwrap(),
+            (_, None) => writeln!(output, "    {op_code:?}").unwrap(),
+        }
+    }
+    if targets.contains(&chunk.code.len()) {
+        writeln!(output, "L{:0>4}:", chunk.code.len()).unwrap();
+    }
+    writeln!(output, ".end").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{compiler::compile, config::Config, vm::VM};
+
+    fn run(memory: Memory) -> String {
+        let output = Rc::new(RefCell::new(String::new()));
+        let mut config = Config::default();
+        config.print_output.redirect(output.clone());
+        let _ = VM::with_script(memory, config).run();
+        let output = output.borrow();
+        output.clone()
+    }
+
+    #[test]
+    fn assemble_and_run() {
+        let memory = assemble(
+            r#"
+            .function <script>
+            .constants
+                string "hi"     ; 0
+                function double ; 1
+                number 3        ; 2
+                false           ; 3
+            .code
+                Constant 3
+                JumpIfFalse skip
+                Constant 0
+                Print
+            skip:
+                Pop
+                Constant 0
+                Print
+                Closure 1
+                Constant 2
+                Call 1
+                Print
+                Nil
+                Return
+            .end
+
+            .function double 1
+            .constants
+                number 2
+            .code
+                GetLocal 1
+                Constant 0
+                Multiply
+                Return
+            .end
+        "#,
+        )
+        .unwrap();
+
+        assert_eq!(run(memory), "hi\n6\n");
+    }
+
+    #[test]
+    fn reports_errors_with_lines() {
+        let error = assemble(".function f\n.code\n    Jump nowhere\n")
+            .err()
+            .unwrap();
+        assert_eq!(
+            error,
+            AssembleError {
+                line: 3,
+                message: "Unknown label 'nowhere'".into()
+            }
+        );
+
+        let error = assemble(".function f\n.code\n    Bogus\n").err().unwrap();
+        assert_eq!(error.to_string(), "[line 3] Unknown opcode 'Bogus'");
+    }
+
+    #[test]
+    fn round_trips_compiled_programs() {
+        let source = r#"
+            fun count(n) {
+                for (var i = 0; i < n; i = i + 1) {
+                    if (i == 1) print "one"; else print i;
+                }
+            }
+            count(3);
+        "#;
+        let program = compile(Rc::from(source), Config::default()).unwrap();
+        let mut listing = String::new();
+        write_program(&program.to_memory(), &mut listing);
+
+        let memory = assemble(&listing).unwrap();
+        assert_eq!(run(memory), "0\none\n2\n");
+
+        let mut again = String::new();
+        write_program(&assemble(&listing).unwrap(), &mut again);
+        assert_eq!(listing, again);
+    }
+
+    #[test]
+    fn round_trips_super_invocations() {
+        let source = r#"
+            class A {
+                greet(name) { return "hi " + name; }
+            }
+            class B < A {
+                shout(name) { return super.greet(name) + "!"; }
+            }
+            print B().shout("bob");
+        "#;
+        let program = compile(Rc::from(source), Config::default()).unwrap();
+        let mut listing = String::new();
+        write_program(&program.to_memory(), &mut listing);
+        assert!(listing.contains("SuperInvoke 0 1"), "{listing}");
+
+        assert_eq!(run(assemble(&listing).unwrap()), "hi bob!\n");
+    }
+
+    #[test]
+    fn round_trips_long_constants() {
+        let mut source: String = (0..200).map(|i| format!("var g{i} = {i};\n")).collect();
+        source.push_str("print g199 + g1;");
+        source.push_str("class C { m(n) { return n; } } print C().m(3);");
+        let program = compile(Rc::from(source), Config::default()).unwrap();
+        let mut listing = String::new();
+        write_program(&program.to_memory(), &mut listing);
+        assert!(listing.contains("GetGlobalLong 398"), "{listing}");
+        assert!(listing.contains("InvokeLong 401 1"), "{listing}");
+
+        assert_eq!(run(assemble(&listing).unwrap()), "200\n3\n");
+    }
+}
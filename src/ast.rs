@@ -0,0 +1,294 @@
+use std::{fmt::Write, rc::Rc};
+
+use crate::{
+    compiler::parse_tree,
+    config::Config,
+    scanner::{Span, Token},
+};
+
+pub fn parse(source: Rc<str>, config: Config) -> Option<Node> {
+    parse_tree(source, config)
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub enum NodeKind {
+    Script,
+
+    ClassDecl,
+    Method,
+    FunDecl,
+    Param,
+    VarDecl,
+    ConstDecl,
+    ImportDecl,
+
+    Block,
+    ExprStmt,
+    PrintStmt,
+    IfStmt,
+    WhileStmt,
+    ForStmt,
+    ForEachStmt,
+    ReturnStmt,
+    ThrowStmt,
+    AssertStmt,
+    BreakStmt,
+    ContinueStmt,
+
+    Assign,
+    Logical,
+    Binary,
+    Unary,
+    Call,
+    Get,
+    Set,
+    Grouping,
+    IfExpr,
+    List,
+    Index,
+    IndexSet,
+    This,
+    Super,
+    Variable,
+    Literal,
+}
+
+impl NodeKind {
+    pub fn is_declaration(self) -> bool {
+        use NodeKind::*;
+        matches!(self, ClassDecl | FunDecl | Param | VarDecl | ConstDecl)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Node {
+    pub kind: NodeKind,
+    pub token: Option<Token>,
+    pub span: Span,
+    pub children: Vec<Node>,
+}
+
+impl Node {
+    pub fn dump(&self, output: &mut impl Write) {
+        self.dump_indented(0, output);
+    }
+
+    pub fn walk(&self, visit: &mut impl FnMut(&Node)) {
+        visit(self);
+        for child in self.children.iter() {
+            child.walk(visit);
+        }
+    }
+
+    pub fn path_at(&self, offset: usize) -> Vec<&Node> {
+        let mut path = Vec::new();
+        let mut node = self;
+        while node.span.start <= offset && offset < node.span.end.max(node.span.start + 1) {
+            path.push(node);
+            match node
+                .children
+                .iter()
+                .find(|child| child.span.start <= offset && offset < child.span.end)
+            {
+                Some(child) => node = child,
+                None => break,
+            }
+        }
+        path
+    }
+
+    pub fn definition_at(&self, offset: usize) -> Option<&Node> {
+        let path = self.path_at(offset);
+        let (target, ancestors) = path.split_last()?;
+        if target.kind.is_declaration() {
+            return Some(target);
+        }
+        let name = match target.kind {
+            NodeKind::Variable | NodeKind::Assign => target.token.as_ref()?.slice.as_str(),
+            _ => return None,
+        };
+
+        ancestors.iter().rev().find_map(|scope| {
+            scope.children.iter().rev().find(|child| {
+                child.kind.is_declaration()
+                    && child.span.start <= offset
+                    && child
+                        .token
+                        .as_ref()
+                        .is_some_and(|t| t.slice.as_str() == name)
+            })
+        })
+    }
+
+    fn dump_indented(&self, depth: usize, output: &mut impl Write) {
+        let Span { line, start, end } = self.span;
+        write!(output, "{:indent$}{:?} ", "", self.kind, indent = depth * 2).unwrap();
+        if let Some(token) = &self.token {
+            write!(output, "'{}' ", token.slice).unwrap();
+        }
+        writeln!(output, "[line {line}] {start}..{end}").unwrap();
+
+        for child in self.children.iter() {
+            child.dump_indented(depth + 1, output);
+        }
+    }
+}
+
+pub(crate) struct TreeBuilder {
+    stack: Vec<Node>,
+}
+
+impl TreeBuilder {
+    pub fn new() -> TreeBuilder {
+        TreeBuilder {
+            stack: vec![Node {
+                kind: NodeKind::Script,
+                token: None,
+                span: Span {
+                    line: 1,
+                    start: 0,
+                    end: 0,
+                },
+                children: Vec::new(),
+            }],
+        }
+    }
+
+    pub fn open(&mut self, kind: NodeKind, token: Option<Token>, start: Span) {
+        self.stack.push(Node {
+            kind,
+            token,
+            span: start,
+            children: Vec::new(),
+        });
+    }
+
+    pub fn open_wrapping_last(&mut self, kind: NodeKind, token: Option<Token>) {
+        let parent = self.stack.last_mut().unwrap();
+        let Some(last) = parent.children.pop() else {
+            return;
+        };
+        self.stack.push(Node {
+            kind,
+            token,
+            span: last.span,
+            children: vec![last],
+        });
+    }
+
+    pub fn close(&mut self, end: Span) {
+        if self.stack.len() == 1 {
+            return;
+        }
+        let mut node = self.stack.pop().unwrap();
+        node.span = node.span.to(end);
+        self.stack.last_mut().unwrap().children.push(node);
+    }
+
+    pub fn leaf(&mut self, kind: NodeKind, token: Token) {
+        let span = token.span();
+        self.open(kind, Some(token), span);
+        self.close(span);
+    }
+
+    pub fn finish(mut self, end: Span) -> Node {
+        while self.stack.len() > 1 {
+            self.close(end);
+        }
+        let mut root = self.stack.pop().unwrap();
+        root.span = root.span.to(end);
+        root
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn dump(source: &str) -> String {
+        let node = parse(Rc::from(source), Config::default()).unwrap();
+        let mut output = String::new();
+        node.dump(&mut output);
+        output
+    }
+
+    #[test]
+    fn dump_expression_tree() {
+        assert_eq!(
+            dump("print 1 + 2 * x;"),
+            "\
+Script [line 1] 0..16
+  PrintStmt [line 1] 0..16
+    Binary '+' [line 1] 6..15
+      Literal '1' [line 1] 6..7
+      Binary '*' [line 1] 10..15
+        Literal '2' [line 1] 10..11
+        Variable 'x' [line 1] 14..15
+"
+        );
+    }
+
+    #[test]
+    fn dump_function_tree() {
+        assert_eq!(
+            dump("fun f(a) {\n  return a;\n}\nf(1);"),
+            "\
+Script [line 1] 0..30
+  FunDecl 'f' [line 1] 0..24
+    Param 'a' [line 1] 6..7
+    Block [line 1] 9..24
+      ReturnStmt [line 2] 13..22
+        Variable 'a' [line 2] 20..21
+  ExprStmt [line 4] 25..30
+    Call [line 4] 25..29
+      Variable 'f' [line 4] 25..26
+      Literal '1' [line 4] 27..28
+"
+        );
+    }
+
+    #[test]
+    fn finds_definitions() {
+        let source = "var a = 1;\nfun f(a) {\n  {\n    var a = 2;\n    print a;\n  }\n  return a + f(a);\n}\nfor (a in []) print a;\nprint a;";
+        let tree = parse(Rc::from(source), Config::default()).unwrap();
+        let definition = |needle: &str, nth: usize| {
+            let offset = source.match_indices(needle).nth(nth).unwrap().0 + needle.len() - 1;
+            let node = tree.definition_at(offset).unwrap();
+            (node.kind, node.span.line)
+        };
+
+        assert_eq!(definition("print a", 0), (NodeKind::VarDecl, 4));
+        assert_eq!(definition("return a", 0), (NodeKind::Param, 2));
+        assert_eq!(definition("+ f", 0), (NodeKind::FunDecl, 2));
+        assert_eq!(definition("print a", 1), (NodeKind::VarDecl, 9));
+        assert_eq!(definition("print a", 2), (NodeKind::VarDecl, 1));
+
+        let path: Vec<_> = tree
+            .path_at(source.find("return").unwrap())
+            .iter()
+            .map(|node| node.kind)
+            .collect();
+        assert_eq!(
+            path,
+            vec![
+                NodeKind::Script,
+                NodeKind::FunDecl,
+                NodeKind::Block,
+                NodeKind::ReturnStmt
+            ]
+        );
+
+        let mut variables = 0;
+        tree.walk(&mut |node| variables += (node.kind == NodeKind::Variable) as usize);
+        assert_eq!(variables, 6);
+    }
+
+    #[test]
+    fn compile_error_yields_no_tree() {
+        let config = Config {
+            compiler_error: crate::config::PrintOutput::Null,
+            ..Default::default()
+        };
+        assert!(parse(Rc::from("print ;"), config).is_none());
+    }
+}
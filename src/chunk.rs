@@ -1,9 +1,21 @@
-use std::{error::Error, fmt};
+use std::{
+    collections::{BTreeMap, HashMap},
+    error::Error,
+    fmt,
+    sync::Arc,
+};
 
-use crate::{value::Value, vm::InstructionPointer};
+use crate::{
+    memory::{FunctionId, Memory},
+    serialize::{write_string, write_u32, Reader},
+    string_intern::StrId,
+    value::Value,
+    vm::InstructionPointer,
+};
 
 #[repr(u8)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum OpCode {
     Constant,
 
@@ -36,6 +48,202 @@ pub enum OpCode {
     Loop,
     Call,
     Closure,
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    Inherit,
+    GetSuper,
+    SuperInvoke,
+    BuildList,
+    IndexGet,
+    IndexSet,
+    Len,
+    TailCall,
+    ConstantLong,
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    GetLocalLong,
+    SetLocalLong,
+    JumpIfLess,
+    JumpIfNotLess,
+    JumpIfGreater,
+    JumpIfNotGreater,
+    AddLocals,
+    IncrementLocal,
+    DefineConst,
+    DefineConstLong,
+    Import,
+    Throw,
+    Assert,
+    Modulo,
+    FloorDivide,
+    Invoke,
+    TypeCheck,
+    ArgMissing,
+    BuildTuple,
+    Unpack,
+    JumpIfNil,
+    Dup,
+    ClosureLong,
+    ClassLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    MethodLong,
+    GetSuperLong,
+    ImportLong,
+    InvokeLong,
+    SuperInvokeLong,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Operand {
+    None,
+    Byte,
+    Constant,
+    ConstantLong,
+    Short,
+    Jump,
+    Loop,
+    Invoke,
+    InvokeLong,
+    Locals,
+    LocalConstant,
+}
+
+impl OpCode {
+    pub fn operand(self) -> Operand {
+        match self {
+            OpCode::Constant
+            | OpCode::DefineGlobal
+            | OpCode::GetGlobal
+            | OpCode::SetGlobal
+            | OpCode::Closure
+            | OpCode::Class
+            | OpCode::GetProperty
+            | OpCode::SetProperty
+            | OpCode::Method
+            | OpCode::GetSuper
+            | OpCode::DefineConst
+            | OpCode::Import => Operand::Constant,
+
+            OpCode::ConstantLong
+            | OpCode::DefineGlobalLong
+            | OpCode::DefineConstLong
+            | OpCode::GetGlobalLong
+            | OpCode::SetGlobalLong
+            | OpCode::ClosureLong
+            | OpCode::ClassLong
+            | OpCode::GetPropertyLong
+            | OpCode::SetPropertyLong
+            | OpCode::MethodLong
+            | OpCode::GetSuperLong
+            | OpCode::ImportLong => Operand::ConstantLong,
+
+            OpCode::GetLocalLong | OpCode::SetLocalLong => Operand::Short,
+
+            OpCode::Invoke | OpCode::SuperInvoke => Operand::Invoke,
+
+            OpCode::InvokeLong | OpCode::SuperInvokeLong => Operand::InvokeLong,
+
+            OpCode::AddLocals => Operand::Locals,
+
+            OpCode::IncrementLocal => Operand::LocalConstant,
+
+            OpCode::GetLocal
+            | OpCode::SetLocal
+            | OpCode::Call
+            | OpCode::TailCall
+            | OpCode::BuildList
+            | OpCode::ArgMissing
+            | OpCode::BuildTuple
+            | OpCode::Unpack
+            | OpCode::Dup => Operand::Byte,
+
+            OpCode::Jump
+            | OpCode::JumpIfFalse
+            | OpCode::JumpIfLess
+            | OpCode::JumpIfNotLess
+            | OpCode::JumpIfGreater
+            | OpCode::JumpIfNotGreater
+            | OpCode::JumpIfNil => Operand::Jump,
+
+            OpCode::Loop => Operand::Loop,
+
+            OpCode::Nil
+            | OpCode::True
+            | OpCode::False
+            | OpCode::Equal
+            | OpCode::Greater
+            | OpCode::Less
+            | OpCode::Add
+            | OpCode::Subtract
+            | OpCode::Multiply
+            | OpCode::Divide
+            | OpCode::Not
+            | OpCode::Negate
+            | OpCode::Return
+            | OpCode::Print
+            | OpCode::Pop
+            | OpCode::Inherit
+            | OpCode::IndexGet
+            | OpCode::IndexSet
+            | OpCode::Len
+            | OpCode::Throw
+            | OpCode::Assert
+            | OpCode::Modulo
+            | OpCode::FloorDivide
+            | OpCode::TypeCheck => Operand::None,
+        }
+    }
+
+    pub fn long(self) -> Option<OpCode> {
+        match self {
+            OpCode::Constant => Some(OpCode::ConstantLong),
+            OpCode::DefineGlobal => Some(OpCode::DefineGlobalLong),
+            OpCode::DefineConst => Some(OpCode::DefineConstLong),
+            OpCode::GetGlobal => Some(OpCode::GetGlobalLong),
+            OpCode::SetGlobal => Some(OpCode::SetGlobalLong),
+            OpCode::GetLocal => Some(OpCode::GetLocalLong),
+            OpCode::SetLocal => Some(OpCode::SetLocalLong),
+            OpCode::Closure => Some(OpCode::ClosureLong),
+            OpCode::Class => Some(OpCode::ClassLong),
+            OpCode::GetProperty => Some(OpCode::GetPropertyLong),
+            OpCode::SetProperty => Some(OpCode::SetPropertyLong),
+            OpCode::Method => Some(OpCode::MethodLong),
+            OpCode::GetSuper => Some(OpCode::GetSuperLong),
+            OpCode::Import => Some(OpCode::ImportLong),
+            OpCode::Invoke => Some(OpCode::InvokeLong),
+            OpCode::SuperInvoke => Some(OpCode::SuperInvokeLong),
+            _ => None,
+        }
+    }
+}
+
+impl Operand {
+    pub fn size(self) -> usize {
+        match self {
+            Operand::None => 0,
+            Operand::Byte | Operand::Constant => 1,
+            Operand::Short
+            | Operand::Jump
+            | Operand::Loop
+            | Operand::Invoke
+            | Operand::Locals
+            | Operand::LocalConstant => 2,
+            Operand::ConstantLong => 3,
+            Operand::InvokeLong => 4,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Decoded {
+    pub op_code: OpCode,
+    pub a: usize,
+    pub b: usize,
+    pub size: usize,
 }
 
 impl TryFrom<u8> for OpCode {
@@ -79,15 +287,137 @@ impl TryFrom<u8> for OpCode {
             x if x == Call as u8 => Call,
 
             x if x == Closure as u8 => Closure,
+
+            x if x == Class as u8 => Class,
+            x if x == GetProperty as u8 => GetProperty,
+            x if x == SetProperty as u8 => SetProperty,
+            x if x == Method as u8 => Method,
+            x if x == Inherit as u8 => Inherit,
+            x if x == GetSuper as u8 => GetSuper,
+            x if x == SuperInvoke as u8 => SuperInvoke,
+            x if x == BuildList as u8 => BuildList,
+            x if x == IndexGet as u8 => IndexGet,
+            x if x == IndexSet as u8 => IndexSet,
+            x if x == Len as u8 => Len,
+            x if x == TailCall as u8 => TailCall,
+            x if x == ConstantLong as u8 => ConstantLong,
+            x if x == DefineGlobalLong as u8 => DefineGlobalLong,
+            x if x == GetGlobalLong as u8 => GetGlobalLong,
+            x if x == SetGlobalLong as u8 => SetGlobalLong,
+            x if x == GetLocalLong as u8 => GetLocalLong,
+            x if x == SetLocalLong as u8 => SetLocalLong,
+            x if x == JumpIfLess as u8 => JumpIfLess,
+            x if x == JumpIfNotLess as u8 => JumpIfNotLess,
+            x if x == JumpIfGreater as u8 => JumpIfGreater,
+            x if x == JumpIfNotGreater as u8 => JumpIfNotGreater,
+            x if x == AddLocals as u8 => AddLocals,
+            x if x == IncrementLocal as u8 => IncrementLocal,
+            x if x == DefineConst as u8 => DefineConst,
+            x if x == DefineConstLong as u8 => DefineConstLong,
+            x if x == Import as u8 => Import,
+            x if x == Throw as u8 => Throw,
+            x if x == Assert as u8 => Assert,
+            x if x == Modulo as u8 => Modulo,
+            x if x == FloorDivide as u8 => FloorDivide,
+            x if x == Invoke as u8 => Invoke,
+            x if x == TypeCheck as u8 => TypeCheck,
+            x if x == ArgMissing as u8 => ArgMissing,
+            x if x == BuildTuple as u8 => BuildTuple,
+            x if x == Unpack as u8 => Unpack,
+            x if x == JumpIfNil as u8 => JumpIfNil,
+            x if x == Dup as u8 => Dup,
+            x if x == ClosureLong as u8 => ClosureLong,
+            x if x == ClassLong as u8 => ClassLong,
+            x if x == GetPropertyLong as u8 => GetPropertyLong,
+            x if x == SetPropertyLong as u8 => SetPropertyLong,
+            x if x == MethodLong as u8 => MethodLong,
+            x if x == GetSuperLong as u8 => GetSuperLong,
+            x if x == ImportLong as u8 => ImportLong,
+            x if x == InvokeLong as u8 => InvokeLong,
+            x if x == SuperInvokeLong as u8 => SuperInvokeLong,
             _ => return Err("Unknown opcode".into()),
         })
     }
 }
 
+pub fn decode(code: &[u8], i: InstructionPointer) -> Option<Decoded> {
+    let op_code = OpCode::try_from(*code.get(i.0)?).ok()?;
+    let operand = op_code.operand();
+    let bytes = code.get(i.0 + 1..i.0 + 1 + operand.size())?;
+    let (a, b) = match operand {
+        Operand::None => (0, 0),
+        Operand::Byte | Operand::Constant => (bytes[0] as usize, 0),
+        Operand::Short | Operand::Jump | Operand::Loop => {
+            ((bytes[0] as usize) << 8 | bytes[1] as usize, 0)
+        }
+        Operand::ConstantLong => (
+            (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize,
+            0,
+        ),
+        Operand::Invoke | Operand::Locals | Operand::LocalConstant => {
+            (bytes[0] as usize, bytes[1] as usize)
+        }
+        Operand::InvokeLong => (
+            (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize,
+            bytes[3] as usize,
+        ),
+    };
+    Some(Decoded {
+        op_code,
+        a,
+        b,
+        size: 1 + operand.size(),
+    })
+}
+
 pub struct Chunk {
     pub code: Vec<u8>,
     constants: Vec<Value>,
+    constant_index: HashMap<ConstantKey, usize>,
     pub lines: Vec<usize>,
+    pub columns: Vec<(usize, usize)>,
+    files: Vec<(usize, Arc<str>)>,
+    locals: Vec<LocalInfo>,
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct LocalInfo {
+    pub name: StrId,
+    pub slot: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+#[derive(PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Nil,
+    Bool(bool),
+    Number(u64),
+    Int(i64),
+    String(&'static str),
+    StringId(StrId),
+    Function(FunctionId),
+}
+
+impl ConstantKey {
+    fn of(value: Value) -> Option<ConstantKey> {
+        Some(match value {
+            Value::Nil => ConstantKey::Nil,
+            Value::Bool(b) => ConstantKey::Bool(b),
+            Value::Number(n) => ConstantKey::Number(n.to_bits()),
+            Value::Int(i) => ConstantKey::Int(i),
+            Value::String(s) => ConstantKey::String(s),
+            Value::StringId(id) => ConstantKey::StringId(id),
+            Value::Function(id) => ConstantKey::Function(id),
+            _ => return None,
+        })
+    }
+}
+
+impl Default for Chunk {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Chunk {
@@ -95,13 +425,28 @@ impl Chunk {
         Chunk {
             code: Vec::with_capacity(8),
             constants: Vec::with_capacity(8),
+            constant_index: HashMap::new(),
             lines: Vec::with_capacity(8),
+            columns: Vec::with_capacity(8),
+            files: Vec::new(),
+            locals: Vec::new(),
         }
     }
 
     pub fn write(&mut self, byte: u8, line: usize) {
+        self.write_at(byte, line, (0, 0));
+    }
+
+    pub fn write_at(&mut self, byte: u8, line: usize, column: (usize, usize)) {
         self.code.push(byte);
         self.lines.push(line);
+        self.columns.push(column);
+    }
+
+    pub fn truncate(&mut self, len: usize) {
+        self.code.truncate(len);
+        self.lines.truncate(len);
+        self.columns.truncate(len);
     }
 
     pub fn write_opcode(&mut self, op_code: OpCode, line: usize) {
@@ -109,14 +454,120 @@ impl Chunk {
     }
 
     pub fn add_constant(&mut self, value: Value) -> ConstantId {
+        let Some(key) = ConstantKey::of(value) else {
+            return self.push_constant(value);
+        };
+        if let Some(index) = self.constant_index.get(&key) {
+            return ConstantId(*index);
+        }
+        let id = self.push_constant(value);
+        self.constant_index.insert(key, id.0);
+        id
+    }
+
+    pub fn push_constant(&mut self, value: Value) -> ConstantId {
         self.constants.push(value);
         ConstantId(self.constants.len() - 1)
     }
 
+    pub fn fork(&self, memory: &mut Memory) -> Chunk {
+        let mut chunk = Chunk {
+            code: self.code.clone(),
+            lines: self.lines.clone(),
+            columns: self.columns.clone(),
+            files: self.files.clone(),
+            locals: self.locals.clone(),
+            ..Chunk::new()
+        };
+        for value in &self.constants {
+            let value = match *value {
+                Value::String(s) => Value::String(memory.string_intern(s)),
+                value => value,
+            };
+            let id = chunk.push_constant(value);
+            if let Some(key) = ConstantKey::of(value) {
+                chunk.constant_index.entry(key).or_insert(id.0);
+            }
+        }
+        chunk
+    }
+
     pub fn line(&self, i: InstructionPointer) -> usize {
         self.lines[i.0]
     }
 
+    pub fn column(&self, i: InstructionPointer) -> (usize, usize) {
+        self.columns.get(i.0).copied().unwrap_or_default()
+    }
+
+    pub fn set_file(&mut self, name: Arc<str>) {
+        self.set_file_at(self.code.len(), name);
+    }
+
+    pub fn set_file_at(&mut self, start: usize, name: Arc<str>) {
+        self.files.push((start, name));
+    }
+
+    pub fn remap_offsets(&mut self, f: impl Fn(usize) -> usize) {
+        for (start, _) in self.files.iter_mut() {
+            *start = f(*start);
+        }
+        for local in self.locals.iter_mut() {
+            local.start = f(local.start);
+            local.end = f(local.end);
+        }
+    }
+
+    pub fn add_local(&mut self, name: StrId, slot: usize) {
+        self.locals.push(LocalInfo {
+            name,
+            slot,
+            start: self.code.len(),
+            end: usize::MAX,
+        });
+    }
+
+    pub fn end_local(&mut self, slot: usize) {
+        let end = self.code.len();
+        for local in self.locals.iter_mut().rev() {
+            if local.slot == slot && local.end == usize::MAX {
+                local.end = end;
+                return;
+            }
+        }
+    }
+
+    pub fn end_locals(&mut self) {
+        let end = self.code.len();
+        for local in self
+            .locals
+            .iter_mut()
+            .filter(|local| local.end == usize::MAX)
+        {
+            local.end = end;
+        }
+    }
+
+    pub fn locals(&self) -> &[LocalInfo] {
+        &self.locals
+    }
+
+    pub fn files(&self) -> &[(usize, Arc<str>)] {
+        &self.files
+    }
+
+    pub fn file(&self, i: InstructionPointer) -> Option<&str> {
+        self.files
+            .iter()
+            .rev()
+            .find(|(start, _)| *start <= i.0)
+            .map(|(_, name)| name.as_ref())
+    }
+
+    pub fn location(&self, i: InstructionPointer) -> String {
+        location(self.file(i), self.line(i))
+    }
+
     pub fn byte(&self, i: InstructionPointer) -> u8 {
         self.code[i.0]
     }
@@ -125,17 +576,200 @@ impl Chunk {
         ConstantId(self.byte(i) as usize)
     }
 
+    pub fn constant_long(&self, i: InstructionPointer) -> ConstantId {
+        let bytes = &self.code[i.0..i.0 + 3];
+        ConstantId((bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize)
+    }
+
+    pub fn decode(&self, i: InstructionPointer) -> Option<Decoded> {
+        decode(&self.code, i)
+    }
+
+    pub fn decode_all(&self) -> Vec<Option<Decoded>> {
+        let mut decoded = vec![None; self.code.len()];
+        let mut offset = 0;
+        while let Some(instruction) = self.decode(InstructionPointer(offset)) {
+            decoded[offset] = Some(instruction);
+            offset += instruction.size;
+        }
+        decoded
+    }
+
+    pub fn line_index(&self) -> BTreeMap<usize, Vec<usize>> {
+        let mut index: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+        let mut previous = None;
+        for (offset, decoded) in self.decode_all().iter().enumerate() {
+            if decoded.is_none() {
+                continue;
+            }
+            let line = self.lines[offset];
+            if previous != Some(line) {
+                index.entry(line).or_default().push(offset);
+            }
+            previous = Some(line);
+        }
+        index
+    }
+
+    pub fn constants(&self) -> &[Value] {
+        &self.constants
+    }
+
     pub fn constant_value(&self, c: ConstantId) -> Value {
         self.constants[c.0]
     }
 }
 
+pub fn location(file: Option<&str>, line: usize) -> String {
+    match file {
+        Some(file) => format!("{file} line {line}"),
+        None => format!("line {line}"),
+    }
+}
+
+const NIL: u8 = 0;
+const FALSE: u8 = 1;
+const TRUE: u8 = 2;
+const NUMBER: u8 = 3;
+const STRING: u8 = 4;
+const STRING_ID: u8 = 5;
+const FUNCTION: u8 = 6;
+const INT: u8 = 7;
+
+pub fn serialize(chunk: &Chunk) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = Vec::new();
+    write_u32(&mut bytes, chunk.code.len());
+    bytes.extend_from_slice(&chunk.code);
+    for line in chunk.lines.iter() {
+        write_u32(&mut bytes, *line);
+    }
+    for (column, width) in chunk.columns.iter() {
+        write_u32(&mut bytes, *column);
+        write_u32(&mut bytes, *width);
+    }
+
+    write_u32(&mut bytes, chunk.constants.len());
+    for constant in chunk.constants.iter() {
+        match constant {
+            Value::Nil => bytes.push(NIL),
+            Value::Bool(false) => bytes.push(FALSE),
+            Value::Bool(true) => bytes.push(TRUE),
+            Value::Number(n) => {
+                bytes.push(NUMBER);
+                bytes.extend_from_slice(&n.to_le_bytes());
+            }
+            Value::Int(i) => {
+                bytes.push(INT);
+                bytes.extend_from_slice(&i.to_le_bytes());
+            }
+            Value::String(s) => {
+                bytes.push(STRING);
+                write_string(&mut bytes, s);
+            }
+            Value::StringId(id) => {
+                bytes.push(STRING_ID);
+                write_u32(&mut bytes, id.0);
+            }
+            Value::Function(id) => {
+                bytes.push(FUNCTION);
+                write_u32(&mut bytes, id.0);
+            }
+            Value::Closure(_)
+            | Value::NativeFunction(_)
+            | Value::Class(_)
+            | Value::Instance(_)
+            | Value::BoundMethod(_)
+            | Value::List(_)
+            | Value::Tuple(_)
+            | Value::Module(_)
+            | Value::Type(_)
+            | Value::Foreign(_) => return Err("Runtime values cannot be serialized".into()),
+        }
+    }
+
+    write_u32(&mut bytes, chunk.files.len());
+    for (start, name) in chunk.files.iter() {
+        write_u32(&mut bytes, *start);
+        write_string(&mut bytes, name);
+    }
+
+    write_u32(&mut bytes, chunk.locals.len());
+    for local in chunk.locals.iter() {
+        write_u32(&mut bytes, local.name.0);
+        write_u32(&mut bytes, local.slot);
+        write_u32(&mut bytes, local.start);
+        write_u32(&mut bytes, local.end);
+    }
+
+    Ok(bytes)
+}
+
+pub fn deserialize(bytes: &[u8], memory: &mut Memory) -> Result<Chunk, Box<dyn Error>> {
+    let mut reader = Reader::new(bytes);
+    let mut chunk = Chunk::new();
+
+    let len = reader.u32_len()?;
+    chunk.code = reader.take(len)?.to_vec();
+    for _ in 0..len {
+        chunk.lines.push(reader.u32()?);
+    }
+    for _ in 0..len {
+        chunk.columns.push((reader.u32()?, reader.u32()?));
+    }
+
+    for _ in 0..reader.u32()? {
+        let constant = match reader.u8()? {
+            NIL => Value::Nil,
+            FALSE => Value::Bool(false),
+            TRUE => Value::Bool(true),
+            NUMBER => Value::Number(f64::from_le_bytes(reader.take(8)?.try_into()?)),
+            INT => Value::Int(i64::from_le_bytes(reader.take(8)?.try_into()?)),
+            STRING => Value::String(memory.string_intern(reader.string()?)),
+            STRING_ID => Value::StringId(StrId(reader.u32()?)),
+            FUNCTION => Value::Function(FunctionId(reader.u32()?)),
+            tag => return Err(format!("Unknown constant tag {tag}").into()),
+        };
+        chunk.push_constant(constant);
+    }
+
+    for _ in 0..reader.u32()? {
+        let start = reader.u32()?;
+        chunk.set_file_at(start, Arc::from(reader.string()?));
+    }
+
+    let strings = memory.strings().count();
+    for _ in 0..reader.u32()? {
+        let name = reader.u32()?;
+        if name >= strings {
+            return Err("Local has an invalid name".into());
+        }
+        chunk.locals.push(LocalInfo {
+            name: StrId(name),
+            slot: reader.u32()?,
+            start: reader.u32()?,
+            end: reader.u32()?,
+        });
+    }
+
+    if !reader.is_at_end() {
+        return Err("Unexpected data after the chunk".into());
+    }
+    Ok(chunk)
+}
+
+#[derive(Clone, Copy)]
 pub struct ConstantId(pub usize);
 
 impl ConstantId {
+    pub const MAX_LONG: usize = (1 << 24) - 1;
+
     pub fn over_u8(&self) -> bool {
         self.0 > u8::MAX as usize
     }
+
+    pub fn over_u24(&self) -> bool {
+        self.0 > Self::MAX_LONG
+    }
 }
 
 impl fmt::Debug for ConstantId {
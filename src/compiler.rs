@@ -1,19 +1,100 @@
-use std::{fmt::Write, rc::Rc};
+use std::{collections::HashMap, fmt::Write, fs, rc::Rc, sync::Arc};
 
 use crate::{
-    chunk::{Chunk, ConstantId, OpCode},
+    ast::{Node, NodeKind, TreeBuilder},
+    chunk::{location, Chunk, ConstantId, OpCode, Operand},
     config::Config,
     debug::disassemble_chunk,
-    memory::{FunctionId, Memory},
+    diagnostic::{write_excerpt, CompileError, Diagnostic, Severity},
+    memory::{CompiledProgram, FunctionId, Memory},
+    messages::Message,
+    peephole::optimize,
     rc_slice::RcSlice,
-    scanner::{Scanner, Token, TokenType},
+    scanner::{Scanner, Span, Token, TokenType},
     value::Value,
-    vm::VM,
+    vm::{ErrorKind, LoxError, StackTrace, VM},
 };
 
-pub fn compile(source: Rc<str>, config: Config) -> Option<VM> {
+pub fn compile(source: Rc<str>, config: Config) -> Result<CompiledProgram, CompileError> {
+    let scanner = Scanner::init(source.clone());
+    let mut parser = Parser::new(scanner, config);
+    parser.memory.add_source(None, source);
+    parser.compile()
+}
+
+pub fn try_compile(source: Rc<str>, name: Option<&str>, config: Config) -> Result<VM, LoxError> {
+    let scanner = Scanner::init(source.clone());
+    let mut parser = Parser::new(scanner, config);
+    let name: Option<Arc<str>> = name.map(Arc::from);
+    parser.memory.add_source(name.clone(), source);
+    if let Some(name) = name {
+        parser.chunk_mut().set_file(name.clone());
+        parser.file = Some(name);
+    }
+    parser.parse();
+
+    match parser.first_error() {
+        Some(error) => Err(error),
+        None => Ok(VM::with_script(parser.memory, parser.config)),
+    }
+}
+
+pub fn compile_script(source: Rc<str>, vm: &mut VM) -> Result<FunctionId, LoxError> {
     let scanner = Scanner::init(source);
-    Parser::new(scanner, config).compile()
+    let memory = std::mem::take(&mut vm.memory);
+    let config = std::mem::take(&mut vm.config);
+    let mut parser = Parser::with_memory(scanner, config, memory);
+    parser.parse();
+
+    let error = parser.first_error();
+    vm.memory = parser.memory;
+    vm.config = parser.config;
+    match error {
+        Some(error) => Err(error),
+        None => Ok(parser.compiler.function),
+    }
+}
+
+pub fn parse_tree(source: Rc<str>, config: Config) -> Option<Node> {
+    let scanner = Scanner::init(source);
+    let mut parser = Parser::new(scanner, config);
+    parser.tree = Some(TreeBuilder::new());
+    parser.parse();
+
+    let end = parser.previous().span();
+    match parser.tree.take() {
+        Some(tree) if !parser.had_error => Some(tree.finish(end)),
+        _ => None,
+    }
+}
+
+pub fn compile_files(files: &[(&str, &str)], config: Config) -> Option<VM> {
+    let mut parser = Parser::new(Scanner::init(Rc::from("")), config);
+    for (name, source) in files {
+        let name: Arc<str> = Arc::from(*name);
+        let source: Rc<str> = Rc::from(*source);
+        parser.memory.add_source(Some(name.clone()), source.clone());
+        parser.scanner = Scanner::init(source);
+        parser.chunk_mut().set_file(name.clone());
+        parser.file = Some(name);
+        parser.panic_mode = false;
+        parser.too_deep = false;
+        parser.parse_file();
+    }
+    parser.end_compiler();
+
+    if parser.had_error {
+        None
+    } else {
+        Some(VM::with_script(parser.memory, parser.config))
+    }
+}
+
+pub fn check(source: Rc<str>, config: Config) -> Vec<Diagnostic> {
+    let scanner = Scanner::init(source);
+    let mut parser = Parser::new(scanner, config);
+    parser.parse();
+    parser.diagnostics
 }
 
 struct Parser {
@@ -25,54 +106,116 @@ struct Parser {
     previous: Option<Token>,
     had_error: bool,
     panic_mode: bool,
+    diagnostics: Vec<Diagnostic>,
+    file: Option<Arc<str>>,
+    constant_count: usize,
+    code_size: usize,
+    classes: Vec<ClassCompiler>,
+    tree: Option<TreeBuilder>,
+    origin: Option<(usize, usize)>,
+    modules: HashMap<String, FunctionId>,
+    importing: Vec<String>,
+    module: Option<FunctionId>,
+    depth: usize,
+    too_deep: bool,
+    nil_jumps: Vec<usize>,
 }
 
 impl Parser {
     fn new(scanner: Scanner, config: Config) -> Parser {
-        let mut parser = Parser {
+        Parser::with_memory(scanner, config, Memory::new())
+    }
+
+    fn with_memory(scanner: Scanner, config: Config, mut memory: Memory) -> Parser {
+        let function = memory.new_function("<script>");
+        Parser {
             config,
             scanner,
-            memory: Memory::new(),
+            memory,
             compiler: Compiler {
                 enclosing: None,
-                function: FunctionId(0),
+                function,
                 function_type: FunctionType::Script,
                 locals: vec![Local {
                     name: Token {
                         typ: TokenType::Fun,
                         line: 0,
+                        column: 0,
                         slice: RcSlice::from_string(""),
                     },
                     depth: LocalDepth::Initialized(0),
+                    constant: false,
+                    read: true,
                 }],
                 scope_depth: 0,
+                loops: Vec::new(),
+                last_call: None,
+                last_comparison: None,
+                last_jump_target: None,
             },
             current: None,
             previous: None,
             had_error: false,
             panic_mode: false,
-        };
-        parser.new_function("<script>");
-        parser
+            diagnostics: Vec::new(),
+            file: None,
+            constant_count: 0,
+            code_size: 0,
+            classes: Vec::new(),
+            tree: None,
+            origin: None,
+            modules: HashMap::new(),
+            importing: Vec::new(),
+            module: None,
+            depth: 0,
+            too_deep: false,
+            nil_jumps: Vec::new(),
+        }
     }
 
-    fn compile(mut self) -> Option<VM> {
-        self.advance();
+    fn compile(mut self) -> Result<CompiledProgram, CompileError> {
+        self.parse();
 
-        while !self.match_token(TokenType::EOF) {
-            self.declaration();
+        if self.had_error {
+            Err(CompileError {
+                diagnostics: self.diagnostics,
+            })
+        } else {
+            Ok(self.memory.into_program())
         }
+    }
 
+    fn first_error(&self) -> Option<LoxError> {
+        let diagnostic = self
+            .diagnostics
+            .iter()
+            .find(|diagnostic| diagnostic.severity == Severity::Error)?;
+        Some(LoxError {
+            kind: ErrorKind::Compile,
+            message: diagnostic.message.clone(),
+            line: diagnostic.span.line,
+            trace: StackTrace::default(),
+            value: None,
+        })
+    }
+
+    fn parse(&mut self) {
+        self.parse_file();
         self.end_compiler();
+    }
 
-        if self.had_error {
-            None
-        } else {
-            let mut vm = VM::new(self.memory, self.config);
-            let closure = vm.new_closure(FunctionId(0));
-            vm.push(Value::Closure(closure));
-            vm.call(closure, 0);
-            Some(vm)
+    fn parse_file(&mut self) {
+        self.advance();
+
+        let limit = self.config.limits.max_source_len;
+        if self.scanner.source.len() > limit {
+            self.previous = self.current.clone();
+            self.limit_error(Message::SourceTooLong, limit);
+            return;
+        }
+
+        while !self.match_token(TokenType::EOF) {
+            self.declaration();
         }
     }
 
@@ -81,30 +224,74 @@ impl Parser {
             enclosing: None,
             function: match function_type {
                 FunctionType::Script => FunctionId(0),
-                FunctionType::Function => self.memory.new_function(self.previous().slice.as_str()),
+                FunctionType::Function
+                | FunctionType::Method
+                | FunctionType::Initializer
+                | FunctionType::Module => {
+                    let limit = self.config.limits.max_functions;
+                    if self.memory.functions().count() == limit.saturating_add(1) {
+                        self.limit_error(Message::ProgramTooManyFunctions, limit);
+                    }
+                    self.memory.new_function(self.previous().slice.as_str())
+                }
             },
             function_type,
             locals: vec![Local {
                 name: Token {
                     typ: TokenType::Fun,
                     line: 0,
-                    slice: RcSlice::from_string(""),
+                    column: 0,
+                    slice: RcSlice::from_string(match function_type {
+                        FunctionType::Method | FunctionType::Initializer => "this",
+                        FunctionType::Script | FunctionType::Function | FunctionType::Module => "",
+                    }),
                 },
                 depth: LocalDepth::Initialized(0),
+                constant: false,
+                read: true,
             }],
             scope_depth: 0,
+            loops: Vec::new(),
+            last_call: None,
+            last_comparison: None,
+            last_jump_target: None,
         };
 
         let enclosing = std::mem::replace(&mut self.compiler, compiler);
 
         self.compiler.enclosing = Some(Box::new(enclosing));
+
+        if function_type == FunctionType::Module {
+            self.module = Some(self.compiler.function);
+        }
+        if function_type != FunctionType::Script {
+            self.memory.function_mut(self.compiler.function).module = self.module;
+        }
+
+        if let Some(file) = self.file.clone() {
+            self.chunk_mut().set_file(file);
+        }
+        if matches!(
+            function_type,
+            FunctionType::Method | FunctionType::Initializer
+        ) {
+            let this = self.memory.string_id("this");
+            self.chunk_mut().add_local(this, 0);
+        }
     }
 
     fn end_compiler(&mut self) -> FunctionId {
         self.emit_return();
+        self.chunk_mut().end_locals();
+        for local in self.compiler.locals.clone() {
+            self.warn_unused(local);
+        }
 
         let f_id = self.compiler.function;
-        
+        if !self.had_error {
+            optimize(self.chunk_mut());
+        }
+
         #[cfg(debug_assertions)]
         if !self.had_error {
             let f = &self.memory.function(f_id);
@@ -144,9 +331,13 @@ impl Parser {
                 self.current = Some(token);
                 break;
             } else {
-                let msg = token.into_string();
+                let message = self
+                    .scanner
+                    .error
+                    .take()
+                    .unwrap_or(Message::UnexpectedCharacter);
                 self.current = Some(token);
-                self.error_at_current(&msg);
+                self.error_at_current(message);
             }
         }
     }
@@ -164,10 +355,16 @@ impl Parser {
     }
 
     fn declaration(&mut self) {
-        if self.match_token(TokenType::Fun) {
+        if self.match_token(TokenType::Class) {
+            self.class_declaration();
+        } else if self.match_token(TokenType::Fun) {
             self.fun_declaration();
         } else if self.match_token(TokenType::Var) {
             self.var_declaration();
+        } else if self.match_token(TokenType::Const) {
+            self.const_declaration();
+        } else if self.match_token(TokenType::Import) {
+            self.import_declaration();
         } else {
             self.statement();
         }
@@ -177,14 +374,74 @@ impl Parser {
         }
     }
 
+    fn class_declaration(&mut self) {
+        let start = self.previous().span();
+        self.consume(TokenType::Identifier, Message::ExpectClassName);
+        if self.panic_mode {
+            return;
+        }
+        let name = self.previous();
+        self.open_node(NodeKind::ClassDecl, Some(name.clone()), start);
+        let name_constant = self.global_constant(name.clone());
+        self.declare_variable();
+
+        self.emit_indexed(OpCode::Class, name_constant.0);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassCompiler {
+            has_superclass: false,
+        });
+
+        if self.match_token(TokenType::Less) {
+            self.consume(TokenType::Identifier, Message::ExpectSuperclassName);
+            self.variable(false);
+            if name.string_eq(&self.previous()) {
+                self.error(Message::InheritFromSelf);
+            }
+            self.load_variable(name.clone());
+            self.emit_byte(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.load_variable(name);
+        self.consume(TokenType::LeftBrace, Message::ExpectBraceBeforeClassBody);
+        while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            self.method();
+        }
+        self.consume(TokenType::RightBrace, Message::ExpectBraceAfterClassBody);
+        self.emit_byte(OpCode::Pop);
+        self.classes.pop();
+        self.close_node();
+    }
+
+    fn method(&mut self) {
+        let start = self.current().span();
+        self.consume(TokenType::Identifier, Message::ExpectMethodName);
+        let name = self.previous();
+        self.open_node(NodeKind::Method, Some(name.clone()), start);
+        let constant = self.identifier_constant(name.clone());
+
+        let function_type = if name.slice.as_str() == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+        self.emit_indexed(OpCode::Method, constant);
+        self.close_node();
+    }
+
     fn fun_declaration(&mut self) {
-        let global = self.parse_variable("Expect function name");
+        let start = self.previous().span();
+        let global = self.parse_variable(Message::ExpectFunctionName);
+        self.open_node(NodeKind::FunDecl, Some(self.previous()), start);
 
         self.mark_initialized();
 
         self.function(FunctionType::Function);
 
         self.define_variable(global);
+        self.close_node();
     }
 
     fn mark_initialized(&mut self) {
@@ -192,47 +449,168 @@ impl Parser {
             return;
         }
 
-        if let Some(x) = self.compiler.locals.last_mut() {
-            x.initialize(self.compiler.scope_depth)
+        if let Some(slot) = self.compiler.locals.len().checked_sub(1) {
+            self.initialize_local(slot);
         }
     }
 
+    fn initialize_local(&mut self, slot: usize) {
+        let local = &mut self.compiler.locals[slot];
+        local.initialize(self.compiler.scope_depth);
+        let name = local.name.slice.as_str().to_owned();
+        let name = self.memory.string_id(&name);
+        self.chunk_mut().add_local(name, slot);
+    }
+
     fn function(&mut self, function_type: FunctionType) {
         self.init_compiler(function_type);
 
         self.begin_scope();
 
-        self.consume(TokenType::LeftParen, "Expect '(' after function name");
+        self.consume(TokenType::LeftParen, Message::ExpectParenAfterFunctionName);
         if !self.check(TokenType::RightParen) {
             let mut arity = 0;
+            let mut min_arity = None;
             loop {
                 arity += 1;
                 if arity > 255 {
-                    self.error_at_current("Can't have more than 255 parameters");
+                    self.error_at_current(Message::TooManyParameters);
                 }
-                let constant = self.parse_variable("Expect parameter name");
+                let rest = self.match_token(TokenType::Ellipsis);
+                let constant = self.parse_variable(Message::ExpectParameterName);
+                self.leaf_node(NodeKind::Param, self.previous());
                 self.define_variable(constant);
 
+                if rest {
+                    min_arity.get_or_insert(arity - 1);
+                    self.memory.function_mut(self.compiler.function).variadic = true;
+                    break;
+                } else if self.match_token(TokenType::Equal) {
+                    min_arity.get_or_insert(arity - 1);
+                    self.default_parameter(arity - 1);
+                } else if min_arity.is_some() {
+                    self.error(Message::ExpectDefaultParameter);
+                }
+
                 if !self.match_token(TokenType::Comma) {
                     break;
                 }
             }
-            self.memory.function_mut(self.compiler.function).arity = arity;
+            let function = self.memory.function_mut(self.compiler.function);
+            function.arity = arity;
+            function.min_arity = min_arity.unwrap_or(arity);
+            for local in self.compiler.locals.iter_mut() {
+                local.read = true;
+            }
         }
-        self.consume(TokenType::RightParen, "Expect ')' after parameters");
-        self.consume(TokenType::LeftBrace, "Expect '{' before function body");
+        self.consume(TokenType::RightParen, Message::ExpectParenAfterParameters);
+        self.consume(TokenType::LeftBrace, Message::ExpectBraceBeforeFunctionBody);
 
         self.block();
 
         let f = self.end_compiler();
 
         let constant = self.make_constant(Value::Function(f));
-        self.emit_bytes(OpCode::Closure, constant)
+        self.emit_indexed(OpCode::Closure, constant.0)
+    }
+
+    fn default_parameter(&mut self, index: usize) {
+        let slot = self.compiler.locals.len() - 1;
+        self.emit_bytes(OpCode::ArgMissing, index as u8);
+        let given_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.expression();
+        self.emit_indexed(OpCode::SetLocal, slot);
+        self.emit_byte(OpCode::Pop);
+        let end_jump = self.emit_jump(OpCode::Jump);
+        self.patch_jump(given_jump);
+        self.emit_byte(OpCode::Pop);
+        self.patch_jump(end_jump);
     }
 
     fn call(&mut self) {
+        self.wrap_node(NodeKind::Call, None);
         let arg_count = self.argument_list();
-        self.emit_bytes(OpCode::Call, arg_count)
+        self.emit_bytes(OpCode::Call, arg_count);
+        self.compiler.last_call = Some(self.chunk().code.len() - 2);
+        self.close_node();
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(TokenType::Identifier, Message::ExpectPropertyName);
+        let name = self.previous();
+        let constant = self.identifier_constant(name.clone());
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.wrap_node(NodeKind::Set, Some(name));
+            self.expression();
+            self.emit_indexed(OpCode::SetProperty, constant);
+        } else if can_assign && self.match_token(TokenType::PlusEqual) {
+            self.wrap_node(NodeKind::Set, Some(name));
+            self.emit_bytes(OpCode::Dup, 1);
+            self.emit_indexed(OpCode::GetProperty, constant);
+            self.expression();
+            self.emit_byte(OpCode::Add);
+            self.emit_indexed(OpCode::SetProperty, constant);
+        } else if self.check(TokenType::LeftParen) {
+            self.wrap_node(NodeKind::Get, Some(name));
+            self.close_node();
+            self.advance();
+            self.wrap_node(NodeKind::Call, None);
+            let arg_count = self.argument_list();
+            self.emit_indexed(OpCode::Invoke, constant);
+            self.emit_byte(arg_count);
+        } else {
+            self.wrap_node(NodeKind::Get, Some(name));
+            self.emit_indexed(OpCode::GetProperty, constant);
+        }
+        self.close_node();
+    }
+
+    fn list(&mut self) {
+        self.open_node(NodeKind::List, None, self.previous().span());
+        let mut count = 0;
+        if !self.check(TokenType::RightBracket) {
+            loop {
+                self.expression();
+                if count == u8::MAX {
+                    self.error(Message::TooManyElements);
+                } else {
+                    count += 1;
+                }
+
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(TokenType::RightBracket, Message::ExpectBracketAfterElements);
+        self.emit_bytes(OpCode::BuildList, count);
+        self.close_node();
+    }
+
+    fn index(&mut self, can_assign: bool) {
+        self.wrap_node(NodeKind::Index, None);
+        self.expression();
+        self.consume(TokenType::RightBracket, Message::ExpectBracketAfterIndex);
+        self.close_node();
+
+        if can_assign && self.match_token(TokenType::Equal) {
+            self.wrap_node(NodeKind::IndexSet, None);
+            self.expression();
+            self.emit_byte(OpCode::IndexSet);
+            self.close_node();
+        } else if can_assign && self.match_token(TokenType::PlusEqual) {
+            self.wrap_node(NodeKind::IndexSet, None);
+            self.emit_bytes(OpCode::Dup, 2);
+            self.emit_byte(OpCode::IndexGet);
+            self.expression();
+            self.emit_byte(OpCode::Add);
+            self.emit_byte(OpCode::IndexSet);
+            self.close_node();
+        } else {
+            self.emit_byte(OpCode::IndexGet);
+        }
     }
 
     fn argument_list(&mut self) -> u8 {
@@ -241,21 +619,27 @@ impl Parser {
             loop {
                 self.expression();
                 if arg_count == u8::MAX {
-                    self.error("Can't have more than 255 arguments");
+                    self.error(Message::TooManyArguments);
+                } else {
+                    arg_count += 1;
                 }
-                arg_count += 1;
 
                 if !self.match_token(TokenType::Comma) {
                     break;
                 }
             }
         }
-        self.consume(TokenType::RightParen, "Expect ')' after arguments");
+        self.consume(TokenType::RightParen, Message::ExpectParenAfterArguments);
         arg_count
     }
 
     fn var_declaration(&mut self) {
-        let addr = self.parse_variable("Expect variable name");
+        let start = self.previous().span();
+        if self.match_token(TokenType::LeftParen) {
+            return self.var_destructuring(start);
+        }
+        let addr = self.parse_variable(Message::ExpectVariableName);
+        self.open_node(NodeKind::VarDecl, Some(self.previous()), start);
 
         if self.match_token(TokenType::Equal) {
             self.expression();
@@ -265,88 +649,267 @@ impl Parser {
 
         self.consume(
             TokenType::SemiColon,
-            "Expect ';' after variable declaration",
+            Message::ExpectSemicolonAfterVariableDeclaration,
         );
 
         self.define_variable(addr);
+        self.close_node();
+    }
+
+    fn var_destructuring(&mut self, start: Span) {
+        self.open_node(NodeKind::VarDecl, None, start);
+        let mut addrs = Vec::new();
+        loop {
+            addrs.push(self.parse_variable(Message::ExpectVariableName));
+            if addrs.len() > u8::MAX as usize {
+                self.error(Message::TooManyElements);
+            }
+            if !self.match_token(TokenType::Comma) {
+                break;
+            }
+        }
+        self.consume(TokenType::RightParen, Message::ExpectParenAfterNames);
+        self.consume(TokenType::Equal, Message::ExpectEqualAfterPattern);
+        self.expression();
+        self.consume(
+            TokenType::SemiColon,
+            Message::ExpectSemicolonAfterVariableDeclaration,
+        );
+
+        self.emit_bytes(OpCode::Unpack, addrs.len() as u8);
+        if self.compiler.scope_depth > 0 {
+            let first = self.compiler.locals.len().saturating_sub(addrs.len());
+            for slot in first..self.compiler.locals.len() {
+                self.initialize_local(slot);
+            }
+        } else {
+            for addr in addrs.into_iter().rev() {
+                self.emit_indexed(OpCode::DefineGlobal, addr.0);
+            }
+        }
+        self.close_node();
+    }
+
+    fn const_declaration(&mut self) {
+        let start = self.previous().span();
+        let addr = self.parse_variable(Message::ExpectVariableName);
+        self.open_node(NodeKind::ConstDecl, Some(self.previous()), start);
+        if self.compiler.scope_depth > 0 {
+            if let Some(local) = self.compiler.locals.last_mut() {
+                local.constant = true;
+            }
+        }
+
+        self.consume(TokenType::Equal, Message::ExpectEqualAfterConstantName);
+        self.expression();
+        self.consume(
+            TokenType::SemiColon,
+            Message::ExpectSemicolonAfterVariableDeclaration,
+        );
+
+        if self.compiler.scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            self.emit_indexed(OpCode::DefineConst, addr.0)
+        }
+        self.close_node();
+    }
+
+    fn import_declaration(&mut self) {
+        let start = self.previous().span();
+        let addr = self.parse_variable(Message::ExpectModuleName);
+        let name = self.previous();
+        self.open_node(NodeKind::ImportDecl, Some(name.clone()), start);
+
+        let module = self.compile_module(name);
+        self.consume(TokenType::SemiColon, Message::ExpectSemicolonAfterImport);
+
+        if let Some(module) = module {
+            let constant = self.make_constant(Value::Function(module));
+            self.emit_indexed(OpCode::Import, constant.0);
+            self.emit_byte(OpCode::Pop);
+        }
+        self.define_variable(addr);
+        self.close_node();
+    }
+
+    fn compile_module(&mut self, name: Token) -> Option<FunctionId> {
+        let module = name.into_string();
+        if let Some(function) = self.modules.get(&module) {
+            return Some(*function);
+        }
+        if self.importing.contains(&module) {
+            self.error_at(name, Message::CircularImport, &[("name", &module)]);
+            return None;
+        }
+
+        let source = match self.config.modules.get(&module) {
+            Some(source) => source.clone(),
+            None => {
+                let path = self
+                    .config
+                    .module_dir
+                    .as_ref()
+                    .map(|dir| dir.join(format!("{module}.lox")));
+                match path.and_then(|path| fs::read_to_string(path).ok()) {
+                    Some(source) => Rc::from(source),
+                    None => {
+                        self.error_at(name, Message::ModuleNotFound, &[("name", &module)]);
+                        return None;
+                    }
+                }
+            }
+        };
+
+        let file: Arc<str> = Arc::from(format!("{module}.lox"));
+        self.memory.add_source(Some(file.clone()), source.clone());
+        let scanner = std::mem::replace(&mut self.scanner, Scanner::init(source));
+        let file = self.file.replace(file);
+        let tree = self.tree.take();
+        let panic_mode = std::mem::take(&mut self.panic_mode);
+        let enclosing = self.module;
+        self.importing.push(module.clone());
+
+        self.init_compiler(FunctionType::Module);
+        let (current, previous) = (self.current.take(), self.previous.take());
+        self.parse_file();
+        let function = self.end_compiler();
+
+        self.importing.pop();
+        self.module = enclosing;
+        self.panic_mode = panic_mode;
+        self.tree = tree;
+        self.file = file;
+        self.scanner = scanner;
+        self.current = current;
+        self.previous = previous;
+
+        self.modules.insert(module, function);
+        Some(function)
     }
 
     fn statement(&mut self) {
+        if !self.enter_nesting() {
+            return;
+        }
         if self.match_token(TokenType::Print) {
             self.print_statement();
         } else if self.match_token(TokenType::If) {
             self.if_statement();
         } else if self.match_token(TokenType::Return) {
             self.return_statement();
+        } else if self.match_token(TokenType::Throw) {
+            self.throw_statement();
+        } else if self.match_token(TokenType::Assert) {
+            self.assert_statement();
         } else if self.match_token(TokenType::While) {
             self.while_statement();
         } else if self.match_token(TokenType::For) {
             self.for_statement();
+        } else if self.match_token(TokenType::Break) {
+            self.break_statement();
+        } else if self.match_token(TokenType::Continue) {
+            self.continue_statement();
         } else if self.match_token(TokenType::LeftBrace) {
+            // `statement` has already counted this level
             self.begin_scope();
-            self.block();
+            self.block_body();
             self.end_scope();
         } else {
             self.expression_statement();
         }
+        self.depth -= 1;
     }
 
     fn return_statement(&mut self) {
-        if self.compiler.function_type == FunctionType::Script {
-            self.error("Can't return from top-level code")
+        self.open_node(NodeKind::ReturnStmt, None, self.previous().span());
+        if matches!(
+            self.compiler.function_type,
+            FunctionType::Script | FunctionType::Module
+        ) {
+            self.error(Message::ReturnFromTopLevel)
         }
 
         if self.match_token(TokenType::SemiColon) {
             self.emit_return();
         } else {
+            if self.compiler.function_type == FunctionType::Initializer {
+                self.error(Message::ReturnFromInitializer);
+            }
             self.expression();
-            self.consume(TokenType::SemiColon, "Expect ':' after return value");
+            self.consume(
+                TokenType::SemiColon,
+                Message::ExpectSemicolonAfterReturnValue,
+            );
+            let end = self.chunk().code.len();
+            if let Some(call) = self.compiler.last_call.filter(|call| call + 2 == end) {
+                self.chunk_mut().code[call] = OpCode::TailCall as u8;
+            }
             self.emit_byte(OpCode::Return);
         }
+        self.close_node();
     }
 
     fn if_statement(&mut self) {
-        self.consume(TokenType::LeftParen, "Expect '(' after 'if'");
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after condition");
+        self.open_node(NodeKind::IfStmt, None, self.previous().span());
+        self.consume(TokenType::LeftParen, Message::ExpectParenAfterIf);
+        self.condition(false);
+        self.consume(TokenType::RightParen, Message::ExpectParenAfterCondition);
 
-        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let (then_jump, pop_condition) = self.emit_condition_jump();
 
-        self.emit_byte(OpCode::Pop);
+        if pop_condition {
+            self.emit_byte(OpCode::Pop);
+        }
 
         self.statement();
         let else_jump = self.emit_jump(OpCode::Jump);
 
         self.patch_jump(then_jump);
 
-        self.emit_byte(OpCode::Pop);
+        if pop_condition {
+            self.emit_byte(OpCode::Pop);
+        }
 
         if self.match_token(TokenType::Else) {
             self.statement();
         }
 
         self.patch_jump(else_jump);
+        self.close_node();
     }
 
     fn while_statement(&mut self) {
+        self.open_node(NodeKind::WhileStmt, None, self.previous().span());
         let loop_start = self.chunk().code.len();
-        self.consume(TokenType::LeftParen, "Expect '(' after 'while'");
-        self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after condition");
+        self.consume(TokenType::LeftParen, Message::ExpectParenAfterWhile);
+        self.condition(true);
+        self.consume(TokenType::RightParen, Message::ExpectParenAfterCondition);
 
-        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
-        self.emit_byte(OpCode::Pop);
-        self.statement();
+        let (exit_jump, pop_condition) = self.emit_condition_jump();
+        if pop_condition {
+            self.emit_byte(OpCode::Pop);
+        }
+        self.loop_body(loop_start);
 
         self.emit_loop(loop_start);
 
         self.patch_jump(exit_jump);
-        self.emit_byte(OpCode::Pop);
+        if pop_condition {
+            self.emit_byte(OpCode::Pop);
+        }
+        self.patch_breaks();
+        self.close_node();
     }
 
     fn for_statement(&mut self) {
+        if self.at_foreach() {
+            return self.foreach_statement();
+        }
+
+        self.open_node(NodeKind::ForStmt, None, self.previous().span());
         self.begin_scope();
-        self.consume(TokenType::LeftParen, "Expect '(' after 'for'");
+        self.consume(TokenType::LeftParen, Message::ExpectParenAfterFor);
 
         if !self.match_token(TokenType::SemiColon) {
             if self.match_token(TokenType::Var) {
@@ -359,11 +922,17 @@ impl Parser {
         let mut loop_start = self.chunk().code.len();
         let mut exit_jump = None;
         if !self.match_token(TokenType::SemiColon) {
-            self.expression();
-            self.consume(TokenType::SemiColon, "Expect ';' after loop");
+            self.condition(true);
+            self.consume(
+                TokenType::SemiColon,
+                Message::ExpectSemicolonAfterLoopCondition,
+            );
 
-            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
-            self.emit_byte(OpCode::Pop); // pop the condition
+            let (jump, pop_condition) = self.emit_condition_jump();
+            exit_jump = Some((jump, pop_condition));
+            if pop_condition {
+                self.emit_byte(OpCode::Pop); // pop the condition
+            }
         }
 
         if !self.match_token(TokenType::RightParen) {
@@ -372,41 +941,184 @@ impl Parser {
 
             self.expression();
             self.emit_byte(OpCode::Pop); // pop the increment expression
-            self.consume(TokenType::RightParen, "Expect ')' after for clauses");
+            self.consume(TokenType::RightParen, Message::ExpectParenAfterForClauses);
 
             self.emit_loop(loop_start);
             loop_start = increment_start;
             self.patch_jump(body_jump);
         }
 
-        self.statement();
+        self.loop_body(loop_start);
         self.emit_loop(loop_start);
 
-        if let Some(exit_jump) = exit_jump {
+        if let Some((exit_jump, pop_condition)) = exit_jump {
             self.patch_jump(exit_jump);
-            self.emit_byte(OpCode::Pop); // pop the condition again
+            if pop_condition {
+                self.emit_byte(OpCode::Pop); // pop the condition again
+            }
         }
+        self.patch_breaks();
+
+        self.end_scope();
+        self.close_node();
+    }
+
+    fn at_foreach(&self) -> bool {
+        use TokenType::*;
+        let mut scanner = self.scanner.clone();
+        let mut next = || scanner.token().typ;
+        self.check(LeftParen)
+            && match next() {
+                Identifier => next() == In,
+                Var => next() == Identifier && next() == In,
+                _ => false,
+            }
+    }
+
+    fn foreach_statement(&mut self) {
+        self.open_node(NodeKind::ForEachStmt, None, self.previous().span());
+        self.begin_scope();
+        self.consume(TokenType::LeftParen, Message::ExpectParenAfterFor);
+        self.match_token(TokenType::Var);
+        self.consume(TokenType::Identifier, Message::ExpectVariableName);
+        let name = self.previous();
+        self.leaf_node(NodeKind::VarDecl, name.clone());
+        self.consume(TokenType::In, Message::ExpectInAfterLoopVariable);
+
+        self.expression();
+        self.consume(TokenType::RightParen, Message::ExpectParenAfterForClauses);
+        let sequence = self.hidden_local(" sequence");
+        self.emit_constant(Value::Number(0.0));
+        let index = self.hidden_local(" index");
+
+        let loop_start = self.chunk().code.len();
+        self.emit_indexed(OpCode::GetLocal, index);
+        self.emit_indexed(OpCode::GetLocal, sequence);
+        self.emit_byte(OpCode::Len);
+        let exit_jump = self.emit_jump(OpCode::JumpIfNotLess);
+        let body_jump = self.emit_jump(OpCode::Jump);
+
+        let increment_start = self.chunk().code.len();
+        self.emit_indexed(OpCode::GetLocal, index);
+        self.emit_constant(Value::Number(1.0));
+        self.emit_byte(OpCode::Add);
+        self.emit_indexed(OpCode::SetLocal, index);
+        self.emit_byte(OpCode::Pop);
+        self.emit_loop(loop_start);
+        self.patch_jump(body_jump);
+
+        self.begin_loop(increment_start);
+        self.begin_scope();
+        self.emit_indexed(OpCode::GetLocal, sequence);
+        self.emit_indexed(OpCode::GetLocal, index);
+        self.emit_byte(OpCode::IndexGet);
+        self.add_local(name);
+        self.mark_initialized();
+        self.statement();
+        self.end_scope();
+        self.emit_loop(increment_start);
+
+        self.patch_jump(exit_jump);
+        self.patch_breaks();
 
         self.end_scope();
+        self.close_node();
     }
 
-    fn define_variable(&mut self, addr: u8) {
+    fn hidden_local(&mut self, name: &str) -> usize {
+        let token = Token {
+            typ: TokenType::Identifier,
+            line: self.previous().line,
+            column: 0,
+            slice: RcSlice::from_string(name),
+        };
+        self.add_local(token);
+        self.mark_initialized();
+        self.compiler.locals.len() - 1
+    }
+
+    fn begin_loop(&mut self, start: usize) {
+        self.compiler.loops.push(Loop {
+            start,
+            scope_depth: self.compiler.scope_depth,
+            breaks: Vec::new(),
+        });
+    }
+
+    fn loop_body(&mut self, start: usize) {
+        self.begin_loop(start);
+        self.statement();
+    }
+
+    fn patch_breaks(&mut self) {
+        let lp = self.compiler.loops.pop().unwrap();
+        for jump in lp.breaks {
+            self.patch_jump(jump);
+        }
+    }
+
+    fn break_statement(&mut self) {
+        self.open_node(NodeKind::BreakStmt, None, self.previous().span());
+        self.consume(TokenType::SemiColon, Message::ExpectSemicolonAfterBreak);
+        match self.compiler.loops.last() {
+            Some(lp) => {
+                self.pop_locals_above(lp.scope_depth);
+                let jump = self.emit_jump(OpCode::Jump);
+                self.compiler.loops.last_mut().unwrap().breaks.push(jump);
+            }
+            None => self.error(Message::BreakOutsideLoop),
+        }
+        self.close_node();
+    }
+
+    fn continue_statement(&mut self) {
+        self.open_node(NodeKind::ContinueStmt, None, self.previous().span());
+        self.consume(TokenType::SemiColon, Message::ExpectSemicolonAfterContinue);
+        match self.compiler.loops.last() {
+            Some(lp) => {
+                let start = lp.start;
+                self.pop_locals_above(lp.scope_depth);
+                self.emit_loop(start);
+            }
+            None => self.error(Message::ContinueOutsideLoop),
+        }
+        self.close_node();
+    }
+
+    fn pop_locals_above(&mut self, depth: usize) {
+        let to_pop = self
+            .compiler
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| match local.depth {
+                LocalDepth::Uninitialized => true,
+                LocalDepth::Initialized(d) => d > depth,
+            })
+            .count();
+
+        for _ in 0..to_pop {
+            self.emit_byte(OpCode::Pop);
+        }
+    }
+
+    fn define_variable(&mut self, addr: ConstantId) {
         if self.compiler.scope_depth > 0 {
             self.mark_initialized();
         } else {
-            self.emit_bytes(OpCode::DefineGlobal as u8, addr)
+            self.emit_indexed(OpCode::DefineGlobal, addr.0)
         }
     }
 
-    fn parse_variable(&mut self, error: &str) -> u8 {
+    fn parse_variable(&mut self, error: Message) -> ConstantId {
         self.consume(TokenType::Identifier, error);
 
         self.declare_variable();
         if self.compiler.scope_depth > 0 {
-            return 0;
+            return ConstantId(0);
         }
 
-        self.identifier_constant(self.previous())
+        self.global_constant(self.previous())
     }
 
     fn declare_variable(&mut self) {
@@ -428,34 +1140,71 @@ impl Parser {
             .any(|local| local.name.string_eq(&name));
 
         if existing {
-            self.error("A variable with this name already exists in this scope");
+            self.error(Message::VariableAlreadyDeclared);
         }
 
         self.add_local(name);
     }
 
     fn add_local(&mut self, name: Token) {
-        match self.compiler.add_local(name) {
-            Err(e) => self.error(e),
-            _ => ()
+        if let Err(e) = self.compiler.add_local(name) {
+            self.error(e)
         }
     }
 
-    fn identifier_constant(&mut self, token: Token) -> u8 {
+    fn identifier_constant(&mut self, token: Token) -> usize {
+        self.global_constant(token).0
+    }
+
+    fn global_constant(&mut self, token: Token) -> ConstantId {
         let value = self.make_string_id(token.into_string());
         self.make_constant(value)
     }
 
     fn print_statement(&mut self) {
+        self.open_node(NodeKind::PrintStmt, None, self.previous().span());
         self.expression();
-        self.consume(TokenType::SemiColon, "Expect ';' after value");
+        self.consume(TokenType::SemiColon, Message::ExpectSemicolonAfterValue);
         self.emit_byte(OpCode::Print);
+        self.close_node();
+    }
+
+    fn throw_statement(&mut self) {
+        self.open_node(NodeKind::ThrowStmt, None, self.previous().span());
+        self.expression();
+        self.consume(TokenType::SemiColon, Message::ExpectSemicolonAfterThrow);
+        self.emit_byte(OpCode::Throw);
+        self.close_node();
+    }
+
+    fn assert_statement(&mut self) {
+        self.open_node(NodeKind::AssertStmt, None, self.previous().span());
+        self.consume(TokenType::LeftParen, Message::ExpectParenAfterAssert);
+        let start = self.current().span();
+        self.expression();
+        let condition = start.to(self.previous().span());
+        if self.match_token(TokenType::Comma) {
+            self.expression();
+        } else {
+            let text = self.scanner.source.get(condition.start..condition.end);
+            let message = self.make_string(text.unwrap_or_default().to_owned());
+            self.emit_constant(message);
+        }
+        self.consume(TokenType::RightParen, Message::ExpectParenAfterAssertion);
+        self.consume(TokenType::SemiColon, Message::ExpectSemicolonAfterAssertion);
+        self.emit_byte(OpCode::Assert);
+        self.close_node();
     }
 
     fn expression_statement(&mut self) {
+        self.open_node(NodeKind::ExprStmt, None, self.current().span());
         self.expression();
-        self.consume(TokenType::SemiColon, "Expect ';' after expression");
+        self.consume(
+            TokenType::SemiColon,
+            Message::ExpectSemicolonAfterExpression,
+        );
         self.emit_byte(OpCode::Pop);
+        self.close_node();
     }
 
     fn begin_scope(&mut self) {
@@ -463,11 +1212,31 @@ impl Parser {
     }
 
     fn block(&mut self) {
+        if !self.enter_nesting() {
+            return;
+        }
+        self.block_body();
+        self.depth -= 1;
+    }
+
+    fn block_body(&mut self) {
+        self.open_node(NodeKind::Block, None, self.previous().span());
+        let mut terminated = false;
         while !self.check(TokenType::RightBrace) && !self.check(TokenType::EOF) {
+            if terminated && self.config.warnings.unreachable_code {
+                self.warning(self.current(), Message::UnreachableCode, &[]);
+                terminated = false;
+            }
+            let exits = matches!(
+                self.current().typ,
+                TokenType::Return | TokenType::Throw | TokenType::Break | TokenType::Continue
+            );
             self.declaration();
+            terminated |= exits;
         }
 
-        self.consume(TokenType::RightBrace, "Expect '}' after block");
+        self.consume(TokenType::RightBrace, Message::ExpectBraceAfterBlock);
+        self.close_node();
     }
 
     fn end_scope(&mut self) {
@@ -485,8 +1254,12 @@ impl Parser {
         self.compiler.scope_depth -= 1;
 
         for _ in 0..to_pop {
+            let slot = self.compiler.locals.len() - 1;
+            self.chunk_mut().end_local(slot);
             self.emit_byte(OpCode::Pop);
-            self.compiler.locals.pop();
+            if let Some(local) = self.compiler.locals.pop() {
+                self.warn_unused(local);
+            }
         }
     }
 
@@ -494,29 +1267,91 @@ impl Parser {
         self.parse_precedence(Precedence::Assignment);
     }
 
+    fn condition(&mut self, looping: bool) {
+        use TokenType::*;
+        let first = self.current();
+        self.expression();
+
+        if !self.config.warnings.constant_conditions || self.previous().span() != first.span() {
+            return;
+        }
+        let value = match first.typ {
+            False | Nil => "false",
+            True if looping => return,
+            True | Number | String => "true",
+            _ => return,
+        };
+        self.warning(first, Message::ConstantCondition, &[("value", value)]);
+    }
+
     fn number(&mut self) {
-        let value: f64 = self.previous().slice.parse().unwrap();
+        let digits = self.previous().slice.replace('_', "");
+        let (digits, radix) = match digits.get(..2) {
+            Some("0x" | "0X") => (&digits[2..], 16),
+            Some("0b" | "0B") => (&digits[2..], 2),
+            _ => (digits.as_str(), 10),
+        };
+        self.leaf_node(NodeKind::Literal, self.previous());
+
+        let integer = radix != 10 || !digits.contains(['.', 'e', 'E']);
+        if self.config.integers && integer {
+            if let Ok(value) = i64::from_str_radix(digits, radix) {
+                self.emit_constant(Value::Int(value));
+                return;
+            }
+        }
+
+        let value = match radix {
+            10 => digits.parse().ok(),
+            _ => parse_radix(digits, radix),
+        };
+        let Some(value) = value else {
+            self.error(Message::InvalidNumber);
+            return;
+        };
 
         self.emit_constant(Value::Number(value));
     }
 
     fn unary(&mut self) {
         let op_type = self.previous().typ;
+        self.open_node(
+            NodeKind::Unary,
+            Some(self.previous()),
+            self.previous().span(),
+        );
 
+        let origin = column(&self.previous());
         self.parse_precedence(Precedence::Unary);
+        self.close_node();
 
+        self.origin = Some(origin);
         match op_type {
             TokenType::Minus => self.emit_byte(OpCode::Negate),
             TokenType::Bang => self.emit_byte(OpCode::Not),
             _ => (),
         }
+        self.origin = None;
     }
 
     fn binary(&mut self) {
         let op_type = self.previous().typ;
         let rule = self.get_rule(op_type);
+        self.wrap_node(NodeKind::Binary, Some(self.previous()));
 
+        let origin = column(&self.previous());
         self.parse_precedence(rule.precedence.next());
+        self.close_node();
+        self.origin = Some(origin);
+
+        let start = self.chunk().code.len();
+        let fused = match op_type {
+            TokenType::Greater => Some(OpCode::JumpIfNotGreater),
+            TokenType::GreaterEqual => Some(OpCode::JumpIfLess),
+            TokenType::Less => Some(OpCode::JumpIfNotLess),
+            TokenType::LessEqual => Some(OpCode::JumpIfGreater),
+            _ => None,
+        };
 
         match op_type {
             TokenType::BangEqual => self.emit_bytes(OpCode::Equal, OpCode::Not),
@@ -529,63 +1364,27 @@ impl Parser {
             TokenType::Minus => self.emit_byte(OpCode::Subtract),
             TokenType::Star => self.emit_byte(OpCode::Multiply),
             TokenType::Slash => self.emit_byte(OpCode::Divide),
+            TokenType::Percent => self.emit_byte(OpCode::Modulo),
+            TokenType::TildeSlash => self.emit_byte(OpCode::FloorDivide),
+            TokenType::Is => self.emit_byte(OpCode::TypeCheck),
             _ => (),
         }
+        self.origin = None;
+        let end = self.chunk().code.len();
+        self.compiler.last_comparison = fused.map(|op_code| (start, end, op_code, origin));
     }
 
     fn get_rule(&self, op_type: TokenType) -> ParseRule {
-        use Precedence::*;
-        use TokenType::*;
-        match op_type {
-            LeftParen => ParseRule::prec(Precedence::Call)
-                .prefix(|p, _| p.grouping())
-                .infix(|p| p.call()),
-            RightParen => ParseRule::new(),
-            LeftBrace => ParseRule::new(),
-            RightBrace => ParseRule::new(),
-            Comma => ParseRule::new(),
-            Dot => ParseRule::new(),
-            Minus => ParseRule::prec(Term)
-                .prefix(|p, _| p.unary())
-                .infix(|p| p.binary()),
-            Plus => ParseRule::prec(Term).infix(|p| p.binary()),
-            SemiColon => ParseRule::new(),
-            Slash => ParseRule::prec(Factor).infix(|p| p.binary()),
-            Star => ParseRule::prec(Factor).infix(|p| p.binary()),
-            Bang => ParseRule::new().prefix(|p, _| p.unary()),
-            BangEqual => ParseRule::prec(Equality).infix(|p| p.binary()),
-            Equal => ParseRule::new(),
-            EqualEqual => ParseRule::prec(Equality).infix(|p| p.binary()),
-            Greater => ParseRule::prec(Comparison).infix(|p| p.binary()),
-            GreaterEqual => ParseRule::prec(Comparison).infix(|p| p.binary()),
-            Less => ParseRule::prec(Comparison).infix(|p| p.binary()),
-            LessEqual => ParseRule::prec(Comparison).infix(|p| p.binary()),
-            Identifier => ParseRule::new().prefix(|p, can_assign| p.variable(can_assign)),
-            String => ParseRule::new().prefix(|p, _| p.string()),
-            Number => ParseRule::new().prefix(|p, _| p.number()),
-            TokenType::And => ParseRule::prec(Precedence::And).infix(|p| p.and()),
-            Class => ParseRule::new(),
-            Else => ParseRule::new(),
-            False => ParseRule::new().prefix(|p, _| p.literal()),
-            For => ParseRule::new(),
-            Fun => ParseRule::new(),
-            If => ParseRule::new(),
-            Nil => ParseRule::new().prefix(|p, _| p.literal()),
-            TokenType::Or => ParseRule::prec(Precedence::Or).infix(|p| p.or()),
-            Print => ParseRule::new(),
-            Return => ParseRule::new(),
-            Super => ParseRule::new(),
-            This => ParseRule::new(),
-            True => ParseRule::new().prefix(|p, _| p.literal()),
-            Var => ParseRule::new(),
-            While => ParseRule::new(),
-            Error => ParseRule::new(),
-            EOF => ParseRule::new(),
-        }
+        RULES[op_type as usize]
     }
 
     fn string(&mut self) {
         let str = String::from(self.previous().slice.trim_matches('\"'));
+        let limit = self.config.limits.max_string_len;
+        if str.len() > limit {
+            self.limit_error(Message::StringTooLong, limit);
+        }
+        self.leaf_node(NodeKind::Literal, self.previous());
         let obj = self.make_string(str);
         self.emit_constant(obj)
     }
@@ -601,6 +1400,7 @@ impl Parser {
     }
 
     fn literal(&mut self) {
+        self.leaf_node(NodeKind::Literal, self.previous());
         match self.previous().typ {
             TokenType::False => self.emit_byte(OpCode::False),
             TokenType::Nil => self.emit_byte(OpCode::Nil),
@@ -609,43 +1409,156 @@ impl Parser {
         }
     }
 
+    fn this(&mut self) {
+        if self.classes.is_empty() {
+            self.error(Message::ThisOutsideClass);
+            return;
+        }
+        self.leaf_node(NodeKind::This, self.previous());
+        self.load_variable(self.previous());
+    }
+
+    fn super_(&mut self) {
+        match self.classes.last() {
+            None => self.error(Message::SuperOutsideClass),
+            Some(class) if !class.has_superclass => self.error(Message::SuperWithoutSuperclass),
+            Some(_) => (),
+        }
+        let this = Token {
+            typ: TokenType::This,
+            line: self.previous().line,
+            column: 0,
+            slice: RcSlice::from_string("this"),
+        };
+
+        self.consume(TokenType::Dot, Message::ExpectDotAfterSuper);
+        self.consume(TokenType::Identifier, Message::ExpectSuperclassMethodName);
+        let name = self.previous();
+        let constant = self.identifier_constant(name.clone());
+        self.leaf_node(NodeKind::Super, name);
+
+        self.load_variable(this);
+        if self.match_token(TokenType::LeftParen) {
+            self.wrap_node(NodeKind::Call, None);
+            let arg_count = self.argument_list();
+            self.emit_indexed(OpCode::SuperInvoke, constant);
+            self.emit_byte(arg_count);
+            self.close_node();
+        } else {
+            self.emit_indexed(OpCode::GetSuper, constant);
+        }
+    }
+
+    fn load_variable(&mut self, name: Token) {
+        if let Some(arg) = self.resolve_local(&name) {
+            self.compiler.locals[arg].read = true;
+            self.emit_indexed(OpCode::GetLocal, arg);
+        } else {
+            let arg = self.global_constant(name);
+            self.emit_indexed(OpCode::GetGlobal, arg.0);
+        }
+    }
+
     fn variable(&mut self, can_assign: bool) {
         self.named_variable(self.previous(), can_assign)
     }
 
     fn named_variable(&mut self, name: Token, can_assign: bool) {
-        let (arg, get, set) = self
-            .resolve_local(&name)
-            .map(|arg| (arg, OpCode::GetLocal, OpCode::SetLocal))
-            .unwrap_or_else(|| {
-                let arg = self.identifier_constant(name);
-                (arg, OpCode::GetGlobal, OpCode::SetGlobal)
-            });
+        let (arg, get, set) = match self.resolve_local(&name) {
+            Some(arg) => (arg, OpCode::GetLocal, OpCode::SetLocal),
+            None => {
+                let arg = self.global_constant(name.clone());
+                (arg.0, OpCode::GetGlobal, OpCode::SetGlobal)
+            }
+        };
 
-        if can_assign && self.match_token(TokenType::Equal) {
-            self.expression();
-            self.emit_bytes(set, arg)
+        let compound = can_assign && self.check(TokenType::PlusEqual);
+        if can_assign && (self.match_token(TokenType::Equal) || compound) {
+            let constant = set == OpCode::SetLocal && self.compiler.locals[arg].constant;
+            if constant && !self.panic_mode {
+                self.error_at(
+                    name.clone(),
+                    Message::AssignToConstantLocal,
+                    &[("name", name.slice.as_str())],
+                );
+            }
+            self.open_node(NodeKind::Assign, Some(name.clone()), name.span());
+            if compound {
+                self.advance();
+                self.emit_indexed(get, arg);
+                self.expression();
+                self.emit_byte(OpCode::Add);
+            } else {
+                self.expression();
+            }
+            self.emit_indexed(set, arg);
+            self.close_node();
         } else {
-            self.emit_bytes(get, arg)
+            if get == OpCode::GetLocal {
+                self.compiler.locals[arg].read = true;
+            }
+            self.leaf_node(NodeKind::Variable, name);
+            self.emit_indexed(get, arg)
         }
     }
 
-    fn resolve_local(&mut self, name: &Token) -> Option<u8> {
+    fn resolve_local(&mut self, name: &Token) -> Option<usize> {
         let (i, depth) = self.compiler.resolve_local(name)?;
 
         if depth == LocalDepth::Uninitialized {
-            self.error("Can't read local variable in its own initializer")
+            self.error(Message::ReadLocalInOwnInitializer)
         }
 
         Some(i)
     }
 
     fn grouping(&mut self) {
+        self.open_node(NodeKind::Grouping, None, self.previous().span());
+        self.expression();
+        if self.match_token(TokenType::Comma) {
+            let mut count = 1;
+            while !self.check(TokenType::RightParen) {
+                self.expression();
+                if count == u8::MAX {
+                    self.error(Message::TooManyTupleElements);
+                } else {
+                    count += 1;
+                }
+                if !self.match_token(TokenType::Comma) {
+                    break;
+                }
+            }
+            self.emit_bytes(OpCode::BuildTuple, count);
+        }
+        self.consume(TokenType::RightParen, Message::ExpectParenAfterExpression);
+        self.close_node();
+    }
+
+    fn if_expression(&mut self) {
+        self.open_node(NodeKind::IfExpr, None, self.previous().span());
+        self.consume(TokenType::LeftParen, Message::ExpectParenAfterIf);
+        self.condition(false);
+        self.consume(TokenType::RightParen, Message::ExpectParenAfterCondition);
+
+        let (then_jump, pop_condition) = self.emit_condition_jump();
+        if pop_condition {
+            self.emit_byte(OpCode::Pop);
+        }
         self.expression();
-        self.consume(TokenType::RightParen, "Expect ')' after expression")
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        if pop_condition {
+            self.emit_byte(OpCode::Pop);
+        }
+        self.consume(TokenType::Else, Message::ExpectElseInIfExpression);
+        self.expression();
+        self.patch_jump(else_jump);
+        self.close_node();
     }
 
     pub fn and(&mut self) {
+        self.wrap_node(NodeKind::Logical, Some(self.previous()));
         let end_jump = self.emit_jump(OpCode::JumpIfFalse);
 
         self.emit_byte(OpCode::Pop);
@@ -653,9 +1566,38 @@ impl Parser {
         self.parse_precedence(Precedence::And);
 
         self.patch_jump(end_jump);
+        self.close_node();
+    }
+
+    fn coalesce(&mut self) {
+        self.wrap_node(NodeKind::Logical, Some(self.previous()));
+        let nil_jump = self.emit_jump(OpCode::JumpIfNil);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(nil_jump);
+        self.emit_byte(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Coalesce);
+
+        self.patch_jump(end_jump);
+        self.close_node();
+    }
+
+    fn optional_dot(&mut self) {
+        let nil_jump = self.emit_jump(OpCode::JumpIfNil);
+        self.nil_jumps.push(nil_jump);
+        self.dot(false);
+    }
+
+    fn end_chain(&mut self, start: usize) {
+        while self.nil_jumps.len() > start {
+            let nil_jump = self.nil_jumps.pop().unwrap();
+            self.patch_jump(nil_jump);
+        }
     }
 
     pub fn or(&mut self) {
+        self.wrap_node(NodeKind::Logical, Some(self.previous()));
         let else_jump = self.emit_jump(OpCode::JumpIfFalse);
         let end_jump = self.emit_jump(OpCode::Jump);
 
@@ -665,32 +1607,58 @@ impl Parser {
         self.parse_precedence(Precedence::Or);
 
         self.patch_jump(end_jump);
+        self.close_node();
     }
 
     fn parse_precedence(&mut self, precedence: Precedence) {
+        if !self.enter_nesting() {
+            return;
+        }
         self.advance();
 
         let rule = self.get_rule(self.previous().typ);
 
         if let Some(prefix) = rule.prefix {
             let can_assign = precedence <= Precedence::Assignment;
+            let chain = self.nil_jumps.len();
             prefix(self, can_assign);
 
             while self.get_rule(self.current().typ).precedence >= precedence {
                 self.advance();
                 let infix = self.get_rule(self.previous().typ).infix.unwrap();
-                infix(self);
+                infix(self, can_assign);
+                if self.get_rule(self.current().typ).precedence != Precedence::Call {
+                    self.end_chain(chain);
+                }
             }
+            self.end_chain(chain);
 
-            if can_assign && self.match_token(TokenType::Equal) {
-                self.error("Invalid assignment target")
+            if can_assign
+                && (self.match_token(TokenType::Equal) || self.match_token(TokenType::PlusEqual))
+            {
+                self.error(Message::InvalidAssignmentTarget)
             }
         } else {
-            self.error("Expect expression");
+            self.error(Message::ExpectExpression);
         }
+        self.depth -= 1;
     }
 
-    fn consume(&mut self, typ: TokenType, message: &str) {
+    fn enter_nesting(&mut self) -> bool {
+        let limit = self.config.limits.max_nesting;
+        if self.depth >= limit {
+            self.limit_error(Message::NestingTooDeep, limit);
+            self.too_deep = true;
+            while !self.check(TokenType::EOF) {
+                self.advance();
+            }
+            return false;
+        }
+        self.depth += 1;
+        true
+    }
+
+    fn consume(&mut self, typ: TokenType, message: Message) {
         if let Some(current) = self.current.as_ref() {
             if current.typ == typ {
                 self.advance();
@@ -698,7 +1666,10 @@ impl Parser {
             }
         }
 
-        self.error_at_current(message)
+        match self.previous.as_ref() {
+            Some(previous) if previous.line < self.current().line => self.error(message),
+            _ => self.error_at_current(message),
+        }
     }
 
     fn emit_jump(&mut self, instruction: OpCode) -> usize {
@@ -710,9 +1681,10 @@ impl Parser {
 
     fn patch_jump(&mut self, offset: usize) {
         let jump = self.chunk().code.len() - offset - 2;
+        self.compiler.last_jump_target = Some(self.chunk().code.len());
 
         if jump > u16::MAX as usize {
-            self.error("Too much code to jump over")
+            self.error(Message::JumpTooLarge)
         }
 
         self.chunk_mut().code[offset] = ((jump >> 8) & 0xFF) as u8;
@@ -721,16 +1693,63 @@ impl Parser {
 
     fn emit_constant(&mut self, value: Value) {
         let constant = self.make_constant(value);
-        self.emit_bytes(OpCode::Constant, constant);
+        self.emit_indexed(OpCode::Constant, constant.0);
+    }
+
+    fn emit_indexed(&mut self, op_code: OpCode, index: usize) {
+        if index <= u8::MAX as usize {
+            self.emit_bytes(op_code, index as u8);
+        } else if let Some(long) = op_code.long() {
+            self.emit_byte(long);
+            if long.operand() != Operand::Short {
+                self.emit_byte((index >> 16) as u8);
+            }
+            self.emit_byte((index >> 8) as u8);
+            self.emit_byte(index as u8);
+        } else {
+            self.error(Message::TooManyConstants);
+        }
     }
 
-    fn make_constant(&mut self, value: Value) -> u8 {
+    fn make_constant(&mut self, value: Value) -> ConstantId {
+        let count = self.chunk().constants().len();
         let c = self.chunk_mut().add_constant(value);
-        if c.over_u8() {
-            self.error("Too many constants in one chunk");
-            0
+        if self.chunk().constants().len() == count {
+            return c;
+        }
+
+        self.constant_count += 1;
+        let limit = self.config.limits.max_constants;
+        if self.constant_count == limit.saturating_add(1) {
+            self.limit_error(Message::ProgramTooManyConstants, limit);
+        }
+
+        if c.over_u24() {
+            self.error(Message::TooManyConstants);
+            ConstantId(0)
         } else {
-            c.to_byte()
+            c
+        }
+    }
+
+    fn emit_condition_jump(&mut self) -> (usize, bool) {
+        let len = self.chunk().code.len();
+        let target = self.compiler.last_jump_target;
+        let fused = self
+            .compiler
+            .last_comparison
+            .filter(|(start, end, ..)| *end == len && target.is_none_or(|target| target <= *start));
+
+        match fused {
+            Some((start, end, op_code, origin)) => {
+                self.code_size -= end - start;
+                self.chunk_mut().truncate(start);
+                self.origin = Some(origin);
+                let jump = self.emit_jump(op_code);
+                self.origin = None;
+                (jump, false)
+            }
+            None => (self.emit_jump(OpCode::JumpIfFalse), true),
         }
     }
 
@@ -739,14 +1758,18 @@ impl Parser {
 
         let offset = self.chunk().code.len() - start + 2;
         if offset > (u16::MAX as usize) {
-            self.error("Loop body too large");
+            self.error(Message::LoopTooLarge);
         }
 
         self.emit_short(offset as u16);
     }
 
     fn emit_return(&mut self) {
-        self.emit_byte(OpCode::Nil);
+        if self.compiler.function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal, 0);
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
         self.emit_byte(OpCode::Return);
     }
 
@@ -758,32 +1781,108 @@ impl Parser {
     }
 
     fn emit_byte(&mut self, byte: impl ToByte) {
-        let line = self.previous().line;
-        self.chunk_mut().write(byte.to_byte(), line)
+        self.code_size += 1;
+        let limit = self.config.limits.max_code_size;
+        if self.code_size == limit.saturating_add(1) {
+            self.limit_error(Message::ProgramTooLarge, limit);
+        }
+
+        let previous = self.previous();
+        let column = self.origin.unwrap_or_else(|| column(&previous));
+        self.chunk_mut()
+            .write_at(byte.to_byte(), previous.line, column)
     }
 
     fn emit_bytes(&mut self, a: impl ToByte, b: impl ToByte) {
-        let line = self.previous().line;
-        self.chunk_mut().write(a.to_byte(), line);
-        self.chunk_mut().write(b.to_byte(), line);
+        self.emit_byte(a);
+        self.emit_byte(b);
     }
 
-    fn error_at_current(&mut self, message: &str) {
+    fn error_at_current(&mut self, message: Message) {
         if self.panic_mode {
             return;
         }
-        self.panic_mode = true;
-        print_error(self.current(), message, &mut self.config.compiler_error);
-        self.had_error = true;
+        self.error_at(self.current(), message, &[]);
     }
 
-    fn error(&mut self, message: &str) {
+    fn error(&mut self, message: Message) {
         if self.panic_mode {
             return;
         }
+        self.error_at(self.previous(), message, &[]);
+    }
+
+    fn limit_error(&mut self, message: Message, limit: usize) {
+        self.error_at(self.previous(), message, &[("limit", &limit.to_string())]);
+    }
+
+    fn error_at(&mut self, token: Token, message: Message, args: &[(&str, &str)]) {
+        if self.too_deep {
+            return;
+        }
         self.panic_mode = true;
-        print_error(self.previous(), message, &mut self.config.compiler_error);
         self.had_error = true;
+        self.report(Severity::Error, token, message, args);
+    }
+
+    fn warning(&mut self, token: Token, message: Message, args: &[(&str, &str)]) {
+        if !self.panic_mode {
+            self.report(Severity::Warning, token, message, args);
+        }
+    }
+
+    fn warn_unused(&mut self, local: Local) {
+        let name = local.name.slice.as_str();
+        if local.read
+            || !self.config.warnings.unused_locals
+            || self.had_error
+            || name.is_empty()
+            || name.starts_with([' ', '_'])
+        {
+            return;
+        }
+        let name = name.to_owned();
+        self.warning(local.name, Message::UnusedLocal, &[("name", &name)]);
+    }
+
+    fn report(
+        &mut self,
+        severity: Severity,
+        token: Token,
+        message: Message,
+        args: &[(&str, &str)],
+    ) {
+        let span = if token.typ == TokenType::Error {
+            Span {
+                line: token.line,
+                start: self.scanner.start,
+                end: self.scanner.current,
+            }
+        } else {
+            token.span()
+        };
+        let text = self.config.messages.format(message, args);
+
+        print_error(
+            severity,
+            token,
+            self.file.as_deref(),
+            &text,
+            &mut self.config.compiler_error,
+        );
+        print_excerpt(&self.scanner.source, span, &mut self.config.compiler_error);
+
+        let source = &self.scanner.source;
+        let (line_start, line_end) = source_line(source, span.start);
+        self.diagnostics.push(Diagnostic {
+            severity,
+            span,
+            column: span.start.min(source.len()) - line_start,
+            message: text,
+            snippet: source[line_start..line_end].trim_end().into(),
+            code: message.code(),
+            file: self.file.clone(),
+        });
     }
 
     fn current(&self) -> Token {
@@ -794,33 +1893,83 @@ impl Parser {
         self.previous.as_ref().unwrap().clone()
     }
 
-    fn new_function(&mut self, name: &str) -> FunctionId {
-        self.memory.new_function(name)
+    fn open_node(&mut self, kind: NodeKind, token: Option<Token>, start: Span) {
+        if let Some(tree) = self.tree.as_mut() {
+            tree.open(kind, token, start);
+        }
+    }
+
+    fn wrap_node(&mut self, kind: NodeKind, token: Option<Token>) {
+        if let Some(tree) = self.tree.as_mut() {
+            tree.open_wrapping_last(kind, token);
+        }
+    }
+
+    fn close_node(&mut self) {
+        let end = self.previous.as_ref().map(Token::span);
+        if let (Some(tree), Some(end)) = (self.tree.as_mut(), end) {
+            tree.close(end);
+        }
+    }
+
+    fn leaf_node(&mut self, kind: NodeKind, token: Token) {
+        if let Some(tree) = self.tree.as_mut() {
+            tree.leaf(kind, token);
+        }
     }
 
     fn synchronize(&mut self) {
         use TokenType::*;
         self.panic_mode = false;
 
+        let mut depth = 0;
         while self.current().typ != EOF {
-            if self.previous().typ == SemiColon {
+            if depth == 0 && self.previous().typ == SemiColon {
                 return;
             }
 
             match self.current().typ {
-                Class | Fun | Var | For | If | While | Print | Return => {
+                Class | Fun | Var | Const | For | If | Import | While | Print | Return | Throw
+                | Assert | Break | Continue
+                    if depth == 0 =>
+                {
                     return;
                 }
+                LeftBrace => depth += 1,
+                RightBrace if depth == 0 => return,
+                RightBrace => depth -= 1,
                 _ => (),
             }
-        }
 
-        self.advance();
+            self.advance();
+        }
     }
 }
 
-fn print_error(token: Token, message: &str, output: &mut impl Write) {
-    write!(output, "[line {}] Error", token.line).unwrap();
+fn parse_radix(digits: &str, radix: u32) -> Option<f64> {
+    digits.chars().try_fold(0.0, |value, c| {
+        Some(value * radix as f64 + c.to_digit(radix)? as f64)
+    })
+}
+
+fn column(token: &Token) -> (usize, usize) {
+    (token.column, token.slice.len())
+}
+
+fn print_error(
+    severity: Severity,
+    token: Token,
+    file: Option<&str>,
+    message: &str,
+    output: &mut impl Write,
+) {
+    let label = match severity {
+        Severity::Error => "Error",
+        Severity::Warning => "Warning",
+        Severity::Information => "Info",
+        Severity::Hint => "Hint",
+    };
+    write!(output, "[{}] {label}", location(file, token.line)).unwrap();
 
     if token.typ == TokenType::EOF {
         write!(output, " at end").unwrap();
@@ -830,13 +1979,36 @@ fn print_error(token: Token, message: &str, output: &mut impl Write) {
         write!(output, " at '{}'", token.slice).unwrap();
     }
 
-    write!(output, ": {message}").unwrap();
+    writeln!(output, ": {message}").unwrap();
+}
+
+fn source_line(source: &str, offset: usize) -> (usize, usize) {
+    let start = offset.min(source.len());
+    let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = source[start..]
+        .find('\n')
+        .map_or(source.len(), |i| start + i);
+    (line_start, line_end)
+}
+
+fn print_excerpt(source: &str, span: Span, output: &mut impl Write) {
+    let start = span.start.min(source.len());
+    let (line_start, line_end) = source_line(source, start);
+    let text = &source[line_start..line_end];
+    if text.trim().is_empty() {
+        return;
+    }
+
+    let line = source[..line_start].matches('\n').count() + 1;
+    let width = span.end.clamp(start, line_end) - start;
+    write_excerpt(output, line, text, start - line_start, width);
 }
 
 #[derive(PartialEq, Eq, Copy, Clone, Debug, PartialOrd, Ord)]
 enum Precedence {
     None,
     Assignment,
+    Coalesce,
     Or,
     And,
     Equality,
@@ -852,7 +2024,8 @@ impl Precedence {
     fn next(&self) -> Precedence {
         match self {
             Precedence::None => Precedence::Assignment,
-            Precedence::Assignment => Precedence::Or,
+            Precedence::Assignment => Precedence::Coalesce,
+            Precedence::Coalesce => Precedence::Or,
             Precedence::Or => Precedence::And,
             Precedence::And => Precedence::Equality,
             Precedence::Equality => Precedence::Comparison,
@@ -866,17 +2039,20 @@ impl Precedence {
     }
 }
 
+type ParseFn = fn(&mut Parser, bool);
+
+#[derive(Clone, Copy)]
 struct ParseRule {
-    prefix: Option<Box<dyn Fn(&mut Parser, bool) -> ()>>,
-    infix: Option<Box<dyn Fn(&mut Parser) -> ()>>,
+    prefix: Option<ParseFn>,
+    infix: Option<ParseFn>,
     precedence: Precedence,
 }
 impl ParseRule {
-    fn new() -> ParseRule {
+    const fn new() -> ParseRule {
         ParseRule::prec(Precedence::None)
     }
 
-    fn prec(precedence: Precedence) -> ParseRule {
+    const fn prec(precedence: Precedence) -> ParseRule {
         ParseRule {
             prefix: None,
             infix: None,
@@ -884,27 +2060,77 @@ impl ParseRule {
         }
     }
 
-    fn prefix(self, prefix: impl Fn(&mut Parser, bool) -> () + 'static) -> ParseRule {
+    const fn prefix(self, prefix: ParseFn) -> ParseRule {
         ParseRule {
-            prefix: Some(Box::new(prefix)),
-            infix: self.infix,
-            precedence: self.precedence,
+            prefix: Some(prefix),
+            ..self
         }
     }
 
-    fn infix(self, infix: impl Fn(&mut Parser) -> () + 'static) -> ParseRule {
+    const fn infix(self, infix: ParseFn) -> ParseRule {
         ParseRule {
-            prefix: self.prefix,
-            infix: Some(Box::new(infix)),
-            precedence: self.precedence,
+            infix: Some(infix),
+            ..self
         }
     }
 }
 
+const RULES: [ParseRule; TokenType::EOF as usize + 1] = {
+    use Precedence::*;
+    use TokenType::*;
+    let mut rules = [ParseRule::new(); EOF as usize + 1];
+    rules[LeftParen as usize] = ParseRule::prec(Precedence::Call)
+        .prefix(|p, _| p.grouping())
+        .infix(|p, _| p.call());
+    rules[LeftBracket as usize] = ParseRule::prec(Precedence::Call)
+        .prefix(|p, _| p.list())
+        .infix(|p, can_assign| p.index(can_assign));
+    rules[Dot as usize] =
+        ParseRule::prec(Precedence::Call).infix(|p, can_assign| p.dot(can_assign));
+    rules[Minus as usize] = ParseRule::prec(Term)
+        .prefix(|p, _| p.unary())
+        .infix(|p, _| p.binary());
+    rules[Plus as usize] = ParseRule::prec(Term).infix(|p, _| p.binary());
+    rules[Slash as usize] = ParseRule::prec(Factor).infix(|p, _| p.binary());
+    rules[Star as usize] = ParseRule::prec(Factor).infix(|p, _| p.binary());
+    rules[Percent as usize] = ParseRule::prec(Factor).infix(|p, _| p.binary());
+    rules[TildeSlash as usize] = ParseRule::prec(Factor).infix(|p, _| p.binary());
+    rules[Bang as usize] = ParseRule::new().prefix(|p, _| p.unary());
+    rules[BangEqual as usize] = ParseRule::prec(Equality).infix(|p, _| p.binary());
+    rules[EqualEqual as usize] = ParseRule::prec(Equality).infix(|p, _| p.binary());
+    rules[Greater as usize] = ParseRule::prec(Comparison).infix(|p, _| p.binary());
+    rules[GreaterEqual as usize] = ParseRule::prec(Comparison).infix(|p, _| p.binary());
+    rules[Less as usize] = ParseRule::prec(Comparison).infix(|p, _| p.binary());
+    rules[LessEqual as usize] = ParseRule::prec(Comparison).infix(|p, _| p.binary());
+    rules[Is as usize] = ParseRule::prec(Comparison).infix(|p, _| p.binary());
+    rules[QuestionQuestion as usize] =
+        ParseRule::prec(Precedence::Coalesce).infix(|p, _| p.coalesce());
+    rules[QuestionDot as usize] = ParseRule::prec(Precedence::Call).infix(|p, _| p.optional_dot());
+    rules[If as usize] = ParseRule::new().prefix(|p, _| p.if_expression());
+    rules[Identifier as usize] = ParseRule::new().prefix(|p, can_assign| p.variable(can_assign));
+    rules[String as usize] = ParseRule::new().prefix(|p, _| p.string());
+    rules[Number as usize] = ParseRule::new().prefix(|p, _| p.number());
+    rules[TokenType::And as usize] = ParseRule::prec(Precedence::And).infix(|p, _| p.and());
+    rules[False as usize] = ParseRule::new().prefix(|p, _| p.literal());
+    rules[Nil as usize] = ParseRule::new().prefix(|p, _| p.literal());
+    rules[TokenType::Or as usize] = ParseRule::prec(Precedence::Or).infix(|p, _| p.or());
+    rules[Super as usize] = ParseRule::new().prefix(|p, _| p.super_());
+    rules[This as usize] = ParseRule::new().prefix(|p, _| p.this());
+    rules[True as usize] = ParseRule::new().prefix(|p, _| p.literal());
+    rules
+};
+
 #[derive(Eq, PartialEq, Clone, Copy, Debug, Hash)]
 enum FunctionType {
     Script,
     Function,
+    Method,
+    Initializer,
+    Module,
+}
+
+struct ClassCompiler {
+    has_superclass: bool,
 }
 
 struct Compiler {
@@ -913,32 +2139,40 @@ struct Compiler {
     function_type: FunctionType,
     locals: Vec<Local>,
     scope_depth: usize,
+    loops: Vec<Loop>,
+    last_call: Option<usize>,
+    last_comparison: Option<(usize, usize, OpCode, (usize, usize))>,
+    last_jump_target: Option<usize>,
+}
+
+struct Loop {
+    start: usize,
+    scope_depth: usize,
+    breaks: Vec<usize>,
 }
 
 impl Compiler {
-    pub fn add_local(&mut self, name: Token) -> Result<(), &'static str> {
-        if self.locals.len() == u8::MAX as usize {
-            return Err("Too many local variables in function");
+    pub fn add_local(&mut self, name: Token) -> Result<(), Message> {
+        if self.locals.len() > u16::MAX as usize {
+            return Err(Message::TooManyLocals);
         }
         self.locals.push(Local {
             name,
             depth: LocalDepth::Uninitialized,
+            constant: false,
+            read: false,
         });
         Ok(())
     }
 
-    pub fn resolve_local(&self, name: &Token) -> Option<(u8, LocalDepth)> {
-        self.locals
-            .iter()
-            .enumerate()
-            .rev()
-            .find_map(|(i, local)| {
-                if local.name.string_eq(name) {
-                    Some((i as u8, local.depth))
-                } else {
-                    None
-                }
-            })
+    pub fn resolve_local(&self, name: &Token) -> Option<(usize, LocalDepth)> {
+        self.locals.iter().enumerate().rev().find_map(|(i, local)| {
+            if local.name.string_eq(name) {
+                Some((i, local.depth))
+            } else {
+                None
+            }
+        })
     }
 }
 
@@ -946,6 +2180,8 @@ impl Compiler {
 struct Local {
     name: Token,
     depth: LocalDepth,
+    constant: bool,
+    read: bool,
 }
 impl Local {
     fn initialize(&mut self, depth: usize) {
@@ -1,35 +1,104 @@
-use std::{cell::RefCell, fmt::Write, rc::Rc};
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    fmt::{self, Write},
+    io,
+    path::PathBuf,
+    rc::Rc,
+};
+
+use crate::{gc::GcConfig, messages::Catalog, observer::Observer, trace::TraceSink};
 
 pub enum PrintOutput {
     Null,
     StdOut,
     StdErr,
     Str(Rc<RefCell<String>>),
+    Writer(Box<dyn io::Write>),
+    Callback(Box<dyn FnMut(&str)>),
 }
 impl PrintOutput {
     pub fn redirect(&mut self, string: Rc<RefCell<String>>) {
         *self = PrintOutput::Str(string);
     }
+
+    pub fn writer(writer: impl io::Write + 'static) -> Self {
+        PrintOutput::Writer(Box::new(writer))
+    }
+
+    pub fn callback(callback: impl FnMut(&str) + 'static) -> Self {
+        PrintOutput::Callback(Box::new(callback))
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        !matches!(self, PrintOutput::Null)
+    }
 }
 
 impl Write for PrintOutput {
-    fn write_str(&mut self, s: &str) -> std::fmt::Result {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
         match self {
             PrintOutput::Null => (),
             PrintOutput::StdOut => print!("{s}"),
             PrintOutput::StdErr => eprint!("{s}"),
             PrintOutput::Str(string) => string.borrow_mut().push_str(s),
+            PrintOutput::Writer(writer) => {
+                writer.write_all(s.as_bytes()).map_err(|_| fmt::Error)?
+            }
+            PrintOutput::Callback(callback) => callback(s),
         }
         Ok(())
     }
 }
 
+pub type PrintHook = Box<dyn FnMut(Option<&str>, usize, &str)>;
+
 pub struct Config {
     pub vm_debug: PrintOutput,
     pub vm_error: PrintOutput,
     pub compiler_debug: PrintOutput,
     pub compiler_error: PrintOutput,
     pub print_output: PrintOutput,
+    pub eprint_output: PrintOutput,
+    pub print_hook: Option<PrintHook>,
+    pub messages: Catalog,
+    pub limits: Limits,
+    pub capabilities: Capabilities,
+    pub warnings: Warnings,
+    pub allow_io: bool,
+    pub allow_env: bool,
+    pub args: Vec<String>,
+    pub gc: GcConfig,
+    pub max_frames: usize,
+    pub max_stack: usize,
+    pub max_instructions: Option<usize>,
+    pub coverage: bool,
+    pub number_format: NumberFormat,
+    pub strict_concat: bool,
+    pub integers: bool,
+    pub division_by_zero: DivisionByZero,
+    pub observers: Vec<Rc<RefCell<dyn Observer>>>,
+    pub trace_sinks: Vec<Rc<RefCell<dyn TraceSink>>>,
+    pub modules: HashMap<String, Rc<str>>,
+    pub module_dir: Option<PathBuf>,
+}
+
+impl Config {
+    pub fn observe(&mut self, observer: Rc<RefCell<dyn Observer>>) {
+        self.observers.push(observer);
+    }
+
+    pub fn trace(&mut self, sink: Rc<RefCell<dyn TraceSink>>) {
+        self.trace_sinks.push(sink);
+    }
+
+    pub fn on_print(&mut self, hook: impl FnMut(Option<&str>, usize, &str) + 'static) {
+        self.print_hook = Some(Box::new(hook));
+    }
+
+    pub fn add_module(&mut self, name: &str, source: &str) {
+        self.modules.insert(name.into(), Rc::from(source));
+    }
 }
 
 impl Default for Config {
@@ -40,6 +109,117 @@ impl Default for Config {
             compiler_debug: PrintOutput::Null,
             compiler_error: PrintOutput::StdErr,
             print_output: PrintOutput::StdOut,
+            eprint_output: PrintOutput::StdErr,
+            print_hook: None,
+            messages: Catalog::new(),
+            limits: Limits::default(),
+            capabilities: Capabilities::default(),
+            warnings: Warnings::default(),
+            allow_io: false,
+            allow_env: false,
+            args: Vec::new(),
+            gc: GcConfig::default(),
+            max_frames: 64,
+            max_stack: 64 * 256,
+            max_instructions: None,
+            coverage: false,
+            number_format: NumberFormat::default(),
+            strict_concat: true,
+            integers: false,
+            division_by_zero: DivisionByZero::default(),
+            observers: Vec::new(),
+            trace_sinks: Vec::new(),
+            modules: HashMap::new(),
+            module_dir: None,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum NumberFormat {
+    #[default]
+    Shortest,
+    Lox,
+    Precision(usize),
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub enum DivisionByZero {
+    #[default]
+    Infinity,
+    RuntimeError,
+    Nil,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Limits {
+    pub max_source_len: usize,
+    pub max_string_len: usize,
+    pub max_constants: usize,
+    pub max_functions: usize,
+    pub max_code_size: usize,
+    pub max_nesting: usize,
+    pub max_sleep_ms: usize,
+}
+
+impl Limits {
+    pub fn untrusted() -> Limits {
+        Limits {
+            max_source_len: 1 << 20,
+            max_string_len: 1 << 16,
+            max_constants: 1 << 16,
+            max_functions: 1 << 12,
+            max_code_size: 1 << 20,
+            max_nesting: 128,
+            max_sleep_ms: 1000,
+        }
+    }
+}
+
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_source_len: usize::MAX,
+            max_string_len: usize::MAX,
+            max_constants: usize::MAX,
+            max_functions: usize::MAX,
+            max_code_size: usize::MAX,
+            max_nesting: 256,
+            max_sleep_ms: 60_000,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Capabilities {
+    pub timers: bool,
+}
+
+impl Capabilities {
+    pub fn untrusted() -> Capabilities {
+        Capabilities { timers: false }
+    }
+}
+
+impl Default for Capabilities {
+    fn default() -> Self {
+        Self { timers: true }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Warnings {
+    pub unused_locals: bool,
+    pub unreachable_code: bool,
+    pub constant_conditions: bool,
+}
+
+impl Warnings {
+    pub fn all() -> Warnings {
+        Warnings {
+            unused_locals: true,
+            unreachable_code: true,
+            constant_conditions: true,
         }
     }
 }
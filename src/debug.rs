@@ -1,8 +1,9 @@
 use crate::{
-    chunk::{Chunk, OpCode},
+    chunk::{Chunk, ConstantId, OpCode, Operand},
+    config::NumberFormat,
     memory::Memory,
     value::Value,
-    vm::InstructionPointer,
+    vm::{InstructionPointer, VM},
 };
 
 use std::fmt::Write;
@@ -16,6 +17,47 @@ pub fn disassemble_chunk(chunk: &Chunk, name: &str, memory: &Memory, output: &mu
     }
 }
 
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct DisasmLine {
+    pub offset: usize,
+    pub line: usize,
+    pub opcode: OpCode,
+    pub operands: Vec<usize>,
+    pub resolved_constant: Option<String>,
+}
+
+pub fn disassemble_to_ir(chunk: &Chunk, memory: &Memory) -> Vec<DisasmLine> {
+    let mut lines = Vec::new();
+    let mut offset = 0;
+    while let Some(decoded) = chunk.decode(InstructionPointer(offset)) {
+        let end = offset + decoded.size;
+        let (operands, constant) = match decoded.op_code.operand() {
+            Operand::None => (vec![], None),
+            Operand::Byte | Operand::Short => (vec![decoded.a], None),
+            Operand::Constant | Operand::ConstantLong => (vec![decoded.a], Some(decoded.a)),
+            Operand::Jump => (vec![end + decoded.a], None),
+            Operand::Loop => (vec![end.saturating_sub(decoded.a)], None),
+            Operand::Invoke | Operand::InvokeLong => (vec![decoded.a, decoded.b], Some(decoded.a)),
+            Operand::Locals => (vec![decoded.a, decoded.b], None),
+            Operand::LocalConstant => (vec![decoded.a, decoded.b], Some(decoded.b)),
+        };
+        let resolved_constant = constant.map(|id| {
+            let mut text = String::new();
+            print_value(&chunk.constant_value(ConstantId(id)), memory, &mut text);
+            text
+        });
+        lines.push(DisasmLine {
+            offset,
+            line: chunk.line(InstructionPointer(offset)),
+            opcode: decoded.op_code,
+            operands,
+            resolved_constant,
+        });
+        offset = end;
+    }
+    lines
+}
+
 pub fn disassemble_instruction(
     chunk: &Chunk,
     mut offset: InstructionPointer,
@@ -43,16 +85,53 @@ pub fn disassemble_instruction(
     match op_code {
         OpCode::Loop => jump_instruction(op_code, -1, chunk, offset, output),
 
-        OpCode::Jump | OpCode::JumpIfFalse => jump_instruction(op_code, 1, chunk, offset, output),
+        OpCode::Jump
+        | OpCode::JumpIfFalse
+        | OpCode::JumpIfLess
+        | OpCode::JumpIfNotLess
+        | OpCode::JumpIfGreater
+        | OpCode::JumpIfNotGreater
+        | OpCode::JumpIfNil => jump_instruction(op_code, 1, chunk, offset, output),
 
-        OpCode::Constant | OpCode::DefineGlobal | OpCode::GetGlobal | OpCode::SetGlobal => {
-            constant_instruction(op_code, chunk, offset, memory, output)
-        }
+        OpCode::Constant
+        | OpCode::DefineGlobal
+        | OpCode::GetGlobal
+        | OpCode::SetGlobal
+        | OpCode::Class
+        | OpCode::GetProperty
+        | OpCode::SetProperty
+        | OpCode::Method
+        | OpCode::GetSuper
+        | OpCode::DefineConst
+        | OpCode::Import => constant_instruction(op_code, chunk, offset, memory, output),
 
-        OpCode::Call | OpCode::GetLocal | OpCode::SetLocal => {
-            byte_instruction(op_code, chunk, offset, output)
+        OpCode::GetLocalLong | OpCode::SetLocalLong => {
+            short_instruction(op_code, chunk, offset, output)
         }
 
+        OpCode::ConstantLong
+        | OpCode::DefineGlobalLong
+        | OpCode::DefineConstLong
+        | OpCode::GetGlobalLong
+        | OpCode::SetGlobalLong
+        | OpCode::ClosureLong
+        | OpCode::ClassLong
+        | OpCode::GetPropertyLong
+        | OpCode::SetPropertyLong
+        | OpCode::MethodLong
+        | OpCode::GetSuperLong
+        | OpCode::ImportLong => constant_long_instruction(op_code, chunk, offset, memory, output),
+
+        OpCode::Call
+        | OpCode::TailCall
+        | OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::BuildList
+        | OpCode::ArgMissing
+        | OpCode::BuildTuple
+        | OpCode::Unpack
+        | OpCode::Dup => byte_instruction(op_code, chunk, offset, output),
+
         OpCode::Nil
         | OpCode::True
         | OpCode::False
@@ -67,7 +146,42 @@ pub fn disassemble_instruction(
         | OpCode::Negate
         | OpCode::Return
         | OpCode::Print
-        | OpCode::Pop => simple_instruction(op_code, offset, output),
+        | OpCode::Pop
+        | OpCode::Inherit
+        | OpCode::IndexGet
+        | OpCode::IndexSet
+        | OpCode::Len
+        | OpCode::Throw
+        | OpCode::Assert
+        | OpCode::Modulo
+        | OpCode::FloorDivide
+        | OpCode::TypeCheck => simple_instruction(op_code, offset, output),
+
+        OpCode::Invoke | OpCode::SuperInvoke => {
+            invoke_instruction(op_code, chunk, offset, memory, output)
+        }
+
+        OpCode::InvokeLong | OpCode::SuperInvokeLong => {
+            invoke_long_instruction(op_code, chunk, offset, memory, output)
+        }
+
+        OpCode::AddLocals => {
+            let a = chunk.byte(offset.plus(1));
+            let b = chunk.byte(offset.plus(2));
+            let s = format!("{op_code:?}");
+            writeln!(output, "{s:<16} {a:0>4} {b:0>4}").unwrap();
+            offset.plus(3)
+        }
+
+        OpCode::IncrementLocal => {
+            let slot = chunk.byte(offset.plus(1));
+            let constant = chunk.constant(offset.plus(2));
+            let s = format!("{op_code:?}");
+            write!(output, "{s:<16} {slot:0>4} {constant:?} ").unwrap();
+            print_value(&chunk.constant_value(constant), memory, output);
+            writeln!(output).unwrap();
+            offset.plus(3)
+        }
 
         OpCode::Closure => {
             offset.increment(1);
@@ -76,7 +190,7 @@ pub fn disassemble_instruction(
             let s = format!("{op_code:?}");
             write!(output, "{s:<16} {constant:?} ").unwrap();
             print_value(&chunk.constant_value(constant), memory, output);
-            write!(output, "\n").unwrap();
+            writeln!(output).unwrap();
             offset
         }
     }
@@ -109,10 +223,57 @@ fn constant_instruction(
     let s = format!("{op_code:?}");
     write!(output, "{s:<16} {constant:?} ").unwrap();
     print_value(&chunk.constant_value(constant), memory, output);
-    write!(output, "\n").unwrap();
+    writeln!(output).unwrap();
     offset.plus(2)
 }
 
+fn constant_long_instruction(
+    op_code: OpCode,
+    chunk: &Chunk,
+    offset: InstructionPointer,
+    memory: &Memory,
+    output: &mut impl Write,
+) -> InstructionPointer {
+    let constant = chunk.constant_long(offset.plus(1));
+    let s = format!("{op_code:?}");
+    write!(output, "{s:<16} {constant:?} ").unwrap();
+    print_value(&chunk.constant_value(constant), memory, output);
+    writeln!(output).unwrap();
+    offset.plus(4)
+}
+
+fn invoke_instruction(
+    op_code: OpCode,
+    chunk: &Chunk,
+    offset: InstructionPointer,
+    memory: &Memory,
+    output: &mut impl Write,
+) -> InstructionPointer {
+    let constant = chunk.constant(offset.plus(1));
+    let arg_count = chunk.byte(offset.plus(2));
+    let s = format!("{op_code:?}");
+    write!(output, "{s:<16} ({arg_count} args) {constant:?} ").unwrap();
+    print_value(&chunk.constant_value(constant), memory, output);
+    writeln!(output).unwrap();
+    offset.plus(3)
+}
+
+fn invoke_long_instruction(
+    op_code: OpCode,
+    chunk: &Chunk,
+    offset: InstructionPointer,
+    memory: &Memory,
+    output: &mut impl Write,
+) -> InstructionPointer {
+    let constant = chunk.constant_long(offset.plus(1));
+    let arg_count = chunk.byte(offset.plus(4));
+    let s = format!("{op_code:?}");
+    write!(output, "{s:<16} ({arg_count} args) {constant:?} ").unwrap();
+    print_value(&chunk.constant_value(constant), memory, output);
+    writeln!(output).unwrap();
+    offset.plus(5)
+}
+
 fn byte_instruction(
     op_code: OpCode,
     chunk: &Chunk,
@@ -125,6 +286,18 @@ fn byte_instruction(
     offset.plus(2)
 }
 
+fn short_instruction(
+    op_code: OpCode,
+    chunk: &Chunk,
+    offset: InstructionPointer,
+    output: &mut impl Write,
+) -> InstructionPointer {
+    let slot = (chunk.byte(offset.plus(1)) as usize) << 8 | chunk.byte(offset.plus(2)) as usize;
+    let s = format!("{op_code:?}");
+    writeln!(output, "{s:<16} {slot:0>4}").unwrap();
+    offset.plus(3)
+}
+
 fn simple_instruction(
     op_code: OpCode,
     offset: InstructionPointer,
@@ -135,6 +308,19 @@ fn simple_instruction(
     offset.plus(1)
 }
 
+pub fn display_value(value: &Value, memory: &Memory, output: &mut impl Write) {
+    match value {
+        Value::String(s) => {
+            write!(output, "{s}").unwrap();
+        }
+        Value::StringId(id) => {
+            let s = memory.get_string(*id);
+            write!(output, "{s}").unwrap();
+        }
+        _ => print_value(value, memory, output),
+    }
+}
+
 pub fn print_value(value: &Value, memory: &Memory, output: &mut impl Write) {
     match value {
         Value::Nil => {
@@ -144,7 +330,10 @@ pub fn print_value(value: &Value, memory: &Memory, output: &mut impl Write) {
             write!(output, "{b}").unwrap();
         }
         Value::Number(n) => {
-            write!(output, "{n}").unwrap();
+            format_number(*n, memory.number_format(), output);
+        }
+        Value::Int(i) => {
+            write!(output, "{i}").unwrap();
         }
         Value::String(s) => {
             write!(output, "\"{s}\"").unwrap();
@@ -169,5 +358,172 @@ pub fn print_value(value: &Value, memory: &Memory, output: &mut impl Write) {
             let s = memory.get_string(f.name);
             write!(output, "<closure {s}>").unwrap();
         }
+        Value::Class(id) => {
+            let s = memory.get_string(memory.class(*id).name);
+            write!(output, "{s}").unwrap();
+        }
+        Value::Instance(id) => {
+            let class = memory.class(memory.instance(*id).class);
+            let s = memory.get_string(class.name);
+            write!(output, "{s} instance").unwrap();
+        }
+        Value::BoundMethod(id) => {
+            let closure = memory.closure(memory.bound_method(*id).method);
+            let s = memory.get_string(memory.function(closure.function).name);
+            write!(output, "<fn {s}>").unwrap();
+        }
+        Value::List(id) => {
+            write!(output, "[").unwrap();
+            for (i, item) in memory.list(*id).items.iter().enumerate() {
+                if i > 0 {
+                    write!(output, ", ").unwrap();
+                }
+                print_value(item, memory, output);
+            }
+            write!(output, "]").unwrap();
+        }
+        Value::Tuple(id) => {
+            write!(output, "(").unwrap();
+            let items = &memory.list(*id).items;
+            for (i, item) in items.iter().enumerate() {
+                if i > 0 {
+                    write!(output, ", ").unwrap();
+                }
+                print_value(item, memory, output);
+            }
+            if items.len() == 1 {
+                write!(output, ",").unwrap();
+            }
+            write!(output, ")").unwrap();
+        }
+        Value::Module(id) => {
+            let s = memory.get_string(memory.module(*id).name);
+            write!(output, "<module {s}>").unwrap();
+        }
+        Value::Foreign(id) => {
+            let name = memory.foreign_name(*id).unwrap_or("freed");
+            let name = name.rsplit("::").next().unwrap_or(name);
+            write!(output, "<foreign {name}>").unwrap();
+        }
+        Value::Type(typ) => {
+            write!(output, "{}", typ.global()).unwrap();
+        }
+    }
+}
+
+pub fn format_number(n: f64, format: NumberFormat, output: &mut impl Write) {
+    let precision = match format {
+        NumberFormat::Shortest => return write!(output, "{n}").unwrap(),
+        NumberFormat::Lox => 6,
+        NumberFormat::Precision(precision) => precision.max(1),
+    };
+    if !n.is_finite() {
+        return write!(output, "{n}").unwrap();
+    }
+
+    let scientific = format!("{:.*e}", precision - 1, n);
+    let (mantissa, exponent) = scientific.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if exponent < -4 || exponent >= precision as i32 {
+        let sign = if exponent < 0 { '-' } else { '+' };
+        let mantissa = trim_fraction(mantissa);
+        write!(output, "{mantissa}e{sign}{:02}", exponent.abs()).unwrap();
+    } else {
+        let decimals = (precision as i32 - 1 - exponent) as usize;
+        let fixed = format!("{n:.decimals$}");
+        write!(output, "{}", trim_fraction(&fixed)).unwrap();
+    }
+}
+
+fn trim_fraction(number: &str) -> &str {
+    if number.contains('.') {
+        number.trim_end_matches('0').trim_end_matches('.')
+    } else {
+        number
+    }
+}
+
+pub fn dump_state(vm: &VM, output: &mut impl Write) {
+    let memory = &vm.memory;
+
+    writeln!(output, "== globals ==").unwrap();
+    let mut globals: Vec<_> = vm
+        .globals
+        .iter()
+        .map(|(name, value)| (memory.get_string(*name), value))
+        .collect();
+    globals.sort_by_key(|(name, _)| *name);
+    for (name, value) in globals {
+        write!(output, "{name} = ").unwrap();
+        print_value(value, memory, output);
+        writeln!(output).unwrap();
+    }
+
+    writeln!(output, "== strings ==").unwrap();
+    for (id, s) in memory.strings() {
+        writeln!(output, "{:>4} {s:?}", id.0).unwrap();
+    }
+
+    writeln!(output, "== functions ==").unwrap();
+    for function in memory.functions() {
+        writeln!(
+            output,
+            "{:<24} {:>6} bytes {:>4} constants",
+            memory.get_string(function.name),
+            function.chunk.code.len(),
+            function.chunk.constants().len()
+        )
+        .unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{compiler::compile, config::Config, memory::FunctionId};
+
+    #[test]
+    fn structured_listing() {
+        let program = compile(
+            Rc::from("var i = 0;\nwhile (i < 2) {\n  i = i + 1;\n}\nprint \"done\";"),
+            Config::default(),
+        )
+        .unwrap();
+        let memory = program.to_memory();
+        let chunk = &memory.function(FunctionId(0)).chunk;
+        let lines = disassemble_to_ir(chunk, &memory);
+
+        let jump = lines
+            .iter()
+            .find(|l| l.opcode == OpCode::JumpIfNotLess)
+            .unwrap();
+        let exit = lines.iter().find(|l| l.offset == jump.operands[0]).unwrap();
+        assert_eq!(exit.resolved_constant.as_deref(), Some("\"done\""));
+        assert_eq!(exit.line, 5);
+
+        let back = lines.iter().find(|l| l.opcode == OpCode::Loop).unwrap();
+        let start = lines.iter().find(|l| l.offset == back.operands[0]).unwrap();
+        assert_eq!(start.opcode, OpCode::GetGlobal);
+        assert_eq!(start.line, 2);
+
+        let end = lines.last().unwrap();
+        assert_eq!(end.opcode, OpCode::Return);
+        assert_eq!(end.offset + 1, chunk.code.len());
+    }
+
+    #[test]
+    fn dump_state_after_run() {
+        let program = compile(Rc::from("var answer = 42;"), Config::default()).unwrap();
+        let mut vm = VM::load(&program, Config::default());
+        vm.run().unwrap();
+
+        let mut output = String::new();
+        dump_state(&vm, &mut output);
+
+        assert!(output.contains("answer = 42\n"), "{output}");
+        assert!(output.contains("\"answer\""), "{output}");
+        assert!(output.contains("<script>"), "{output}");
     }
 }
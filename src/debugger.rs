@@ -0,0 +1,286 @@
+use std::{collections::BTreeSet, fmt::Write, io::BufRead};
+
+use crate::{
+    debug::{disassemble_instruction, print_value},
+    vm::{InstructionPointer, InterpretResult, StepResult, VM},
+};
+
+const CONTEXT: usize = 3;
+
+const HELP: &str = "\
+s, step          execute one instruction
+n, next          execute until the source line changes
+c, continue      execute until a breakpoint or the end of the program
+b, break [line]  set a breakpoint, or list breakpoints
+d, delete <line> remove a breakpoint
+stack            show the value stack
+locals           show the slots of the current frame
+globals          show global variables
+bt, backtrace    show the call frames
+l, list          disassemble around the current instruction
+q, quit          stop debugging";
+
+pub struct Debugger {
+    pub vm: VM,
+    breakpoints: BTreeSet<usize>,
+    result: Option<InterpretResult>,
+}
+
+impl Debugger {
+    pub fn new(vm: VM) -> Debugger {
+        Debugger {
+            vm,
+            breakpoints: BTreeSet::new(),
+            result: None,
+        }
+    }
+
+    pub fn result(&self) -> Option<InterpretResult> {
+        self.result
+    }
+
+    pub fn add_breakpoint(&mut self, line: usize) {
+        self.breakpoints.insert(line);
+    }
+
+    pub fn remove_breakpoint(&mut self, line: usize) -> bool {
+        self.breakpoints.remove(&line)
+    }
+
+    pub fn step_instruction(&mut self) {
+        if self.result.is_some() {
+            return;
+        }
+        if let StepResult::Finished(result) = self.vm.step() {
+            self.result = Some(result);
+        }
+    }
+
+    pub fn step_line(&mut self) {
+        if self.result.is_some() {
+            return;
+        }
+        if let StepResult::Finished(result) = self.vm.step_line() {
+            self.result = Some(result);
+        }
+    }
+
+    pub fn continue_to_breakpoint(&mut self) {
+        let mut line = self.vm.current_line();
+        self.step_instruction();
+        while self.result.is_none() {
+            let current = self.vm.current_line();
+            if current != line && current.is_some_and(|l| self.breakpoints.contains(&l)) {
+                return;
+            }
+            line = current;
+            self.step_instruction();
+        }
+    }
+
+    pub fn run(&mut self, mut input: impl BufRead, output: &mut impl Write) {
+        self.location(output);
+
+        let mut last = String::new();
+        loop {
+            write!(output, "(rlox) ").unwrap();
+            let mut line = String::new();
+            match input.read_line(&mut line) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => (),
+            }
+            let command = match line.trim() {
+                "" => last.clone(),
+                command => command.to_owned(),
+            };
+            let mut words = command.split_whitespace();
+            let name = words.next().unwrap_or_default();
+            let arg = words.next().and_then(|w| w.parse::<usize>().ok());
+
+            match (name, arg) {
+                ("s" | "step", _) => {
+                    self.step_instruction();
+                    self.location(output);
+                }
+                ("n" | "next", _) => {
+                    self.step_line();
+                    self.location(output);
+                }
+                ("c" | "continue", _) => {
+                    self.continue_to_breakpoint();
+                    self.location(output);
+                }
+                ("b" | "break", Some(line)) => {
+                    self.add_breakpoint(line);
+                    writeln!(output, "Breakpoint at line {line}").unwrap();
+                }
+                ("b" | "break", None) => {
+                    for line in self.breakpoints.iter() {
+                        writeln!(output, "Breakpoint at line {line}").unwrap();
+                    }
+                }
+                ("d" | "delete", Some(line)) => {
+                    if self.remove_breakpoint(line) {
+                        writeln!(output, "Removed breakpoint at line {line}").unwrap();
+                    } else {
+                        writeln!(output, "No breakpoint at line {line}").unwrap();
+                    }
+                }
+                ("stack", _) => self.stack(output),
+                ("locals", _) => self.locals(output),
+                ("globals", _) => self.globals(output),
+                ("bt" | "backtrace", _) => self.backtrace(output),
+                ("l" | "list", _) => self.list(output),
+                ("h" | "help", _) => writeln!(output, "{HELP}").unwrap(),
+                ("q" | "quit", _) => return,
+                _ => writeln!(output, "Unknown command '{command}'; try 'help'").unwrap(),
+            }
+
+            if self.result.is_some() {
+                return;
+            }
+            last = command;
+        }
+    }
+
+    fn location(&self, output: &mut impl Write) {
+        if let Some(result) = self.result {
+            writeln!(output, "Program finished: {result:?}").unwrap();
+            return;
+        }
+
+        let frame = self.vm.current_frame().unwrap();
+        let function = self.vm.frame_function(frame);
+        let name = self.vm.memory.get_string(function.name);
+        let line = function.chunk.line(frame.instruction_pointer);
+        writeln!(output, "[line {line}] in {name}").unwrap();
+        disassemble_instruction(
+            &function.chunk,
+            frame.instruction_pointer,
+            &self.vm.memory,
+            output,
+        );
+    }
+
+    fn stack(&self, output: &mut impl Write) {
+        for value in self.vm.stack() {
+            write!(output, "[ ").unwrap();
+            print_value(value, &self.vm.memory, output);
+            write!(output, " ]").unwrap();
+        }
+        writeln!(output).unwrap();
+    }
+
+    fn locals(&self, output: &mut impl Write) {
+        let frame = self.vm.frames.len().saturating_sub(1);
+        for (i, value) in self.vm.locals().iter().enumerate() {
+            write!(output, "{i:>4}: ").unwrap();
+            if let Some(name) = self.vm.local_name(frame, i).filter(|name| !name.is_empty()) {
+                write!(output, "{name} = ").unwrap();
+            }
+            print_value(value, &self.vm.memory, output);
+            writeln!(output).unwrap();
+        }
+    }
+
+    fn globals(&self, output: &mut impl Write) {
+        let mut globals: Vec<_> = self
+            .vm
+            .globals
+            .iter()
+            .map(|(name, value)| (self.vm.memory.get_string(*name), value))
+            .collect();
+        globals.sort_by_key(|(name, _)| *name);
+
+        for (name, value) in globals {
+            write!(output, "{name} = ").unwrap();
+            print_value(value, &self.vm.memory, output);
+            writeln!(output).unwrap();
+        }
+    }
+
+    fn backtrace(&self, output: &mut impl Write) {
+        for (i, frame) in self.vm.frames.iter().enumerate().rev() {
+            let function = self.vm.frame_function(frame);
+            let name = self.vm.memory.get_string(function.name);
+            let line = function.chunk.line(frame.instruction_pointer);
+            writeln!(output, "#{i} [line {line}] in {name}").unwrap();
+        }
+    }
+
+    fn list(&self, output: &mut impl Write) {
+        let Some(frame) = self.vm.current_frame() else {
+            return;
+        };
+        let function = self.vm.frame_function(frame);
+        let chunk = &function.chunk;
+        let current = frame.instruction_pointer;
+
+        let mut offsets = Vec::new();
+        let mut offset = InstructionPointer(0);
+        let mut scratch = String::new();
+        while offset.0 < chunk.code.len() {
+            offsets.push(offset);
+            offset = disassemble_instruction(chunk, offset, &self.vm.memory, &mut scratch);
+        }
+
+        let index = offsets.iter().position(|o| *o == current).unwrap_or(0);
+        let from = index.saturating_sub(CONTEXT);
+        let to = (index + CONTEXT + 1).min(offsets.len());
+        for offset in offsets[from..to].iter() {
+            let marker = if *offset == current { "=>" } else { "  " };
+            write!(output, "{marker} ").unwrap();
+            disassemble_instruction(chunk, *offset, &self.vm.memory, output);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{compiler::compile, config::Config};
+
+    fn debugger(source: &str) -> (Debugger, Rc<RefCell<String>>) {
+        let output = Rc::new(RefCell::new(String::new()));
+        let mut config = Config::default();
+        config.print_output.redirect(output.clone());
+        let program = compile(Rc::from(source), Config::default()).unwrap();
+        (Debugger::new(VM::load(&program, config)), output)
+    }
+
+    #[test]
+    fn continue_stops_at_breakpoint() {
+        let (mut debugger, output) = debugger("print 1;\nprint 2;\nprint 3;\n");
+        debugger.add_breakpoint(3);
+
+        debugger.continue_to_breakpoint();
+        assert_eq!(debugger.vm.current_line(), Some(3));
+        assert_eq!(*output.borrow(), "1\n2\n");
+
+        debugger.continue_to_breakpoint();
+        assert_eq!(debugger.result(), Some(InterpretResult::OK));
+        assert_eq!(*output.borrow(), "1\n2\n3\n");
+    }
+
+    #[test]
+    fn next_steps_over_a_line() {
+        let (mut debugger, output) = debugger("var a = 1;\nprint a;\n");
+
+        debugger.step_line();
+        assert_eq!(debugger.vm.current_line(), Some(2));
+        assert_eq!(*output.borrow(), "");
+    }
+
+    #[test]
+    fn interactive_session() {
+        let (mut debugger, _) = debugger("var a = 1;\nprint a + 2;\n");
+        let mut output = String::new();
+
+        debugger.run("n\nglobals\nq\n".as_bytes(), &mut output);
+
+        assert!(output.contains("[line 2] in <script>"), "{output}");
+        assert!(output.contains("a = 1"), "{output}");
+    }
+}
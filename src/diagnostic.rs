@@ -0,0 +1,253 @@
+use std::{
+    error::Error,
+    fmt::{self, Write},
+    sync::Arc,
+};
+
+use crate::{scanner::Span, trace::json_string};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Severity {
+    Error = 1,
+    Warning = 2,
+    Information = 3,
+    Hint = 4,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub span: Span,
+    pub column: usize,
+    pub message: String,
+    pub snippet: String,
+    pub code: &'static str,
+    pub file: Option<Arc<str>>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct CompileError {
+    pub diagnostics: Vec<Diagnostic>,
+}
+
+impl fmt::Display for CompileError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, diagnostic) in self.diagnostics.iter().enumerate() {
+            if i > 0 {
+                writeln!(f)?;
+            }
+            write!(f, "[line {}] {}", diagnostic.span.line, diagnostic.message)?;
+        }
+        Ok(())
+    }
+}
+
+impl Error for CompileError {}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Position {
+    pub line: usize,
+    pub character: usize,
+}
+
+impl Position {
+    pub fn at(source: &str, offset: usize) -> Position {
+        let offset = offset.min(source.len());
+        let before = &source.as_bytes()[..offset];
+        let line_start = before
+            .iter()
+            .rposition(|b| *b == b'\n')
+            .map_or(0, |i| i + 1);
+        let line = before.iter().filter(|b| **b == b'\n').count();
+        let character = String::from_utf8_lossy(&before[line_start..])
+            .encode_utf16()
+            .count();
+        Position { line, character }
+    }
+}
+
+pub fn write_excerpt(
+    output: &mut impl Write,
+    line: usize,
+    text: &str,
+    column: usize,
+    width: usize,
+) {
+    let text = text.trim_end();
+    writeln!(output, "{line:>5} | {text}").unwrap();
+    if width == 0 {
+        return;
+    }
+
+    let column = (0..=column.min(text.len()))
+        .rev()
+        .find(|i| text.is_char_boundary(*i))
+        .unwrap_or(0);
+    let pad: String = text[..column]
+        .chars()
+        .map(|c| if c == '\t' { '\t' } else { ' ' })
+        .collect();
+    let end = (column + width).min(text.len());
+    let width = text
+        .get(column..end)
+        .map_or(1, |s| s.chars().count().max(1));
+    writeln!(output, "      | {pad}^{}", "~".repeat(width - 1)).unwrap();
+}
+
+pub fn write_json(uri: &str, source: &str, diagnostics: &[Diagnostic], output: &mut impl Write) {
+    write!(output, r#"{{"uri":{},"diagnostics":["#, json_string(uri)).unwrap();
+    for (i, diagnostic) in diagnostics.iter().enumerate() {
+        if i > 0 {
+            write!(output, ",").unwrap();
+        }
+        let start = Position::at(source, diagnostic.span.start);
+        let end = Position::at(source, diagnostic.span.end);
+        write!(
+            output,
+            r#"{{"range":{{"start":{{"line":{},"character":{}}},"end":{{"line":{},"character":{}}}}},"severity":{},"code":{},"source":"rlox","message":{}}}"#,
+            start.line,
+            start.character,
+            end.line,
+            end.character,
+            diagnostic.severity as u8,
+            json_string(diagnostic.code),
+            json_string(&diagnostic.message)
+        )
+        .unwrap();
+    }
+    writeln!(output, "]}}").unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use std::rc::Rc;
+
+    use super::*;
+    use crate::{
+        compiler::{check, compile},
+        config::{Config, PrintOutput, Warnings},
+    };
+
+    fn diagnostics(source: &str) -> Vec<Diagnostic> {
+        let config = Config {
+            compiler_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        check(Rc::from(source), config)
+    }
+
+    #[test]
+    fn positions_are_zero_based() {
+        let source = "var a;\n  print \u{e9}x;";
+        assert_eq!(
+            Position::at(source, 0),
+            Position {
+                line: 0,
+                character: 0
+            }
+        );
+        assert_eq!(
+            Position::at(source, 17),
+            Position {
+                line: 1,
+                character: 9
+            }
+        );
+    }
+
+    #[test]
+    fn collects_compile_errors() {
+        let diagnostics = diagnostics("var a = 1;\nprint a +;\n");
+        assert_eq!(
+            diagnostics,
+            vec![Diagnostic {
+                severity: Severity::Error,
+                span: Span {
+                    line: 2,
+                    start: 20,
+                    end: 21
+                },
+                column: 9,
+                message: "Expect expression".into(),
+                snippet: "print a +;".into(),
+                code: "C001",
+                file: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn compile_returns_every_diagnostic() {
+        let config = Config {
+            compiler_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let Err(error) = compile(Rc::from("var a = ;\n  print a +;\n"), config) else {
+            panic!("expected compile errors");
+        };
+        let found: Vec<_> = error
+            .diagnostics
+            .iter()
+            .map(|d| (d.span.line, d.column, d.snippet.as_str()))
+            .collect();
+        assert_eq!(found, vec![(1, 8, "var a = ;"), (2, 11, "  print a +;")]);
+        assert_eq!(
+            error.to_string(),
+            "[line 1] Expect expression\n[line 2] Expect expression"
+        );
+    }
+
+    #[test]
+    fn warnings_are_opt_in() {
+        let source = "fun f(a) {\n  var b = 1;\n  return a;\n  print a;\n}\nif (nil) print 1;\nwhile (true) { break; }\n";
+        assert!(diagnostics(source).is_empty());
+
+        let config = Config {
+            compiler_error: PrintOutput::Null,
+            warnings: Warnings::all(),
+            ..Default::default()
+        };
+        let found: Vec<_> = check(Rc::from(source), config)
+            .iter()
+            .map(|d| (d.severity, d.span.line, d.code, d.message.clone()))
+            .collect();
+        assert_eq!(
+            found,
+            vec![
+                (Severity::Warning, 4, "W002", "Unreachable code".into()),
+                (
+                    Severity::Warning,
+                    2,
+                    "W001",
+                    "Local variable 'b' is never read".into()
+                ),
+                (
+                    Severity::Warning,
+                    6,
+                    "W003",
+                    "Condition is always false".into()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_errors_point_at_the_character() {
+        let diagnostics = diagnostics("print @;");
+        assert_eq!(diagnostics[0].code, "L001");
+        assert_eq!(diagnostics[0].span.start, 6);
+        assert_eq!(diagnostics[0].span.end, 7);
+    }
+
+    #[test]
+    fn json_output() {
+        let source = "print ;";
+        let mut output = String::new();
+        write_json("file:///a.lox", source, &diagnostics(source), &mut output);
+        assert_eq!(
+            output,
+            r#"{"uri":"file:///a.lox","diagnostics":[{"range":{"start":{"line":0,"character":6},"end":{"line":0,"character":7}},"severity":1,"code":"C001","source":"rlox","message":"Expect expression"}]}
+"#
+        );
+    }
+}
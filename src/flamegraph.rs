@@ -0,0 +1,162 @@
+use std::{collections::HashMap, fmt::Write, time::Instant};
+
+use crate::{
+    memory::{FunctionId, Memory},
+    observer::{Instruction, Observer},
+};
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Weight {
+    Instructions,
+    Microseconds,
+    Samples(u64),
+}
+
+pub struct FoldedStacks {
+    weight: Weight,
+    stack: Vec<String>,
+    current: String,
+    samples: HashMap<String, u64>,
+    last_event: Instant,
+    until_sample: u64,
+}
+
+impl FoldedStacks {
+    pub fn new(weight: Weight) -> FoldedStacks {
+        FoldedStacks {
+            weight,
+            stack: Vec::new(),
+            current: String::new(),
+            samples: HashMap::new(),
+            last_event: Instant::now(),
+            until_sample: 0,
+        }
+    }
+
+    pub fn write_folded(&self, output: &mut impl Write) {
+        let mut samples = self.samples.clone();
+        if self.weight == Weight::Microseconds && !self.current.is_empty() {
+            let elapsed = self.last_event.elapsed().as_micros() as u64;
+            *samples.entry(self.current.clone()).or_default() += elapsed;
+        }
+
+        let mut lines: Vec<_> = samples.into_iter().filter(|(_, w)| *w > 0).collect();
+        lines.sort();
+        for (stack, weight) in lines {
+            writeln!(output, "{stack} {weight}").unwrap();
+        }
+    }
+
+    fn record_elapsed(&mut self) {
+        if self.weight != Weight::Microseconds {
+            return;
+        }
+        let now = Instant::now();
+        if !self.current.is_empty() {
+            let elapsed = (now - self.last_event).as_micros() as u64;
+            *self.samples.entry(self.current.clone()).or_default() += elapsed;
+        }
+        self.last_event = now;
+    }
+
+    fn sample(&mut self) {
+        if self.current.is_empty() {
+            return;
+        }
+        if let Some(weight) = self.samples.get_mut(&self.current) {
+            *weight += 1;
+        } else {
+            self.samples.insert(self.current.clone(), 1);
+        }
+    }
+
+    fn refresh_current(&mut self) {
+        self.current = self.stack.join(";");
+    }
+}
+
+impl Observer for FoldedStacks {
+    fn on_call(&mut self, function: FunctionId, memory: &Memory) {
+        self.record_elapsed();
+        let name = memory.get_string(memory.function(function).name);
+        // ';' separates frames and ' ' separates the weight in the folded format
+        self.stack.push(name.replace([';', ' '], "_"));
+        self.refresh_current();
+    }
+
+    fn on_return(&mut self, _function: FunctionId, _memory: &Memory) {
+        self.record_elapsed();
+        self.stack.pop();
+        self.refresh_current();
+    }
+
+    fn on_instruction(&mut self, _instruction: &Instruction) {
+        match self.weight {
+            Weight::Instructions => self.sample(),
+            Weight::Samples(interval) => {
+                if self.until_sample == 0 {
+                    self.until_sample = interval.max(1);
+                    self.sample();
+                }
+                self.until_sample -= 1;
+            }
+            Weight::Microseconds => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{config::Config, vm::interpret};
+
+    #[test]
+    fn folds_stacks_by_instruction_count() {
+        let stacks = Rc::new(RefCell::new(FoldedStacks::new(Weight::Instructions)));
+        let mut config = Config::default();
+        config.observe(stacks.clone());
+
+        interpret(
+            r#"
+            fun inner() { return 1; }
+            fun outer() { return inner() + 1; }
+            outer();
+        "#,
+            config,
+        )
+        .unwrap();
+
+        let mut output = String::new();
+        stacks.borrow().write_folded(&mut output);
+        assert_eq!(
+            output,
+            "<script> 9\n<script>;outer 5\n<script>;outer;inner 2\n"
+        );
+    }
+
+    #[test]
+    fn samples_every_n_instructions() {
+        let stacks = Rc::new(RefCell::new(FoldedStacks::new(Weight::Samples(3))));
+        let mut config = Config::default();
+        config.observe(stacks.clone());
+
+        interpret(
+            r#"
+            fun inner() { return 1; }
+            fun outer() { return inner() + 1; }
+            outer();
+        "#,
+            config,
+        )
+        .unwrap();
+
+        let mut output = String::new();
+        stacks.borrow().write_folded(&mut output);
+        assert_eq!(
+            output,
+            "<script> 3\n<script>;outer 2\n<script>;outer;inner 1\n"
+        );
+    }
+}
@@ -0,0 +1,151 @@
+use std::{any::Any, marker::PhantomData};
+
+use crate::{
+    memory::{Memory, NativeError, NativeFunctionId},
+    string_intern::StrId,
+    value::{FromArgs, FromLox, IntoLox, Value},
+    vm::VM,
+};
+
+pub struct ForeignType<'a, T> {
+    memory: &'a mut Memory,
+    marker: PhantomData<T>,
+}
+
+impl<'a, T: Any> ForeignType<'a, T> {
+    pub fn new(memory: &'a mut Memory) -> Self {
+        ForeignType {
+            memory,
+            marker: PhantomData,
+        }
+    }
+
+    pub fn method<F>(mut self, name: &str, method: F) -> Self
+    where
+        F: Fn(&mut T, &mut Memory, &[Value]) -> Result<Value, NativeError> + 'static,
+    {
+        let (name, native) = self.native(name, method);
+        self.memory
+            .foreign_class_mut::<T>()
+            .methods
+            .insert(name, native);
+        self
+    }
+
+    pub fn getter<F>(mut self, name: &str, get: F) -> Self
+    where
+        F: Fn(&mut T, &mut Memory) -> Result<Value, NativeError> + 'static,
+    {
+        let (name, native) = self.native(name, move |object, memory, _| get(object, memory));
+        self.memory
+            .foreign_class_mut::<T>()
+            .getters
+            .insert(name, native);
+        self
+    }
+
+    pub fn setter<F>(mut self, name: &str, set: F) -> Self
+    where
+        F: Fn(&mut T, &mut Memory, Value) -> Result<(), NativeError> + 'static,
+    {
+        let (name, native) = self.native(name, move |object, memory, args| match args {
+            [value] => set(object, memory, *value).map(|_| *value),
+            _ => Err("Expected a value".into()),
+        });
+        self.memory
+            .foreign_class_mut::<T>()
+            .setters
+            .insert(name, native);
+        self
+    }
+
+    fn native<F>(&mut self, name: &str, f: F) -> (StrId, NativeFunctionId)
+    where
+        F: Fn(&mut T, &mut Memory, &[Value]) -> Result<Value, NativeError> + 'static,
+    {
+        let native = self.memory.new_native(name, move |memory, args| {
+            let Some((receiver, args)) = args.split_first() else {
+                return Err("Expected a receiver".into());
+            };
+            let receiver = receiver
+                .as_foreign()
+                .ok_or("Receiver is not a foreign object")?;
+            memory
+                .with_foreign(receiver, |object, memory| f(object, memory, args))
+                .ok_or("Receiver has the wrong type")?
+        });
+        (self.memory.string_id(name), native)
+    }
+}
+
+pub struct LoxBindings<'a, T> {
+    vm: &'a mut VM,
+    name: String,
+    marker: PhantomData<T>,
+}
+
+impl<'a, T: Any> LoxBindings<'a, T> {
+    pub fn new(vm: &'a mut VM, name: &str) -> Self {
+        LoxBindings {
+            vm,
+            name: name.to_owned(),
+            marker: PhantomData,
+        }
+    }
+
+    pub fn constructor<A, F>(self, construct: F) -> Self
+    where
+        A: FromArgs,
+        F: Fn(A) -> T + 'static,
+    {
+        self.vm
+            .define_fallible_native(&self.name, move |memory, args| {
+                let args = A::from_args(args, memory)?;
+                Ok(Value::Foreign(memory.new_foreign(construct(args))))
+            });
+        self
+    }
+
+    pub fn field<V, G, S>(mut self, name: &str, get: G, set: S) -> Self
+    where
+        V: FromLox + IntoLox,
+        G: Fn(&T) -> V + 'static,
+        S: Fn(&mut T, V) + 'static,
+    {
+        let field = format!("{}.{name}", self.name);
+        self.foreign().setter(name, move |object, memory, value| {
+            let value = V::from_lox(value, memory)
+                .ok_or_else(|| format!("Wrong type assigned to '{field}'"))?;
+            set(object, value);
+            Ok(())
+        });
+        self.readonly(name, get)
+    }
+
+    pub fn readonly<V, G>(mut self, name: &str, get: G) -> Self
+    where
+        V: IntoLox,
+        G: Fn(&T) -> V + 'static,
+    {
+        self.foreign()
+            .getter(name, move |object, memory| Ok(get(object).into_lox(memory)));
+        self
+    }
+
+    pub fn method<A, R, F>(mut self, name: &str, method: F) -> Self
+    where
+        A: FromArgs,
+        R: IntoLox,
+        F: Fn(&mut T, A) -> R + 'static,
+    {
+        self.foreign().method(name, move |object, memory, args| {
+            let args = A::from_args(args, memory)?;
+            Ok(method(object, args).into_lox(memory))
+        });
+        self
+    }
+
+    fn foreign(&mut self) -> ForeignType<'_, T> {
+        ForeignType::new(&mut self.vm.memory)
+    }
+}
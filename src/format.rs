@@ -0,0 +1,291 @@
+use crate::scanner::{tokenize, SpannedToken, TokenType};
+
+const INDENT: &str = "    ";
+
+pub fn format(source: &str) -> Option<String> {
+    let tokens = tokenize(source);
+    if tokens.iter().any(|token| token.typ == TokenType::Error) {
+        return None;
+    }
+
+    let mut formatter = Formatter::default();
+    let mut end = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        formatter.gap(&source[end..token.span.start]);
+        formatter.token(token, tokens.get(i + 1).map(|next| next.typ));
+        end = token.span.end;
+    }
+    formatter.gap(&source[end..]);
+
+    let mut output = formatter.output;
+    if !output.is_empty() {
+        output.push('\n');
+    }
+    Some(output)
+}
+
+#[derive(Default)]
+struct Formatter {
+    output: String,
+    indent: usize,
+    parens: usize,
+    line_start: bool,
+    pending_newline: bool,
+    blank_lines: bool,
+    commented: bool,
+    previous: Option<TokenType>,
+    unary: bool,
+}
+
+impl Formatter {
+    fn gap(&mut self, gap: &str) {
+        self.commented = false;
+        let mut rest = gap;
+        let mut newlines = 0;
+        loop {
+            let comment = match (rest.find("//"), rest.find("/*")) {
+                (Some(line), Some(block)) => line.min(block),
+                (Some(start), None) | (None, Some(start)) => start,
+                (None, None) => break,
+            };
+            newlines += rest[..comment].matches('\n').count();
+            rest = &rest[comment..];
+            let len = if rest.starts_with("//") {
+                rest.find('\n').unwrap_or(rest.len())
+            } else {
+                block_comment_len(rest)
+            };
+            self.comment(rest[..len].trim_end(), newlines);
+            rest = &rest[len..];
+            newlines = 0;
+        }
+        newlines += rest.matches('\n').count();
+        self.blank_lines = newlines >= 2;
+    }
+
+    fn comment(&mut self, text: &str, newlines: usize) {
+        if newlines == 0 && !self.output.is_empty() && !self.line_start {
+            self.output.push(' ');
+        } else {
+            if !self.output.is_empty() {
+                if self.pending_newline || !self.line_start {
+                    self.newline();
+                }
+                if newlines >= 2 && self.previous != Some(TokenType::LeftBrace) {
+                    self.newline();
+                }
+            }
+            self.push_indent();
+        }
+        self.output.push_str(text);
+        self.line_start = false;
+        self.pending_newline = true;
+        self.commented = true;
+    }
+
+    fn token(&mut self, token: &SpannedToken, next: Option<TokenType>) {
+        use TokenType::*;
+        let typ = token.typ;
+
+        if typ == RightBrace {
+            self.indent = self.indent.saturating_sub(1);
+            if self.previous == Some(LeftBrace) && !self.commented {
+                self.pending_newline = false;
+                self.line_start = false;
+            }
+        }
+
+        if self.pending_newline {
+            self.newline();
+            if self.blank_lines && self.previous != Some(LeftBrace) && typ != RightBrace {
+                self.newline();
+            }
+            self.pending_newline = false;
+        }
+
+        if self.line_start {
+            self.push_indent();
+        } else if !self.output.is_empty() && self.space_before(typ) {
+            self.output.push(' ');
+        }
+        self.output.push_str(&token.text);
+        self.line_start = false;
+
+        self.unary = match typ {
+            Bang => true,
+            Minus => !matches!(
+                self.previous,
+                Some(
+                    Identifier
+                        | Number
+                        | String
+                        | RightParen
+                        | RightBracket
+                        | True
+                        | False
+                        | Nil
+                        | This
+                )
+            ),
+            _ => false,
+        };
+
+        match typ {
+            LeftParen | LeftBracket => self.parens += 1,
+            RightParen | RightBracket => self.parens = self.parens.saturating_sub(1),
+            LeftBrace => {
+                self.indent += 1;
+                self.pending_newline = true;
+            }
+            RightBrace => {
+                self.pending_newline =
+                    !matches!(next, Some(Else | SemiColon | Comma | RightParen | Dot));
+            }
+            SemiColon => self.pending_newline = self.parens == 0,
+            _ => (),
+        }
+        self.previous = Some(typ);
+    }
+
+    fn space_before(&self, typ: TokenType) -> bool {
+        use TokenType::*;
+        if self.unary {
+            return false;
+        }
+        if matches!(
+            typ,
+            RightParen | RightBracket | Comma | SemiColon | Dot | QuestionDot
+        ) {
+            return false;
+        }
+        let previous = self.previous;
+        if matches!(
+            previous,
+            Some(LeftParen | LeftBracket | Dot | QuestionDot | Ellipsis)
+        ) {
+            return false;
+        }
+        if typ == RightBrace && previous == Some(LeftBrace) && !self.commented {
+            return false;
+        }
+        match typ {
+            LeftParen => !matches!(
+                previous,
+                Some(Identifier | RightParen | RightBracket | This | Super | Assert)
+            ),
+            LeftBracket => !matches!(
+                previous,
+                Some(Identifier | String | RightParen | RightBracket)
+            ),
+            _ => true,
+        }
+    }
+
+    fn newline(&mut self) {
+        let trimmed = self.output.trim_end_matches(' ').len();
+        self.output.truncate(trimmed);
+        self.output.push('\n');
+        self.line_start = true;
+    }
+
+    fn push_indent(&mut self) {
+        for _ in 0..self.indent {
+            self.output.push_str(INDENT);
+        }
+    }
+}
+
+fn block_comment_len(comment: &str) -> usize {
+    let mut depth = 0;
+    let mut i = 0;
+    let bytes = comment.as_bytes();
+    while i < bytes.len() {
+        if bytes[i..].starts_with(b"/*") {
+            depth += 1;
+            i += 2;
+        } else if bytes[i..].starts_with(b"*/") {
+            depth -= 1;
+            i += 2;
+            if depth == 0 {
+                return i;
+            }
+        } else {
+            i += 1;
+        }
+    }
+    bytes.len()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::scanner::tokenize;
+
+    #[test]
+    fn normalizes_indentation_and_spacing() {
+        let source = "class A<B{init(x){this.x=x;}\nget( ){return this.x ;}}\n\n\n\nfun f(a,b){if(a>-b){print a[0]+f(b , -a);}else{return !a;}\nfor(var i=0;i<3;i=i+1)print i;}\nvar l=[1,2 ,3];\n";
+        assert_eq!(
+            format(source).unwrap(),
+            "\
+class A < B {
+    init(x) {
+        this.x = x;
+    }
+    get() {
+        return this.x;
+    }
+}
+
+fun f(a, b) {
+    if (a > -b) {
+        print a[0] + f(b, -a);
+    } else {
+        return !a;
+    }
+    for (var i = 0; i < 3; i = i + 1) print i;
+}
+var l = [1, 2, 3];
+"
+        );
+    }
+
+    #[test]
+    fn keeps_comments() {
+        let source = "// header\n\nvar a = 1; // trailing\n{\n  /* block\n     comment */\n   print a;\n\n\n  // before close\n}\nclass E {}\n// end";
+        assert_eq!(
+            format(source).unwrap(),
+            "\
+// header
+
+var a = 1; // trailing
+{
+    /* block
+     comment */
+    print a;
+
+    // before close
+}
+class E {}
+// end
+"
+        );
+    }
+
+    #[test]
+    fn formatting_is_stable() {
+        let source =
+            "fun fib(n){if(n<2)return n;// base\nreturn fib(n-2)+fib(n-1);}\nprint fib(10);";
+        let once = format(source).unwrap();
+        assert_eq!(format(&once).unwrap(), once);
+
+        let texts =
+            |source: &str| -> Vec<_> { tokenize(source).into_iter().map(|t| t.text).collect() };
+        assert_eq!(texts(&once), texts(source));
+    }
+
+    #[test]
+    fn refuses_invalid_tokens() {
+        assert_eq!(format("print @;"), None);
+        assert_eq!(format(""), Some(String::new()));
+    }
+}
@@ -0,0 +1,202 @@
+#[derive(Clone)]
+pub struct Heap<T> {
+    slots: Vec<Option<T>>,
+    marks: Vec<bool>,
+    free: Vec<usize>,
+}
+
+impl<T> Default for Heap<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Heap<T> {
+    pub fn new() -> Heap<T> {
+        Heap {
+            slots: Vec::new(),
+            marks: Vec::new(),
+            free: Vec::new(),
+        }
+    }
+
+    pub fn insert(&mut self, value: T) -> usize {
+        if let Some(index) = self.free.pop() {
+            self.slots[index] = Some(value);
+            index
+        } else {
+            self.slots.push(Some(value));
+            self.marks.push(false);
+            self.slots.len() - 1
+        }
+    }
+
+    pub fn get(&self, index: usize) -> &T {
+        self.slots[index].as_ref().expect("Object was freed")
+    }
+
+    pub fn get_mut(&mut self, index: usize) -> &mut T {
+        self.slots[index].as_mut().expect("Object was freed")
+    }
+
+    pub fn try_get(&self, index: usize) -> Option<&T> {
+        self.slots.get(index)?.as_ref()
+    }
+
+    pub fn try_get_mut(&mut self, index: usize) -> Option<&mut T> {
+        self.slots.get_mut(index)?.as_mut()
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.slots
+            .iter()
+            .enumerate()
+            .filter_map(|(i, slot)| slot.as_ref().map(|value| (i, value)))
+    }
+
+    pub fn mark(&mut self, index: usize) -> bool {
+        !std::mem::replace(&mut self.marks[index], true)
+    }
+
+    pub fn sweep(&mut self) -> usize {
+        let mut freed = 0;
+        for (index, slot) in self.slots.iter_mut().enumerate() {
+            if std::mem::take(&mut self.marks[index]) || slot.is_none() {
+                continue;
+            }
+            *slot = None;
+            self.free.push(index);
+            freed += 1;
+        }
+        freed
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct GcConfig {
+    pub heap_grow_factor: usize,
+    pub initial_heap: usize,
+    pub stress: bool,
+}
+
+impl Default for GcConfig {
+    fn default() -> Self {
+        Self {
+            heap_grow_factor: 2,
+            initial_heap: 1024,
+            stress: false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        compiler::compile,
+        config::{Config, PrintOutput},
+        vm::VM,
+    };
+
+    #[test]
+    fn sweep_frees_unmarked_slots_for_reuse() {
+        let mut heap = Heap::new();
+        let a = heap.insert("a");
+        let b = heap.insert("b");
+        heap.mark(a);
+        assert_eq!(heap.sweep(), 1);
+        assert_eq!(heap.len(), 1);
+        assert_eq!(heap.insert("c"), b);
+        assert_eq!(*heap.get(a), "a");
+        assert_eq!(heap.sweep(), 2);
+        assert!(heap.is_empty());
+    }
+
+    fn run(source: &str, gc: GcConfig) -> (String, usize) {
+        let output = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            print_output: PrintOutput::Str(output.clone()),
+            gc,
+            ..Default::default()
+        };
+        let program = compile(Rc::from(source), Config::default()).unwrap();
+        let mut vm = VM::load(&program, config);
+        assert_eq!(vm.run(), Ok(()));
+        let output = output.borrow().clone();
+        (output, vm.memory.objects())
+    }
+
+    const PROGRAM: &str = r#"
+        class Node {
+            init(value, next) {
+                this.value = value;
+                this.next = next;
+            }
+            sum() {
+                if (this.next == nil) return this.value;
+                return this.value + this.next.sum();
+            }
+        }
+
+        var kept = Node(1, Node(2, nil));
+        for (var i = 0; i < 500; i = i + 1) {
+            var garbage = Node(i, Node(i, nil));
+            garbage.sum();
+        }
+        print kept.sum();
+    "#;
+
+    #[test]
+    fn stress_collection_keeps_reachable_objects() {
+        let stress = GcConfig {
+            stress: true,
+            ..Default::default()
+        };
+        let (output, objects) = run(PROGRAM, stress);
+        assert_eq!(output, "3\n");
+        assert!(objects < 20, "{objects} objects survived");
+    }
+
+    #[test]
+    fn strings_outlive_collection() {
+        let config = Config {
+            print_output: PrintOutput::Null,
+            ..Default::default()
+        };
+        let source = r#"
+            for (var i = 0; i < 100; i = i + 1) {
+                var garbage = "item " + str(i);
+            }
+        "#;
+        let program = compile(Rc::from(source), Config::default()).unwrap();
+        let mut vm = VM::load(&program, config);
+        assert_eq!(vm.run(), Ok(()));
+
+        let strings = vm.memory.strings().count();
+        vm.collect_garbage();
+        assert_eq!(vm.memory.strings().count(), strings);
+        assert!(vm.memory.strings().any(|(_, s)| s == "item 99"));
+    }
+
+    #[test]
+    fn heap_grows_by_factor() {
+        let gc = GcConfig {
+            heap_grow_factor: 2,
+            initial_heap: 64,
+            stress: false,
+        };
+        let (output, objects) = run(PROGRAM, gc);
+        assert_eq!(output, "3\n");
+        assert!(objects <= 128, "{objects} objects survived");
+    }
+}
@@ -1,32 +1,52 @@
+pub mod asm;
+pub mod ast;
 pub mod chunk;
 pub mod compiler;
 pub mod config;
 pub mod debug;
+pub mod debugger;
+pub mod diagnostic;
+pub mod flamegraph;
+pub mod foreign;
+pub mod format;
+pub mod gc;
 pub mod memory;
+pub mod messages;
+pub mod observer;
+pub mod peephole;
+pub mod profiler;
 pub mod rc_slice;
 pub mod scanner;
+pub mod serialize;
 pub mod string_intern;
+pub mod test_runner;
+pub mod testing;
+pub mod trace;
 pub mod value;
+pub mod verify;
 pub mod vm;
 
 #[cfg(test)]
 mod tests {
     use std::{cell::RefCell, rc::Rc};
 
-    use crate::config::{Config, PrintOutput};
+    use crate::config::{Config, DivisionByZero, NumberFormat, PrintOutput};
+    use crate::testing::{run_script, run_script_with};
 
     fn interpret(str: &str) {
-        let mut config = Config::default();
-        config.compiler_debug = PrintOutput::StdOut;
-        config.vm_debug = PrintOutput::StdOut;
-        crate::vm::interpret(str, config);
+        let config = Config {
+            compiler_debug: PrintOutput::StdOut,
+            vm_debug: PrintOutput::StdOut,
+            ..Default::default()
+        };
+        let _ = crate::vm::interpret(str, config);
     }
 
     fn interpret_str(str: &str) -> String {
         let mut config = Config::default();
         let output = Rc::new(RefCell::new(String::new()));
         config.print_output.redirect(output.clone());
-        crate::vm::interpret(str, config);
+        let _ = crate::vm::interpret(str, config);
         let rc = output.borrow();
         rc.trim_matches('\n').trim_matches('"').into()
     }
@@ -107,6 +127,461 @@ mod tests {
         );
     }
 
+    #[test]
+    fn clock_precision_and_timers() {
+        use crate::config::Limits;
+
+        let res = interpret_str(
+            r#"
+            var start = clock();
+            var millis = clockMillis();
+            sleep(20);
+            var elapsed = clock() - start;
+            print elapsed >= 0.02 and elapsed < 10;
+            print clockMillis() - millis >= 20;
+            print sleep(0);
+        "#,
+        );
+        assert_eq!(res, "true\ntrue\nnil");
+
+        let config = Config {
+            capabilities: crate::config::Capabilities::untrusted(),
+            vm_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        assert!(crate::vm::interpret("sleep(1);", config).is_err());
+
+        let sleep_error = |source: &str| {
+            let config = Config {
+                vm_error: PrintOutput::Null,
+                limits: Limits {
+                    max_sleep_ms: 50,
+                    ..Default::default()
+                },
+                ..Default::default()
+            };
+            crate::vm::interpret(source, config).unwrap_err().message
+        };
+        assert_eq!(
+            sleep_error("sleep(1e300);"),
+            "Error in sleep: Sleep duration exceeds the limit of 50 ms"
+        );
+        assert_eq!(
+            sleep_error("sleep(51);"),
+            "Error in sleep: Sleep duration exceeds the limit of 50 ms"
+        );
+        assert_eq!(
+            sleep_error("sleep(-1);"),
+            "Error in sleep: Sleep duration must be a non-negative number"
+        );
+        assert_eq!(
+            sleep_error("sleep(0 / 0);"),
+            "Error in sleep: Sleep duration must be a non-negative number"
+        );
+        assert_eq!(
+            sleep_error("sleep(\"1\");"),
+            "Error in sleep: Sleep duration must be a number"
+        );
+    }
+
+    #[test]
+    fn io_natives() {
+        let config = |allow_io| Config {
+            allow_io,
+            vm_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let path = std::env::temp_dir().join(format!("rlox-io-{}.txt", std::process::id()));
+        let path = path.to_string_lossy().replace('\\', "/");
+
+        let source = format!(
+            r#"
+            writeFile("{path}", "line " + "one");
+            print readFile("{path}");
+        "#
+        );
+        crate::assert_lox! { &source, config(true) => "line one\n" };
+        std::fs::remove_file(&path).unwrap();
+
+        let error = crate::vm::interpret(&format!("readFile(\"{path}\");"), config(true));
+        assert!(error
+            .unwrap_err()
+            .message
+            .starts_with(&format!("Error in readFile: Could not read '{path}'")));
+        let error = crate::vm::interpret("writeFile(1, 2);", config(true)).unwrap_err();
+        assert_eq!(error.message, "Error in writeFile: Path must be a string");
+
+        let error = crate::vm::interpret("readFile(\"x\");", config(false)).unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'readFile'");
+
+        let errors = Rc::new(RefCell::new(String::new()));
+        let mut with_errors = config(true);
+        with_errors.eprint_output.redirect(errors.clone());
+        let source = "eprint(\"warn \", 1); eprint([true]);";
+        assert_eq!(crate::vm::interpret(source, with_errors), Ok(()));
+        assert_eq!(errors.take(), "warn 1\n[true]\n");
+    }
+
+    #[test]
+    fn script_args_and_env() {
+        let config = |allow_env| Config {
+            allow_env,
+            args: vec!["one".into(), "two".into()],
+            vm_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        std::env::set_var("RLOX_TEST_ENV", "value");
+
+        let source = r#"
+            var args = args();
+            print len(args);
+            print args[1];
+            print env("RLOX_TEST_ENV");
+            print env("RLOX_TEST_ENV_UNSET");
+        "#;
+        crate::assert_lox! { source, config(true) => "2\ntwo\nvalue\nnil\n" };
+
+        let error = crate::vm::interpret("env(\"RLOX_TEST_ENV\");", config(false)).unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'env'");
+    }
+
+    #[test]
+    fn writer_and_callback_outputs() {
+        struct Shared(Rc<RefCell<Vec<u8>>>);
+        impl std::io::Write for Shared {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.borrow_mut().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let bytes = Rc::new(RefCell::new(Vec::new()));
+        let config = Config {
+            print_output: PrintOutput::writer(Shared(bytes.clone())),
+            ..Default::default()
+        };
+        assert_eq!(
+            crate::vm::interpret("print 1; print \"two\";", config),
+            Ok(())
+        );
+        assert_eq!(bytes.borrow().as_slice(), b"1\ntwo\n");
+
+        let lines = Rc::new(RefCell::new(Vec::new()));
+        let sink = lines.clone();
+        let config = Config {
+            vm_error: PrintOutput::callback(move |s| sink.borrow_mut().push(s.to_owned())),
+            ..Default::default()
+        };
+        assert!(crate::vm::interpret("print -nil;", config).is_err());
+        assert_eq!(lines.borrow()[0], "Operand must be a number");
+    }
+
+    #[test]
+    fn print_hook_receives_script_identity() {
+        let prints = Rc::new(RefCell::new(Vec::new()));
+        let sink = prints.clone();
+        let mut config = Config::default();
+        config.on_print(move |name, line, text| {
+            sink.borrow_mut()
+                .push((name.map(str::to_owned), line, text.to_owned()))
+        });
+        config.add_module("util", "print \"loaded\";");
+
+        let source = "import util;\nprint 1 + 2;";
+        assert_eq!(
+            crate::vm::interpret_named(source, Some("main"), config),
+            Ok(())
+        );
+        assert_eq!(
+            *prints.borrow(),
+            vec![
+                (Some("util.lox".into()), 1, "loaded".into()),
+                (Some("main".into()), 2, "3".into()),
+            ]
+        );
+
+        let mut config = Config::default();
+        let sink = prints.clone();
+        config.on_print(move |name, line, text| {
+            sink.borrow_mut()
+                .push((name.map(str::to_owned), line, text.to_owned()))
+        });
+        assert_eq!(crate::vm::interpret("print nil;", config), Ok(()));
+        assert_eq!(prints.borrow()[2], (None, 1, "nil".into()));
+    }
+
+    #[test]
+    fn compiled_programs_run_in_fresh_vms() {
+        use crate::vm::VM;
+
+        let program = crate::compiler::compile(
+            Rc::from(
+                r#"
+                var greeting = "hello";
+                class Counter { init() { this.count = 0; } }
+                var counter = Counter();
+                counter.count = counter.count + 1;
+                print greeting + " " + "world";
+                print counter.count;
+            "#,
+            ),
+            Config::default(),
+        )
+        .unwrap();
+
+        let outputs: Vec<_> = (0..3)
+            .map(|_| {
+                let output = Rc::new(RefCell::new(String::new()));
+                let mut config = Config::default();
+                config.print_output.redirect(output.clone());
+                (VM::load(&program, config), output)
+            })
+            .collect();
+        drop(program);
+
+        for (mut vm, output) in outputs {
+            assert_eq!(vm.run(), Ok(()));
+            assert_eq!(output.take(), "hello world\n1\n");
+        }
+    }
+
+    #[test]
+    fn compiled_programs_are_send_and_sync() {
+        fn assert_send_sync<T: Send + Sync>() {}
+        assert_send_sync::<crate::memory::CompiledProgram>();
+
+        let program = crate::compiler::compile(
+            Rc::from("fun square(n) { return n * n; } print \"sq\" + \"uare\"; print square(7);"),
+            Config::default(),
+        )
+        .unwrap();
+        let program = std::sync::Arc::new(program);
+
+        let workers: Vec<_> = (0..2)
+            .map(|_| {
+                let program = program.clone();
+                std::thread::spawn(move || {
+                    let output = Rc::new(RefCell::new(String::new()));
+                    let mut config = Config::default();
+                    config.print_output.redirect(output.clone());
+                    crate::vm::VM::run_program(&program, config).unwrap();
+                    output.take()
+                })
+            })
+            .collect();
+        for worker in workers {
+            assert_eq!(worker.join().unwrap(), "square\n49\n");
+        }
+    }
+
+    #[test]
+    fn snapshot_and_restore() {
+        use crate::{
+            value::Value,
+            vm::{StepResult, VM},
+        };
+
+        let output = Rc::new(RefCell::new(String::new()));
+        let program = crate::compiler::compile(
+            Rc::from(
+                r#"
+                class Counter { init() { this.count = 0; } }
+                var counter = Counter();
+                var seen = [];
+                fun tick() {
+                    counter.count = counter.count + 1;
+                    push(seen, counter.count);
+                    return counter.count;
+                }
+                for (var i = 0; i < 3; i = i + 1) print tick();
+            "#,
+            ),
+            Config::default(),
+        )
+        .unwrap();
+        let mut config = Config::default();
+        config.print_output.redirect(output.clone());
+        let mut vm = VM::load(&program, config);
+
+        for _ in 0..40 {
+            vm.step();
+        }
+        let snapshot = vm.snapshot();
+        let finish = |vm: &mut VM| while !matches!(vm.step(), StepResult::Finished(_)) {};
+
+        finish(&mut vm);
+        let first = output.take();
+        vm.restore(&snapshot);
+        finish(&mut vm);
+        assert_eq!(output.take(), first);
+        assert_eq!(first.lines().last(), Some("3"));
+
+        let tick = vm.global("tick").unwrap();
+        let saved = vm.snapshot();
+        vm.call_function(tick, &[]).unwrap();
+        assert_eq!(vm.call_function(tick, &[]), Ok(Value::Number(5.0)));
+        vm.restore(&saved);
+        assert_eq!(vm.call_function(tick, &[]), Ok(Value::Number(4.0)));
+        let seen = vm.global("seen").unwrap();
+        assert_eq!(
+            seen.try_into_rust::<Vec<f64>>(&vm.memory),
+            Some(vec![1.0, 2.0, 3.0, 4.0])
+        );
+    }
+
+    #[test]
+    fn snapshot_keeps_foreign_objects() {
+        use crate::{value::Value, vm::VM};
+
+        struct Tag(&'static str);
+
+        let program = crate::compiler::compile(Rc::from("var tag;"), Config::default()).unwrap();
+        let mut vm = VM::load(&program, Config::default());
+        assert_eq!(vm.run(), Ok(()));
+        let first = vm.memory.new_foreign(Tag("first"));
+        let global = vm.memory.string_id("tag");
+        vm.globals.insert(global, Value::Foreign(first));
+        let snapshot = vm.snapshot();
+
+        vm.globals.remove(&global);
+        vm.collect_garbage();
+        assert!(vm.memory.foreign::<Tag>(first).is_none());
+        let second = vm.memory.new_foreign(Tag("second"));
+        assert_eq!(second, first);
+
+        vm.restore(&snapshot);
+        let tag = vm
+            .global("tag")
+            .and_then(|value| value.as_foreign())
+            .unwrap();
+        assert_eq!(vm.memory.foreign::<Tag>(tag).unwrap().0, "first");
+    }
+
+    #[test]
+    fn step_execution_api() {
+        use crate::{
+            value::Value,
+            vm::{InterpretResult, StepResult, VM},
+        };
+
+        let source =
+            "fun add(a, b) {\n  var sum = a + b;\n  return sum;\n}\nvar x = add(1, 2);\nprint x;";
+        let program = crate::compiler::compile(Rc::from(source), Config::default()).unwrap();
+        let config = Config {
+            print_output: PrintOutput::Null,
+            ..Default::default()
+        };
+        let mut vm = VM::load(&program, config);
+
+        assert_eq!(vm.step(), StepResult::Running);
+        for _ in 0..2 {
+            assert_eq!(vm.step_line(), StepResult::Running);
+        }
+        assert_eq!(vm.current_line(), Some(2));
+        assert_eq!(vm.frames.len(), 2);
+        assert_eq!(vm.locals()[1..], [Value::Number(1.0), Value::Number(2.0)]);
+        assert_eq!(vm.step_line(), StepResult::Running);
+        assert_eq!(vm.current_line(), Some(3));
+        assert_eq!(vm.locals().last(), Some(&Value::Number(3.0)));
+        assert!(vm.stack().len() > vm.locals().len());
+
+        while vm.step_line() == StepResult::Running {}
+        assert!(vm.is_finished());
+        assert_eq!(vm.current_frame().map(|f| f.slot_start), None);
+        assert!(vm.locals().is_empty());
+        assert_eq!(vm.step_line(), StepResult::Finished(InterpretResult::OK));
+        for _ in 0..2 {
+            assert_eq!(vm.step(), StepResult::Finished(InterpretResult::OK));
+        }
+    }
+
+    #[test]
+    fn breakpoints_pause_execution() {
+        use crate::{value::Value, vm::InterpretResult};
+
+        let output = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            print_output: PrintOutput::Str(output.clone()),
+            ..Default::default()
+        };
+        let source = "fun show(n) {\n  print n;\n}\nfor (var i = 0; i < 3; i = i + 1) show(i);\nprint \"done\";";
+        let mut vm =
+            crate::compiler::try_compile(Rc::from(source), Some("main.lox"), config).unwrap();
+
+        assert!(!vm.set_breakpoint(Some("main.lox"), 1));
+        assert!(!vm.set_breakpoint(None, 2));
+        assert!(vm.set_breakpoint(Some("main.lox"), 2));
+
+        for i in 0..3 {
+            assert_eq!(vm.run_until_break(), Ok(InterpretResult::Paused));
+            assert_eq!(vm.current_line(), Some(2));
+            assert_eq!(vm.locals()[1], Value::Number(i as f64));
+            assert_eq!(output.borrow().lines().count(), i);
+        }
+
+        assert!(vm.clear_breakpoint(Some("main.lox"), 2));
+        assert!(vm.set_breakpoint(Some("main.lox"), 5));
+        assert_eq!(vm.run_until_break(), Ok(InterpretResult::Paused));
+        assert_eq!(output.take(), "0\n1\n2\n");
+        assert_eq!(vm.run_until_break(), Ok(InterpretResult::OK));
+        assert_eq!(output.take(), "done\n");
+    }
+
+    #[test]
+    fn reads_locals_by_name() {
+        use crate::{value::Value, vm::InterpretResult};
+
+        let source = "fun inner(x) {\n  print x;\n}\nfun outer(a) {\n  var b = a * 2;\n  {\n    var a = 10;\n    inner(a + b);\n  }\n  print a;\n}\nouter(1);";
+        let config = Config {
+            print_output: PrintOutput::Null,
+            ..Default::default()
+        };
+        let mut vm = crate::compiler::try_compile(Rc::from(source), None, config).unwrap();
+        vm.set_breakpoint(None, 2);
+        vm.set_breakpoint(None, 10);
+
+        assert_eq!(vm.run_until_break(), Ok(InterpretResult::Paused));
+        assert_eq!(vm.read_local(2, "x"), Some(Value::Number(12.0)));
+        assert_eq!(vm.read_local(1, "a"), Some(Value::Number(10.0)));
+        assert_eq!(vm.read_local(1, "b"), Some(Value::Number(2.0)));
+        assert_eq!(vm.local_name(1, 1), Some("a"));
+        assert_eq!(vm.read_local(2, "a"), None);
+        assert_eq!(vm.read_local(3, "x"), None);
+
+        assert_eq!(vm.run_until_break(), Ok(InterpretResult::Paused));
+        assert_eq!(vm.read_local(1, "a"), Some(Value::Number(1.0)));
+        assert_eq!(vm.run_until_break(), Ok(InterpretResult::OK));
+    }
+
+    #[test]
+    fn line_coverage() {
+        use std::collections::BTreeMap;
+
+        let source = "fun f(n) {\n  if (n > 1) {\n    return 1;\n  }\n  return 0;\n}\nfor (var i = 0; i < 3; i = i + 1) f(i);";
+        let config = Config {
+            print_output: PrintOutput::Null,
+            coverage: true,
+            ..Default::default()
+        };
+        let mut vm = crate::compiler::try_compile(Rc::from(source), None, config).unwrap();
+        vm.run().unwrap();
+
+        let coverage = vm.coverage(None);
+        assert_eq!(
+            coverage,
+            BTreeMap::from([(2, 3), (3, 1), (4, 0), (5, 2), (6, 1), (7, 4)])
+        );
+        assert!(vm.coverage(Some("other.lox")).is_empty());
+    }
+
+    #[test]
+    fn native_calls_keep_locals_in_place() {
+        let res = interpret_str(r#"{ var x = 1; len("a"); var y = 2; print x + y; }"#);
+        assert_eq!(res, "3");
+    }
+
     #[test]
     fn higher_order_fuction() {
         let res = interpret_str(
@@ -226,230 +701,2427 @@ mod tests {
     }
 
     #[test]
-    fn if_condition() {
-        interpret(
+    fn break_and_continue() {
+        let res = interpret_str(
             r#"
-            var x = 1;
-            if (x < 5) {
-                print x;
-                x = x + 1;
+            for (var i = 0; i < 10; i = i + 1) {
+                var skip = i - 2;
+                if (skip == 0) continue;
+                if (i == 5) break;
+                print i;
             }
+            var j = 0;
+            while (true) {
+                var k = j;
+                j = j + 1;
+                if (k < 2) continue;
+                if (k > 3) break;
+                print k;
+            }
+            print "done";
         "#,
         );
+
+        assert_eq!(res, "0\n1\n3\n4\n2\n3\ndone")
     }
 
     #[test]
-    fn or() {
-        interpret(
-            r#"
-            if (true or true) {
-                print "a";
-            }
-            if (true or false) {
-                print "b";
-            }
-            if (false or true) {
-                print "c";
-            }
-            if (false or false) {
-                print "d";
-            }
-        "#,
+    fn break_and_continue_outside_loops() {
+        let errors = run_script("break;", |_| {}).unwrap_err();
+        assert!(
+            errors.contains("Can't use 'break' outside of a loop"),
+            "{errors}"
+        );
+        let errors = run_script("while (true) { fun f() { continue; } }", |_| {}).unwrap_err();
+        assert!(
+            errors.contains("Can't use 'continue' outside of a loop"),
+            "{errors}"
         );
     }
 
     #[test]
-    fn and() {
-        interpret(
+    fn lists() {
+        let res = interpret_str(
             r#"
-            if (true and true) {
-                print "a";
-            }
-            if (true and false) {
-                print "b";
-            }
-            if (false and true) {
-                print "c";
-            }
-            if (false and false) {
-                print "d";
-            }
+            var xs = [1, "two", [3]];
+            print xs;
+            print xs[1];
+            xs[0] = xs[0] + 10;
+            print xs[2][0] = 4;
+            print push(xs, nil);
+            print len(xs);
+            print pop(xs);
+            print xs;
+            print len([]);
+            print len("four");
         "#,
         );
+
+        assert_eq!(
+            res,
+            "[1, \"two\", [3]]\ntwo\n4\n4\n4\nnil\n[11, \"two\", [4]]\n0\n4"
+        )
     }
 
     #[test]
-    fn if_else() {
-        interpret(
+    fn string_conversion() {
+        let res = interpret_str(
             r#"
-            if (false) {
-                print "a";
-            } else {
-                print "b";
-            }
+            print str(3) + " items";
+            print str(nil) + str(true) + str([1, "a"]);
+            print len(str(0.5));
         "#,
         );
+        assert_eq!(res, "3 items\nniltrue[1, \"a\"]\n3");
     }
 
     #[test]
-    fn if_then() {
-        interpret(
+    fn typeof_names_value_types() {
+        let res = interpret_str(
             r#"
-            if (true) {
-                print "a";
-            }
-
-            if (false) {
-                print "b";
-            }
+            class A { m() {} }
+            fun f() {}
+            print typeof(nil) + " " + typeof(false) + " " + typeof(1) + " " + typeof("s");
+            print typeof(f) + " " + typeof(clock) + " " + typeof(A().m);
+            print typeof(A) + " " + typeof(A()) + " " + typeof([]) + " " + typeof(Number);
+            print typeof(1) == "number";
         "#,
         );
+        assert_eq!(
+            res,
+            "nil bool number string\nfunction function function\nclass instance list class\ntrue"
+        );
     }
 
     #[test]
-    fn begin_end_scope_with_override() {
-        interpret(
+    fn list_natives_take_callbacks() {
+        let res = interpret_str(
             r#"
-            var a = 99;
-            {
-                 a = 50;
-            }
-            print a;
+            fun desc(a, b) { return b - a; }
+            fun square(n) { return n * n; }
+            fun odd(n) { return n % 2 == 1; }
+            fun add(acc, n) { return acc + n; }
+            var xs = [3, 1, 4, 1, 5, 9, 2, 6];
+            print map(xs, square);
+            print filter(xs, odd);
+            print reduce(xs, add, 0);
+            print sort(xs, desc);
+            print xs;
+            print map([], square);
         "#,
         );
+        assert_eq!(
+            res,
+            "[9, 1, 16, 1, 25, 81, 4, 36]\n[3, 1, 1, 5, 9]\n31\n[9, 6, 5, 4, 3, 2, 1, 1]\n[9, 6, 5, 4, 3, 2, 1, 1]\n[]"
+        );
     }
 
     #[test]
-    fn begin_end_scope_with_same_var() {
-        interpret(
-            r#"
-            var a = 99;
-            {
-                var a = 50;
+    fn sort_keeps_items_alive_while_the_comparator_runs() {
+        let mut config = Config::default();
+        config.gc.stress = true;
+        let source = r#"
+            var xs = [[3], [1], [2]];
+            fun cmp(a, b) {
+                while (len(xs) > 0) pop(xs);
+                var junk = [[a[0] * 10], [b[0] * 10]];
+                return a[0] - b[0];
             }
-            print a;
-        "#,
-        );
+            sort(xs, cmp);
+            print xs;
+        "#;
+        crate::assert_lox! { source, config => "[[1], [2], [3]]\n" };
     }
 
     #[test]
-    fn begin_end_scope_with_var() {
-        interpret(
-            r#"
-            var a = 99;
-            {
-                var b = 50;
-            }
-            print a;
-        "#,
+    fn concatenation_is_strict_by_default() {
+        let config = |strict_concat| Config {
+            strict_concat,
+            ..Default::default()
+        };
+
+        let run = run_script_with(r#"print "count: " + 3;"#, config(true), |_| {});
+        assert!(run.result.is_err());
+        assert!(
+            run.errors.contains("convert other operands with str()"),
+            "{}",
+            run.errors
+        );
+        let run = run_script_with("print nil + 3;", config(false), |_| {});
+        assert!(run.result.is_err());
+        assert!(
+            run.errors.contains("Operands must be strings or numbers"),
+            "{}",
+            run.errors
         );
+        crate::assert_lox! {
+            r#"var n = 2; print "count: " + n; print [1] + "!"; n = n + "x"; print n;"#,
+            config(false) => "count: 2\n[1]!\n2x\n"
+        };
     }
 
     #[test]
-    fn begin_end_scope() {
-        interpret(
-            r#"
-            var a = 99;
-            {
-            }
-            print a;
-        "#,
-        );
+    fn list_errors() {
+        for (source, expected) in [
+            ("var a = 1; print a[0];", "Can only index lists and strings"),
+            ("print [1][0.5];", "Index must be an integer"),
+            (
+                "var a = [1]; a[1] = 2;",
+                "Index 1 out of range for length 1",
+            ),
+            ("print [1, 2;", "Expect ']' after list elements"),
+        ] {
+            let errors = run_script(source, |_| {}).unwrap_err();
+            assert!(errors.contains(expected), "{errors}");
+        }
     }
 
     #[test]
-    fn scopes_and_locals() {
-        interpret(
+    fn foreach_loops() {
+        let res = interpret_str(
             r#"
-            var a = 1;
-            {
-                var a = 2;
-                {
-                    var a = 3;
-                    print a;
-                }
-                print a;
+            var total = 0;
+            for (var x in [1, 2, 3, 4, 5]) {
+                if (x == 2) continue;
+                if (x == 5) break;
+                var doubled = x * 2;
+                total = total + doubled;
             }
-            print a;
+            print total;
+            var letters = "";
+            for (c in "abc") letters = c + letters;
+            print letters;
+            for (x in []) print "never";
+            print "ab"[1];
         "#,
         );
+
+        assert_eq!(res, "16\ncba\nb")
     }
 
     #[test]
-    fn global_assignment() {
-        interpret(
-            r#"
-            var breakfast = "beignets";
-            var beverage = "cafe au lait";
-            breakfast = breakfast + " with " + beverage;
-            
-            print breakfast;
-        "#,
+    fn foreach_requires_a_sequence() {
+        let errors = run_script("for (x in 1) print x;", |_| {}).unwrap_err();
+        assert!(
+            errors.contains("Can only iterate over lists and strings"),
+            "{errors}"
         );
     }
 
     #[test]
-    fn globals() {
-        interpret(
-            r#"
-            var beverage = "cafe au lait";
-            var breakfast = "beignets with " + beverage;
-            print breakfast;
-        "#,
-        );
+    fn lists_survive_collection() {
+        let mut config = Config::default();
+        config.gc.stress = true;
+        crate::assert_lox! {
+            "var xs = []; for (var i = 0; i < 3; i = i + 1) push(xs, [i]); print xs;",
+            config => "[[0], [1], [2]]\n"
+        };
     }
 
     #[test]
-    fn concat_string_intern() {
-        interpret(
-            r#"
-            print "a" + "a" + "aa";
-        "#,
-        );
+    fn long_constants() {
+        let mut source: String = (0..300).map(|i| format!("var g{i} = {i};\n")).collect();
+        source.push_str("print g299 + g0; g299 = 1.5; print g299; print \"last\";");
+
+        assert_eq!(interpret_str(&source), "299\n1.5\nlast");
     }
 
     #[test]
-    fn concat_twice() {
-        interpret(
+    fn long_constants_for_functions_classes_and_properties() {
+        let mut source: String = (0..300).map(|i| format!("var g{i} = {i};\n")).collect();
+        source.push_str(
             r#"
-            print "st" + "ri" + "ng";
+            fun g() { return "g"; }
+            class A { name() { return "a"; } }
+            class B < A {
+                init() { this.zz = 1; }
+                name() { return super.name() + "b"; }
+                parent() { return super.name; }
+            }
+            var b = B();
+            b.zz = b.zz + 1;
+            print g();
+            print b.zz;
+            print b.name();
+            print b.parent()();
         "#,
         );
+
+        assert_eq!(interpret_str(&source), "g\n2\nab\na");
     }
 
     #[test]
-    fn string_concat() {
-        interpret(
-            r#"
-            print "a" + "b";
+    fn many_locals() {
+        let mut body: String = (0..300).map(|i| format!("var l{i} = {i};\n")).collect();
+        body.push_str("l299 = l299 + l1; print l299; print l0;");
+        let source = format!("fun f() {{ {body} }} f(); {{ {body} }}");
+
+        assert_eq!(interpret_str(&source), "300\n0\n300\n0");
+    }
+
+    #[test]
+    fn constants_are_deduplicated() {
+        use crate::memory::FunctionId;
+
+        let source = "var a = 1; a = a + 1; a = a + 1; print a; print -0; print 0;";
+        let program = crate::compiler::compile(Rc::from(source), Config::default()).unwrap();
+        let constants = program
+            .to_memory()
+            .function(FunctionId(0))
+            .chunk
+            .constants()
+            .len();
+
+        assert_eq!(constants, 3);
+        assert_eq!(interpret_str(source), "3\n-0\n0");
+    }
+
+    #[test]
+    fn const_declarations() {
+        let res = interpret_str(
+            r#"
+            const greeting = "hi";
+            fun f() {
+                const n = 2;
+                var m = n;
+                m = m + 1;
+                return m;
+            }
+            print greeting;
+            print f();
         "#,
         );
+        assert_eq!(res, "hi\n3");
+
+        let run = |source| run_script_with(source, Config::default(), |_| {});
+        let error = run("const c = 1; c = 2;").result.unwrap_err();
+        assert_eq!(error.message, "Can't assign to constant 'c'");
+        for (source, expected) in [
+            (
+                "{ const a = 1; a = 2; }",
+                "Error at 'a': Can't assign to constant 'a'",
+            ),
+            ("const b;", "Expect '=' after constant name"),
+            ("const d = 1; var d = 2;", "Can't assign to constant 'd'"),
+        ] {
+            let errors = run_script(source, |_| {}).unwrap_err();
+            assert!(errors.contains(expected), "{errors}");
+        }
     }
 
     #[test]
-    fn num_add() {
+    fn configurable_number_format() {
+        let run = |number_format| {
+            let config = Config {
+                number_format,
+                ..Default::default()
+            };
+            let source =
+                "print 0.1 + 0.2; print 1e21; print 3; print -0.00001234; print [1.5, 100000000];";
+            let run = run_script_with(source, config, |_| {});
+            assert_eq!(run.result, Ok(()));
+            run.output
+        };
+
+        assert_eq!(
+            run(NumberFormat::Shortest),
+            "0.30000000000000004\n1000000000000000000000\n3\n-0.00001234\n[1.5, 100000000]\n"
+        );
+        assert_eq!(
+            run(NumberFormat::Lox),
+            "0.3\n1e+21\n3\n-1.234e-05\n[1.5, 1e+08]\n"
+        );
+        assert_eq!(
+            run(NumberFormat::Precision(17)),
+            "0.30000000000000004\n1e+21\n3\n-1.234e-05\n[1.5, 100000000]\n"
+        );
+    }
+
+    #[test]
+    fn integer_values() {
+        let run = |integers, source: &str| {
+            let config = Config {
+                integers,
+                ..Default::default()
+            };
+            let run = run_script_with(source, config, |_| {});
+            (run.result.is_ok(), run.output)
+        };
+
+        let source = r#"
+            print 9007199254740993 + 0;
+            print 7 / 2;
+            print 7 ~/ 2;
+            print -7 ~/ 2;
+            print -7 % 3;
+            print 7 % -3;
+            print 7.5 % 2;
+            print -7.5 ~/ 2;
+            print 2 * 0.5;
+            print 1 == 1.0;
+            print 0xFF - 1;
+            print [10, 20][1];
+            print 9223372036854775807 + 1;
+        "#;
+        assert_eq!(
+            run(true, source),
+            (
+                true,
+                "9007199254740993\n3.5\n3\n-4\n2\n-2\n1.5\n-4\n1\ntrue\n254\n20\n9223372036854776000\n"
+                    .into()
+            )
+        );
+        assert_eq!(
+            run(
+                false,
+                "print 9007199254740993 + 0; print 7 ~/ 2; print 7 % 0;"
+            ),
+            (true, "9007199254740992\n3\nNaN\n".into())
+        );
+
+        for (a, b) in [(-7, 2), (7, -2), (-7, -2), (6, 3)] {
+            let source = format!("var a = {a}; var b = {b}; print a ~/ b * b + a % b;");
+            assert_eq!(run(true, &source), (true, format!("{a}\n")));
+        }
+
+        for source in ["print 1 % 0;", "print 1 ~/ 0;"] {
+            let config = Config {
+                integers: true,
+                ..Default::default()
+            };
+            let errors = run_script_with(source, config, |_| {}).errors;
+            assert!(errors.contains("Integer division by zero"), "{errors}");
+        }
+    }
+
+    #[test]
+    fn division_by_zero_policy() {
+        let config = |division_by_zero| Config {
+            division_by_zero,
+            ..Default::default()
+        };
+        let source = "print 1 / 2; print -10 / 0;";
+
+        crate::assert_lox! { source, config(DivisionByZero::Infinity) => "0.5\n-inf\n" };
+        crate::assert_lox! { source, config(DivisionByZero::Nil) => "0.5\nnil\n" };
+        let run = run_script_with(source, config(DivisionByZero::RuntimeError), |_| {});
+        assert!(run.result.is_err());
+        assert_eq!(run.output, "0.5\n");
+        assert!(run.errors.contains("Division by zero"), "{}", run.errors);
+    }
+
+    #[test]
+    fn division_by_zero_policy_covers_floor_divide_and_modulo() {
+        let run = |division_by_zero, integers, source: &str| {
+            let config = Config {
+                division_by_zero,
+                integers,
+                ..Default::default()
+            };
+            let run = run_script_with(source, config, |_| {});
+            match run.result {
+                Ok(()) => run.output,
+                Err(error) => error.message,
+            }
+        };
+
+        let floor = "print 1 ~/ 0; print 1.5 ~/ 0;";
+        let modulo = "print 1 % 0; print 1.5 % 0;";
+        assert_eq!(run(DivisionByZero::Infinity, false, floor), "inf\ninf\n");
+        assert_eq!(run(DivisionByZero::Infinity, false, modulo), "NaN\nNaN\n");
+        assert_eq!(run(DivisionByZero::Nil, false, floor), "nil\nnil\n");
+        assert_eq!(run(DivisionByZero::Nil, false, modulo), "nil\nnil\n");
+        assert_eq!(
+            run(DivisionByZero::RuntimeError, false, floor),
+            "Division by zero"
+        );
+        assert_eq!(
+            run(DivisionByZero::RuntimeError, false, modulo),
+            "Division by zero"
+        );
+
+        assert_eq!(
+            run(DivisionByZero::Infinity, true, floor),
+            "Integer division by zero"
+        );
+        assert_eq!(
+            run(DivisionByZero::Infinity, true, modulo),
+            "Integer division by zero"
+        );
+        assert_eq!(run(DivisionByZero::Nil, true, floor), "nil\nnil\n");
+        assert_eq!(run(DivisionByZero::Nil, true, modulo), "nil\nnil\n");
+        assert_eq!(
+            run(DivisionByZero::RuntimeError, true, floor),
+            "Integer division by zero"
+        );
+        assert_eq!(run(DivisionByZero::Nil, true, "print 7 / 0;"), "nil\n");
+        assert_eq!(run(DivisionByZero::Infinity, true, "print 7 / 0;"), "inf\n");
+    }
+
+    #[test]
+    fn number_literal_formats() {
+        let res = interpret_str(
+            r#"
+            print 1e3;
+            print 2.5e-3;
+            print 1E+2;
+            print 1_000_000 + 0.000_5;
+            var e = 1;
+            print 2 - e;
+        "#,
+        );
+        assert_eq!(res, "1000\n0.0025\n100\n1000000.0005\n1");
+    }
+
+    #[test]
+    fn hex_and_binary_literals() {
+        let res = interpret_str(
+            r#"
+            print 0xFF;
+            print 0Xff_ff;
+            print 0b1010;
+            print 0b1111_0000 + 0x10;
+            print 0;
+        "#,
+        );
+        assert_eq!(res, "255\n65535\n10\n256\n0");
+    }
+
+    #[test]
+    fn utf8_strings_and_comments() {
+        let res = interpret_str(
+            r#"
+            // ñ comment
+            var s = "héllo"; /* ✓ */
+            print s + " wörld";
+        "#,
+        );
+        assert_eq!(res, "héllo wörld");
+    }
+
+    #[test]
+    fn caret_diagnostics() {
+        assert_eq!(
+            run_script("var x = 1;\n\tprint x +;", |_| {}),
+            Err("[line 2] Error at ';': Expect expression\n    2 | \tprint x +;\n      | \t         ^\n".into())
+        );
+
+        let errors = run_script("var s = \"a\";\nprint -s;", |_| {}).unwrap_err();
+        assert!(
+            errors.starts_with("Operand must be a number\n    2 | print -s;\n      |       ^\n")
+        );
+
+        let errors = run_script("if (nil >= 1) print 1;", |_| {}).unwrap_err();
+        assert!(errors.contains("    1 | if (nil >= 1) print 1;\n      |         ^~\n"));
+    }
+
+    #[test]
+    fn decodes_instructions() {
+        use crate::{
+            chunk::{ConstantId, OpCode},
+            memory::FunctionId,
+            value::Value,
+        };
+
+        let source = "var a = 1; while (a < 300) a = a + 1;";
+        let program = crate::compiler::compile(Rc::from(source), Config::default()).unwrap();
+        let memory = program.to_memory();
+        let chunk = &memory.function(FunctionId(0)).chunk;
+        let decoded: Vec<_> = chunk.decode_all().into_iter().flatten().collect();
+
+        let size: usize = decoded.iter().map(|d| d.size).sum();
+        assert_eq!(size, chunk.code.len());
+        let ops: Vec<_> = decoded.iter().map(|d| d.op_code).collect();
+        assert_eq!(
+            ops,
+            [
+                OpCode::Constant,
+                OpCode::DefineGlobal,
+                OpCode::GetGlobal,
+                OpCode::Constant,
+                OpCode::JumpIfNotLess,
+                OpCode::GetGlobal,
+                OpCode::Constant,
+                OpCode::Add,
+                OpCode::SetGlobal,
+                OpCode::Pop,
+                OpCode::Loop,
+                OpCode::Nil,
+                OpCode::Return,
+            ]
+        );
+        assert!(chunk.constant_value(ConstantId(decoded[3].a)) == Value::Number(300.0));
+        assert_eq!(interpret_str(&format!("{source} print a;")), "300");
+    }
+
+    #[test]
+    fn fused_comparison_jumps() {
+        use crate::{debug::disassemble_chunk, memory::FunctionId};
+
+        let source = r#"
+            var i = 0;
+            while (i < 3) i = i + 1;
+            print i;
+            for (var j = 5; j > 2; j = j - 1) print j;
+            if (i <= 3) print "le"; else print "gt";
+            if (i >= 4) print "ge"; else print "lt";
+            if (i > 0 and i < 2) print "between"; else print "outside";
+            if (true and i < 4) print "and";
+            var k = 2 < 3;
+            print k;
+        "#;
+        let program = crate::compiler::compile(Rc::from(source), Config::default()).unwrap();
+        let memory = program.to_memory();
+        let mut output = String::new();
+        disassemble_chunk(
+            &memory.function(FunctionId(0)).chunk,
+            "<script>",
+            &memory,
+            &mut output,
+        );
+
+        for op_code in [
+            "JumpIfNotLess",
+            "JumpIfNotGreater",
+            "JumpIfGreater",
+            "JumpIfLess",
+        ] {
+            assert!(output.contains(op_code), "{output}");
+        }
+        assert_eq!(
+            interpret_str(source),
+            "3\n5\n4\n3\nle\nlt\noutside\nand\ntrue"
+        );
+        let error = crate::vm::interpret(
+            r#"if ("a" < 1) print "bad";"#,
+            Config {
+                vm_error: PrintOutput::Null,
+                ..Default::default()
+            },
+        )
+        .unwrap_err();
+        assert_eq!(error.message, "Operands must be numbers");
+    }
+
+    #[test]
+    fn if_condition() {
         interpret(
             r#"
-            print 1 + 2;
+            var x = 1;
+            if (x < 5) {
+                print x;
+                x = x + 1;
+            }
         "#,
         );
     }
 
     #[test]
-    fn num_div() {
+    fn or() {
         interpret(
             r#"
-            print 10 / 2;
+            if (true or true) {
+                print "a";
+            }
+            if (true or false) {
+                print "b";
+            }
+            if (false or true) {
+                print "c";
+            }
+            if (false or false) {
+                print "d";
+            }
         "#,
         );
     }
 
     #[test]
-    fn hello_world() {
+    fn and() {
         interpret(
             r#"
-            print "hello world";
+            if (true and true) {
+                print "a";
+            }
+            if (true and false) {
+                print "b";
+            }
+            if (false and true) {
+                print "c";
+            }
+            if (false and false) {
+                print "d";
+            }
+        "#,
+        );
+    }
+
+    #[test]
+    fn if_else() {
+        interpret(
+            r#"
+            if (false) {
+                print "a";
+            } else {
+                print "b";
+            }
+        "#,
+        );
+    }
+
+    #[test]
+    fn if_then() {
+        interpret(
+            r#"
+            if (true) {
+                print "a";
+            }
+
+            if (false) {
+                print "b";
+            }
+        "#,
+        );
+    }
+
+    #[test]
+    fn begin_end_scope_with_override() {
+        interpret(
+            r#"
+            var a = 99;
+            {
+                 a = 50;
+            }
+            print a;
+        "#,
+        );
+    }
+
+    #[test]
+    fn begin_end_scope_with_same_var() {
+        interpret(
+            r#"
+            var a = 99;
+            {
+                var a = 50;
+            }
+            print a;
+        "#,
+        );
+    }
+
+    #[test]
+    fn begin_end_scope_with_var() {
+        interpret(
+            r#"
+            var a = 99;
+            {
+                var b = 50;
+            }
+            print a;
+        "#,
+        );
+    }
+
+    #[test]
+    fn begin_end_scope() {
+        interpret(
+            r#"
+            var a = 99;
+            {
+            }
+            print a;
+        "#,
+        );
+    }
+
+    #[test]
+    fn scopes_and_locals() {
+        interpret(
+            r#"
+            var a = 1;
+            {
+                var a = 2;
+                {
+                    var a = 3;
+                    print a;
+                }
+                print a;
+            }
+            print a;
+        "#,
+        );
+    }
+
+    #[test]
+    fn global_assignment() {
+        interpret(
+            r#"
+            var breakfast = "beignets";
+            var beverage = "cafe au lait";
+            breakfast = breakfast + " with " + beverage;
+            
+            print breakfast;
+        "#,
+        );
+    }
+
+    #[test]
+    fn globals() {
+        interpret(
+            r#"
+            var beverage = "cafe au lait";
+            var breakfast = "beignets with " + beverage;
+            print breakfast;
+        "#,
+        );
+    }
+
+    #[test]
+    fn concat_string_intern() {
+        interpret(
+            r#"
+            print "a" + "a" + "aa";
         "#,
         );
     }
+
+    #[test]
+    fn concat_twice() {
+        interpret(
+            r#"
+            print "st" + "ri" + "ng";
+        "#,
+        );
+    }
+
+    #[test]
+    fn string_concat() {
+        interpret(
+            r#"
+            print "a" + "b";
+        "#,
+        );
+    }
+
+    #[test]
+    fn num_add() {
+        interpret(
+            r#"
+            print 1 + 2;
+        "#,
+        );
+    }
+
+    #[test]
+    fn num_div() {
+        interpret(
+            r#"
+            print 10 / 2;
+        "#,
+        );
+    }
+
+    #[test]
+    fn hello_world() {
+        interpret(
+            r#"
+            print "hello world";
+        "#,
+        );
+    }
+
+    #[test]
+    fn compile_files_share_globals() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            print_output: PrintOutput::Str(output.clone()),
+            ..Default::default()
+        };
+        let files = [
+            ("lib.lox", "fun greet(name) { return \"hi \" + name; }"),
+            ("main.lox", "print greet(\"bob\");"),
+        ];
+        let mut vm = crate::compiler::compile_files(&files, config).unwrap();
+        assert_eq!(vm.run(), Ok(()));
+        assert_eq!(*output.borrow(), "hi bob\n");
+    }
+
+    #[test]
+    fn compile_files_attribute_errors() {
+        let errors = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            compiler_error: PrintOutput::Str(errors.clone()),
+            ..Default::default()
+        };
+        let files = [("a.lox", "var a = 1;"), ("b.lox", "\nprint a +;")];
+        assert!(crate::compiler::compile_files(&files, config).is_none());
+        assert_eq!(
+            *errors.borrow(),
+            "[b.lox line 2] Error at ';': Expect expression\n    2 | print a +;\n      |          ^\n"
+        );
+    }
+
+    #[test]
+    fn compile_files_attribute_runtime_errors() {
+        let errors = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            vm_error: PrintOutput::Str(errors.clone()),
+            ..Default::default()
+        };
+        let files = [
+            ("a.lox", "fun f() {\n  return -\"x\";\n}"),
+            ("b.lox", "f();"),
+        ];
+        let mut vm = crate::compiler::compile_files(&files, config).unwrap();
+        assert!(vm.run().is_err());
+        let errors = errors.borrow();
+        assert!(errors.contains("[a.lox line 2 in f]"), "{errors}");
+        assert!(errors.contains("[b.lox line 1 in <script>]"), "{errors}");
+    }
+
+    #[test]
+    fn class_instances_and_fields() {
+        let res = interpret_str(
+            r#"
+            class Pair {}
+
+            var pair = Pair();
+            pair.first = 1;
+            pair.second = 2;
+            print Pair;
+            print pair;
+            print pair.first + pair.second;
+        "#,
+        );
+
+        assert_eq!(res, "Pair\nPair instance\n3");
+    }
+
+    #[test]
+    fn class_field_assignment_is_an_expression() {
+        let res = interpret_str(
+            r#"
+            {
+                class Box {}
+                var a = Box();
+                var b = Box();
+                a.value = b.value = "shared";
+                a.value = a.value + "!";
+                print a.value + " " + b.value;
+            }
+        "#,
+        );
+
+        assert_eq!(res, "shared! shared");
+    }
+
+    #[test]
+    fn methods_bind_this() {
+        let res = interpret_str(
+            r#"
+            class Counter {
+                init(start) {
+                    this.count = start;
+                }
+                increment() {
+                    this.count = this.count + 1;
+                    return this;
+                }
+            }
+
+            var counter = Counter(10);
+            var increment = counter.increment;
+            increment();
+            print counter.increment().count;
+            print increment;
+        "#,
+        );
+
+        assert_eq!(res, "12\n<fn increment>");
+    }
+
+    #[test]
+    fn initializer_returns_instance() {
+        let res = interpret_str(
+            r#"
+            class Foo {
+                init() {
+                    this.x = "x";
+                    return;
+                }
+            }
+
+            var foo = Foo();
+            print foo.init();
+        "#,
+        );
+
+        assert_eq!(res, "Foo instance");
+    }
+
+    #[test]
+    fn fields_shadow_methods() {
+        let res = interpret_str(
+            r#"
+            class Foo {
+                bar() { return "method"; }
+            }
+            fun field() { return "field"; }
+
+            var foo = Foo();
+            print foo.bar();
+            foo.bar = field;
+            print foo.bar();
+        "#,
+        );
+
+        assert_eq!(res, "method\nfield");
+    }
+
+    #[test]
+    fn this_and_initializer_errors() {
+        let errors = run_script("print this;", |_| {}).unwrap_err();
+        assert!(
+            errors.contains("Error at 'this': Can't use 'this' outside of a class"),
+            "{errors}"
+        );
+        let errors = run_script("class A { init() { return 1; } }", |_| {}).unwrap_err();
+        assert!(
+            errors.contains("Error at 'return': Can't return a value from an initializer"),
+            "{errors}"
+        );
+    }
+
+    #[test]
+    fn inheritance_and_super() {
+        let res = interpret_str(
+            r#"
+            class Doughnut {
+                init(flavour) {
+                    this.flavour = flavour;
+                }
+                cook() {
+                    return "Fry " + this.flavour;
+                }
+                name() {
+                    return "doughnut";
+                }
+            }
+
+            class BostonCream < Doughnut {
+                init() {
+                    super.init("cream");
+                }
+                cook() {
+                    var base = super.cook;
+                    return base() + " and pipe full of custard";
+                }
+            }
+
+            var d = BostonCream();
+            print d.cook();
+            print d.name();
+        "#,
+        );
+
+        assert_eq!(res, "Fry cream and pipe full of custard\ndoughnut");
+    }
+
+    #[test]
+    fn super_resolves_from_the_defining_class() {
+        let res = interpret_str(
+            r#"
+            class A {
+                method() { return "A"; }
+            }
+            class B < A {
+                method() { return "B"; }
+                test() { return super.method(); }
+            }
+            class C < B {}
+
+            print C().test();
+        "#,
+        );
+
+        assert_eq!(res, "A");
+    }
+
+    #[test]
+    fn inheritance_errors() {
+        for (source, expected) in [
+            ("class A < A {}", "A class can't inherit from itself"),
+            (
+                "class A { f() { return super.f(); } }",
+                "Can't use 'super' in a class with no superclass",
+            ),
+            (
+                "fun f() { super.g(); }",
+                "Can't use 'super' outside of a class",
+            ),
+            (
+                "var NotClass = 1;\nclass A < NotClass {}",
+                "Superclass must be a class",
+            ),
+        ] {
+            let errors = run_script(source, |_| {}).unwrap_err();
+            assert!(errors.contains(expected), "{errors}");
+        }
+    }
+
+    #[test]
+    fn class_property_errors() {
+        for (source, expected) in [
+            (
+                "class A {}\nprint A().missing;",
+                "Undefined property 'missing'",
+            ),
+            ("var n = 1;\nn.field = 2;", "Only instances have fields"),
+            ("class A {}\nA(1);", "Expected 0 arguments but got 1"),
+        ] {
+            let errors = run_script(source, |_| {}).unwrap_err();
+            assert!(errors.contains(expected), "{errors}");
+        }
+    }
+
+    #[test]
+    fn is_checks_types_and_classes() {
+        let res = interpret_str(
+            r#"
+            class A {}
+            class B < A {}
+            fun f() {}
+            var b = B();
+            print b is A;
+            print b is B;
+            print A() is B;
+            print b is Instance;
+            print 1 is Number;
+            print "s" is String;
+            print nil is Nil;
+            print true is Bool;
+            print f is Function;
+            print clock is Function;
+            print [1] is List;
+            print A is Class;
+            print 1 is String == false;
+            print Number;
+        "#,
+        );
+
+        assert_eq!(
+            res,
+            "true\ntrue\nfalse\ntrue\ntrue\ntrue\ntrue\ntrue\ntrue\ntrue\ntrue\ntrue\ntrue\nNumber"
+        );
+
+        let errors = run_script("print 1 is 2;", |_| {}).unwrap_err();
+        assert!(
+            errors.contains("Right operand of 'is' must be a type or class"),
+            "{errors}"
+        );
+    }
+
+    #[test]
+    fn repl_keeps_state_between_lines() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            print_output: PrintOutput::Str(output.clone()),
+            vm_error: PrintOutput::Null,
+            compiler_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let mut repl = crate::vm::Repl::new(config);
+
+        use crate::vm::ErrorKind::*;
+        assert_eq!(repl.interpret("var x = 1;"), Ok(()));
+        assert_eq!(repl.interpret("fun inc() { x = x + 1; }"), Ok(()));
+        assert_eq!(repl.interpret("print nope;").unwrap_err().kind, Runtime);
+        assert_eq!(repl.interpret("print x +;").unwrap_err().kind, Compile);
+        assert_eq!(repl.interpret("inc(); print x;"), Ok(()));
+        assert_eq!(*output.borrow(), "2\n");
+    }
+
+    #[test]
+    fn max_stack_limits_recursion() {
+        let config = |max_stack| Config {
+            max_stack,
+            ..Default::default()
+        };
+        let source = "fun f(n) { if (n < 1) return 0; return f(n - 1) + 1; }\nprint f(10);";
+
+        crate::assert_lox! { source, config(64) => "10\n" };
+        let run = run_script_with(source, config(8), |_| {});
+        assert!(run.result.is_err());
+        assert!(run.errors.starts_with("Stack overflow\n"), "{}", run.errors);
+    }
+
+    #[test]
+    fn max_stack_limits_growth_within_a_frame() {
+        let config = |max_stack| Config {
+            vm_error: PrintOutput::Null,
+            print_output: PrintOutput::Null,
+            max_stack,
+            ..Default::default()
+        };
+        let names: Vec<_> = (0..40).map(|i| format!("v{i}")).collect();
+        let locals: String = names
+            .iter()
+            .map(|name| format!("var {name} = 1;"))
+            .collect();
+        let locals = format!("{{ {locals} }}");
+        let unpack = format!(
+            "var xs = [{}]; {{ var ({}) = xs; }}",
+            vec!["0"; 40].join(", "),
+            names.join(", ")
+        );
+        let rest = format!(
+            "fun f(...rest) {{ return len(rest); }} print f({});",
+            vec!["1"; 40].join(", ")
+        );
+
+        for source in [&locals, &unpack, &rest] {
+            assert_eq!(crate::vm::interpret(source, config(64)), Ok(()), "{source}");
+            let error = crate::vm::interpret(source, config(32)).unwrap_err();
+            assert_eq!(error.message, "Stack overflow", "{source}");
+        }
+    }
+
+    #[test]
+    fn max_frames_limits_call_depth() {
+        let config = |max_frames| Config {
+            vm_error: PrintOutput::Null,
+            print_output: PrintOutput::Null,
+            max_frames,
+            ..Default::default()
+        };
+        let source = "fun f(n) { if (n < 1) return 0; return f(n - 1) + 1; }\nprint f(100);";
+
+        assert!(crate::vm::interpret(source, config(64)).is_err());
+        assert_eq!(crate::vm::interpret(source, config(128)), Ok(()));
+        let error = crate::vm::interpret(source, config(4)).unwrap_err();
+        assert_eq!(error.message, "Stack overflow");
+        assert_eq!(error.trace.len(), 4);
+    }
+
+    #[test]
+    fn default_parameter_values() {
+        let res = interpret_str(
+            r#"
+            fun greet(name, greeting = "hello", punct = greeting == "hi" and "!" or ".") {
+                return greeting + " " + name + punct;
+            }
+            print greet("bob");
+            print greet("bob", "hi");
+            print greet("bob", "yo", "?");
+            class Counter {
+                init(start = 10) { this.count = start; }
+                add(step = 1) { this.count = this.count + step; return this; }
+            }
+            print Counter().add().add(5).count;
+            print Counter(0).add().count;
+            fun later(n, acc = 0) { if (n == 0) return acc; return later(n - 1, acc + n); }
+            print later(4);
+        "#,
+        );
+        assert_eq!(res, "hello bob.\nhi bob!\nyo bob?\n16\n1\n10");
+
+        let config = || Config {
+            vm_error: PrintOutput::Null,
+            compiler_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let source = "fun f(a, b = 1) {}\nf(1, 2, 3);";
+        let error = crate::vm::interpret(source, config()).unwrap_err();
+        assert_eq!(error.message, "Expected 1 to 2 arguments but got 3");
+        let error = crate::vm::interpret("fun f(a, b = 1) {}\nf();", config()).unwrap_err();
+        assert_eq!(error.message, "Expected 1 to 2 arguments but got 0");
+        let error = crate::vm::interpret("fun f(a = 1, b) {}", config()).unwrap_err();
+        assert_eq!(
+            error.message,
+            "Parameters after a default value must also have defaults"
+        );
+    }
+
+    #[test]
+    fn rest_parameters_collect_arguments() {
+        let res = interpret_str(
+            r#"
+            fun log(level, ...args) { print level + ": " + str(args); }
+            log("info");
+            log("warn", 1, "two", nil);
+            fun tagged(tag = "x", ...rest) { return tag + str(len(rest)); }
+            print tagged();
+            print tagged("y", 1, 2);
+            class Sum { add(...xs) { return reduce(xs, fun_add, 0); } }
+            fun fun_add(a, b) { return a + b; }
+            print Sum().add(1, 2, 3, 4);
+        "#,
+        );
+        assert_eq!(res, "info: []\nwarn: [1, \"two\", nil]\nx0\ny2\n10");
+
+        let config = || Config {
+            vm_error: PrintOutput::Null,
+            compiler_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let error = crate::vm::interpret("fun f(a, ...b) {}\nf();", config()).unwrap_err();
+        assert_eq!(error.message, "Expected at least 1 arguments but got 0");
+        let error = crate::vm::interpret("fun f(...a, b) {}", config()).unwrap_err();
+        assert_eq!(error.message, "Expect ')' after parameters");
+    }
+
+    #[test]
+    fn tuples_and_destructuring() {
+        let res = interpret_str(
+            r#"
+            fun minmax(xs) {
+                var lo = xs[0];
+                var hi = xs[0];
+                for (x in xs) {
+                    if (x < lo) lo = x;
+                    if (x > hi) hi = x;
+                }
+                return (lo, hi);
+            }
+            var (lo, hi) = minmax([3, 1, 4, 1, 5]);
+            print lo;
+            print hi;
+            print minmax([2]);
+            print (1,) == (1,);
+            print (1, "a") == (1, "a");
+            print (1, 2) == (2, 1);
+            print typeof((1, 2)) + " " + str(len((1, 2, 3)));
+            {
+                var (a, b, c) = [1, 2, 3];
+                var (x, y) = (b, a);
+                print x + y * 10 + c * 100;
+            }
+            print (1, 2) is Tuple;
+        "#,
+        );
+        assert_eq!(res, "1\n5\n(2, 2)\ntrue\ntrue\nfalse\ntuple 3\n312\ntrue");
+
+        let config = || Config {
+            vm_error: PrintOutput::Null,
+            compiler_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let error = crate::vm::interpret("var (a, b) = (1, 2, 3);", config()).unwrap_err();
+        assert_eq!(error.message, "Expected 2 values to destructure but got 3");
+        let error = crate::vm::interpret("var (a, b) = 1;", config()).unwrap_err();
+        assert_eq!(error.message, "Can only destructure tuples and lists");
+        let error = crate::vm::interpret("var t = (1, 2);\nt[0] = 3;", config()).unwrap_err();
+        assert_eq!(error.message, "Only list elements can be assigned");
+        let error = crate::vm::interpret("var (a, b);", config()).unwrap_err();
+        assert_eq!(error.message, "Expect '=' after destructuring pattern");
+    }
+
+    #[test]
+    fn nested_functions_do_not_capture_loop_variables() {
+        let config = Config {
+            vm_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let source = r#"
+            var fs = [];
+            for (var i = 0; i < 3; i = i + 1) {
+                fun f() { return i; }
+                push(fs, f);
+            }
+            print fs[0]();
+        "#;
+        let error = crate::vm::interpret(source, config).unwrap_err();
+        assert_eq!(error.message, "Undefined variable 'i'");
+    }
+
+    #[test]
+    fn if_expressions_yield_values() {
+        let res = interpret_str(
+            r#"
+            fun sign(n) { return if (n < 0) -1 else if (n == 0) 0 else 1; }
+            print sign(-5);
+            print sign(0);
+            print sign(3);
+            var label = if (nil) "yes" else "no";
+            print label;
+            print [if (true) 1 else 2, 3];
+            fun count(n, acc) { return if (n == 0) acc else count(n - 1, acc + 1); }
+            print count(500, 0);
+        "#,
+        );
+        assert_eq!(res, "-1\n0\n1\nno\n[1, 3]\n500");
+
+        let config = Config {
+            compiler_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let error = crate::vm::interpret("var x = if (true) 1;", config).unwrap_err();
+        assert_eq!(error.message, "Expect 'else' in if expression");
+    }
+
+    #[test]
+    fn nil_coalescing_and_optional_chaining() {
+        let res = interpret_str(
+            r#"
+            class Node {
+                init(value, next) { this.value = value; this.next = next; }
+                describe() { return "node " + str(this.value); }
+            }
+            var list = Node(1, Node(2, nil));
+            print nil ?? "default";
+            print false ?? "default";
+            print nil ?? nil ?? 3;
+            print list.next?.value;
+            print list.next.next?.value;
+            print list.next.next?.value ?? "end";
+            print list?.next?.next?.next;
+            print list.next?.describe();
+            print list.next.next?.describe(missing);
+            print 1 + 2 ?? 4;
+            print nil or false ?? "after or";
+        "#,
+        );
+        assert_eq!(
+            res,
+            "default\nfalse\n3\n2\nnil\nend\nnil\nnode 2\nnil\n3\nfalse"
+        );
+
+        let config = Config {
+            compiler_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let error = crate::vm::interpret("var a; a?.b = 1;", config).unwrap_err();
+        assert_eq!(error.message, "Invalid assignment target");
+    }
+
+    #[test]
+    fn optional_chaining_short_circuits_the_whole_chain() {
+        let res = interpret_str(
+            r#"
+            class Node {
+                init(value, next) { this.value = value; this.next = next; }
+                describe() { return "node " + str(this.value); }
+            }
+            var list = Node(1, Node(2, nil));
+            var none;
+            print none?.next.value;
+            print none?.describe().missing.deeper;
+            print none?.items[0].value;
+            print list.next.next?.next.value ?? "end";
+            print str(none?.next.value) + "!";
+            print list?.next.describe();
+            print [none?.a.b, list?.next.value];
+        "#,
+        );
+        assert_eq!(res, "nil\nnil\nnil\nend\nnil!\nnode 2\n[nil, 2]");
+    }
+
+    #[test]
+    fn string_repeat_and_plus_equal() {
+        use crate::config::Limits;
+
+        let res = interpret_str(
+            r#"
+            print "-" * 5;
+            print 3 * "ab";
+            print "x" * 0 == "";
+            var s = "a";
+            s += "b";
+            s += str(1);
+            print s;
+            var n = 1;
+            n += 2;
+            print n;
+            {
+                var line = "";
+                for (var i = 0; i < 3; i = i + 1) line += "=" * (i + 1) + "|";
+                print line;
+            }
+        "#,
+        );
+        assert_eq!(res, "-----\nababab\ntrue\nab1\n3\n=|==|===|");
+
+        let config = || Config {
+            vm_error: PrintOutput::Null,
+            compiler_error: PrintOutput::Null,
+            limits: Limits {
+                max_string_len: 8,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        let error = crate::vm::interpret("print \"a\" * 1.5;", config()).unwrap_err();
+        assert_eq!(error.message, "Repeat count must be a non-negative integer");
+        let error = crate::vm::interpret("print \"a\" * -1;", config()).unwrap_err();
+        assert_eq!(error.message, "Repeat count must be a non-negative integer");
+        let error = crate::vm::interpret("print \"ab\" * 5;", config()).unwrap_err();
+        assert_eq!(error.message, "Repeated string is longer than 8 bytes");
+        let error = crate::vm::interpret("var a = 1; a + 1 += 1;", config()).unwrap_err();
+        assert_eq!(error.message, "Invalid assignment target");
+
+        let config = || Config {
+            vm_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let error = crate::vm::interpret("print \"ab\" * 1e17;", config()).unwrap_err();
+        assert_eq!(
+            error.message,
+            "Repeated string is longer than 1073741824 bytes"
+        );
+        assert_eq!(interpret_str("print \"\" * 1e300 == \"\";"), "true");
+    }
+
+    #[test]
+    fn plus_equal_on_properties_and_indexes() {
+        let res = interpret_str(
+            r#"
+            class Counter { init() { this.n = 1; } }
+            var c = Counter();
+            c.n += 2;
+            print c.n;
+            print c.n += 10;
+            var xs = [1, "a"];
+            xs[0] += 5;
+            xs[1] += "b";
+            print xs;
+            var i = 0;
+            fun next() { i = i + 1; return i - 1; }
+            xs[next()] += 1;
+            print xs[0];
+            print i;
+        "#,
+        );
+        assert_eq!(res, "3\n13\n[6, \"ab\"]\n7\n1");
+    }
+
+    #[test]
+    fn string_append_keeps_earlier_strings_intact() {
+        let res = interpret_str(
+            r#"
+            var a = "x" * 3;
+            var b = a + "y";
+            var c = a + "z";
+            var d = a + "y";
+            print b;
+            print c;
+            print a;
+            print b == d;
+            var s = "";
+            for (var i = 0; i < 5; i = i + 1) s = s + str(i);
+            print s;
+        "#,
+        );
+        assert_eq!(res, "xxxy\nxxxz\nxxx\ntrue\n01234");
+    }
+
+    #[test]
+    fn tail_calls_reuse_frames() {
+        let res = interpret_str(
+            r#"
+            fun count(n, total) {
+                if (n == 0) return total;
+                return count(n - 1, total + n);
+            }
+            fun even(n) { if (n == 0) return true; return odd(n - 1); }
+            fun odd(n) { if (n == 0) return false; return even(n - 1); }
+            print count(1000, 0);
+            print even(501);
+            print clock() > 0;
+        "#,
+        );
+
+        assert_eq!(res, "500500\nfalse\ntrue")
+    }
+
+    #[test]
+    fn max_instructions_cancels_runaway_scripts() {
+        use crate::vm::ErrorKind;
+
+        let config = |max_instructions| Config {
+            vm_error: PrintOutput::Null,
+            print_output: PrintOutput::Null,
+            max_instructions,
+            ..Default::default()
+        };
+
+        let error = crate::vm::interpret("while (true) {}", config(Some(1000))).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Budget);
+        assert_eq!(error.message, "Exceeded the budget of 1000 instructions");
+        assert_eq!(error.line, 1);
+
+        let source = "for (var i = 0; i < 10; i = i + 1) print i;";
+        assert_eq!(crate::vm::interpret(source, config(Some(1000))), Ok(()));
+        assert!(crate::vm::interpret(source, config(Some(50))).is_err());
+        assert_eq!(crate::vm::interpret(source, config(None)), Ok(()));
+    }
+
+    #[test]
+    fn interpret_returns_structured_errors() {
+        use crate::vm::{ErrorKind, TraceFrame};
+
+        let config = || Config {
+            vm_error: PrintOutput::Null,
+            compiler_error: PrintOutput::Null,
+            ..Default::default()
+        };
+
+        let error = crate::vm::interpret("var a = 1;\nprint a +;", config()).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Compile);
+        assert_eq!(error.message, "Expect expression");
+        assert_eq!(error.line, 2);
+        assert!(error.trace.is_empty());
+
+        let source = "fun inner() {\n  return -nil;\n}\nfun outer() { inner(); }\nouter();";
+        let error = crate::vm::interpret(source, config()).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Runtime);
+        assert_eq!(error.to_string(), "[line 2] Operand must be a number");
+        let frame = |function: &str, line| TraceFrame {
+            function: function.into(),
+            file: None,
+            line,
+        };
+        assert_eq!(
+            error.trace.frames,
+            vec![frame("inner", 2), frame("outer", 4), frame("<script>", 5)]
+        );
+        assert_eq!(
+            error.trace.to_string(),
+            "[line 2 in inner]\n[line 4 in outer]\n[line 5 in <script>]\n"
+        );
+    }
+
+    #[test]
+    fn native_failures_include_native_frames() {
+        use crate::vm::{TraceFrame, VM};
+
+        let config = || Config {
+            vm_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let frame = |function: &str, line| TraceFrame {
+            function: function.into(),
+            file: None,
+            line,
+        };
+        let run = |source: &str| {
+            let program = crate::compiler::compile(Rc::from(source), config()).unwrap();
+            let mut vm = VM::load(&program, config());
+            vm.define_fallible_native("fail", |_, _| Err("broken".into()));
+            vm.define_native("explode", |_| panic!("kaboom"));
+            vm.run().unwrap_err()
+        };
+
+        let error = run("fun f() {\n  fail();\n}\nf();");
+        assert_eq!(error.message, "Error in fail: broken");
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.trace.frames,
+            vec![
+                frame("<native fn fail>", 2),
+                frame("f", 2),
+                frame("<script>", 4)
+            ]
+        );
+
+        let error = run("fun cmp(a, b) {\n  return a + nil;\n}\nsort([1, 2], cmp);");
+        assert_eq!(
+            error.trace.frames,
+            vec![
+                frame("cmp", 2),
+                frame("<native fn sort>", 4),
+                frame("<script>", 4)
+            ]
+        );
+
+        let error = run("print 1;\nexplode();");
+        assert_eq!(error.message, "Error in explode: kaboom");
+        assert_eq!(error.trace.len(), 2);
+    }
+
+    #[test]
+    fn natives_enforce_arity() {
+        use crate::{memory::Arity, value::Value, vm::VM};
+
+        let config = || Config {
+            vm_error: PrintOutput::Null,
+            print_output: PrintOutput::Null,
+            ..Default::default()
+        };
+        let run = |source: &str| {
+            let program = crate::compiler::compile(Rc::from(source), config()).unwrap();
+            let mut vm = VM::load(&program, config());
+            vm.define_native("max", |args| {
+                let max = args
+                    .iter()
+                    .filter_map(|arg| arg.as_number())
+                    .reduce(f64::max);
+                Value::Number(max.unwrap_or_default())
+            });
+            assert!(vm.set_native_arity("max", Arity::Variadic(1)));
+            assert!(!vm.set_native_arity("missing", Arity::Fixed(0)));
+            vm.run()
+        };
+
+        let error = run("print clock(1);").unwrap_err();
+        assert_eq!(
+            error.message,
+            "Expected 0 arguments but got 1 in native 'clock'"
+        );
+        let error = run("print reduce([1], max);").unwrap_err();
+        assert_eq!(
+            error.message,
+            "Expected 3 arguments but got 2 in native 'reduce'"
+        );
+        let error = run("print max();").unwrap_err();
+        assert_eq!(
+            error.message,
+            "Expected at least 1 arguments but got 0 in native 'max'"
+        );
+        assert_eq!(run("print max(1, 3, 2);"), Ok(()));
+    }
+
+    #[test]
+    fn invokes_methods_and_callable_fields() {
+        let res = interpret_str(
+            r#"
+            class Counter {
+                init() { this.n = 0; }
+                add(by) { this.n = this.n + by; return this; }
+            }
+            fun twice(x) { return x * 2; }
+            var c = Counter();
+            c.add(1).add(2);
+            print c.n;
+            c.add = twice;
+            print c.add(5);
+            print Counter().add(3).n;
+        "#,
+        );
+        assert_eq!(res, "3\n10\n3");
+
+        let program =
+            crate::compiler::compile(Rc::from("var o; o.f(1, 2);"), Config::default()).unwrap();
+        let memory = program.to_memory();
+        let chunk = &memory.function(crate::memory::FunctionId(0)).chunk;
+        let lines = crate::debug::disassemble_to_ir(chunk, &memory);
+        let invoke = lines
+            .iter()
+            .find(|line| line.opcode == crate::chunk::OpCode::Invoke)
+            .unwrap();
+        assert_eq!(invoke.operands[1], 2);
+        assert_eq!(invoke.resolved_constant.as_deref(), Some("\"f\""));
+        assert!(!lines
+            .iter()
+            .any(|line| line.opcode == crate::chunk::OpCode::GetProperty));
+
+        let errors = run_script("var x = 1; x.f();", |_| {}).unwrap_err();
+        assert!(
+            errors.contains("Only instances have properties"),
+            "{errors}"
+        );
+        let errors = run_script("class A {} A().missing();", |_| {}).unwrap_err();
+        assert!(errors.contains("Undefined property 'missing'"), "{errors}");
+    }
+
+    #[test]
+    fn polymorphic_call_sites_stay_correct() {
+        let source = r#"
+            class A { name() { return "a"; } }
+            class B < A { name() { return "b" + super.name(); } }
+            class C < A {}
+            fun f() { return "f"; }
+            var items = [A(), B(), C(), B()];
+            var out = "";
+            for (var round = 0; round < 2; round = round + 1) {
+                for (var i = 0; i < len(items); i = i + 1) {
+                    out = out + items[i].name();
+                }
+                items[0].name = f;
+            }
+            print out;
+        "#;
+        let expected = "abaabafbaaba";
+        assert_eq!(interpret_str(source), expected);
+
+        let mut config = Config::default();
+        config.gc.stress = true;
+        crate::assert_lox! { source, config => format!("{expected}\n") };
+    }
+
+    #[test]
+    fn field_caches_track_shape_per_class() {
+        let source = r#"
+            class A {}
+            class B {}
+            var a = A();
+            a.x = "ax";
+            a.y = "ay";
+            var b = B();
+            b.y = "by";
+            b.x = "bx";
+            var c = A();
+            c.y = "cy";
+            var items = [a, b, c, a];
+            var out = "";
+            for (var i = 0; i < len(items); i = i + 1) {
+                out = out + items[i].y;
+            }
+            print out;
+            print c.x;
+        "#;
+        let run = run_script_with(source, Config::default(), |_| {});
+        assert!(run.result.is_err());
+        assert_eq!(run.output, "aybycyay\n");
+        assert!(
+            run.errors.contains("Undefined property 'x'"),
+            "{}",
+            run.errors
+        );
+    }
+
+    #[test]
+    fn imports_modules_as_namespaces() {
+        let config = || {
+            let mut config = Config::default();
+            config.add_module(
+                "math",
+                "var calls = 0;\nfun square(x) { calls = calls + 1; return x * x; }\nconst pi = 3;\nprint \"loaded\";",
+            );
+            config.add_module(
+                "geometry",
+                "import math;\nfun area(r) { return math.pi * math.square(r); }",
+            );
+            config.add_module("loop", "import loop;");
+            config
+        };
+
+        let source = r#"
+            var calls = 100;
+            import math;
+            import geometry;
+            print math.square(4);
+            print geometry.area(2);
+            print math.calls;
+            print calls;
+            print math;
+            { import math; print math.pi; }
+        "#;
+        crate::assert_lox! {
+            source, config() => "loaded\n16\n12\n2\n100\n<module math>\n3\n"
+        };
+
+        for (source, expected) in [
+            ("import math; print math.nope;", "Undefined property 'nope'"),
+            ("import missing;", "Could not find module 'missing'"),
+            ("import loop;", "Module 'loop' imports itself"),
+        ] {
+            let run = run_script_with(source, config(), |_| {});
+            assert!(run.result.is_err());
+            assert!(run.errors.contains(expected), "{}", run.errors);
+        }
+    }
+
+    #[test]
+    fn host_calls_after_run_report_setup_errors() {
+        use crate::{value::Value, vm::ErrorKind};
+
+        let config = Config {
+            vm_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let mut vm = crate::compiler::try_compile(Rc::from("fun f(a) {}"), None, config).unwrap();
+        assert_eq!(vm.run(), Ok(()));
+
+        let f = vm.global("f").unwrap();
+        let error = vm.call_function(f, &[]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Runtime);
+        assert_eq!(error.message, "Expected 1 arguments but got 0");
+        assert!(error.trace.is_empty());
+
+        let error = vm.call_function(Value::Number(1.0), &[]).unwrap_err();
+        assert_eq!(error.message, "Can only call functions and classes");
+
+        assert_eq!(vm.call_function(f, &[Value::Nil]), Ok(Value::Nil));
+    }
+
+    #[test]
+    fn uncaught_exceptions_carry_values() {
+        use crate::{value::Value, vm::ErrorKind};
+
+        let config = || Config {
+            vm_error: PrintOutput::Null,
+            print_output: PrintOutput::Null,
+            ..Default::default()
+        };
+        let program = crate::compiler::compile(
+            Rc::from(
+                r#"
+                class Failure { init(code) { this.code = code; } }
+                fun fail(code) { throw Failure(code); }
+                fun add(a, b) { return a + b; }
+            "#,
+            ),
+            config(),
+        )
+        .unwrap();
+        let mut vm = crate::vm::VM::load(&program, config());
+        assert_eq!(vm.run(), Ok(()));
+
+        let add = vm.global("add").unwrap();
+        let sum = vm.call_function(add, &[Value::Number(1.0), Value::Number(2.0)]);
+        assert_eq!(sum, Ok(Value::Number(3.0)));
+
+        let fail = vm.global("fail").unwrap();
+        let error = vm.call_function(fail, &[Value::Number(7.0)]).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Uncaught);
+        assert_eq!(error.message, "Uncaught exception: Failure instance");
+        let instance = error.value.and_then(|value| value.as_instance()).unwrap();
+        let code = vm.memory.string_id("code");
+        assert_eq!(vm.memory.field(instance, code), Some(Value::Number(7.0)));
+
+        let error = crate::vm::interpret("throw \"oops\";", config()).unwrap_err();
+        assert_eq!(error.message, "Uncaught exception: oops");
+        assert_eq!(error.trace.len(), 1);
+    }
+
+    #[test]
+    fn assert_statements() {
+        let run = |source| run_script_with(source, Config::default(), |_| {});
+
+        crate::assert_lox! { "assert(1 < 2);\nassert(true, \"ok\");\nprint 1;" => "1\n" };
+
+        let failed = run("print 1;\nassert(1 > 2);\nprint 2;");
+        let error = failed.result.unwrap_err();
+        assert_eq!(error.message, "Assertion failed at line 2: 1 > 2");
+        assert_eq!(error.line, 2);
+        assert_eq!(failed.output, "1\n");
+
+        let source = "fun check(x) {\n  assert(x, \"expected a value\");\n}\ncheck(nil);";
+        let error = run(source).result.unwrap_err();
+        assert_eq!(
+            error.message,
+            "Assertion failed at line 2: expected a value"
+        );
+        assert_eq!(error.trace.len(), 2);
+    }
+
+    #[test]
+    fn equality_semantics() {
+        let res = interpret_str(
+            r#"
+            print nil == nil;
+            print nil == false;
+            print true == true;
+            print true == 1;
+            print 1 == 1.0;
+            print 0 == -0;
+            print (0 / 0) == (0 / 0);
+            print "a" == "a";
+            print "a" + "b" == "ab";
+            print "1" == 1;
+            fun f() {}
+            fun g() {}
+            print f == f;
+            print f == g;
+            fun make() { fun inner() {} return inner; }
+            var a = make();
+            print a == a;
+            print a == make();
+            class C {}
+            var c = C();
+            print C == C;
+            print c == c;
+            print c == C();
+            print clock == clock;
+        "#,
+        );
+        assert_eq!(
+            res,
+            "true\nfalse\ntrue\nfalse\ntrue\ntrue\nfalse\ntrue\ntrue\nfalse\ntrue\nfalse\ntrue\nfalse\ntrue\ntrue\nfalse\ntrue"
+        );
+    }
+
+    #[test]
+    fn string_equality_across_representations() {
+        use crate::{memory::Memory, value::Value};
+
+        let mut memory = Memory::new();
+        let string = Value::String(memory.string_intern("name"));
+        let id = Value::StringId(memory.string_id("name"));
+        let other = Value::StringId(memory.string_id("other"));
+
+        assert!(memory.values_equal(string, id));
+        assert!(memory.values_equal(id, string));
+        assert!(!memory.values_equal(string, other));
+        assert!(!memory.values_equal(id, Value::Nil));
+    }
+
+    #[test]
+    fn converts_values_between_lox_and_rust() {
+        use crate::{
+            memory::Memory,
+            value::{IntoLox, Value},
+        };
+
+        let mut memory = Memory::new();
+        let list = vec![Some(1.0), None].into_lox(&mut memory);
+        assert_eq!(
+            list.try_into_rust::<Vec<Option<f64>>>(&memory),
+            Some(vec![Some(1.0), None])
+        );
+        assert_eq!(list.try_into_rust::<Vec<f64>>(&memory), None);
+
+        let name = "lox".into_lox(&mut memory);
+        assert_eq!(name.try_into_rust::<String>(&memory), Some("lox".into()));
+        let id = Value::StringId(memory.string_id("id"));
+        assert_eq!(id.try_into_rust::<String>(&memory), Some("id".into()));
+        assert_eq!(name.try_into_rust::<f64>(&memory), None);
+
+        assert_eq!(
+            true.into_lox(&mut memory).try_into_rust(&memory),
+            Some(true)
+        );
+        assert_eq!(
+            Value::Nil.try_into_rust::<Option<bool>>(&memory),
+            Some(None)
+        );
+        assert_eq!(Value::Nil.try_into_rust::<()>(&memory), Some(()));
+    }
+
+    #[test]
+    fn foreign_handles_wrap_host_objects() {
+        use crate::value::Value;
+
+        struct Counter {
+            count: usize,
+        }
+
+        let output = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            print_output: PrintOutput::Str(output.clone()),
+            ..Default::default()
+        };
+        let program = crate::compiler::compile(
+            Rc::from(
+                r#"
+                var counter = counter();
+                bump(counter);
+                print bump(counter);
+                print counter;
+                print typeof(counter) + " " + str(counter is Foreign);
+                print bump(1);
+            "#,
+            ),
+            Config::default(),
+        )
+        .unwrap();
+        let mut vm = crate::vm::VM::load(&program, config);
+        vm.define_memory_native("counter", |memory, _| {
+            Value::Foreign(memory.new_foreign(Counter { count: 0 }))
+        });
+        vm.define_memory_native("bump", |memory, args| {
+            let counter = args.first().and_then(Value::as_foreign);
+            match counter.and_then(|id| memory.foreign_mut::<Counter>(id)) {
+                Some(mut counter) => {
+                    counter.count += 1;
+                    Value::Number(counter.count as f64)
+                }
+                None => Value::Nil,
+            }
+        });
+        assert_eq!(vm.run(), Ok(()));
+        assert_eq!(
+            *output.borrow(),
+            "2\n<foreign Counter>\nforeign true\nnil\n"
+        );
+
+        let counter = vm.global("counter").and_then(|value| value.as_foreign());
+        let counter = counter.unwrap();
+        assert_eq!(vm.memory.foreign::<Counter>(counter).unwrap().count, 2);
+        assert!(vm.memory.foreign::<String>(counter).is_none());
+
+        let global = vm.memory.string_id("counter");
+        vm.globals.remove(&global);
+        vm.collect_garbage();
+        assert!(vm.memory.foreign::<Counter>(counter).is_none());
+    }
+
+    #[test]
+    fn foreign_types_dispatch_methods() {
+        use crate::value::{IntoLox, Value};
+
+        struct Connection {
+            queries: Vec<String>,
+        }
+
+        let source = r#"
+                var conn = connect();
+                print conn.query("select 1");
+                print conn.query("select 2");
+                print conn.count();
+                conn.close();
+            "#;
+        let run = run_script_with(source, Config::default(), |vm| {
+            vm.define_memory_native("connect", |memory, _| {
+                let queries = Vec::new();
+                Value::Foreign(memory.new_foreign(Connection { queries }))
+            });
+            vm.register_foreign_type::<Connection>()
+                .method("query", |conn, memory, args| {
+                    let sql = args.first().and_then(|sql| memory.string_value(*sql));
+                    let sql = sql.ok_or("Expected a query string")?.to_owned();
+                    conn.queries.push(sql.clone());
+                    Ok(format!("ran {sql}").into_lox(memory))
+                })
+                .method("count", |conn, _, _| {
+                    Ok(Value::Number(conn.queries.len() as f64))
+                });
+        });
+
+        assert!(run.result.is_err());
+        assert_eq!(run.output, "ran select 1\nran select 2\n2\n");
+        assert!(
+            run.errors.contains("Undefined property 'close'"),
+            "{}",
+            run.errors
+        );
+    }
+
+    #[test]
+    fn bindings_export_struct_fields() {
+        struct Point {
+            x: f64,
+            y: f64,
+        }
+
+        let source = r#"
+                var p = Point(1, 2);
+                p.x = p.x + 10;
+                print p.x;
+                print p.y = 5;
+                print p.length();
+                print p.norm;
+                p.norm = 1;
+            "#;
+        let run = run_script_with(source, Config::default(), |vm| {
+            vm.bind::<Point>("Point")
+                .constructor(|(x, y)| Point { x, y })
+                .field("x", |p| p.x, |p, x| p.x = x)
+                .field("y", |p| p.y, |p, y| p.y = y)
+                .readonly("norm", |p| p.x.abs() + p.y.abs())
+                .method("length", |p, ()| (p.x * p.x + p.y * p.y).sqrt());
+        });
+
+        assert!(run.result.is_err());
+        assert_eq!(run.output, "11\n5\n12.083045973594572\n16\n");
+        assert!(
+            run.errors.contains("Undefined property 'norm'"),
+            "{}",
+            run.errors
+        );
+    }
+
+    #[test]
+    fn natives_call_back_into_lox() {
+        let source = r#"
+                fun add(a, b) { return a + b; }
+                print twice(add, 1);
+                fun nested(a, b) { return twice(add, a) + b; }
+                print twice(nested, 1);
+                fun fail(a, b) { return a + nil; }
+                twice(fail, 1);
+            "#;
+        let run = run_script_with(source, Config::default(), |vm| {
+            vm.define_context_native("twice", |ctx, args| {
+                let [callee, value] = args else {
+                    return Err("Expected a function and a value".into());
+                };
+                let once = ctx.call(*callee, &[*value, *value])?;
+                ctx.call(*callee, &[once, once])
+            });
+        });
+
+        assert!(run.result.is_err());
+        assert_eq!(run.output, "4\n25\n");
+        assert_eq!(
+            run.errors.matches("Operands must be").count(),
+            1,
+            "{}",
+            run.errors
+        );
+    }
+
+    #[test]
+    fn malformed_bytecode_is_a_runtime_error() {
+        use crate::{
+            chunk::OpCode,
+            memory::Memory,
+            vm::{ErrorKind, VM},
+        };
+
+        let run = |code: &[u8]| {
+            let mut memory = Memory::new();
+            let script = memory.new_function("<script>");
+            for byte in code {
+                memory.function_mut(script).chunk.write(*byte, 1);
+            }
+            let config = Config {
+                vm_error: PrintOutput::Null,
+                ..Default::default()
+            };
+            let error = VM::with_script(memory, config).run().unwrap_err();
+            assert_eq!(error.kind, ErrorKind::Runtime);
+            error.message
+        };
+
+        let pop = OpCode::Pop as u8;
+        assert_eq!(run(&[pop, pop, OpCode::Return as u8]), "Stack underflow");
+        assert_eq!(run(&[OpCode::GetLocal as u8, 5]), "Stack underflow");
+        assert_eq!(run(&[OpCode::Constant as u8, 3]), "Malformed bytecode");
+        assert_eq!(run(&[OpCode::BuildList as u8, 9]), "Stack underflow");
+
+        let mut memory = Memory::new();
+        let script = memory.new_function("<script>");
+        memory.function_mut(script).variadic = true;
+        memory
+            .function_mut(script)
+            .chunk
+            .write(OpCode::Return as u8, 1);
+        let config = Config {
+            vm_error: PrintOutput::Null,
+            ..Default::default()
+        };
+        let error = VM::with_script(memory, config).run().unwrap_err();
+        assert_eq!(error.message, "Malformed bytecode");
+    }
+
+    fn compile_errors(source: &str, limits: crate::config::Limits) -> String {
+        let errors = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            compiler_error: PrintOutput::Str(errors.clone()),
+            limits,
+            ..Default::default()
+        };
+        assert!(crate::compiler::compile(Rc::from(source), config).is_err());
+        let errors = errors.borrow();
+        errors
+            .lines()
+            .filter(|line| line.starts_with('['))
+            .map(|line| format!("{line}\n"))
+            .collect()
+    }
+
+    #[test]
+    fn list_literals_are_limited_to_255_elements() {
+        let list = |count| format!("print [{}];", vec!["1"; count].join(", "));
+        assert_eq!(
+            run_script(&list(255), |_| {}).unwrap().matches('1').count(),
+            255
+        );
+        assert_eq!(
+            compile_errors(&list(256), crate::config::Limits::default()),
+            "[line 1] Error at '1': Can't have more than 255 elements in a list literal\n"
+        );
+    }
+
+    #[test]
+    fn calls_are_limited_to_255_arguments() {
+        let call = |count| format!("len({});", vec!["1"; count].join(", "));
+        let errors = run_script(&call(255), |_| {}).unwrap_err();
+        assert!(
+            errors.starts_with("Expected 1 arguments but got 255"),
+            "{errors}"
+        );
+        assert_eq!(
+            compile_errors(&call(256), crate::config::Limits::default()),
+            "[line 1] Error at '1': Can't have more than 255 arguments\n"
+        );
+    }
+
+    #[test]
+    fn tuple_literals_are_limited_to_255_elements() {
+        let tuple = |count| format!("print len(({}));", vec!["1"; count].join(", "));
+        assert_eq!(run_script(&tuple(255), |_| {}), Ok("255\n".into()));
+        assert_eq!(
+            compile_errors(&tuple(256), crate::config::Limits::default()),
+            "[line 1] Error at '1': Can't have more than 255 elements in a tuple\n"
+        );
+    }
+
+    #[test]
+    fn malformed_sources_compile_without_panicking() {
+        for source in [
+            "0x",
+            "0b2",
+            "1e",
+            "1e+",
+            "1_",
+            "1__0",
+            "0x_",
+            "1.",
+            ".5",
+            "\"abc",
+            "/* x",
+            "@",
+            "fun (",
+            "fun f(a, a) {}",
+            "class A < A {}",
+            "class { }",
+            "super.x;",
+            "this;",
+            "return 1;",
+            "break;",
+            "continue;",
+            "a.b.c = ;",
+            "[1, 2",
+            "{{{{",
+            "}}}}",
+            "var;",
+            "for (;;",
+            "if (",
+            "throw",
+            "assert(",
+            "import",
+            "a = b = ;",
+            "1 = 2;",
+            "\u{e9}",
+        ] {
+            let config = Config {
+                compiler_error: PrintOutput::Null,
+                ..Default::default()
+            };
+            let _ = crate::compiler::compile(Rc::from(source), config);
+        }
+    }
+
+    #[test]
+    fn reports_each_independent_error() {
+        let source = "class A {\n  m() { return 1 }\n}\nvar a = ;\nfun f( {\n  print 2;\n}\nvar b = 2\nprint b;\nclass { print 1; }\nprint 3";
+        assert_eq!(
+            compile_errors(source, Default::default()),
+            "[line 2] Error at '}': Expect ';' after return value\n\
+             [line 4] Error at ';': Expect expression\n\
+             [line 5] Error at '{': Expect parameter name\n\
+             [line 8] Error at '2': Expect ';' after variable declaration\n\
+             [line 10] Error at '{': Expect class name\n\
+             [line 11] Error at end: Expect ';' after value\n"
+        );
+    }
+
+    #[test]
+    fn synchronize_skips_runs_of_ordinary_tokens() {
+        let source = "print 1 2 3 4 5;\nprint + + + ;\nvar a = 1 a a a a\nprint;";
+        assert_eq!(
+            compile_errors(source, Default::default()),
+            "[line 1] Error at '2': Expect ';' after value\n\
+             [line 2] Error at '+': Expect expression\n\
+             [line 3] Error at 'a': Expect ';' after variable declaration\n\
+             [line 4] Error at ';': Expect expression\n"
+        );
+    }
+
+    #[test]
+    fn limits_source_and_strings() {
+        use crate::config::Limits;
+
+        let limits = Limits {
+            max_source_len: 8,
+            ..Limits::default()
+        };
+        assert_eq!(
+            compile_errors("print 1 + 2;", limits),
+            "[line 1] Error at 'print': Source is longer than 8 bytes\n"
+        );
+
+        let limits = Limits {
+            max_string_len: 3,
+            ..Limits::default()
+        };
+        assert_eq!(
+            compile_errors("print \"abcd\";", limits),
+            "[line 1] Error at '\"abcd\"': String literal is longer than 3 bytes\n"
+        );
+    }
+
+    #[test]
+    fn limits_program_size() {
+        use crate::config::Limits;
+
+        let limits = Limits {
+            max_constants: 2,
+            ..Limits::default()
+        };
+        assert_eq!(
+            compile_errors("print 1;\nprint 2;\nprint 3;\nprint 4;", limits),
+            "[line 3] Error at '3': Program has more than 2 constants\n"
+        );
+
+        let limits = Limits {
+            max_functions: 1,
+            ..Limits::default()
+        };
+        assert_eq!(
+            compile_errors("fun a() {}\nfun b() {}\nfun c() {}", limits),
+            "[line 2] Error at 'b': Program has more than 1 functions\n"
+        );
+
+        let limits = Limits {
+            max_code_size: 4,
+            ..Limits::default()
+        };
+        assert_eq!(
+            compile_errors("print 1;\nprint 2;", limits),
+            "[line 2] Error at '2': Program bytecode is larger than 4 bytes\n"
+        );
+    }
+
+    #[test]
+    fn limits_nesting_depth() {
+        use crate::config::Limits;
+
+        let nested = |open: &str, close: &str, depth| {
+            format!("{}1{};", open.repeat(depth), close.repeat(depth))
+        };
+        let errors = compile_errors(&nested("(", ")", 100_000), Limits::default());
+        assert_eq!(
+            errors,
+            "[line 1] Error at '(': Code is nested more than 256 levels deep\n"
+        );
+        let errors = compile_errors(&nested("-", "", 100_000), Limits::default());
+        assert_eq!(
+            errors,
+            "[line 1] Error at '-': Code is nested more than 256 levels deep\n"
+        );
+        let errors = compile_errors(&"if (true) ".repeat(100_000), Limits::default());
+        assert_eq!(
+            errors,
+            "[line 1] Error at '(': Code is nested more than 256 levels deep\n"
+        );
+        let errors = compile_errors(&nested("{", "}", 100_000), Limits::default());
+        assert_eq!(
+            errors,
+            "[line 1] Error at '{': Code is nested more than 256 levels deep\n"
+        );
+
+        // Each `{` is one level
+        let blocks = |depth| format!("{}{}", "{".repeat(depth), "}".repeat(depth));
+        let limits = Limits {
+            max_nesting: 128,
+            ..Limits::default()
+        };
+        let config = |limits| Config {
+            limits,
+            ..Default::default()
+        };
+        assert!(crate::compiler::compile(Rc::from(blocks(128)), config(limits)).is_ok());
+        assert_eq!(
+            compile_errors(&blocks(129), limits),
+            "[line 1] Error at '{': Code is nested more than 128 levels deep\n"
+        );
+        assert!(crate::compiler::compile(Rc::from(blocks(256)), config(Limits::default())).is_ok());
+
+        let source = format!("print {};", nested("(", ")", 200).trim_end_matches(';'));
+        crate::assert_lox! { &source => "1\n" };
+    }
 }
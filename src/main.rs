@@ -0,0 +1,567 @@
+use std::{
+    cell::RefCell,
+    collections::HashMap,
+    env, fs,
+    io::{self, Write},
+    path::Path,
+    process::ExitCode,
+    rc::Rc,
+};
+
+use rlox::{
+    asm::{assemble, write_program},
+    ast,
+    compiler::{self, compile},
+    config::{Config, DivisionByZero, NumberFormat, PrintOutput, Warnings},
+    debug::{disassemble_chunk, dump_state},
+    debugger::Debugger,
+    diagnostic::{self, Severity},
+    flamegraph::{FoldedStacks, Weight},
+    format,
+    messages::Catalog,
+    profiler::Profiler,
+    scanner::{Scanner, TokenType},
+    serialize, test_runner,
+    trace::JsonTrace,
+    vm::{ErrorKind, Repl, VM},
+};
+
+const USAGE: &str = "\
+Usage: rlox [repl] [--messages=<path>] [--trace] [--stack-size=<n>] [--max-frames=<n>]
+                   [--allow-io] [--allow-env] [--integers] [--number-format=shortest|lox|<n>]
+                   [--division-by-zero=infinity|error|nil]
+       rlox [run] <file|file.lbc> [--profile[=<path>]] [--trace-json[=<path>]]
+                       [--flamegraph[=<path>]] [--flamegraph-weight=instructions|time|samples]
+                       [--sample-interval=<n>] [--trace] [--dump-bytecode] [--stack-size=<n>]
+                       [--max-frames=<n>] [--dump-state] [--messages=<path>]
+                       [--allow-io] [--allow-env] [--integers]
+                       [--number-format=shortest|lox|<n>]
+                       [--division-by-zero=infinity|error|nil] [-- <args>...]
+       rlox compile <file> [-o <path>] [--strip-source]
+       rlox check <file> [--diagnostics=text|json] [--messages=<path>]
+       rlox test <dir>
+       rlox fmt <file> [--check] [--write]
+       rlox tokens <file>
+       rlox ast <file>
+       rlox bytecode <file> [--asm]
+       rlox asm <file>
+       rlox debug <file>";
+
+struct Args {
+    positional: Vec<String>,
+    flags: HashMap<String, Option<String>>,
+    rest: Vec<String>,
+}
+
+impl Args {
+    fn parse(args: impl Iterator<Item = String>) -> Args {
+        let mut positional = Vec::new();
+        let mut flags = HashMap::new();
+        let mut args = args.peekable();
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                break;
+            } else if let Some(flag) = arg.strip_prefix("--") {
+                match flag.split_once('=') {
+                    Some((name, value)) => flags.insert(name.into(), Some(value.into())),
+                    None => flags.insert(flag.into(), None),
+                };
+            } else if let Some(flag) = arg.strip_prefix('-').filter(|f| !f.is_empty()) {
+                flags.insert(flag.into(), args.next());
+            } else {
+                positional.push(arg);
+            }
+        }
+        Args {
+            positional,
+            flags,
+            rest: args.collect(),
+        }
+    }
+
+    fn flag(&self, name: &str) -> Option<Option<&str>> {
+        self.flags.get(name).map(|value| value.as_deref())
+    }
+}
+
+fn main() -> ExitCode {
+    let args = Args::parse(env::args().skip(1));
+
+    match args.positional.as_slice() {
+        [] => repl(&args),
+        [command] if command == "repl" => repl(&args),
+        [command, file] if command == "run" => with_bytes(file, |bytes| run(file, bytes, &args)),
+        [command, file] if command == "compile" => {
+            with_source(file, |source| compile_file(file, source, &args))
+        }
+        [command, file] if command == "check" => {
+            with_source(file, |source| check(file, source, &args))
+        }
+        [command, dir] if command == "test" => test(Path::new(dir)),
+        [file] => with_bytes(file, |bytes| run(file, bytes, &args)),
+        [command, file] if command == "fmt" => with_source(file, |source| fmt(file, source, &args)),
+        [command, file] if command == "tokens" => with_source(file, tokens),
+        [command, file] if command == "ast" => with_source(file, ast),
+        [command, file] if command == "bytecode" => {
+            with_source(file, |source| bytecode(source, &args))
+        }
+        [command, file] if command == "asm" => with_source(file, assembly),
+        [command, file] if command == "debug" => with_source(file, debug),
+        _ => {
+            eprintln!("{USAGE}");
+            ExitCode::from(64)
+        }
+    }
+}
+
+fn with_source(path: &str, f: impl FnOnce(Rc<str>) -> ExitCode) -> ExitCode {
+    match fs::read_to_string(path) {
+        Ok(source) => f(Rc::from(source)),
+        Err(e) => {
+            eprintln!("Could not read {path}: {e}");
+            ExitCode::from(74)
+        }
+    }
+}
+
+fn with_bytes(path: &str, f: impl FnOnce(Vec<u8>) -> ExitCode) -> ExitCode {
+    match fs::read(path) {
+        Ok(bytes) => f(bytes),
+        Err(e) => {
+            eprintln!("Could not read {path}: {e}");
+            ExitCode::from(74)
+        }
+    }
+}
+
+fn run(path: &str, bytes: Vec<u8>, args: &Args) -> ExitCode {
+    let mut config = match config(args) {
+        Ok(config) => config,
+        Err(code) => return code,
+    };
+    config.module_dir = Path::new(path).parent().map(Path::to_path_buf);
+
+    let profiler = args.flag("profile").map(|path| {
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        config.observe(profiler.clone());
+        (profiler, path)
+    });
+
+    let interval = match args.flag("sample-interval") {
+        Some(interval) => match interval.and_then(|interval| interval.parse().ok()) {
+            Some(interval) => interval,
+            None => {
+                eprintln!("--sample-interval expects a number of instructions");
+                return ExitCode::from(64);
+            }
+        },
+        None => 1000,
+    };
+
+    let flamegraph = args.flag("flamegraph").map(|path| {
+        let weight = match args.flag("flamegraph-weight") {
+            Some(Some("time")) => Weight::Microseconds,
+            Some(Some("samples")) => Weight::Samples(interval),
+            _ => Weight::Instructions,
+        };
+        let stacks = Rc::new(RefCell::new(FoldedStacks::new(weight)));
+        config.observe(stacks.clone());
+        (stacks, path)
+    });
+
+    let trace = args.flag("trace-json").map(|path| {
+        let (output, buffer) = match path {
+            Some(_) => {
+                let buffer = Rc::new(RefCell::new(String::new()));
+                (PrintOutput::Str(buffer.clone()), Some(buffer))
+            }
+            None => (PrintOutput::StdErr, None),
+        };
+        config.observe(Rc::new(RefCell::new(JsonTrace::new(output))));
+        (buffer, path)
+    });
+
+    let vm = if serialize::is_bytecode(&bytes) {
+        match serialize::deserialize(&bytes) {
+            Ok(memory) => Some(VM::with_script(memory, config)),
+            Err(e) => {
+                eprintln!("Could not load {path}: {e}");
+                None
+            }
+        }
+    } else {
+        match String::from_utf8(bytes) {
+            Ok(source) => {
+                let mut compiler_config = match self::config(args) {
+                    Ok(compiler_config) => compiler_config,
+                    Err(code) => return code,
+                };
+                compiler_config.module_dir = config.module_dir.clone();
+                compile(Rc::from(source), compiler_config)
+                    .ok()
+                    .map(|program| VM::load(&program, config))
+            }
+            Err(e) => {
+                eprintln!("Could not read {path}: {e}");
+                return ExitCode::from(74);
+            }
+        }
+    };
+
+    let result = match vm {
+        Some(mut vm) => {
+            if args.flag("dump-bytecode").is_some() {
+                let mut listing = String::new();
+                for function in vm.memory.functions() {
+                    let name = vm.memory.get_string(function.name);
+                    disassemble_chunk(&function.chunk, name, &vm.memory, &mut listing);
+                }
+                eprint!("{listing}");
+            }
+            let result = vm.run().map_err(|e| e.kind);
+            if args.flag("dump-state").is_some() {
+                let mut state = String::new();
+                dump_state(&vm, &mut state);
+                eprint!("{state}");
+            }
+            result
+        }
+        None => Err(ErrorKind::Compile),
+    };
+
+    if let Some((Some(buffer), Some(path))) = trace {
+        if let Err(e) = fs::write(path, buffer.borrow().as_str()) {
+            eprintln!("Could not write {path}: {e}");
+        }
+    }
+
+    if let Some((profiler, path)) = profiler {
+        let mut report = String::new();
+        profiler.borrow().write_report(&mut report);
+        write_report(path, &report);
+    }
+
+    if let Some((stacks, path)) = flamegraph {
+        let mut folded = String::new();
+        stacks.borrow().write_folded(&mut folded);
+        write_report(path, &folded);
+    }
+
+    exit_code(result)
+}
+
+fn compile_file(path: &str, source: Rc<str>, args: &Args) -> ExitCode {
+    let config = Config {
+        module_dir: Path::new(path).parent().map(Path::to_path_buf),
+        ..Default::default()
+    };
+    let Ok(program) = compile(source, config) else {
+        return ExitCode::from(65);
+    };
+
+    let output = match args.flag("o").or(args.flag("output")) {
+        Some(Some(output)) => output.to_owned(),
+        Some(None) => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(64);
+        }
+        None => Path::new(path)
+            .with_extension("lbc")
+            .to_string_lossy()
+            .into_owned(),
+    };
+
+    let embed_source = args.flag("strip-source").is_none();
+    let bytes = match serialize::serialize(&program.to_memory(), embed_source) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            eprintln!("Could not compile {path}: {e}");
+            return ExitCode::from(70);
+        }
+    };
+    match fs::write(&output, bytes) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Could not write {output}: {e}");
+            ExitCode::from(74)
+        }
+    }
+}
+
+fn config(args: &Args) -> Result<Config, ExitCode> {
+    let mut config = Config::default();
+    if let Some(Some(path)) = args.flag("messages") {
+        let source = fs::read_to_string(path).map_err(|e| {
+            eprintln!("Could not read {path}: {e}");
+            ExitCode::from(74)
+        })?;
+        config.messages = Catalog::parse(&source).map_err(|e| {
+            eprintln!("{path}: {e}");
+            ExitCode::from(65)
+        })?;
+    }
+    if args.flag("allow-io").is_some() {
+        config.allow_io = true;
+    }
+    if args.flag("allow-env").is_some() {
+        config.allow_env = true;
+    }
+    if args.flag("integers").is_some() {
+        config.integers = true;
+    }
+    config.args = args.rest.clone();
+    if args.flag("trace").is_some() {
+        config.vm_debug = PrintOutput::StdErr;
+    }
+    if let Some(size) = args.flag("stack-size") {
+        config.max_stack = size.and_then(|size| size.parse().ok()).ok_or_else(|| {
+            eprintln!("--stack-size expects a number of stack slots");
+            ExitCode::from(64)
+        })?;
+    }
+    if let Some(frames) = args.flag("max-frames") {
+        config.max_frames = frames
+            .and_then(|frames| frames.parse().ok())
+            .ok_or_else(|| {
+                eprintln!("--max-frames expects a number of call frames");
+                ExitCode::from(64)
+            })?;
+    }
+    if let Some(policy) = args.flag("division-by-zero") {
+        config.division_by_zero = match policy {
+            Some("infinity") => DivisionByZero::Infinity,
+            Some("error") => DivisionByZero::RuntimeError,
+            Some("nil") => DivisionByZero::Nil,
+            _ => {
+                eprintln!("--division-by-zero expects infinity, error or nil");
+                return Err(ExitCode::from(64));
+            }
+        };
+    }
+    if let Some(format) = args.flag("number-format") {
+        config.number_format = match format {
+            Some("shortest") => NumberFormat::Shortest,
+            Some("lox") => NumberFormat::Lox,
+            digits => match digits.and_then(|digits| digits.parse().ok()) {
+                Some(digits) => NumberFormat::Precision(digits),
+                None => {
+                    eprintln!("--number-format expects shortest, lox or a number of digits");
+                    return Err(ExitCode::from(64));
+                }
+            },
+        };
+    }
+    Ok(config)
+}
+
+fn repl(args: &Args) -> ExitCode {
+    let config = match config(args) {
+        Ok(config) => config,
+        Err(code) => return code,
+    };
+    let mut repl = Repl::new(config);
+    let stdin = io::stdin();
+    loop {
+        print!("> ");
+        io::stdout().flush().unwrap();
+
+        let mut line = String::new();
+        match stdin.read_line(&mut line) {
+            Ok(0) => {
+                println!();
+                return ExitCode::SUCCESS;
+            }
+            Ok(_) => {
+                let _ = repl.interpret(&line);
+            }
+            Err(e) => {
+                eprintln!("Could not read input: {e}");
+                return ExitCode::from(74);
+            }
+        }
+    }
+}
+
+fn check(path: &str, source: Rc<str>, args: &Args) -> ExitCode {
+    let mut config = match config(args) {
+        Ok(config) => config,
+        Err(code) => return code,
+    };
+    config.module_dir = Path::new(path).parent().map(Path::to_path_buf);
+    config.warnings = Warnings::all();
+    let diagnostics = match args.flag("diagnostics") {
+        Some(Some("json")) => {
+            config.compiler_error = PrintOutput::Null;
+            let diagnostics = compiler::check(source.clone(), config);
+            let uri = match fs::canonicalize(path) {
+                Ok(path) => format!("file://{}", path.display()),
+                Err(_) => path.to_owned(),
+            };
+            let mut output = String::new();
+            diagnostic::write_json(&uri, &source, &diagnostics, &mut output);
+            print!("{output}");
+            diagnostics
+        }
+        Some(Some("text")) | None => compiler::check(source, config),
+        Some(_) => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(64);
+        }
+    };
+
+    if diagnostics.iter().any(|d| d.severity == Severity::Error) {
+        ExitCode::from(65)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+fn write_report(path: Option<&str>, report: &str) {
+    match path {
+        Some(path) => {
+            if let Err(e) = fs::write(path, report) {
+                eprintln!("Could not write {path}: {e}");
+            }
+        }
+        None => eprint!("{report}"),
+    }
+}
+
+fn exit_code(result: Result<(), ErrorKind>) -> ExitCode {
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(ErrorKind::Compile) => ExitCode::from(65),
+        Err(ErrorKind::Runtime | ErrorKind::Budget | ErrorKind::Uncaught) => ExitCode::from(70),
+    }
+}
+
+fn fmt(path: &str, source: Rc<str>, args: &Args) -> ExitCode {
+    let Some(formatted) = format::format(&source) else {
+        eprintln!("Could not format {path}: it contains invalid tokens");
+        return ExitCode::from(65);
+    };
+    if args.flag("check").is_some() {
+        if formatted == *source {
+            return ExitCode::SUCCESS;
+        }
+        eprintln!("{path} is not formatted");
+        return ExitCode::from(1);
+    }
+    if args.flag("write").is_some() {
+        if let Err(e) = fs::write(path, formatted) {
+            eprintln!("Could not write {path}: {e}");
+            return ExitCode::from(74);
+        }
+        return ExitCode::SUCCESS;
+    }
+    print!("{formatted}");
+    ExitCode::SUCCESS
+}
+
+fn tokens(source: Rc<str>) -> ExitCode {
+    let mut scanner = Scanner::init(source);
+    loop {
+        let token = scanner.token();
+        let span = token.span();
+        let range = format!("{}..{}", span.start, span.end);
+        println!(
+            "{:>4} {range:<10} {:<14} '{}'",
+            span.line,
+            format!("{:?}", token.typ),
+            token.slice
+        );
+        if token.typ == TokenType::EOF {
+            return ExitCode::SUCCESS;
+        }
+    }
+}
+
+fn ast(source: Rc<str>) -> ExitCode {
+    match ast::parse(source, Config::default()) {
+        Some(node) => {
+            let mut output = String::new();
+            node.dump(&mut output);
+            print!("{output}");
+            ExitCode::SUCCESS
+        }
+        None => ExitCode::from(65),
+    }
+}
+
+fn bytecode(source: Rc<str>, args: &Args) -> ExitCode {
+    match compile(source, Config::default()) {
+        Ok(program) => {
+            let memory = &program.to_memory();
+            let mut output = String::new();
+            if args.flag("asm").is_some() {
+                write_program(memory, &mut output);
+            } else {
+                for function in memory.functions() {
+                    let name = memory.get_string(function.name);
+                    disassemble_chunk(&function.chunk, name, memory, &mut output);
+                }
+            }
+            print!("{output}");
+            ExitCode::SUCCESS
+        }
+        Err(_) => ExitCode::from(65),
+    }
+}
+
+fn assembly(source: Rc<str>) -> ExitCode {
+    match assemble(&source) {
+        Ok(memory) => exit_code(
+            VM::with_script(memory, Config::default())
+                .run()
+                .map_err(|e| e.kind),
+        ),
+        Err(e) => {
+            eprintln!("{e}");
+            ExitCode::from(65)
+        }
+    }
+}
+
+fn debug(source: Rc<str>) -> ExitCode {
+    match compile(source, Config::default()) {
+        Ok(program) => {
+            let mut debugger = Debugger::new(VM::load(&program, Config::default()));
+            debugger.run(io::stdin().lock(), &mut PrintOutput::StdOut);
+            ExitCode::SUCCESS
+        }
+        Err(_) => ExitCode::from(65),
+    }
+}
+
+fn test(dir: &Path) -> ExitCode {
+    let outcomes = match test_runner::run_dir(dir) {
+        Ok(outcomes) => outcomes,
+        Err(e) => {
+            eprintln!("Could not read {}: {e}", dir.display());
+            return ExitCode::from(74);
+        }
+    };
+
+    let mut failed = 0;
+    for outcome in outcomes.iter() {
+        if outcome.passed() {
+            println!("PASS {}", outcome.path.display());
+        } else {
+            failed += 1;
+            println!("FAIL {}", outcome.path.display());
+            for failure in outcome.failures.iter() {
+                println!("     {failure}");
+            }
+        }
+    }
+
+    let passed = outcomes.len() - failed;
+    println!();
+    println!("{passed} passed, {failed} failed");
+
+    if failed == 0 {
+        ExitCode::SUCCESS
+    } else {
+        ExitCode::FAILURE
+    }
+}
@@ -1,14 +1,41 @@
+use std::{
+    any::{self, Any, TypeId},
+    cell::{Ref, RefCell, RefMut},
+    collections::{HashMap, HashSet},
+    fmt,
+    rc::Rc,
+    sync::Arc,
+};
+
 use crate::{
     chunk::Chunk,
+    config::NumberFormat,
+    gc::Heap,
     string_intern::{StrId, StringInterner},
     value::Value,
+    vm::NativeContext,
 };
 
 pub struct Memory {
     strings: StringInterner,
     functions: Vec<Function>,
     natives: Vec<NativeFunction>,
-    closures: Vec<Closure>,
+    closures: Heap<Closure>,
+    classes: Heap<Class>,
+    instances: Heap<Instance>,
+    bound_methods: Heap<BoundMethod>,
+    lists: Heap<List>,
+    foreign: Heap<Foreign>,
+    foreign_types: HashMap<TypeId, ForeignClass>,
+    modules: Vec<Module>,
+    sources: Vec<(Option<Arc<str>>, Rc<str>)>,
+    number_format: NumberFormat,
+}
+
+impl Default for Memory {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl Memory {
@@ -17,10 +44,27 @@ impl Memory {
             strings: StringInterner::with_capacity(16),
             functions: Vec::new(),
             natives: Vec::new(),
-            closures: Vec::new(),
+            closures: Heap::new(),
+            classes: Heap::new(),
+            instances: Heap::new(),
+            bound_methods: Heap::new(),
+            lists: Heap::new(),
+            foreign: Heap::new(),
+            foreign_types: HashMap::new(),
+            modules: Vec::new(),
+            sources: Vec::new(),
+            number_format: NumberFormat::default(),
         }
     }
 
+    pub fn number_format(&self) -> NumberFormat {
+        self.number_format
+    }
+
+    pub fn set_number_format(&mut self, format: NumberFormat) {
+        self.number_format = format;
+    }
+
     pub fn string_id(&mut self, string: &str) -> StrId {
         self.strings.intern(string).0
     }
@@ -29,14 +73,69 @@ impl Memory {
         self.strings.intern(string).1
     }
 
+    pub fn string_concat(&mut self, a: &'static str, b: &str) -> &'static str {
+        self.strings.concat(a, b).1
+    }
+
     pub fn get_string(&self, id: StrId) -> &str {
         self.strings.lookup(id)
     }
 
+    pub fn string_value(&self, value: Value) -> Option<&str> {
+        match value {
+            Value::String(s) => Some(s),
+            Value::StringId(id) => Some(self.get_string(id)),
+            _ => None,
+        }
+    }
+
+    pub fn values_equal(&self, a: Value, b: Value) -> bool {
+        match (a, b) {
+            (Value::Int(_), Value::Number(_)) | (Value::Number(_), Value::Int(_)) => {
+                a.as_number() == b.as_number()
+            }
+            (Value::Tuple(a), Value::Tuple(b)) => {
+                let (a, b) = (&self.list(a).items, &self.list(b).items);
+                a.len() == b.len() && a.iter().zip(b).all(|(a, b)| self.values_equal(*a, *b))
+            }
+            _ => match (self.string_value(a), self.string_value(b)) {
+                (Some(a), Some(b)) => a == b,
+                _ => a == b,
+            },
+        }
+    }
+
+    pub fn type_name(&mut self, value: Value) -> &'static str {
+        self.string_intern(value.value_type().name())
+    }
+
+    pub fn strings(&self) -> impl Iterator<Item = (StrId, &str)> {
+        self.strings.iter()
+    }
+
+    pub fn add_source(&mut self, file: Option<Arc<str>>, source: Rc<str>) {
+        self.sources.push((file, source));
+    }
+
+    pub fn sources(&self) -> impl Iterator<Item = (Option<&str>, &str)> {
+        self.sources
+            .iter()
+            .map(|(file, source)| (file.as_deref(), source.as_ref()))
+    }
+
+    pub fn source_line(&self, file: Option<&str>, line: usize) -> Option<&str> {
+        let (_, source) = self.sources().find(|(f, _)| *f == file)?;
+        source.lines().nth(line.checked_sub(1)?)
+    }
+
     pub fn function(&self, id: FunctionId) -> &Function {
         &self.functions[id.0]
     }
 
+    pub fn functions(&self) -> impl Iterator<Item = &Function> {
+        self.functions.iter()
+    }
+
     pub fn function_mut(&mut self, id: FunctionId) -> &mut Function {
         &mut self.functions[id.0]
     }
@@ -46,41 +145,346 @@ impl Memory {
         let name = self.string_id(name);
         self.functions.push(Function {
             arity: 0,
+            min_arity: 0,
+            variadic: false,
             chunk: Chunk::new(),
             name,
+            module: None,
         });
         FunctionId(id)
     }
 
+    pub fn into_program(self) -> CompiledProgram {
+        let sources = self
+            .sources
+            .iter()
+            .map(|(file, source)| (file.clone(), Arc::from(&**source)))
+            .collect();
+        CompiledProgram {
+            strings: self.strings,
+            functions: self.functions,
+            sources,
+        }
+    }
+
+    pub fn snapshot(&self) -> HeapSnapshot {
+        HeapSnapshot {
+            closures: self.closures.clone(),
+            classes: self.classes.clone(),
+            instances: self.instances.clone(),
+            bound_methods: self.bound_methods.clone(),
+            lists: self.lists.clone(),
+            foreign: self.foreign.clone(),
+            modules: self.modules.clone(),
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &HeapSnapshot) {
+        self.closures = snapshot.closures.clone();
+        self.classes = snapshot.classes.clone();
+        self.instances = snapshot.instances.clone();
+        self.bound_methods = snapshot.bound_methods.clone();
+        self.lists = snapshot.lists.clone();
+        self.foreign = snapshot.foreign.clone();
+        self.modules = snapshot.modules.clone();
+    }
+
     pub fn closure(&self, id: ClosureId) -> &Closure {
-        &self.closures[id.0]
+        self.closures.get(id.0)
     }
 
     pub fn closure_mut(&mut self, id: ClosureId) -> &mut Closure {
-        &mut self.closures[id.0]
+        self.closures.get_mut(id.0)
     }
 
     pub fn new_closure(&mut self, function: FunctionId) -> ClosureId {
-        let id = self.closures.len();
-        self.closures.push(Closure { function });
-        ClosureId(id)
+        ClosureId(self.closures.insert(Closure {
+            function,
+            class: None,
+        }))
+    }
+
+    pub fn class(&self, id: ClassId) -> &Class {
+        self.classes.get(id.0)
+    }
+
+    pub fn class_mut(&mut self, id: ClassId) -> &mut Class {
+        self.classes.get_mut(id.0)
+    }
+
+    pub fn new_class(&mut self, name: StrId) -> ClassId {
+        ClassId(self.classes.insert(Class {
+            name,
+            superclass: None,
+            methods: HashMap::new(),
+            fields: HashMap::new(),
+        }))
+    }
+
+    pub fn field_slot(&self, class: ClassId, name: StrId) -> Option<usize> {
+        self.class(class).fields.get(&name).copied()
+    }
+
+    pub fn add_field_slot(&mut self, class: ClassId, name: StrId) -> usize {
+        let fields = &mut self.class_mut(class).fields;
+        let slot = fields.len();
+        *fields.entry(name).or_insert(slot)
+    }
+
+    pub fn field(&self, id: InstanceId, name: StrId) -> Option<Value> {
+        let instance = self.instance(id);
+        self.field_slot(instance.class, name)
+            .and_then(|slot| instance.slot(slot))
+    }
+
+    pub fn set_field(&mut self, id: InstanceId, name: StrId, value: Value) {
+        let slot = self.add_field_slot(self.instance(id).class, name);
+        self.instance_mut(id).set_slot(slot, value);
+    }
+
+    pub fn instance(&self, id: InstanceId) -> &Instance {
+        self.instances.get(id.0)
+    }
+
+    pub fn instance_mut(&mut self, id: InstanceId) -> &mut Instance {
+        self.instances.get_mut(id.0)
+    }
+
+    pub fn new_instance(&mut self, class: ClassId) -> InstanceId {
+        InstanceId(self.instances.insert(Instance {
+            class,
+            fields: Vec::new(),
+        }))
+    }
+
+    pub fn bound_method(&self, id: BoundMethodId) -> &BoundMethod {
+        self.bound_methods.get(id.0)
+    }
+
+    pub fn new_bound_method(&mut self, receiver: Value, method: ClosureId) -> BoundMethodId {
+        BoundMethodId(self.bound_methods.insert(BoundMethod { receiver, method }))
+    }
+
+    pub fn list(&self, id: ListId) -> &List {
+        self.lists.get(id.0)
+    }
+
+    pub fn list_mut(&mut self, id: ListId) -> &mut List {
+        self.lists.get_mut(id.0)
+    }
+
+    pub fn new_list(&mut self, items: Vec<Value>) -> ListId {
+        ListId(self.lists.insert(List { items }))
+    }
+
+    pub fn new_foreign<T: Any>(&mut self, object: T) -> ForeignId {
+        ForeignId(self.foreign.insert(Foreign {
+            name: any::type_name::<T>(),
+            type_id: TypeId::of::<T>(),
+            object: Rc::new(RefCell::new(object)),
+        }))
+    }
+
+    pub fn foreign_name(&self, id: ForeignId) -> Option<&'static str> {
+        self.foreign.try_get(id.0).map(|foreign| foreign.name)
+    }
+
+    pub fn foreign<T: Any>(&self, id: ForeignId) -> Option<Ref<'_, T>> {
+        let object = self.foreign.try_get(id.0)?.object.try_borrow().ok()?;
+        Ref::filter_map(object, |object| object.downcast_ref()).ok()
+    }
+
+    pub fn foreign_mut<T: Any>(&mut self, id: ForeignId) -> Option<RefMut<'_, T>> {
+        let object = self.foreign.try_get(id.0)?.object.try_borrow_mut().ok()?;
+        RefMut::filter_map(object, |object| object.downcast_mut()).ok()
+    }
+
+    pub fn with_foreign<T: Any, R>(
+        &mut self,
+        id: ForeignId,
+        f: impl FnOnce(&mut T, &mut Memory) -> R,
+    ) -> Option<R> {
+        let object = self.foreign.try_get(id.0)?.object.clone();
+        let mut object = object.try_borrow_mut().ok()?;
+        Some(f(object.downcast_mut()?, self))
+    }
+
+    pub fn foreign_class_mut<T: Any>(&mut self) -> &mut ForeignClass {
+        self.foreign_types.entry(TypeId::of::<T>()).or_default()
+    }
+
+    pub fn foreign_class(&self, id: ForeignId) -> Option<&ForeignClass> {
+        let type_id = self.foreign.try_get(id.0)?.type_id;
+        self.foreign_types.get(&type_id)
+    }
+
+    pub fn module(&self, id: ModuleId) -> &Module {
+        &self.modules[id.0]
+    }
+
+    pub fn module_mut(&mut self, id: ModuleId) -> &mut Module {
+        &mut self.modules[id.0]
+    }
+
+    pub fn modules(&self) -> impl Iterator<Item = &Module> {
+        self.modules.iter()
+    }
+
+    pub fn new_module(&mut self, function: FunctionId) -> ModuleId {
+        let id = self.modules.len();
+        self.modules.push(Module {
+            name: self.function(function).name,
+            globals: HashMap::new(),
+            constants: HashSet::new(),
+        });
+        ModuleId(id)
+    }
+
+    pub fn objects(&self) -> usize {
+        self.closures.len()
+            + self.classes.len()
+            + self.instances.len()
+            + self.bound_methods.len()
+            + self.lists.len()
+            + self.foreign.len()
+    }
+
+    /// Marks everything reachable from `roots` and sweeps the object heaps.
+    ///
+    /// Strings are not collected. They are interned for the life of the
+    /// `Memory` and handed out as `&'static str`, so strings built at runtime
+    /// by concatenation, `str()` or repetition stay until it is dropped.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) -> usize {
+        let mut gray: Vec<Value> = roots.into_iter().collect();
+        for module in self.modules.iter() {
+            gray.extend(module.globals.values().copied());
+        }
+        while let Some(value) = gray.pop() {
+            match value {
+                Value::Closure(id) => {
+                    if self.closures.mark(id.0) {
+                        gray.extend(self.closures.get(id.0).class.map(Value::Class));
+                    }
+                }
+                Value::Class(id) => {
+                    if self.classes.mark(id.0) {
+                        let class = self.classes.get(id.0);
+                        gray.extend(class.superclass.map(Value::Class));
+                        gray.extend(class.methods.values().copied().map(Value::Closure));
+                    }
+                }
+                Value::Instance(id) => {
+                    if self.instances.mark(id.0) {
+                        let instance = self.instances.get(id.0);
+                        gray.push(Value::Class(instance.class));
+                        gray.extend(instance.fields.iter().flatten().copied());
+                    }
+                }
+                Value::BoundMethod(id) => {
+                    if self.bound_methods.mark(id.0) {
+                        let bound = self.bound_methods.get(id.0);
+                        gray.push(bound.receiver);
+                        gray.push(Value::Closure(bound.method));
+                    }
+                }
+                Value::List(id) | Value::Tuple(id) => {
+                    if self.lists.mark(id.0) {
+                        gray.extend(self.lists.get(id.0).items.iter().copied());
+                    }
+                }
+                Value::Foreign(id) => {
+                    self.foreign.mark(id.0);
+                }
+                Value::Nil
+                | Value::Bool(_)
+                | Value::Number(_)
+                | Value::Int(_)
+                | Value::String(_)
+                | Value::StringId(_)
+                | Value::Function(_)
+                | Value::NativeFunction(_)
+                | Value::Module(_)
+                | Value::Type(_) => (),
+            }
+        }
+
+        self.closures.sweep()
+            + self.classes.sweep()
+            + self.instances.sweep()
+            + self.bound_methods.sweep()
+            + self.lists.sweep()
+            + self.foreign.sweep()
     }
 
     pub fn native(&self, id: NativeFunctionId) -> &NativeFunction {
         &self.natives[id.0]
     }
 
+    pub fn native_mut(&mut self, id: NativeFunctionId) -> &mut NativeFunction {
+        &mut self.natives[id.0]
+    }
+
     pub fn new_native(
         &mut self,
         name: &str,
-        function: impl Fn(&[Value]) -> Value + 'static,
+        function: impl Fn(&mut Memory, &[Value]) -> Result<Value, NativeError> + 'static,
     ) -> NativeFunctionId {
         let id = self.natives.len();
         let name = self.string_id(name);
-        self.natives
-            .push(NativeFunction::new(name, Box::new(function)));
+        self.natives.push(NativeFunction::new(
+            name,
+            NativeFn::Memory(Rc::new(function)),
+        ));
         NativeFunctionId(id)
     }
+
+    pub fn new_context_native(
+        &mut self,
+        name: &str,
+        function: impl Fn(&mut NativeContext, &[Value]) -> Result<Value, NativeError> + 'static,
+    ) -> NativeFunctionId {
+        let id = self.natives.len();
+        let name = self.string_id(name);
+        self.natives.push(NativeFunction::new(
+            name,
+            NativeFn::Context(Rc::new(function)),
+        ));
+        NativeFunctionId(id)
+    }
+}
+
+#[derive(Clone)]
+pub struct HeapSnapshot {
+    closures: Heap<Closure>,
+    classes: Heap<Class>,
+    instances: Heap<Instance>,
+    bound_methods: Heap<BoundMethod>,
+    lists: Heap<List>,
+    foreign: Heap<Foreign>,
+    modules: Vec<Module>,
+}
+
+pub struct CompiledProgram {
+    strings: StringInterner,
+    functions: Vec<Function>,
+    sources: Vec<(Option<Arc<str>>, Arc<str>)>,
+}
+
+impl CompiledProgram {
+    pub fn to_memory(&self) -> Memory {
+        let mut memory = Memory::new();
+        for (_, string) in self.strings.iter() {
+            memory.string_id(string);
+        }
+        for function in self.functions.iter() {
+            let chunk = function.chunk.fork(&mut memory);
+            memory.functions.push(Function { chunk, ..*function });
+        }
+        for (file, source) in self.sources.iter() {
+            memory.add_source(file.clone(), Rc::from(&**source));
+        }
+        memory
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
@@ -92,23 +496,166 @@ pub struct ClosureId(pub usize);
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
 pub struct NativeFunctionId(pub usize);
 
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ClassId(pub usize);
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct InstanceId(pub usize);
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct BoundMethodId(pub usize);
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ListId(pub usize);
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ModuleId(pub usize);
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub struct ForeignId(pub usize);
+
 pub struct Function {
     pub arity: usize,
+    pub min_arity: usize,
+    pub variadic: bool,
     pub chunk: Chunk,
     pub name: StrId,
+    pub module: Option<FunctionId>,
 }
 
+#[derive(Clone)]
 pub struct Closure {
     pub function: FunctionId,
+    pub class: Option<ClassId>,
+}
+
+#[derive(Clone)]
+pub struct Class {
+    pub name: StrId,
+    pub superclass: Option<ClassId>,
+    pub methods: HashMap<StrId, ClosureId>,
+    pub fields: HashMap<StrId, usize>,
+}
+
+#[derive(Clone)]
+pub struct Instance {
+    pub class: ClassId,
+    pub fields: Vec<Option<Value>>,
+}
+
+impl Instance {
+    pub fn slot(&self, slot: usize) -> Option<Value> {
+        self.fields.get(slot).copied().flatten()
+    }
+
+    pub fn set_slot(&mut self, slot: usize, value: Value) {
+        if self.fields.len() <= slot {
+            self.fields.resize(slot + 1, None);
+        }
+        self.fields[slot] = Some(value);
+    }
+}
+
+#[derive(Clone)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ClosureId,
+}
+
+#[derive(Clone)]
+pub struct List {
+    pub items: Vec<Value>,
+}
+
+#[derive(Clone)]
+pub struct Foreign {
+    pub name: &'static str,
+    pub type_id: TypeId,
+    pub object: Rc<RefCell<dyn Any>>,
+}
+
+#[derive(Default)]
+pub struct ForeignClass {
+    pub methods: HashMap<StrId, NativeFunctionId>,
+    pub getters: HashMap<StrId, NativeFunctionId>,
+    pub setters: HashMap<StrId, NativeFunctionId>,
+}
+
+#[derive(Clone)]
+pub struct Module {
+    pub name: StrId,
+    pub globals: HashMap<StrId, Value>,
+    pub constants: HashSet<StrId>,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct NativeError {
+    pub message: String,
+}
+
+impl From<String> for NativeError {
+    fn from(message: String) -> Self {
+        NativeError { message }
+    }
+}
+
+impl From<&str> for NativeError {
+    fn from(message: &str) -> Self {
+        message.to_owned().into()
+    }
+}
+
+impl fmt::Display for NativeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.message)
+    }
+}
+
+pub type MemoryNative = dyn Fn(&mut Memory, &[Value]) -> Result<Value, NativeError>;
+pub type ContextNative = dyn Fn(&mut NativeContext, &[Value]) -> Result<Value, NativeError>;
+
+#[derive(Clone)]
+pub enum NativeFn {
+    Memory(Rc<MemoryNative>),
+    Context(Rc<ContextNative>),
 }
 
 pub struct NativeFunction {
     pub name: StrId,
-    pub callable: Box<dyn Fn(&[Value]) -> Value>,
+    pub arity: Arity,
+    pub callable: NativeFn,
 }
 
 impl NativeFunction {
-    pub fn new(name: StrId, callable: Box<dyn Fn(&[Value]) -> Value>) -> Self {
-        Self { name, callable }
+    pub fn new(name: StrId, callable: NativeFn) -> Self {
+        Self {
+            name,
+            arity: Arity::Variadic(0),
+            callable,
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Arity {
+    Fixed(usize),
+    Variadic(usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, arg_count: usize) -> bool {
+        match self {
+            Arity::Fixed(n) => arg_count == *n,
+            Arity::Variadic(min) => arg_count >= *min,
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Fixed(n) => write!(f, "{n}"),
+            Arity::Variadic(min) => write!(f, "at least {min}"),
+        }
     }
 }
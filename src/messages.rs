@@ -0,0 +1,522 @@
+use std::{collections::HashMap, error::Error, fmt};
+
+#[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
+pub enum Message {
+    UnexpectedCharacter,
+    UnterminatedString,
+    UnterminatedBlockComment,
+
+    ExpectExpression,
+    ExpectFunctionName,
+    ExpectParenAfterFunctionName,
+    TooManyParameters,
+    ExpectParameterName,
+    ExpectParenAfterParameters,
+    ExpectBraceBeforeFunctionBody,
+    TooManyArguments,
+    ExpectParenAfterArguments,
+    ExpectVariableName,
+    ExpectSemicolonAfterVariableDeclaration,
+    ReturnFromTopLevel,
+    ExpectSemicolonAfterReturnValue,
+    ExpectParenAfterIf,
+    ExpectParenAfterCondition,
+    ExpectParenAfterWhile,
+    ExpectParenAfterFor,
+    ExpectSemicolonAfterLoopCondition,
+    ExpectParenAfterForClauses,
+    VariableAlreadyDeclared,
+    TooManyLocals,
+    ExpectSemicolonAfterValue,
+    ExpectSemicolonAfterExpression,
+    ExpectBraceAfterBlock,
+    ReadLocalInOwnInitializer,
+    ExpectParenAfterExpression,
+    InvalidAssignmentTarget,
+    JumpTooLarge,
+    TooManyConstants,
+    LoopTooLarge,
+    SourceTooLong,
+    StringTooLong,
+    ProgramTooManyConstants,
+    ProgramTooManyFunctions,
+    ProgramTooLarge,
+    ExpectClassName,
+    ExpectBraceBeforeClassBody,
+    ExpectBraceAfterClassBody,
+    ExpectPropertyName,
+    ExpectMethodName,
+    ThisOutsideClass,
+    ReturnFromInitializer,
+    ExpectSuperclassName,
+    InheritFromSelf,
+    SuperOutsideClass,
+    SuperWithoutSuperclass,
+    ExpectDotAfterSuper,
+    ExpectSuperclassMethodName,
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+    ExpectSemicolonAfterBreak,
+    ExpectSemicolonAfterContinue,
+    ExpectBracketAfterElements,
+    TooManyElements,
+    ExpectBracketAfterIndex,
+    ExpectInAfterLoopVariable,
+    AssignToConstantLocal,
+    ExpectEqualAfterConstantName,
+    ExpectModuleName,
+    ExpectSemicolonAfterImport,
+    ModuleNotFound,
+    CircularImport,
+    ExpectSemicolonAfterThrow,
+    ExpectParenAfterAssert,
+    ExpectParenAfterAssertion,
+    ExpectSemicolonAfterAssertion,
+    InvalidNumber,
+    NestingTooDeep,
+    ExpectDefaultParameter,
+    ExpectParenAfterNames,
+    ExpectEqualAfterPattern,
+    ExpectElseInIfExpression,
+    TooManyTupleElements,
+
+    OperandsMustBeNumbers,
+    OperandsMustBeStringsOrNumbers,
+    OperandMustBeNumber,
+    UndefinedVariable,
+    ExpectedClosure,
+    NotCallable,
+    WrongArgumentCount,
+    StackOverflow,
+    OnlyInstancesHaveProperties,
+    OnlyInstancesHaveFields,
+    UndefinedProperty,
+    SuperclassMustBeClass,
+    NotIndexable,
+    IndexMustBeInteger,
+    IndexOutOfRange,
+    OnlyListsSupportIndexAssignment,
+    NotIterable,
+    InstructionBudgetExceeded,
+    AssignToConstantGlobal,
+    StackUnderflow,
+    MalformedBytecode,
+    UncaughtException,
+    AssertionFailed,
+    NativeFailed,
+    ConcatenateNonString,
+    IntegerDivisionByZero,
+    DivisionByZero,
+    TypeCheckNeedsType,
+    NativeWrongArgumentCount,
+    NotDestructurable,
+    DestructureCountMismatch,
+    InvalidRepeatCount,
+    RepeatTooLong,
+
+    UnusedLocal,
+    UnreachableCode,
+    ConstantCondition,
+}
+
+impl Message {
+    pub const ALL: &'static [Message] = &[
+        Message::UnexpectedCharacter,
+        Message::UnterminatedString,
+        Message::UnterminatedBlockComment,
+        Message::ExpectExpression,
+        Message::ExpectFunctionName,
+        Message::ExpectParenAfterFunctionName,
+        Message::TooManyParameters,
+        Message::ExpectParameterName,
+        Message::ExpectParenAfterParameters,
+        Message::ExpectBraceBeforeFunctionBody,
+        Message::TooManyArguments,
+        Message::ExpectParenAfterArguments,
+        Message::ExpectVariableName,
+        Message::ExpectSemicolonAfterVariableDeclaration,
+        Message::ReturnFromTopLevel,
+        Message::ExpectSemicolonAfterReturnValue,
+        Message::ExpectParenAfterIf,
+        Message::ExpectParenAfterCondition,
+        Message::ExpectParenAfterWhile,
+        Message::ExpectParenAfterFor,
+        Message::ExpectSemicolonAfterLoopCondition,
+        Message::ExpectParenAfterForClauses,
+        Message::VariableAlreadyDeclared,
+        Message::TooManyLocals,
+        Message::ExpectSemicolonAfterValue,
+        Message::ExpectSemicolonAfterExpression,
+        Message::ExpectBraceAfterBlock,
+        Message::ReadLocalInOwnInitializer,
+        Message::ExpectParenAfterExpression,
+        Message::InvalidAssignmentTarget,
+        Message::JumpTooLarge,
+        Message::TooManyConstants,
+        Message::LoopTooLarge,
+        Message::SourceTooLong,
+        Message::StringTooLong,
+        Message::ProgramTooManyConstants,
+        Message::ProgramTooManyFunctions,
+        Message::ProgramTooLarge,
+        Message::ExpectClassName,
+        Message::ExpectBraceBeforeClassBody,
+        Message::ExpectBraceAfterClassBody,
+        Message::ExpectPropertyName,
+        Message::ExpectMethodName,
+        Message::ThisOutsideClass,
+        Message::ReturnFromInitializer,
+        Message::ExpectSuperclassName,
+        Message::InheritFromSelf,
+        Message::SuperOutsideClass,
+        Message::SuperWithoutSuperclass,
+        Message::ExpectDotAfterSuper,
+        Message::ExpectSuperclassMethodName,
+        Message::BreakOutsideLoop,
+        Message::ContinueOutsideLoop,
+        Message::ExpectSemicolonAfterBreak,
+        Message::ExpectSemicolonAfterContinue,
+        Message::ExpectBracketAfterElements,
+        Message::TooManyElements,
+        Message::ExpectBracketAfterIndex,
+        Message::ExpectInAfterLoopVariable,
+        Message::AssignToConstantLocal,
+        Message::ExpectEqualAfterConstantName,
+        Message::ExpectModuleName,
+        Message::ExpectSemicolonAfterImport,
+        Message::ModuleNotFound,
+        Message::CircularImport,
+        Message::ExpectSemicolonAfterThrow,
+        Message::ExpectParenAfterAssert,
+        Message::ExpectParenAfterAssertion,
+        Message::ExpectSemicolonAfterAssertion,
+        Message::InvalidNumber,
+        Message::NestingTooDeep,
+        Message::ExpectDefaultParameter,
+        Message::ExpectParenAfterNames,
+        Message::ExpectEqualAfterPattern,
+        Message::ExpectElseInIfExpression,
+        Message::TooManyTupleElements,
+        Message::OperandsMustBeNumbers,
+        Message::OperandsMustBeStringsOrNumbers,
+        Message::OperandMustBeNumber,
+        Message::UndefinedVariable,
+        Message::ExpectedClosure,
+        Message::NotCallable,
+        Message::WrongArgumentCount,
+        Message::StackOverflow,
+        Message::OnlyInstancesHaveProperties,
+        Message::OnlyInstancesHaveFields,
+        Message::UndefinedProperty,
+        Message::SuperclassMustBeClass,
+        Message::NotIndexable,
+        Message::IndexMustBeInteger,
+        Message::IndexOutOfRange,
+        Message::OnlyListsSupportIndexAssignment,
+        Message::NotIterable,
+        Message::InstructionBudgetExceeded,
+        Message::AssignToConstantGlobal,
+        Message::StackUnderflow,
+        Message::MalformedBytecode,
+        Message::UncaughtException,
+        Message::AssertionFailed,
+        Message::NativeFailed,
+        Message::ConcatenateNonString,
+        Message::IntegerDivisionByZero,
+        Message::DivisionByZero,
+        Message::TypeCheckNeedsType,
+        Message::NativeWrongArgumentCount,
+        Message::NotDestructurable,
+        Message::DestructureCountMismatch,
+        Message::InvalidRepeatCount,
+        Message::RepeatTooLong,
+        Message::UnusedLocal,
+        Message::UnreachableCode,
+        Message::ConstantCondition,
+    ];
+
+    pub fn code(self) -> &'static str {
+        self.entry().0
+    }
+
+    pub fn text(self) -> &'static str {
+        self.entry().1
+    }
+
+    fn entry(self) -> (&'static str, &'static str) {
+        use Message::*;
+        match self {
+            UnexpectedCharacter => ("L001", "Unexpected character"),
+            UnterminatedString => ("L002", "Unterminated string"),
+            UnterminatedBlockComment => ("L003", "Unterminated block comment"),
+
+            ExpectExpression => ("C001", "Expect expression"),
+            ExpectFunctionName => ("C002", "Expect function name"),
+            ExpectParenAfterFunctionName => ("C003", "Expect '(' after function name"),
+            TooManyParameters => ("C004", "Can't have more than 255 parameters"),
+            ExpectParameterName => ("C005", "Expect parameter name"),
+            ExpectParenAfterParameters => ("C006", "Expect ')' after parameters"),
+            ExpectBraceBeforeFunctionBody => ("C007", "Expect '{' before function body"),
+            TooManyArguments => ("C008", "Can't have more than 255 arguments"),
+            ExpectParenAfterArguments => ("C009", "Expect ')' after arguments"),
+            ExpectVariableName => ("C010", "Expect variable name"),
+            ExpectSemicolonAfterVariableDeclaration => {
+                ("C011", "Expect ';' after variable declaration")
+            }
+            ReturnFromTopLevel => ("C012", "Can't return from top-level code"),
+            ExpectSemicolonAfterReturnValue => ("C013", "Expect ';' after return value"),
+            ExpectParenAfterIf => ("C014", "Expect '(' after 'if'"),
+            ExpectParenAfterCondition => ("C015", "Expect ')' after condition"),
+            ExpectParenAfterWhile => ("C016", "Expect '(' after 'while'"),
+            ExpectParenAfterFor => ("C017", "Expect '(' after 'for'"),
+            ExpectSemicolonAfterLoopCondition => ("C018", "Expect ';' after loop condition"),
+            ExpectParenAfterForClauses => ("C019", "Expect ')' after for clauses"),
+            VariableAlreadyDeclared => (
+                "C020",
+                "A variable with this name already exists in this scope",
+            ),
+            TooManyLocals => ("C021", "Too many local variables in function"),
+            ExpectSemicolonAfterValue => ("C022", "Expect ';' after value"),
+            ExpectSemicolonAfterExpression => ("C023", "Expect ';' after expression"),
+            ExpectBraceAfterBlock => ("C024", "Expect '}' after block"),
+            ReadLocalInOwnInitializer => {
+                ("C025", "Can't read local variable in its own initializer")
+            }
+            ExpectParenAfterExpression => ("C026", "Expect ')' after expression"),
+            InvalidAssignmentTarget => ("C027", "Invalid assignment target"),
+            JumpTooLarge => ("C028", "Too much code to jump over"),
+            TooManyConstants => ("C029", "Too many constants in one chunk"),
+            LoopTooLarge => ("C030", "Loop body too large"),
+            SourceTooLong => ("C031", "Source is longer than {limit} bytes"),
+            StringTooLong => ("C032", "String literal is longer than {limit} bytes"),
+            ProgramTooManyConstants => ("C033", "Program has more than {limit} constants"),
+            ProgramTooManyFunctions => ("C034", "Program has more than {limit} functions"),
+            ProgramTooLarge => ("C035", "Program bytecode is larger than {limit} bytes"),
+            ExpectClassName => ("C036", "Expect class name"),
+            ExpectBraceBeforeClassBody => ("C037", "Expect '{' before class body"),
+            ExpectBraceAfterClassBody => ("C038", "Expect '}' after class body"),
+            ExpectPropertyName => ("C039", "Expect property name after '.'"),
+            ExpectMethodName => ("C040", "Expect method name"),
+            ThisOutsideClass => ("C041", "Can't use 'this' outside of a class"),
+            ReturnFromInitializer => ("C042", "Can't return a value from an initializer"),
+            ExpectSuperclassName => ("C043", "Expect superclass name"),
+            InheritFromSelf => ("C044", "A class can't inherit from itself"),
+            SuperOutsideClass => ("C045", "Can't use 'super' outside of a class"),
+            SuperWithoutSuperclass => ("C046", "Can't use 'super' in a class with no superclass"),
+            ExpectDotAfterSuper => ("C047", "Expect '.' after 'super'"),
+            ExpectSuperclassMethodName => ("C048", "Expect superclass method name"),
+            BreakOutsideLoop => ("C049", "Can't use 'break' outside of a loop"),
+            ContinueOutsideLoop => ("C050", "Can't use 'continue' outside of a loop"),
+            ExpectSemicolonAfterBreak => ("C051", "Expect ';' after 'break'"),
+            ExpectSemicolonAfterContinue => ("C052", "Expect ';' after 'continue'"),
+            ExpectBracketAfterElements => ("C053", "Expect ']' after list elements"),
+            TooManyElements => (
+                "C054",
+                "Can't have more than 255 elements in a list literal",
+            ),
+            ExpectBracketAfterIndex => ("C055", "Expect ']' after index"),
+            ExpectInAfterLoopVariable => ("C056", "Expect 'in' after loop variable"),
+            AssignToConstantLocal => ("C057", "Can't assign to constant '{name}'"),
+            ExpectEqualAfterConstantName => ("C058", "Expect '=' after constant name"),
+            ExpectModuleName => ("C059", "Expect module name"),
+            ExpectSemicolonAfterImport => ("C060", "Expect ';' after import"),
+            ModuleNotFound => ("C061", "Could not find module '{name}'"),
+            CircularImport => ("C062", "Module '{name}' imports itself"),
+            ExpectSemicolonAfterThrow => ("C063", "Expect ';' after thrown value"),
+            ExpectParenAfterAssert => ("C064", "Expect '(' after 'assert'"),
+            ExpectParenAfterAssertion => ("C065", "Expect ')' after assertion"),
+            ExpectSemicolonAfterAssertion => ("C066", "Expect ';' after assertion"),
+            InvalidNumber => ("C067", "Invalid number literal"),
+            NestingTooDeep => ("C068", "Code is nested more than {limit} levels deep"),
+            ExpectDefaultParameter => (
+                "C069",
+                "Parameters after a default value must also have defaults",
+            ),
+            ExpectParenAfterNames => ("C070", "Expect ')' after variable names"),
+            ExpectEqualAfterPattern => ("C071", "Expect '=' after destructuring pattern"),
+            ExpectElseInIfExpression => ("C072", "Expect 'else' in if expression"),
+            TooManyTupleElements => ("C073", "Can't have more than 255 elements in a tuple"),
+
+            OperandsMustBeNumbers => ("R001", "Operands must be numbers"),
+            OperandsMustBeStringsOrNumbers => ("R002", "Operands must be strings or numbers"),
+            OperandMustBeNumber => ("R003", "Operand must be a number"),
+            UndefinedVariable => ("R004", "Undefined variable '{name}'"),
+            ExpectedClosure => ("R005", "Expected closure"),
+            NotCallable => ("R006", "Can only call functions and classes"),
+            WrongArgumentCount => ("R007", "Expected {expected} arguments but got {got}"),
+            StackOverflow => ("R008", "Stack overflow"),
+            OnlyInstancesHaveProperties => ("R009", "Only instances have properties"),
+            OnlyInstancesHaveFields => ("R010", "Only instances have fields"),
+            UndefinedProperty => ("R011", "Undefined property '{name}'"),
+            SuperclassMustBeClass => ("R012", "Superclass must be a class"),
+            NotIndexable => ("R013", "Can only index lists and strings"),
+            IndexMustBeInteger => ("R014", "Index must be an integer"),
+            IndexOutOfRange => ("R015", "Index {index} out of range for length {len}"),
+            OnlyListsSupportIndexAssignment => ("R016", "Only list elements can be assigned"),
+            NotIterable => ("R017", "Can only iterate over lists and strings"),
+            InstructionBudgetExceeded => ("R018", "Exceeded the budget of {max} instructions"),
+            AssignToConstantGlobal => ("R019", "Can't assign to constant '{name}'"),
+            StackUnderflow => ("R020", "Stack underflow"),
+            MalformedBytecode => ("R021", "Malformed bytecode"),
+            UncaughtException => ("R022", "Uncaught exception: {value}"),
+            AssertionFailed => ("R023", "Assertion failed at line {line}: {message}"),
+            NativeFailed => ("R024", "Error in {name}: {message}"),
+            ConcatenateNonString => (
+                "R025",
+                "Can only concatenate strings; convert other operands with str()",
+            ),
+            IntegerDivisionByZero => ("R026", "Integer division by zero"),
+            DivisionByZero => ("R027", "Division by zero"),
+            TypeCheckNeedsType => ("R028", "Right operand of 'is' must be a type or class"),
+            NativeWrongArgumentCount => (
+                "R029",
+                "Expected {expected} arguments but got {got} in native '{name}'",
+            ),
+            NotDestructurable => ("R030", "Can only destructure tuples and lists"),
+            DestructureCountMismatch => (
+                "R031",
+                "Expected {expected} values to destructure but got {got}",
+            ),
+            InvalidRepeatCount => ("R032", "Repeat count must be a non-negative integer"),
+            RepeatTooLong => ("R033", "Repeated string is longer than {limit} bytes"),
+
+            UnusedLocal => ("W001", "Local variable '{name}' is never read"),
+            UnreachableCode => ("W002", "Unreachable code"),
+            ConstantCondition => ("W003", "Condition is always {value}"),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct CatalogError {
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for CatalogError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
+
+impl Error for CatalogError {}
+
+#[derive(Clone, Default, Debug)]
+pub struct Catalog {
+    translations: HashMap<&'static str, String>,
+}
+
+impl Catalog {
+    pub fn new() -> Catalog {
+        Catalog::default()
+    }
+
+    pub fn parse(source: &str) -> Result<Catalog, CatalogError> {
+        let mut catalog = Catalog::new();
+        for (i, line) in source.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |message: String| CatalogError {
+                line: i + 1,
+                message,
+            };
+            let (code, text) = line
+                .split_once('=')
+                .ok_or_else(|| err("Expect 'CODE = text'".into()))?;
+            let code = code.trim();
+            let message = Message::ALL
+                .iter()
+                .find(|m| m.code() == code)
+                .ok_or_else(|| err(format!("Unknown message code '{code}'")))?;
+            catalog.translate(*message, text.trim());
+        }
+        Ok(catalog)
+    }
+
+    pub fn translate(&mut self, message: Message, text: &str) {
+        self.translations.insert(message.code(), text.into());
+    }
+
+    pub fn text(&self, message: Message) -> &str {
+        self.translations
+            .get(message.code())
+            .map_or(message.text(), String::as_str)
+    }
+
+    pub fn format(&self, message: Message, args: &[(&str, &str)]) -> String {
+        let mut text = self.text(message).to_owned();
+        for (name, value) in args {
+            text = text.replace(&format!("{{{name}}}"), value);
+        }
+        text
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn codes_are_unique() {
+        let codes: HashSet<_> = Message::ALL.iter().map(|m| m.code()).collect();
+        assert_eq!(codes.len(), Message::ALL.len());
+    }
+
+    #[test]
+    fn translations_replace_defaults() {
+        let catalog = Catalog::parse(
+            "# Español\nC001 = Se esperaba una expresión\nR004 = Variable '{name}' no definida\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            catalog.text(Message::ExpectExpression),
+            "Se esperaba una expresión"
+        );
+        assert_eq!(
+            catalog.format(Message::UndefinedVariable, &[("name", "x")]),
+            "Variable 'x' no definida"
+        );
+        assert_eq!(catalog.text(Message::StackOverflow), "Stack overflow");
+    }
+
+    #[test]
+    fn unknown_codes_are_rejected() {
+        let error = Catalog::parse("\nX999 = nope").unwrap_err();
+        assert_eq!(error.to_string(), "[line 2] Unknown message code 'X999'");
+    }
+
+    #[test]
+    fn config_translates_compile_and_runtime_errors() {
+        use crate::{config::Config, testing::run_script_with};
+
+        let mut messages = Catalog::new();
+        messages.translate(Message::ExpectExpression, "Ausdruck erwartet");
+        messages.translate(
+            Message::UndefinedVariable,
+            "Variable '{name}' ist nicht definiert",
+        );
+        let errors = |source| {
+            let config = Config {
+                messages: messages.clone(),
+                ..Default::default()
+            };
+            run_script_with(source, config, |_| {}).errors
+        };
+
+        let compile = errors("print ;");
+        assert_eq!(
+            compile.lines().next(),
+            Some("[line 1] Error at ';': Ausdruck erwartet")
+        );
+        let runtime = errors("print x;");
+        assert_eq!(
+            runtime.lines().next(),
+            Some("Variable 'x' ist nicht definiert")
+        );
+    }
+}
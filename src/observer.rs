@@ -0,0 +1,22 @@
+use crate::{
+    chunk::OpCode,
+    memory::{FunctionId, Memory},
+    vm::InstructionPointer,
+};
+
+pub trait Observer {
+    fn on_call(&mut self, _function: FunctionId, _memory: &Memory) {}
+
+    fn on_return(&mut self, _function: FunctionId, _memory: &Memory) {}
+
+    fn on_instruction(&mut self, _instruction: &Instruction) {}
+}
+
+pub struct Instruction {
+    pub function: FunctionId,
+    pub op_code: OpCode,
+    pub instruction_pointer: InstructionPointer,
+    pub line: usize,
+    pub stack_depth: usize,
+    pub frame_depth: usize,
+}
@@ -0,0 +1,211 @@
+use std::collections::HashSet;
+
+use crate::chunk::{Chunk, OpCode, Operand};
+
+struct Instruction {
+    offset: usize,
+    op_code: OpCode,
+    target: Option<usize>,
+}
+
+pub fn optimize(chunk: &mut Chunk) {
+    let instructions = decode(chunk);
+    let targets: HashSet<usize> = instructions.iter().filter_map(|i| i.target).collect();
+
+    let mut code = Vec::with_capacity(chunk.code.len());
+    let mut lines = Vec::with_capacity(chunk.lines.len());
+    let mut columns = Vec::with_capacity(chunk.columns.len());
+    let mut offsets = vec![0; chunk.code.len() + 1];
+    let mut jumps = Vec::new();
+
+    let mut i = 0;
+    while i < instructions.len() {
+        let window = &instructions[i..];
+        let old = &chunk.code;
+        let start = window[0].offset;
+        let fused = fuse(window, old).filter(|(len, _)| {
+            window[1..*len]
+                .iter()
+                .all(|instruction| !targets.contains(&instruction.offset))
+        });
+
+        let (len, bytes, origin) = match fused {
+            Some((len, bytes)) => (len, bytes, window[len - 1].offset),
+            None => {
+                let end = window.get(1).map(|next| next.offset).unwrap_or(old.len());
+                (1, old[start..end].to_vec(), start)
+            }
+        };
+        let line = chunk.lines[origin];
+        let column = chunk.columns.get(origin).copied().unwrap_or_default();
+
+        let end = window.get(len).map(|next| next.offset).unwrap_or(old.len());
+        offsets[start..end].fill(code.len());
+        if len == 1 {
+            if let Some(target) = window[0].target {
+                jumps.push((code.len(), window[0].op_code, target));
+            }
+        }
+        lines.extend(std::iter::repeat_n(line, bytes.len()));
+        columns.extend(std::iter::repeat_n(column, bytes.len()));
+        code.extend(bytes);
+        i += len;
+    }
+    offsets[chunk.code.len()] = code.len();
+
+    for (offset, op_code, target) in jumps {
+        let after = offset + 3;
+        let target = offsets[target];
+        let jump = if op_code.operand() == Operand::Loop {
+            after - target
+        } else {
+            target - after
+        };
+        code[offset + 1] = (jump >> 8) as u8;
+        code[offset + 2] = jump as u8;
+    }
+
+    chunk.code = code;
+    chunk.lines = lines;
+    chunk.columns = columns;
+    chunk.remap_offsets(|offset| offsets[offset]);
+}
+
+fn decode(chunk: &Chunk) -> Vec<Instruction> {
+    let code = &chunk.code;
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let Ok(op_code) = OpCode::try_from(code[offset]) else {
+            return Vec::new();
+        };
+        let operand = op_code.operand();
+        let next = offset + 1 + operand.size();
+        if next > code.len() {
+            return Vec::new();
+        }
+        let target = match operand {
+            Operand::Jump | Operand::Loop => {
+                let jump = u16::from_be_bytes([code[offset + 1], code[offset + 2]]) as usize;
+                if operand == Operand::Jump {
+                    Some(next + jump)
+                } else {
+                    next.checked_sub(jump)
+                }
+            }
+            _ => None,
+        };
+        instructions.push(Instruction {
+            offset,
+            op_code,
+            target,
+        });
+        offset = next;
+    }
+    instructions
+}
+
+fn fuse(window: &[Instruction], code: &[u8]) -> Option<(usize, Vec<u8>)> {
+    use OpCode::*;
+    let ops: Vec<_> = window.iter().take(4).map(|i| i.op_code).collect();
+    let operand = |i: usize| code[window[i].offset + 1];
+    match ops.as_slice() {
+        [GetLocal, Constant, Add, SetLocal] if operand(0) == operand(3) => {
+            Some((4, vec![IncrementLocal as u8, operand(0), operand(1)]))
+        }
+        [GetLocal, GetLocal, Add, ..] => Some((3, vec![AddLocals as u8, operand(0), operand(1)])),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use crate::{
+        chunk::OpCode,
+        compiler::compile,
+        config::{Config, PrintOutput},
+        debug::{disassemble_chunk, disassemble_to_ir},
+        memory::FunctionId,
+        vm::VM,
+    };
+
+    fn run(source: &str) -> (String, String) {
+        let output = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            print_output: PrintOutput::Str(output.clone()),
+            ..Default::default()
+        };
+        let program = compile(Rc::from(source), Config::default()).unwrap();
+
+        let mut disassembly = String::new();
+        let memory = &program.to_memory();
+        for id in 0..memory.functions().count() {
+            let chunk = &memory.function(FunctionId(id)).chunk;
+            disassemble_chunk(chunk, "", memory, &mut disassembly);
+        }
+
+        let mut vm = VM::load(&program, config);
+        vm.run().unwrap();
+        let output = output.borrow().clone();
+        (output, disassembly)
+    }
+
+    #[test]
+    fn fuses_local_arithmetic() {
+        let (output, disassembly) = run(r#"
+            fun sum(n) {
+                var total = 0;
+                for (var i = 0; i < n; i = i + 1) {
+                    total = total + i;
+                }
+                return total;
+            }
+            fun greet(s) {
+                s = s + "!";
+                return s;
+            }
+            fun add(a, b) { return a + b; }
+            print sum(5);
+            print greet("hi");
+            print add(2, 3);
+        "#);
+
+        assert!(disassembly.contains("IncrementLocal"), "{disassembly}");
+        assert!(disassembly.contains("AddLocals"), "{disassembly}");
+        assert_eq!(output, "10\nhi!\n5\n");
+    }
+
+    #[test]
+    fn keeps_jump_targets() {
+        let (output, disassembly) = run(r#"
+            fun f(a, b, c) { return (a or b) + c; }
+            print f(1, 2, 3);
+            print f(nil, 2, 3);
+        "#);
+
+        assert!(!disassembly.contains("AddLocals"), "{disassembly}");
+        assert_eq!(output, "4\n5\n");
+    }
+
+    #[test]
+    fn fused_increment_resolves_its_constant() {
+        let program = compile(
+            Rc::from("fun f(i) { i = i + 2; return i; }"),
+            Config::default(),
+        )
+        .unwrap();
+        let memory = program.to_memory();
+        let chunk = &memory.function(FunctionId(1)).chunk;
+        let opcodes: Vec<_> = disassemble_to_ir(chunk, &memory)
+            .into_iter()
+            .map(|line| (line.opcode, line.operands, line.resolved_constant))
+            .collect();
+
+        assert_eq!(
+            opcodes[0],
+            (OpCode::IncrementLocal, vec![1, 0], Some("2".to_string()))
+        );
+    }
+}
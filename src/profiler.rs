@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    memory::{FunctionId, Memory},
+    observer::{Instruction, Observer},
+};
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct FunctionProfile {
+    pub name: String,
+    pub calls: usize,
+    pub inclusive: Duration,
+    pub exclusive: Duration,
+    pub instructions: usize,
+}
+
+struct ActiveCall {
+    function: FunctionId,
+    started: Instant,
+    children: Duration,
+}
+
+#[derive(Default)]
+pub struct Profiler {
+    functions: HashMap<FunctionId, FunctionProfile>,
+    active: Vec<ActiveCall>,
+}
+
+impl Profiler {
+    pub fn new() -> Profiler {
+        Profiler::default()
+    }
+
+    pub fn report(&self) -> Vec<FunctionProfile> {
+        let mut functions = self.functions.clone();
+
+        let now = Instant::now();
+        let mut active_child = Duration::ZERO;
+        for (i, call) in self.active.iter().enumerate().rev() {
+            let elapsed = now - call.started;
+            let profile = functions.get_mut(&call.function).unwrap();
+            if !self.active[..i].iter().any(|c| c.function == call.function) {
+                profile.inclusive += elapsed;
+            }
+            profile.exclusive += elapsed.saturating_sub(call.children + active_child);
+            active_child = elapsed;
+        }
+
+        let mut report: Vec<_> = functions.into_values().collect();
+        report.sort_by(|a, b| b.inclusive.cmp(&a.inclusive).then(a.name.cmp(&b.name)));
+        report
+    }
+
+    pub fn write_report(&self, output: &mut impl Write) {
+        writeln!(
+            output,
+            "{:<24} {:>8} {:>14} {:>14} {:>12}",
+            "function", "calls", "inclusive ms", "exclusive ms", "instructions"
+        )
+        .unwrap();
+        for profile in self.report() {
+            writeln!(
+                output,
+                "{:<24} {:>8} {:>14.3} {:>14.3} {:>12}",
+                profile.name,
+                profile.calls,
+                profile.inclusive.as_secs_f64() * 1000.0,
+                profile.exclusive.as_secs_f64() * 1000.0,
+                profile.instructions
+            )
+            .unwrap();
+        }
+    }
+}
+
+impl Observer for Profiler {
+    fn on_call(&mut self, function: FunctionId, memory: &Memory) {
+        let profile = self.functions.entry(function).or_insert_with(|| {
+            let name = memory.get_string(memory.function(function).name);
+            FunctionProfile {
+                name: name.into(),
+                ..Default::default()
+            }
+        });
+        profile.calls += 1;
+
+        self.active.push(ActiveCall {
+            function,
+            started: Instant::now(),
+            children: Duration::ZERO,
+        });
+    }
+
+    fn on_return(&mut self, _function: FunctionId, _memory: &Memory) {
+        let Some(call) = self.active.pop() else {
+            return;
+        };
+
+        let elapsed = call.started.elapsed();
+        let recursive = self.active.iter().any(|c| c.function == call.function);
+        let profile = self.functions.get_mut(&call.function).unwrap();
+        if !recursive {
+            profile.inclusive += elapsed;
+        }
+        profile.exclusive += elapsed.saturating_sub(call.children);
+
+        if let Some(parent) = self.active.last_mut() {
+            parent.children += elapsed;
+        }
+    }
+
+    fn on_instruction(&mut self, instruction: &Instruction) {
+        if let Some(profile) = self.functions.get_mut(&instruction.function) {
+            profile.instructions += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{config::Config, vm::interpret};
+
+    #[test]
+    fn counts_calls_and_instructions() {
+        let profiler = Rc::new(RefCell::new(Profiler::new()));
+        let mut config = Config::default();
+        config.observe(profiler.clone());
+
+        interpret(
+            r#"
+            fun fib(n) {
+                if (n < 2) return n;
+                return fib(n - 2) + fib(n - 1);
+            }
+            fib(5);
+        "#,
+            config,
+        )
+        .unwrap();
+
+        let report = profiler.borrow().report();
+        let names: Vec<_> = report.iter().map(|p| p.name.as_str()).collect();
+        assert_eq!(names, vec!["<script>", "fib"]);
+
+        let fib = &report[1];
+        assert_eq!(fib.calls, 15);
+        assert!(fib.instructions > 0);
+        assert!(fib.exclusive <= fib.inclusive);
+        assert!(fib.inclusive <= report[0].inclusive);
+    }
+}
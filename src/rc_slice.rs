@@ -15,8 +15,12 @@ impl RcSlice {
         Self { string, range }
     }
 
+    pub fn range(&self) -> Range<usize> {
+        self.range.clone()
+    }
+
     pub fn as_str(&self) -> &str {
-        &*self
+        self
     }
 
     pub fn from_string(str: &str) -> RcSlice {
@@ -35,15 +39,15 @@ impl Deref for RcSlice {
     }
 }
 
-impl Into<String> for RcSlice {
-    fn into(self) -> String {
-        self.as_str().into()
+impl From<RcSlice> for String {
+    fn from(val: RcSlice) -> Self {
+        val.as_str().into()
     }
 }
 
-impl<'a> Into<String> for &'a RcSlice {
-    fn into(self) -> String {
-        self.as_str().into()
+impl From<&RcSlice> for String {
+    fn from(val: &RcSlice) -> Self {
+        val.as_str().into()
     }
 }
 
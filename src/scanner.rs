@@ -1,16 +1,20 @@
 use std::rc::Rc;
 
-use crate::rc_slice::RcSlice;
+use crate::{messages::Message, rc_slice::RcSlice};
 
 fn is_alpha(c: char) -> bool {
     c.is_ascii_alphabetic() || c == '_'
 }
 
+#[derive(Clone)]
 pub struct Scanner {
     pub source: Rc<str>,
     pub start: usize,
     pub current: usize,
     pub line: usize,
+    pub line_start: usize,
+    pub column: usize,
+    pub error: Option<Message>,
 }
 
 impl Scanner {
@@ -20,12 +24,19 @@ impl Scanner {
             start: 0,
             current: 0,
             line: 1,
+            line_start: 0,
+            column: 0,
+            error: None,
         }
     }
 
     pub fn token(&mut self) -> Token {
-        self.skip_whitespace();
+        let skipped = self.skip_whitespace();
         self.start = self.current;
+        self.column = self.start - self.line_start;
+        if let Err(error) = skipped {
+            return self.error_token(error);
+        }
 
         if self.is_at_end() {
             return self.make_token(TokenType::EOF);
@@ -38,19 +49,39 @@ impl Scanner {
             ')' => self.make_token(TokenType::RightParen),
             '{' => self.make_token(TokenType::LeftBrace),
             '}' => self.make_token(TokenType::RightBrace),
+            '[' => self.make_token(TokenType::LeftBracket),
+            ']' => self.make_token(TokenType::RightBracket),
             ';' => self.make_token(TokenType::SemiColon),
             ',' => self.make_token(TokenType::Comma),
+            '.' if self.peek() == '.' && self.peek_next() == '.' => {
+                self.advance();
+                self.advance();
+                self.make_token(TokenType::Ellipsis)
+            }
             '.' => self.make_token(TokenType::Dot),
             '-' => self.make_token(TokenType::Minus),
-            '+' => self.make_token(TokenType::Plus),
+            '+' => self.token_if_match('=', TokenType::PlusEqual, TokenType::Plus),
             '/' => self.make_token(TokenType::Slash),
             '*' => self.make_token(TokenType::Star),
+            '%' => self.make_token(TokenType::Percent),
+            '?' if self.peek() == '?' => {
+                self.advance();
+                self.make_token(TokenType::QuestionQuestion)
+            }
+            '?' if self.peek() == '.' => {
+                self.advance();
+                self.make_token(TokenType::QuestionDot)
+            }
+            '~' if self.peek() == '/' => {
+                self.advance();
+                self.make_token(TokenType::TildeSlash)
+            }
             '!' => self.token_if_match('=', TokenType::BangEqual, TokenType::Bang),
             '=' => self.token_if_match('=', TokenType::EqualEqual, TokenType::Equal),
             '<' => self.token_if_match('=', TokenType::LessEqual, TokenType::Less),
             '>' => self.token_if_match('=', TokenType::GreaterEqual, TokenType::Greater),
             '"' => self.string(),
-            _ => self.error_token("Unexpected character"),
+            _ => self.error_token(Message::UnexpectedCharacter),
         }
     }
 
@@ -64,8 +95,27 @@ impl Scanner {
 
     fn identifier_type(&self) -> TokenType {
         match self.get_char(self.start) {
-            'a' => self.check_keyword(1, "nd", TokenType::And),
-            'c' => self.check_keyword(1, "lass", TokenType::Class),
+            'a' => match self.check_keyword(1, "nd", TokenType::And) {
+                TokenType::Identifier => self.check_keyword(1, "ssert", TokenType::Assert),
+                typ => typ,
+            },
+            'b' => self.check_keyword(1, "reak", TokenType::Break),
+            'c' => {
+                if self.current - self.start > 1 {
+                    match self.get_char(self.start + 1) {
+                        'l' => self.check_keyword(2, "ass", TokenType::Class),
+                        'o' => match self.check_keyword(2, "nst", TokenType::Const) {
+                            TokenType::Identifier => {
+                                self.check_keyword(2, "ntinue", TokenType::Continue)
+                            }
+                            typ => typ,
+                        },
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
             'e' => self.check_keyword(1, "lse", TokenType::Else),
             'f' => {
                 if self.current - self.start > 1 {
@@ -79,7 +129,19 @@ impl Scanner {
                     TokenType::Identifier
                 }
             }
-            'i' => self.check_keyword(1, "f", TokenType::If),
+            'i' => {
+                if self.current - self.start > 1 {
+                    match self.get_char(self.start + 1) {
+                        'f' => self.check_keyword(2, "", TokenType::If),
+                        'm' => self.check_keyword(2, "port", TokenType::Import),
+                        'n' => self.check_keyword(2, "", TokenType::In),
+                        's' => self.check_keyword(2, "", TokenType::Is),
+                        _ => TokenType::Identifier,
+                    }
+                } else {
+                    TokenType::Identifier
+                }
+            }
             'n' => self.check_keyword(1, "il", TokenType::Nil),
             'o' => self.check_keyword(1, "r", TokenType::Or),
             'p' => self.check_keyword(1, "rint", TokenType::Print),
@@ -88,7 +150,10 @@ impl Scanner {
             't' => {
                 if self.current - self.start > 1 {
                     match self.get_char(self.start + 1) {
-                        'h' => self.check_keyword(2, "is", TokenType::This),
+                        'h' => match self.check_keyword(2, "is", TokenType::This) {
+                            TokenType::Identifier => self.check_keyword(2, "row", TokenType::Throw),
+                            typ => typ,
+                        },
                         'r' => self.check_keyword(2, "ue", TokenType::True),
                         _ => TokenType::Identifier,
                     }
@@ -103,10 +168,8 @@ impl Scanner {
     }
 
     fn check_keyword(&self, start: usize, rest: &str, typ: TokenType) -> TokenType {
-        let s = self.start + start;
-        let end = self.source.len().min(s + rest.len());
-        let slice = &self.source[s..end];
-        if slice == rest {
+        let slice = &self.source.as_bytes()[self.start + start..self.current];
+        if slice == rest.as_bytes() {
             typ
         } else {
             TokenType::Identifier
@@ -114,38 +177,74 @@ impl Scanner {
     }
 
     fn number(&mut self) -> Token {
-        while self.peek().is_ascii_digit() {
-            self.advance();
+        if self.get_char(self.start) == '0' {
+            let radix = match self.peek() {
+                'x' | 'X' => 16,
+                'b' | 'B' => 2,
+                _ => 10,
+            };
+            if radix != 10 && self.peek_next().is_digit(radix) {
+                self.advance();
+                while self.peek().is_digit(radix)
+                    || (self.peek() == '_' && self.peek_next().is_digit(radix))
+                {
+                    self.advance();
+                }
+                return self.make_token(TokenType::Number);
+            }
         }
 
+        self.digits();
+
         if self.peek() == '.' && self.peek_next().is_ascii_digit() {
             self.advance();
+            self.digits();
+        }
 
-            while self.peek().is_ascii_digit() {
+        if matches!(self.peek(), 'e' | 'E') {
+            let sign = matches!(self.peek_next(), '+' | '-');
+            let digit = if sign {
+                self.get_char(self.current + 2)
+            } else {
+                self.peek_next()
+            };
+            if digit.is_ascii_digit() {
                 self.advance();
+                if sign {
+                    self.advance();
+                }
+                self.digits();
             }
         }
 
         self.make_token(TokenType::Number)
     }
 
+    fn digits(&mut self) {
+        while self.peek().is_ascii_digit()
+            || (self.peek() == '_' && self.peek_next().is_ascii_digit())
+        {
+            self.advance();
+        }
+    }
+
     fn string(&mut self) -> Token {
         while self.peek() != '"' && !self.is_at_end() {
             if self.peek() == '\n' {
-                self.line += 1;
+                self.newline();
             }
             self.advance();
         }
 
         if self.is_at_end() {
-            return self.error_token("Unterminated string");
+            return self.error_token(Message::UnterminatedString);
         }
 
         self.advance();
         self.make_token(TokenType::String)
     }
 
-    fn skip_whitespace(&mut self) {
+    fn skip_whitespace(&mut self) -> Result<(), Message> {
         loop {
             let c = self.peek();
             match c {
@@ -153,27 +252,61 @@ impl Scanner {
                     self.advance();
                 }
                 '\n' => {
-                    self.line += 1;
+                    self.newline();
                     self.advance();
                 }
-                '/' => {
-                    if self.peek_next() != '/' {
-                        return;
+                '/' => match self.peek_next() {
+                    '/' => {
+                        while self.peek() != '\n' && !self.is_at_end() {
+                            self.advance();
+                        }
                     }
+                    '*' => self.block_comment()?,
+                    _ => return Ok(()),
+                },
+                _ => {
+                    return Ok(());
+                }
+            }
+        }
+    }
 
-                    while self.peek() != '\n' && !self.is_at_end() {
-                        self.advance();
+    fn block_comment(&mut self) -> Result<(), Message> {
+        let mut depth = 0;
+        loop {
+            if self.is_at_end() {
+                return Err(Message::UnterminatedBlockComment);
+            }
+            match (self.peek(), self.peek_next()) {
+                ('/', '*') => {
+                    depth += 1;
+                    self.current += 2;
+                }
+                ('*', '/') => {
+                    depth -= 1;
+                    self.current += 2;
+                    if depth == 0 {
+                        return Ok(());
                     }
                 }
+                ('\n', _) => {
+                    self.newline();
+                    self.advance();
+                }
                 _ => {
-                    return;
+                    self.advance();
                 }
             }
         }
     }
 
+    fn newline(&mut self) {
+        self.line += 1;
+        self.line_start = self.current + 1;
+    }
+
     fn peek_next(&self) -> char {
-        self.get_char(self.current + 1)
+        self.get_char(self.current + self.peek().len_utf8())
     }
 
     fn peek(&self) -> char {
@@ -197,22 +330,22 @@ impl Scanner {
         if self.is_at_end() || self.get_char(self.current) != expected {
             false
         } else {
-            self.current += 1;
+            self.current += expected.len_utf8();
             true
         }
     }
 
     fn get_char(&self, i: usize) -> char {
-        if i >= self.source.len() {
-            '\0'
-        } else {
-            self.source.as_bytes()[i] as char
-        }
+        self.source
+            .get(i..)
+            .and_then(|rest| rest.chars().next())
+            .unwrap_or('\0')
     }
 
     fn advance(&mut self) -> char {
-        self.current += 1;
-        self.get_char(self.current - 1)
+        let c = self.peek();
+        self.current += c.len_utf8();
+        c
     }
 
     fn is_at_end(&self) -> bool {
@@ -223,15 +356,18 @@ impl Scanner {
         Token {
             typ,
             line: self.line,
+            column: self.column,
             slice: RcSlice::new(self.source.clone(), self.start..self.current),
         }
     }
 
-    fn error_token(&self, error: &'static str) -> Token {
+    fn error_token(&mut self, error: Message) -> Token {
+        self.error = Some(error);
         Token {
             typ: TokenType::Error,
             line: self.line,
-            slice: RcSlice::from_string(error),
+            column: self.column,
+            slice: RcSlice::from_string(error.text()),
         }
     }
 }
@@ -240,6 +376,7 @@ impl Scanner {
 pub struct Token {
     pub typ: TokenType,
     pub line: usize,
+    pub column: usize,
     pub slice: RcSlice,
 }
 
@@ -251,21 +388,56 @@ impl Token {
     pub fn string_eq(&self, name: &Token) -> bool {
         self.slice.as_str() == name.slice.as_str()
     }
+
+    pub fn span(&self) -> Span {
+        let range = self.slice.range();
+        Span {
+            line: self.line,
+            start: range.start,
+            end: range.end,
+        }
+    }
 }
 
 #[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+pub struct Span {
+    pub line: usize,
+    pub start: usize,
+    pub end: usize,
+}
+
+impl Span {
+    pub fn to(&self, end: Span) -> Span {
+        Span {
+            line: self.line,
+            start: self.start,
+            end: end.end.max(self.end),
+        }
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Hash)]
+#[cfg_attr(feature = "arbitrary", derive(arbitrary::Arbitrary))]
 pub enum TokenType {
     LeftParen,
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
+    Ellipsis,
     Minus,
     Plus,
+    PlusEqual,
     SemiColon,
     Slash,
     Star,
+    Percent,
+    TildeSlash,
+    QuestionQuestion,
+    QuestionDot,
 
     Bang,
     BangEqual,
@@ -281,18 +453,26 @@ pub enum TokenType {
     Number,
 
     And,
+    Assert,
+    Break,
     Class,
+    Const,
+    Continue,
     Else,
     False,
     For,
     Fun,
     If,
+    Import,
+    In,
+    Is,
     Nil,
     Or,
     Print,
     Return,
     Super,
     This,
+    Throw,
     True,
     Var,
     While,
@@ -301,6 +481,48 @@ pub enum TokenType {
     EOF,
 }
 
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct SpannedToken {
+    pub typ: TokenType,
+    pub span: Span,
+    pub text: String,
+}
+
+pub fn tokenize(source: &str) -> Vec<SpannedToken> {
+    let mut scanner = Scanner::init(Rc::from(source));
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.token();
+        let span = match token.typ {
+            TokenType::EOF => return tokens,
+            TokenType::Error => Span {
+                line: token.line,
+                start: scanner.start,
+                end: scanner.current,
+            },
+            _ => token.span(),
+        };
+        tokens.push(SpannedToken {
+            typ: token.typ,
+            span,
+            text: source[span.start..span.end].into(),
+        });
+    }
+}
+
+pub fn scan_all(source: &str) -> Vec<Token> {
+    let mut scanner = Scanner::init(Rc::from(source));
+    let mut tokens = Vec::new();
+    loop {
+        let token = scanner.token();
+        let done = token.typ == TokenType::EOF;
+        tokens.push(token);
+        if done {
+            return tokens;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -310,21 +532,36 @@ mod tests {
         for (s, t) in [
             ("1", TokenType::Number),
             ("1.2", TokenType::Number),
+            ("1e9", TokenType::Number),
+            ("2.5e-3", TokenType::Number),
+            ("1_000_000", TokenType::Number),
+            ("0xFF", TokenType::Number),
+            ("0b1010", TokenType::Number),
             ("\"abc\"", TokenType::String),
             ("tru", TokenType::Identifier),
             ("tr", TokenType::Identifier),
             ("t", TokenType::Identifier),
+            ("andy", TokenType::Identifier),
+            ("nils", TokenType::Identifier),
             ("(", TokenType::LeftParen),
             (")", TokenType::RightParen),
             ("{", TokenType::LeftBrace),
             ("}", TokenType::RightBrace),
+            ("[", TokenType::LeftBracket),
+            ("]", TokenType::RightBracket),
             (",", TokenType::Comma),
             (".", TokenType::Dot),
+            ("...", TokenType::Ellipsis),
             ("-", TokenType::Minus),
             ("+", TokenType::Plus),
+            ("+=", TokenType::PlusEqual),
             (";", TokenType::SemiColon),
             ("/", TokenType::Slash),
             ("*", TokenType::Star),
+            ("%", TokenType::Percent),
+            ("~/", TokenType::TildeSlash),
+            ("??", TokenType::QuestionQuestion),
+            ("?.", TokenType::QuestionDot),
             ("!", TokenType::Bang),
             ("!=", TokenType::BangEqual),
             ("=", TokenType::Equal),
@@ -334,18 +571,29 @@ mod tests {
             ("<", TokenType::Less),
             ("<=", TokenType::LessEqual),
             ("and", TokenType::And),
+            ("assert", TokenType::Assert),
+            ("break", TokenType::Break),
             ("class", TokenType::Class),
+            ("const", TokenType::Const),
+            ("cons", TokenType::Identifier),
+            ("continue", TokenType::Continue),
+            ("co", TokenType::Identifier),
             ("else", TokenType::Else),
             ("false", TokenType::False),
             ("for", TokenType::For),
             ("fun", TokenType::Fun),
             ("if", TokenType::If),
+            ("import", TokenType::Import),
+            ("in", TokenType::In),
+            ("is", TokenType::Is),
+            ("int", TokenType::Identifier),
             ("nil", TokenType::Nil),
             ("or", TokenType::Or),
             ("print", TokenType::Print),
             ("return", TokenType::Return),
             ("super", TokenType::Super),
             ("this", TokenType::This),
+            ("throw", TokenType::Throw),
             ("true", TokenType::True),
             ("var", TokenType::Var),
             ("while", TokenType::While),
@@ -357,4 +605,134 @@ mod tests {
             assert_eq!(token.typ, t)
         }
     }
+
+    #[test]
+    fn scan_all_ends_with_eof() {
+        let types: Vec<_> = scan_all("var x = 1 @").iter().map(|t| t.typ).collect();
+        assert_eq!(
+            types,
+            vec![
+                TokenType::Var,
+                TokenType::Identifier,
+                TokenType::Equal,
+                TokenType::Number,
+                TokenType::Error,
+                TokenType::EOF,
+            ]
+        );
+        assert_eq!(scan_all("").len(), 1);
+    }
+
+    #[test]
+    fn tokenize_reports_source_spans() {
+        let tokens: Vec<_> = tokenize("var s = \"a\nb\"; @")
+            .into_iter()
+            .map(|t| (t.typ, t.span.line, t.span.start, t.span.end, t.text))
+            .collect();
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::Var, 1, 0, 3, "var".into()),
+                (TokenType::Identifier, 1, 4, 5, "s".into()),
+                (TokenType::Equal, 1, 6, 7, "=".into()),
+                (TokenType::String, 2, 8, 13, "\"a\nb\"".into()),
+                (TokenType::SemiColon, 2, 13, 14, ";".into()),
+                (TokenType::Error, 2, 15, 16, "@".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn scan_non_ascii_after_identifier() {
+        let mut scanner = Scanner::init("a\u{e9}".into());
+        assert_eq!(scanner.token().typ, TokenType::Identifier);
+        assert_eq!(scanner.token().typ, TokenType::Error);
+    }
+
+    #[test]
+    fn block_comments() {
+        let mut scanner = Scanner::init("/* a /* nested\n */ b */ x /* \n".into());
+        let token = scanner.token();
+        assert_eq!(token.typ, TokenType::Identifier);
+        assert_eq!(token.slice.as_str(), "x");
+        assert_eq!(token.line, 2);
+
+        let token = scanner.token();
+        assert_eq!(token.typ, TokenType::Error);
+        assert_eq!(token.slice.as_str(), "Unterminated block comment");
+        assert_eq!(token.line, 3);
+    }
+
+    #[test]
+    fn utf8_source() {
+        let mut scanner = Scanner::init("/* ünï */ \"héllo ✓\" €x".into());
+        let tokens: Vec<_> = std::iter::from_fn(|| Some(scanner.token()))
+            .take(4)
+            .map(|t| (t.typ, t.slice.as_str().to_owned()))
+            .collect();
+
+        assert_eq!(
+            tokens,
+            vec![
+                (TokenType::String, "\"héllo ✓\"".into()),
+                (TokenType::Error, "Unexpected character".into()),
+                (TokenType::Identifier, "x".into()),
+                (TokenType::EOF, "".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn token_columns() {
+        let mut scanner = Scanner::init("var x\n  = \"a\nb\" y;".into());
+        let columns: Vec<_> = std::iter::from_fn(|| Some(scanner.token()))
+            .take(6)
+            .map(|t| (t.line, t.column))
+            .collect();
+
+        assert_eq!(
+            columns,
+            vec![(1, 0), (1, 4), (2, 2), (3, 4), (3, 3), (3, 4)]
+        );
+    }
+
+    #[test]
+    fn token_spans() {
+        let mut scanner = Scanner::init("var x\n  = 1;".into());
+        let spans: Vec<_> = std::iter::from_fn(|| Some(scanner.token()))
+            .take(5)
+            .map(|t| t.span())
+            .collect();
+
+        assert_eq!(
+            spans,
+            vec![
+                Span {
+                    line: 1,
+                    start: 0,
+                    end: 3
+                },
+                Span {
+                    line: 1,
+                    start: 4,
+                    end: 5
+                },
+                Span {
+                    line: 2,
+                    start: 8,
+                    end: 9
+                },
+                Span {
+                    line: 2,
+                    start: 10,
+                    end: 11
+                },
+                Span {
+                    line: 2,
+                    start: 11,
+                    end: 12
+                },
+            ]
+        );
+    }
 }
@@ -0,0 +1,381 @@
+use std::{error::Error, rc::Rc, sync::Arc};
+
+use crate::{
+    chunk,
+    memory::{FunctionId, Memory},
+    string_intern::StrId,
+    verify::verify,
+};
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+pub const VERSION: u8 = 15;
+
+const END_SECTION: u8 = 0;
+const SOURCE_SECTION: u8 = 2;
+
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn serialize(memory: &Memory, embed_source: bool) -> Result<Vec<u8>, Box<dyn Error>> {
+    let mut bytes = MAGIC.to_vec();
+    bytes.push(VERSION);
+
+    let strings: Vec<_> = memory.strings().collect();
+    write_u32(&mut bytes, strings.len());
+    for (_, string) in strings.iter() {
+        write_string(&mut bytes, string);
+    }
+
+    let functions: Vec<_> = memory.functions().collect();
+    write_u32(&mut bytes, functions.len());
+    for function in functions {
+        write_u32(&mut bytes, function.name.0);
+        write_u32(&mut bytes, function.arity);
+        write_u32(&mut bytes, function.min_arity);
+        bytes.push(function.variadic as u8);
+        write_u32(&mut bytes, function.module.map_or(0, |module| module.0 + 1));
+        let chunk = chunk::serialize(&function.chunk)?;
+        write_u32(&mut bytes, chunk.len());
+        bytes.extend_from_slice(&chunk);
+    }
+
+    if embed_source {
+        let sources: Vec<_> = memory.sources().collect();
+        let mut section = Vec::new();
+        write_u32(&mut section, sources.len());
+        for (file, source) in sources {
+            match file {
+                Some(file) => {
+                    section.push(1);
+                    write_string(&mut section, file);
+                }
+                None => section.push(0),
+            }
+            write_string(&mut section, source);
+        }
+        write_section(&mut bytes, SOURCE_SECTION, &section);
+    }
+    bytes.push(END_SECTION);
+
+    Ok(bytes)
+}
+
+pub fn deserialize(bytes: &[u8]) -> Result<Memory, Box<dyn Error>> {
+    let mut reader = Reader::new(bytes);
+    if reader.take(MAGIC.len())? != MAGIC {
+        return Err("Not an rlox bytecode file".into());
+    }
+    let version = reader.u8()?;
+    if version != VERSION {
+        return Err(format!("Unsupported bytecode version {version}").into());
+    }
+
+    let mut memory = Memory::new();
+
+    let string_count = reader.u32()?;
+    for i in 0..string_count {
+        let string = reader.string()?;
+        if memory.string_id(string) != StrId(i) {
+            return Err(format!("Duplicate string {string:?}").into());
+        }
+    }
+
+    let function_count = reader.u32()?;
+    for i in 0..function_count {
+        let name = reader.u32()?;
+        if name >= string_count {
+            return Err(format!("Function {i} has an invalid name").into());
+        }
+        let name = memory.get_string(StrId(name)).to_owned();
+        let id = memory.new_function(&name);
+        let arity = reader.u32()?;
+        let min_arity = reader.u32()?;
+        let variadic = reader.u8()? != 0;
+        let module = match reader.u32()? {
+            0 => None,
+            module if module <= function_count => Some(FunctionId(module - 1)),
+            _ => return Err(format!("Function {i} has an invalid module").into()),
+        };
+        let len = reader.u32_len()?;
+        let chunk = chunk::deserialize(reader.take(len)?, &mut memory)?;
+
+        let function = memory.function_mut(id);
+        function.arity = arity;
+        function.min_arity = min_arity;
+        function.variadic = variadic;
+        function.module = module;
+        function.chunk = chunk;
+    }
+
+    loop {
+        let tag = reader.u8()?;
+        if tag == END_SECTION {
+            break;
+        }
+        let len = reader.u32_len()?;
+        let mut section = Reader::new(reader.take(len)?);
+        match tag {
+            SOURCE_SECTION => {
+                for _ in 0..section.u32()? {
+                    let file = match section.u8()? {
+                        0 => None,
+                        _ => Some(Arc::from(section.string()?)),
+                    };
+                    memory.add_source(file, Rc::from(section.string()?));
+                }
+            }
+            _ => continue,
+        }
+        if section.offset != len {
+            return Err(format!("Malformed section {tag}").into());
+        }
+    }
+
+    if reader.offset != bytes.len() {
+        return Err("Unexpected data after the last section".into());
+    }
+
+    verify(&memory)?;
+    Ok(memory)
+}
+
+pub(crate) fn write_u32(bytes: &mut Vec<u8>, n: usize) {
+    bytes.extend_from_slice(&(n as u32).to_le_bytes());
+}
+
+pub(crate) fn write_string(bytes: &mut Vec<u8>, s: &str) {
+    write_u32(bytes, s.len());
+    bytes.extend_from_slice(s.as_bytes());
+}
+
+fn write_section(bytes: &mut Vec<u8>, tag: u8, section: &[u8]) {
+    bytes.push(tag);
+    write_u32(bytes, section.len());
+    bytes.extend_from_slice(section);
+}
+
+pub(crate) struct Reader<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub(crate) fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, offset: 0 }
+    }
+
+    pub(crate) fn is_at_end(&self) -> bool {
+        self.offset == self.bytes.len()
+    }
+
+    pub(crate) fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn Error>> {
+        let end = self
+            .offset
+            .checked_add(len)
+            .filter(|end| *end <= self.bytes.len())
+            .ok_or("Unexpected end of bytecode")?;
+        let slice = &self.bytes[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    pub(crate) fn u8(&mut self) -> Result<u8, Box<dyn Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub(crate) fn u32(&mut self) -> Result<usize, Box<dyn Error>> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into()?) as usize)
+    }
+
+    pub(crate) fn u32_len(&mut self) -> Result<usize, Box<dyn Error>> {
+        let len = self.u32()?;
+        if len > self.bytes.len() - self.offset {
+            return Err("Unexpected end of bytecode".into());
+        }
+        Ok(len)
+    }
+
+    pub(crate) fn string(&mut self) -> Result<&'a str, Box<dyn Error>> {
+        let len = self.u32_len()?;
+        Ok(std::str::from_utf8(self.take(len)?)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{
+        compiler::{compile, compile_files},
+        config::{Config, PrintOutput},
+        memory::FunctionId,
+        vm::{run_precompiled, ErrorKind, VM},
+    };
+
+    const PROGRAM: &str = r#"
+        fun greet(name) {
+            return "hello " + name;
+        }
+        var i = 0;
+        while (i < 2) {
+            print greet("world");
+            i = i + 1;
+        }
+    "#;
+
+    fn compiled() -> Vec<u8> {
+        let program = compile(Rc::from(PROGRAM), Config::default()).unwrap();
+        serialize(&program.to_memory(), true).unwrap()
+    }
+
+    #[test]
+    fn round_trip_runs() {
+        let bytes = compiled();
+        assert!(is_bytecode(&bytes));
+
+        let memory = deserialize(&bytes).unwrap();
+        let output = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            print_output: PrintOutput::Str(output.clone()),
+            ..Default::default()
+        };
+
+        let result = VM::with_script(memory, config).run();
+        assert_eq!(result, Ok(()));
+        assert_eq!(*output.borrow(), "hello world\nhello world\n");
+    }
+
+    #[test]
+    fn round_trip_is_stable() {
+        let bytes = compiled();
+        let memory = deserialize(&bytes).unwrap();
+        assert_eq!(serialize(&memory, true).unwrap(), bytes);
+    }
+
+    #[test]
+    fn rejects_truncated_and_corrupt_files() {
+        let bytes = compiled();
+        for len in 0..bytes.len() {
+            assert!(deserialize(&bytes[..len]).is_err());
+        }
+
+        let mut bad_version = bytes.clone();
+        bad_version[4] = 99;
+        let error = deserialize(&bad_version).err().unwrap();
+        assert_eq!(error.to_string(), "Unsupported bytecode version 99");
+    }
+
+    #[test]
+    fn rejects_corrupt_function_headers() {
+        let corrupt = |edit: fn(&mut Memory)| {
+            let mut memory = deserialize(&compiled()).unwrap();
+            edit(&mut memory);
+            let bytes = serialize(&memory, false).unwrap();
+            deserialize(&bytes).err().unwrap().to_string()
+        };
+
+        assert_eq!(
+            corrupt(|memory| memory.function_mut(FunctionId(0)).variadic = true),
+            "[<script> at 0000] Expect a script function with no parameters"
+        );
+        assert_eq!(
+            corrupt(|memory| memory.function_mut(FunctionId(1)).min_arity = 2),
+            "[greet at 0000] Minimum arity exceeds arity"
+        );
+        assert_eq!(
+            corrupt(|memory| {
+                let greet = memory.function_mut(FunctionId(1));
+                (greet.arity, greet.min_arity, greet.variadic) = (0, 0, true);
+            }),
+            "[greet at 0000] Variadic function has no rest parameter"
+        );
+    }
+
+    fn runtime_error(embed_source: bool) -> String {
+        let files = [
+            ("lib.lox", "fun f() {\n  return -\"x\";\n}"),
+            ("main.lox", "f();"),
+        ];
+        let vm = compile_files(&files, Config::default()).unwrap();
+        let memory = deserialize(&serialize(&vm.memory, embed_source).unwrap()).unwrap();
+
+        let errors = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            vm_error: PrintOutput::Str(errors.clone()),
+            ..Default::default()
+        };
+        assert!(VM::with_script(memory, config).run().is_err());
+        let errors = errors.borrow().clone();
+        errors
+    }
+
+    #[test]
+    fn embedded_source_shows_excerpts() {
+        let errors = runtime_error(true);
+        assert!(errors.contains("    2 |   return -\"x\";"), "{errors}");
+        assert!(errors.contains("[lib.lox line 2 in f]"), "{errors}");
+        assert!(errors.contains("[main.lox line 1 in <script>]"), "{errors}");
+    }
+
+    #[test]
+    fn stripped_source_keeps_locations() {
+        let errors = runtime_error(false);
+        assert!(!errors.contains(" | "), "{errors}");
+        assert!(errors.contains("[lib.lox line 2 in f]"), "{errors}");
+    }
+
+    #[test]
+    fn chunk_round_trips() {
+        let program = compile(Rc::from(PROGRAM), Config::default()).unwrap();
+        let memory = program.to_memory();
+        let chunk = &memory.function(FunctionId(0)).chunk;
+        let bytes = chunk::serialize(chunk).unwrap();
+
+        let mut memory = Memory::new();
+        let copy = chunk::deserialize(&bytes, &mut memory).unwrap();
+        assert_eq!(copy.code, chunk.code);
+        assert_eq!(copy.lines, chunk.lines);
+        assert_eq!(copy.columns, chunk.columns);
+        assert!(copy.constants() == chunk.constants());
+        assert_eq!(chunk::serialize(&copy).unwrap(), bytes);
+        assert!(chunk::deserialize(&bytes[..bytes.len() - 1], &mut memory).is_err());
+    }
+
+    #[test]
+    fn integer_constants_round_trip() {
+        let config = Config {
+            integers: true,
+            ..Default::default()
+        };
+        let program = compile(Rc::from("print 9007199254740993; print 3 * 0.5;"), config).unwrap();
+        let bytes = serialize(&program.to_memory(), false).unwrap();
+
+        let output = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            print_output: PrintOutput::Str(output.clone()),
+            ..Default::default()
+        };
+        assert_eq!(run_precompiled(&bytes, config), Ok(()));
+        assert_eq!(*output.borrow(), "9007199254740993\n1.5\n");
+    }
+
+    #[test]
+    fn runs_precompiled_bytes() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let config = Config {
+            print_output: PrintOutput::Str(output.clone()),
+            ..Default::default()
+        };
+        assert_eq!(run_precompiled(&compiled(), config), Ok(()));
+        assert_eq!(*output.borrow(), "hello world\nhello world\n");
+
+        let error = run_precompiled(b"LOXC", Config::default()).unwrap_err();
+        assert_eq!(error.kind, ErrorKind::Compile);
+        assert_eq!(
+            error.message,
+            "Could not load bytecode: Unexpected end of bytecode"
+        );
+    }
+}
@@ -1,7 +1,7 @@
 use std::{collections::HashMap, mem};
 
 #[derive(Copy, Clone, PartialEq, Eq, Hash, Debug)]
-pub struct StrId(usize);
+pub struct StrId(pub usize);
 
 pub struct StringInterner {
     map: HashMap<&'static str, StrId>,
@@ -34,10 +34,39 @@ impl StringInterner {
         (id, name)
     }
 
+    pub fn concat(&mut self, a: &'static str, b: &str) -> (StrId, &'static str) {
+        let start = self.buf.len().wrapping_sub(a.len());
+        let at_end =
+            a.len() <= self.buf.len() && self.buf.as_ptr().wrapping_add(start) == a.as_ptr();
+        if !at_end || self.buf.capacity() - self.buf.len() < b.len() {
+            let mut concat = String::with_capacity(a.len() + b.len());
+            concat.push_str(a);
+            concat.push_str(b);
+            return self.intern(&concat);
+        }
+
+        // `a` is the newest string in the buffer, so appending `b` after it
+        // yields `a + b` without copying `a`
+        self.buf.push_str(b);
+        let joined = unsafe { &*(&self.buf[start..] as *const str) };
+        if let Some(&id) = self.map.get(joined) {
+            self.buf.truncate(self.buf.len() - b.len());
+            return (id, self.vec[id.0]);
+        }
+        let id = StrId(self.map.len());
+        self.map.insert(joined, id);
+        self.vec.push(joined);
+        (id, joined)
+    }
+
     pub fn lookup(&self, id: StrId) -> &str {
         self.vec[id.0]
     }
 
+    pub fn iter(&self) -> impl Iterator<Item = (StrId, &str)> {
+        self.vec.iter().enumerate().map(|(i, s)| (StrId(i), *s))
+    }
+
     unsafe fn alloc(&mut self, name: &str) -> &'static str {
         let cap = self.buf.capacity();
         if cap < self.buf.len() + name.len() {
@@ -0,0 +1,273 @@
+use std::{
+    cell::RefCell,
+    fs, io,
+    panic::{self, AssertUnwindSafe},
+    path::{Path, PathBuf},
+    rc::Rc,
+};
+
+use crate::{
+    config::Config,
+    vm::{interpret, ErrorKind},
+};
+
+const EXPECT: &str = "// expect: ";
+const EXPECT_RUNTIME_ERROR: &str = "// expect runtime error: ";
+const EXPECT_ERROR: &str = "// Error";
+const EXPECT_LINE_ERROR: &str = "// [line ";
+const EXPECT_C_LINE_ERROR: &str = "// [c line ";
+
+#[derive(Debug, Default, PartialEq)]
+pub struct Expectations {
+    pub output: Vec<(usize, String)>,
+    pub runtime_error: Option<(usize, String)>,
+    pub compile_errors: Vec<(usize, String)>,
+}
+
+impl Expectations {
+    pub fn parse(source: &str) -> Expectations {
+        let mut expectations = Expectations::default();
+
+        for (i, line) in source.lines().enumerate() {
+            let line_number = i + 1;
+            if let Some(index) = line.find(EXPECT) {
+                let expected = &line[index + EXPECT.len()..];
+                expectations.output.push((line_number, expected.into()));
+            } else if let Some(index) = line.find(EXPECT_RUNTIME_ERROR) {
+                let expected = &line[index + EXPECT_RUNTIME_ERROR.len()..];
+                expectations.runtime_error = Some((line_number, expected.into()));
+            } else if let Some(index) = line.find(EXPECT_ERROR) {
+                let expected = &line[index + 3..];
+                let expected = format!("[line {line_number}] {expected}");
+                expectations.compile_errors.push((line_number, expected));
+            } else if let Some(index) = line.find(EXPECT_LINE_ERROR) {
+                let expected = &line[index + 3..];
+                expectations
+                    .compile_errors
+                    .push((line_number, expected.into()));
+            } else if let Some(index) = line.find(EXPECT_C_LINE_ERROR) {
+                let expected = &line[index + EXPECT_C_LINE_ERROR.len()..];
+                let expected = format!("[line {expected}");
+                expectations.compile_errors.push((line_number, expected));
+            }
+        }
+
+        expectations
+    }
+}
+
+pub struct TestOutcome {
+    pub path: PathBuf,
+    pub failures: Vec<String>,
+}
+
+impl TestOutcome {
+    pub fn passed(&self) -> bool {
+        self.failures.is_empty()
+    }
+}
+
+pub fn run_source(source: &str) -> Vec<String> {
+    let expectations = Expectations::parse(source);
+
+    let output = Rc::new(RefCell::new(String::new()));
+    let errors = Rc::new(RefCell::new(String::new()));
+    let mut config = Config::default();
+    config.print_output.redirect(output.clone());
+    config.vm_error.redirect(errors.clone());
+    config.compiler_error.redirect(errors.clone());
+
+    let result = panic::catch_unwind(AssertUnwindSafe(|| interpret(source, config)));
+
+    let mut failures = Vec::new();
+    let result = match result {
+        Ok(result) => result,
+        Err(_) => {
+            failures.push("Interpreter panicked".into());
+            return failures;
+        }
+    };
+
+    let output = output.borrow();
+    let mut actual = output.lines();
+    for (line, expected) in expectations.output.iter() {
+        match actual.next() {
+            Some(got) if got == expected => (),
+            Some(got) => failures.push(format!(
+                "[line {line}] Expected output '{expected}' but got '{got}'"
+            )),
+            None => failures.push(format!(
+                "[line {line}] Missing expected output '{expected}'"
+            )),
+        }
+    }
+    for got in actual {
+        failures.push(format!("Unexpected output '{got}'"));
+    }
+
+    let errors = errors.borrow();
+    if !expectations.compile_errors.is_empty() {
+        if !matches!(&result, Err(e) if e.kind == ErrorKind::Compile) {
+            failures.push("Expected a compile error but the script compiled".into());
+            return failures;
+        }
+
+        let mut actual = errors.lines().filter(|line| line.starts_with('['));
+        for (line, expected) in expectations.compile_errors.iter() {
+            match actual.next() {
+                Some(got) if got == expected => (),
+                Some(got) => failures.push(format!(
+                    "[line {line}] Expected compile error '{expected}' but got '{got}'"
+                )),
+                None => failures.push(format!(
+                    "[line {line}] Missing expected compile error '{expected}'"
+                )),
+            }
+        }
+        for got in actual {
+            failures.push(format!("Unexpected compile error '{got}'"));
+        }
+        return failures;
+    }
+
+    match (result, &expectations.runtime_error) {
+        (Ok(()), None) => (),
+        (Ok(()), Some((line, expected))) => failures.push(format!(
+            "[line {line}] Expected runtime error '{expected}' but the script succeeded"
+        )),
+        (Err(error), _) if error.kind == ErrorKind::Compile => {
+            failures.push(format!("Unexpected compile error: {}", errors.trim_end()))
+        }
+        (Err(error), Some((line, expected))) => {
+            if error.message != *expected {
+                failures.push(format!(
+                    "[line {line}] Expected runtime error '{expected}' but got '{}'",
+                    error.message
+                ));
+            }
+        }
+        (Err(_), None) => failures.push(format!("Unexpected runtime error: {}", errors.trim_end())),
+    }
+
+    failures
+}
+
+pub fn run_file(path: &Path) -> io::Result<TestOutcome> {
+    let source = fs::read_to_string(path)?;
+    Ok(TestOutcome {
+        path: path.to_owned(),
+        failures: run_source(&source),
+    })
+}
+
+pub fn run_dir(dir: &Path) -> io::Result<Vec<TestOutcome>> {
+    let mut paths = Vec::new();
+    collect_lox_files(dir, &mut paths)?;
+    paths.sort();
+    paths.iter().map(|path| run_file(path)).collect()
+}
+
+fn collect_lox_files(dir: &Path, paths: &mut Vec<PathBuf>) -> io::Result<()> {
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        if path.is_dir() {
+            collect_lox_files(&path, paths)?;
+        } else if path.extension().is_some_and(|ext| ext == "lox") {
+            paths.push(path);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_expectations() {
+        let expectations = Expectations::parse(
+            r#"
+            print 1; // expect: 1
+            print "a"; // expect: a
+            -nil; // expect runtime error: Operand must be a number
+        "#,
+        );
+
+        assert_eq!(
+            expectations,
+            Expectations {
+                output: vec![(2, "1".into()), (3, "a".into())],
+                runtime_error: Some((4, "Operand must be a number".into())),
+                compile_errors: vec![],
+            }
+        );
+    }
+
+    #[test]
+    fn passing_script() {
+        let failures = run_source(
+            r#"
+            var a = "x";
+            print a + "y"; // expect: xy
+            print 1 + 2; // expect: 3
+        "#,
+        );
+
+        assert!(failures.is_empty(), "{failures:?}");
+    }
+
+    #[test]
+    fn mismatched_output() {
+        let failures = run_source(
+            r#"
+            print 1; // expect: 2
+        "#,
+        );
+
+        assert_eq!(failures, vec!["[line 2] Expected output '2' but got '1'"]);
+    }
+
+    #[test]
+    fn expected_runtime_error() {
+        let failures = run_source(
+            r#"
+            print "before"; // expect: before
+            print -"a"; // expect runtime error: Operand must be a number
+        "#,
+        );
+
+        assert!(failures.is_empty(), "{failures:?}");
+    }
+
+    #[test]
+    fn parse_compile_error_expectations() {
+        let expectations = Expectations::parse(
+            r#"
+            print; // Error at ';': Expect expression
+            // [line 4] Error at end: Expect '}' after block
+            // [c line 5] Error at 'x': Expect ';'
+            // [java line 6] Error: ignored
+        "#,
+        );
+
+        assert_eq!(
+            expectations.compile_errors,
+            vec![
+                (2, "[line 2] Error at ';': Expect expression".into()),
+                (3, "[line 4] Error at end: Expect '}' after block".into()),
+                (4, "[line 5] Error at 'x': Expect ';'".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn expected_compile_error() {
+        let failures = run_source(
+            r#"
+            print; // Error at ';': Expect expression
+        "#,
+        );
+
+        assert!(failures.is_empty(), "{failures:?}");
+    }
+}
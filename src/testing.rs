@@ -0,0 +1,491 @@
+use std::{cell::RefCell, fmt::Write, rc::Rc};
+
+use crate::{
+    compiler::try_compile,
+    config::{Config, PrintOutput},
+    vm::{InterpretResult, LoxError, StepResult, VM},
+};
+
+#[macro_export]
+macro_rules! assert_lox {
+    ($source:expr => $expected:expr $(,)?) => {
+        $crate::assert_lox!($source => $expected, |_: &mut $crate::vm::VM| {})
+    };
+    ($source:expr => $expected:expr, $setup:expr $(,)?) => {
+        $crate::assert_lox!($source, $crate::config::Config::default() => $expected, $setup)
+    };
+    ($source:expr, $config:expr => $expected:expr $(,)?) => {
+        $crate::assert_lox!($source, $config => $expected, |_: &mut $crate::vm::VM| {})
+    };
+    ($source:expr, $config:expr => $expected:expr, $setup:expr $(,)?) => {{
+        let run = $crate::testing::run_script_with($source, $config, $setup);
+        match run.result {
+            Ok(()) => assert_eq!(run.output, $expected, "unexpected output from:\n{}", $source),
+            Err(_) => panic!("script failed:\n{}\nsource:\n{}", run.errors, $source),
+        }
+    }};
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Type {
+    Number,
+    String,
+    Bool,
+}
+
+const TYPES: [Type; 3] = [Type::Number, Type::String, Type::Bool];
+
+struct Variable {
+    name: String,
+    typ: Type,
+    assignable: bool,
+}
+
+struct Function {
+    name: String,
+    arity: usize,
+}
+
+pub struct Generator {
+    state: u64,
+    pub max_depth: usize,
+    pub max_statements: usize,
+    budget: usize,
+    names: usize,
+    globals: Vec<Variable>,
+    functions: Vec<Function>,
+    scopes: Vec<Vec<Variable>>,
+    visible_globals: usize,
+    output: String,
+    indent: usize,
+}
+
+impl Generator {
+    pub fn new(seed: u64) -> Generator {
+        Generator {
+            state: seed ^ 0x9e37_79b9_7f4a_7c15,
+            max_depth: 3,
+            max_statements: 24,
+            budget: 0,
+            names: 0,
+            globals: Vec::new(),
+            functions: Vec::new(),
+            scopes: Vec::new(),
+            visible_globals: 0,
+            output: String::new(),
+            indent: 0,
+        }
+    }
+
+    pub fn program(&mut self) -> String {
+        self.budget = self.max_statements;
+        self.names = 0;
+        self.globals.clear();
+        self.functions.clear();
+        self.scopes.clear();
+        self.output.clear();
+
+        while self.budget > 0 {
+            self.visible_globals = self.globals.len();
+            match self.below(6) {
+                0 if self.functions.len() < 4 => self.fun_declaration(),
+                1 | 2 => self.var_declaration(),
+                _ => self.statement(0),
+            }
+        }
+        std::mem::take(&mut self.output)
+    }
+
+    fn fun_declaration(&mut self) {
+        self.budget = self.budget.saturating_sub(1);
+        let name = self.fresh_name("f");
+        let arity = self.below(4);
+        let params: Vec<_> = (0..arity).map(|_| self.fresh_name("p")).collect();
+
+        self.line(&format!("fun {name}({}) {{", params.join(", ")));
+        self.indent += 1;
+        self.scopes.push(
+            params
+                .into_iter()
+                .map(|name| Variable {
+                    name,
+                    typ: Type::Number,
+                    assignable: true,
+                })
+                .collect(),
+        );
+        for _ in 0..self.below(3) {
+            self.statement(1);
+        }
+        let value = self.number(self.max_depth.saturating_sub(1));
+        self.line(&format!("return {value};"));
+        self.scopes.pop();
+        self.indent -= 1;
+        self.line("}");
+
+        self.functions.push(Function { name, arity });
+    }
+
+    fn var_declaration(&mut self) {
+        self.budget = self.budget.saturating_sub(1);
+        let typ = self.pick_type();
+        let value = self.expression(typ, self.max_depth.saturating_sub(1));
+        let name = self.fresh_name("v");
+        self.line(&format!("var {name} = {value};"));
+        self.declare(Variable {
+            name,
+            typ,
+            assignable: true,
+        });
+    }
+
+    fn statement(&mut self, depth: usize) {
+        self.budget = self.budget.saturating_sub(1);
+        let nested = depth < self.max_depth && self.budget > 0;
+        match self.below(7) {
+            0 | 1 => {
+                let typ = self.pick_type();
+                let value = self.expression(typ, self.max_depth.saturating_sub(1));
+                self.line(&format!("print {value};"));
+            }
+            2 => {
+                let assignable: Vec<_> = self
+                    .visible()
+                    .filter(|v| v.assignable)
+                    .map(|v| (v.name.clone(), v.typ))
+                    .collect();
+                if assignable.is_empty() {
+                    self.var_declaration();
+                    return;
+                }
+                let (name, typ) = assignable[self.below(assignable.len())].clone();
+                let value = self.expression(typ, self.max_depth.saturating_sub(1));
+                self.line(&format!("{name} = {value};"));
+            }
+            3 if nested => {
+                let condition = self.bool(self.max_depth.saturating_sub(1));
+                self.line(&format!("if ({condition}) {{"));
+                self.block(depth + 1);
+                if self.below(2) == 0 {
+                    self.line("} else {");
+                    self.block(depth + 1);
+                }
+                self.line("}");
+            }
+            4 if nested => {
+                let counter = self.fresh_name("i");
+                let limit = self.below(5);
+                self.line(&format!(
+                    "for (var {counter} = 0; {counter} < {limit}; {counter} = {counter} + 1) {{"
+                ));
+                self.scopes.push(vec![Variable {
+                    name: counter,
+                    typ: Type::Number,
+                    assignable: false,
+                }]);
+                self.block(depth + 1);
+                self.scopes.pop();
+                self.line("}");
+            }
+            5 if nested => {
+                self.line("{");
+                self.block(depth + 1);
+                self.line("}");
+            }
+            _ => match self.call(self.max_depth.saturating_sub(1)) {
+                Some(call) => self.line(&format!("{call};")),
+                None => self.var_declaration(),
+            },
+        }
+    }
+
+    fn block(&mut self, depth: usize) {
+        self.indent += 1;
+        self.scopes.push(Vec::new());
+        for _ in 0..1 + self.below(3) {
+            if self.below(3) == 0 {
+                self.var_declaration();
+            } else {
+                self.statement(depth);
+            }
+        }
+        self.scopes.pop();
+        self.indent -= 1;
+    }
+
+    fn expression(&mut self, typ: Type, depth: usize) -> String {
+        match typ {
+            Type::Number => self.number(depth),
+            Type::String => self.string(depth),
+            Type::Bool => self.bool(depth),
+        }
+    }
+
+    fn number(&mut self, depth: usize) -> String {
+        let choice = if depth == 0 {
+            self.below(2)
+        } else {
+            self.below(7)
+        };
+        match choice {
+            0 => match self.variable(Type::Number) {
+                Some(name) => name,
+                None => self.below(100).to_string(),
+            },
+            1 => format!("{}.5", self.below(10)),
+            2 => format!("-{}", self.number(depth - 1)),
+            3 => format!("({})", self.number(depth - 1)),
+            4 => match self.call(depth - 1) {
+                Some(call) => call,
+                None => self.below(100).to_string(),
+            },
+            _ => {
+                let op = ["+", "-", "*", "/"][self.below(4)];
+                let (a, b) = (self.number(depth - 1), self.number(depth - 1));
+                format!("{a} {op} {b}")
+            }
+        }
+    }
+
+    fn string(&mut self, depth: usize) -> String {
+        let choice = if depth == 0 {
+            self.below(2)
+        } else {
+            self.below(3)
+        };
+        match choice {
+            0 => match self.variable(Type::String) {
+                Some(name) => name,
+                None => format!("\"s{}\"", self.below(10)),
+            },
+            1 => format!("\"s{}\"", self.below(10)),
+            _ => {
+                let (a, b) = (self.string(depth - 1), self.string(depth - 1));
+                format!("{a} + {b}")
+            }
+        }
+    }
+
+    fn bool(&mut self, depth: usize) -> String {
+        let choice = if depth == 0 {
+            self.below(2)
+        } else {
+            self.below(6)
+        };
+        match choice {
+            0 => match self.variable(Type::Bool) {
+                Some(name) => name,
+                None => ["true", "false"][self.below(2)].into(),
+            },
+            1 => ["true", "false", "nil == nil"][self.below(3)].into(),
+            2 => format!("!{}", self.bool(depth - 1)),
+            3 => {
+                let op = ["and", "or"][self.below(2)];
+                let (a, b) = (self.bool(depth - 1), self.bool(depth - 1));
+                format!("({a} {op} {b})")
+            }
+            4 => {
+                let typ = self.pick_type();
+                let op = ["==", "!="][self.below(2)];
+                let (a, b) = (
+                    self.expression(typ, depth - 1),
+                    self.expression(typ, depth - 1),
+                );
+                format!("({a} {op} {b})")
+            }
+            _ => {
+                let op = ["<", "<=", ">", ">="][self.below(4)];
+                let (a, b) = (self.number(depth - 1), self.number(depth - 1));
+                format!("({a} {op} {b})")
+            }
+        }
+    }
+
+    fn call(&mut self, depth: usize) -> Option<String> {
+        if self.functions.is_empty() {
+            return None;
+        }
+        let i = self.below(self.functions.len());
+        let (name, arity) = (self.functions[i].name.clone(), self.functions[i].arity);
+        let args: Vec<_> = (0..arity).map(|_| self.number(depth)).collect();
+        Some(format!("{name}({})", args.join(", ")))
+    }
+
+    fn variable(&mut self, typ: Type) -> Option<String> {
+        let names: Vec<_> = self
+            .visible()
+            .filter(|v| v.typ == typ)
+            .map(|v| v.name.clone())
+            .collect();
+        if names.is_empty() {
+            None
+        } else {
+            Some(names[self.below(names.len())].clone())
+        }
+    }
+
+    fn visible(&self) -> impl Iterator<Item = &Variable> {
+        self.globals[..self.visible_globals]
+            .iter()
+            .chain(self.scopes.iter().flatten())
+    }
+
+    fn declare(&mut self, variable: Variable) {
+        match self.scopes.last_mut() {
+            Some(scope) => scope.push(variable),
+            None => {
+                self.globals.push(variable);
+                self.visible_globals = self.globals.len();
+            }
+        }
+    }
+
+    fn fresh_name(&mut self, prefix: &str) -> String {
+        self.names += 1;
+        format!("{prefix}{}", self.names)
+    }
+
+    fn pick_type(&mut self) -> Type {
+        TYPES[self.below(TYPES.len())]
+    }
+
+    fn line(&mut self, line: &str) {
+        writeln!(
+            self.output,
+            "{:indent$}{line}",
+            "",
+            indent = self.indent * 2
+        )
+        .unwrap();
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        (self.state % n.max(1) as u64) as usize
+    }
+}
+
+pub struct ScriptOutput {
+    pub result: Result<(), LoxError>,
+    pub output: String,
+    pub errors: String,
+}
+
+/// Runs `source` under `config`, capturing printed output and compiler and
+/// runtime errors in place of the config's own outputs.
+pub fn run_script_with(
+    source: &str,
+    mut config: Config,
+    setup: impl FnOnce(&mut VM),
+) -> ScriptOutput {
+    let output = Rc::new(RefCell::new(String::new()));
+    let errors = Rc::new(RefCell::new(String::new()));
+    config.print_output = PrintOutput::Str(output.clone());
+    config.compiler_error = PrintOutput::Str(errors.clone());
+    config.vm_error = PrintOutput::Str(errors.clone());
+
+    let result = try_compile(Rc::from(source), None, config).and_then(|mut vm| {
+        setup(&mut vm);
+        vm.run()
+    });
+    ScriptOutput {
+        result,
+        output: output.take(),
+        errors: errors.take(),
+    }
+}
+
+pub fn run_script(source: &str, setup: impl FnOnce(&mut VM)) -> Result<String, String> {
+    let run = run_script_with(source, Config::default(), setup);
+    match run.result {
+        Ok(()) => Ok(run.output),
+        Err(_) => Err(run.errors),
+    }
+}
+
+pub fn run_with_fuel(vm: &mut VM, fuel: usize) -> Option<InterpretResult> {
+    for _ in 0..fuel {
+        if let StepResult::Finished(result) = vm.step() {
+            return Some(result);
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::compile, value::Value};
+
+    #[test]
+    fn programs_are_deterministic() {
+        assert_eq!(Generator::new(7).program(), Generator::new(7).program());
+        assert_ne!(Generator::new(7).program(), Generator::new(8).program());
+    }
+
+    #[test]
+    fn generated_programs_compile_and_terminate() {
+        for seed in 0..200 {
+            let source = Generator::new(seed).program();
+            let errors = Rc::new(RefCell::new(String::new()));
+            let config = || Config {
+                compiler_error: PrintOutput::Str(errors.clone()),
+                vm_error: PrintOutput::Str(errors.clone()),
+                print_output: PrintOutput::Null,
+                ..Default::default()
+            };
+
+            let Ok(program) = compile(Rc::from(source.as_str()), config()) else {
+                panic!(
+                    "seed {seed} did not compile:\n{}\n{source}",
+                    errors.borrow()
+                );
+            };
+            let mut vm = VM::load(&program, config());
+            assert_eq!(
+                run_with_fuel(&mut vm, 1_000_000),
+                Some(InterpretResult::OK),
+                "seed {seed} failed:\n{}\n{source}",
+                errors.borrow()
+            );
+        }
+    }
+
+    #[test]
+    fn assert_lox_checks_output() {
+        crate::assert_lox! { "print 1 + 2; print \"a\" + \"b\";" => "3\nab\n" };
+    }
+
+    #[test]
+    fn assert_lox_with_natives() {
+        crate::assert_lox! {
+            "print double(21);" => "42\n",
+            |vm: &mut VM| vm.define_native("double", |args| match args {
+                [Value::Number(n)] => Value::Number(n * 2.0),
+                _ => Value::Nil,
+            }),
+        };
+    }
+
+    #[test]
+    fn assert_lox_with_config() {
+        let config = Config {
+            integers: true,
+            ..Default::default()
+        };
+        crate::assert_lox! { "print 7 ~/ 2; print 7 / 2;", config => "3\n3.5\n" };
+
+        let run = run_script_with("print 1;\nprint missing;", Config::default(), |_| {});
+        assert_eq!(run.output, "1\n");
+        assert_eq!(run.result.unwrap_err().line, 2);
+        assert!(run.errors.starts_with("Undefined variable 'missing'"));
+    }
+
+    #[test]
+    #[should_panic(expected = "Undefined variable 'missing'")]
+    fn assert_lox_reports_runtime_errors() {
+        crate::assert_lox! { "print missing;" => "" };
+    }
+}
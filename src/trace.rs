@@ -0,0 +1,212 @@
+use std::fmt::Write;
+
+use crate::{
+    chunk::OpCode,
+    config::PrintOutput,
+    memory::{FunctionId, Memory},
+    observer::{Instruction, Observer},
+    value::Value,
+    vm::InstructionPointer,
+};
+
+pub trait TraceSink {
+    fn on_event(&mut self, event: &TraceEvent, memory: &Memory);
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TraceEvent {
+    pub function: FunctionId,
+    pub frame: usize,
+    pub op_code: OpCode,
+    pub instruction_pointer: InstructionPointer,
+    pub line: usize,
+    pub stack_depth: usize,
+    pub popped: Vec<Value>,
+    pub pushed: Vec<Value>,
+}
+
+impl TraceEvent {
+    pub(crate) fn new(
+        function: FunctionId,
+        frame: usize,
+        op_code: OpCode,
+        instruction_pointer: InstructionPointer,
+        line: usize,
+        before: &[Value],
+        after: &[Value],
+    ) -> TraceEvent {
+        let common = before.iter().zip(after).take_while(|(a, b)| a == b).count();
+        TraceEvent {
+            function,
+            frame,
+            op_code,
+            instruction_pointer,
+            line,
+            stack_depth: before.len(),
+            popped: before[common..].to_vec(),
+            pushed: after[common..].to_vec(),
+        }
+    }
+}
+
+impl TraceSink for Vec<TraceEvent> {
+    fn on_event(&mut self, event: &TraceEvent, _memory: &Memory) {
+        self.push(event.clone());
+    }
+}
+
+pub struct JsonTrace {
+    output: PrintOutput,
+    names: Vec<String>,
+    depth: usize,
+}
+
+impl JsonTrace {
+    pub fn new(output: PrintOutput) -> JsonTrace {
+        JsonTrace {
+            output,
+            names: Vec::new(),
+            depth: 0,
+        }
+    }
+
+    fn name(&mut self, function: FunctionId, memory: &Memory) -> &str {
+        if self.names.len() <= function.0 {
+            self.names.resize(function.0 + 1, String::new());
+        }
+        if self.names[function.0].is_empty() {
+            let name = memory.get_string(memory.function(function).name);
+            self.names[function.0] = json_string(name);
+        }
+        &self.names[function.0]
+    }
+}
+
+impl Observer for JsonTrace {
+    fn on_call(&mut self, function: FunctionId, memory: &Memory) {
+        self.depth += 1;
+        let depth = self.depth;
+        let name = self.name(function, memory).to_owned();
+        writeln!(
+            self.output,
+            r#"{{"event":"call","function":{name},"frame_depth":{depth}}}"#
+        )
+        .unwrap();
+    }
+
+    fn on_return(&mut self, function: FunctionId, memory: &Memory) {
+        let depth = self.depth;
+        self.depth = self.depth.saturating_sub(1);
+        let name = self.name(function, memory).to_owned();
+        writeln!(
+            self.output,
+            r#"{{"event":"return","function":{name},"frame_depth":{depth}}}"#
+        )
+        .unwrap();
+    }
+
+    fn on_instruction(&mut self, instruction: &Instruction) {
+        let function = self
+            .names
+            .get(instruction.function.0)
+            .map(String::as_str)
+            .unwrap_or("null");
+        writeln!(
+            self.output,
+            r#"{{"event":"instruction","function":{},"op":"{:?}","ip":{},"line":{},"stack_depth":{},"frame_depth":{}}}"#,
+            function,
+            instruction.op_code,
+            instruction.instruction_pointer.0,
+            instruction.line,
+            instruction.stack_depth,
+            instruction.frame_depth,
+        )
+        .unwrap();
+    }
+}
+
+pub fn json_string(s: &str) -> String {
+    let mut json = String::with_capacity(s.len() + 2);
+    json.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => json.push_str("\\\""),
+            '\\' => json.push_str("\\\\"),
+            '\n' => json.push_str("\\n"),
+            '\r' => json.push_str("\\r"),
+            '\t' => json.push_str("\\t"),
+            c if (c as u32) < 0x20 => write!(json, "\\u{:04x}", c as u32).unwrap(),
+            c => json.push(c),
+        }
+    }
+    json.push('"');
+    json
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, rc::Rc};
+
+    use super::*;
+    use crate::{config::Config, vm::interpret};
+
+    #[test]
+    fn emits_one_object_per_event() {
+        let output = Rc::new(RefCell::new(String::new()));
+        let trace = Rc::new(RefCell::new(JsonTrace::new(PrintOutput::Str(
+            output.clone(),
+        ))));
+        let mut config = Config {
+            print_output: PrintOutput::Null,
+            ..Default::default()
+        };
+        config.observe(trace);
+
+        interpret("print 1;", config).unwrap();
+
+        let output = output.borrow();
+        let lines: Vec<_> = output.lines().collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#"{"event":"call","function":"<script>","frame_depth":1}"#,
+                r#"{"event":"instruction","function":"<script>","op":"Constant","ip":0,"line":1,"stack_depth":1,"frame_depth":1}"#,
+                r#"{"event":"instruction","function":"<script>","op":"Print","ip":2,"line":1,"stack_depth":2,"frame_depth":1}"#,
+                r#"{"event":"instruction","function":"<script>","op":"Nil","ip":3,"line":1,"stack_depth":1,"frame_depth":1}"#,
+                r#"{"event":"instruction","function":"<script>","op":"Return","ip":4,"line":1,"stack_depth":2,"frame_depth":1}"#,
+                r#"{"event":"return","function":"<script>","frame_depth":1}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn sinks_receive_stack_changes() {
+        let events = Rc::new(RefCell::new(Vec::<TraceEvent>::new()));
+        let mut config = Config {
+            print_output: PrintOutput::Null,
+            ..Default::default()
+        };
+        config.trace(events.clone());
+
+        interpret("fun f(a) { return a + 1; }\nprint f(2);", config).unwrap();
+
+        let events = events.borrow();
+        let add = events.iter().find(|e| e.op_code == OpCode::Add).unwrap();
+        assert_eq!(add.frame, 1);
+        assert_eq!(add.line, 1);
+        assert_eq!(add.popped, vec![Value::Number(2.0), Value::Number(1.0)]);
+        assert_eq!(add.pushed, vec![Value::Number(3.0)]);
+
+        let print = events.iter().find(|e| e.op_code == OpCode::Print).unwrap();
+        assert_eq!(print.frame, 0);
+        assert_eq!(print.line, 2);
+        assert_eq!(print.popped, vec![Value::Number(3.0)]);
+        assert!(print.pushed.is_empty());
+        assert_eq!(print.stack_depth, 2);
+    }
+
+    #[test]
+    fn escapes_strings() {
+        assert_eq!(json_string("a\"b\\c\n"), r#""a\"b\\c\n""#);
+    }
+}
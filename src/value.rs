@@ -1,24 +1,126 @@
 use crate::{
-    memory::{ClosureId, FunctionId, NativeFunctionId},
+    memory::{
+        BoundMethodId, ClassId, ClosureId, ForeignId, FunctionId, InstanceId, ListId, Memory,
+        ModuleId, NativeFunctionId,
+    },
     string_intern::StrId,
 };
 
-#[derive(PartialEq, Clone, Copy)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum Value {
     Nil,
     Bool(bool),
     Number(f64),
+    Int(i64),
     String(&'static str),
     StringId(StrId),
     Function(FunctionId),
     Closure(ClosureId),
     NativeFunction(NativeFunctionId),
+    Class(ClassId),
+    Instance(InstanceId),
+    BoundMethod(BoundMethodId),
+    List(ListId),
+    Tuple(ListId),
+    Module(ModuleId),
+    Type(ValueType),
+    Foreign(ForeignId),
+}
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash)]
+pub enum ValueType {
+    Nil,
+    Bool,
+    Number,
+    String,
+    Function,
+    Class,
+    Instance,
+    List,
+    Tuple,
+    Module,
+    Foreign,
+}
+
+impl ValueType {
+    pub const ALL: [ValueType; 11] = [
+        ValueType::Nil,
+        ValueType::Bool,
+        ValueType::Number,
+        ValueType::String,
+        ValueType::Function,
+        ValueType::Class,
+        ValueType::Instance,
+        ValueType::List,
+        ValueType::Tuple,
+        ValueType::Module,
+        ValueType::Foreign,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            ValueType::Nil => "nil",
+            ValueType::Bool => "bool",
+            ValueType::Number => "number",
+            ValueType::String => "string",
+            ValueType::Function => "function",
+            ValueType::Class => "class",
+            ValueType::Instance => "instance",
+            ValueType::List => "list",
+            ValueType::Tuple => "tuple",
+            ValueType::Module => "module",
+            ValueType::Foreign => "foreign",
+        }
+    }
+
+    pub fn global(self) -> &'static str {
+        match self {
+            ValueType::Nil => "Nil",
+            ValueType::Bool => "Bool",
+            ValueType::Number => "Number",
+            ValueType::String => "String",
+            ValueType::Function => "Function",
+            ValueType::Class => "Class",
+            ValueType::Instance => "Instance",
+            ValueType::List => "List",
+            ValueType::Tuple => "Tuple",
+            ValueType::Module => "Module",
+            ValueType::Foreign => "Foreign",
+        }
+    }
 }
 
 impl Value {
+    pub fn value_type(&self) -> ValueType {
+        match self {
+            Value::Nil => ValueType::Nil,
+            Value::Bool(_) => ValueType::Bool,
+            Value::Number(_) | Value::Int(_) => ValueType::Number,
+            Value::String(_) | Value::StringId(_) => ValueType::String,
+            Value::Function(_)
+            | Value::Closure(_)
+            | Value::NativeFunction(_)
+            | Value::BoundMethod(_) => ValueType::Function,
+            Value::Class(_) | Value::Type(_) => ValueType::Class,
+            Value::Instance(_) => ValueType::Instance,
+            Value::List(_) => ValueType::List,
+            Value::Tuple(_) => ValueType::Tuple,
+            Value::Module(_) => ValueType::Module,
+            Value::Foreign(_) => ValueType::Foreign,
+        }
+    }
+
     pub fn as_number(&self) -> Option<f64> {
         match self {
             Value::Number(n) => Some(*n),
+            Value::Int(i) => Some(*i as f64),
+            _ => None,
+        }
+    }
+
+    pub fn as_int(&self) -> Option<i64> {
+        match self {
+            Value::Int(i) => Some(*i),
             _ => None,
         }
     }
@@ -57,4 +159,210 @@ impl Value {
             _ => None,
         }
     }
+
+    pub fn as_class(&self) -> Option<ClassId> {
+        match self {
+            Value::Class(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn as_instance(&self) -> Option<InstanceId> {
+        match self {
+            Value::Instance(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn as_bound_method(&self) -> Option<BoundMethodId> {
+        match self {
+            Value::BoundMethod(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn as_list(&self) -> Option<ListId> {
+        match self {
+            Value::List(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn as_tuple(&self) -> Option<ListId> {
+        match self {
+            Value::Tuple(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn as_module(&self) -> Option<ModuleId> {
+        match self {
+            Value::Module(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn as_foreign(&self) -> Option<ForeignId> {
+        match self {
+            Value::Foreign(id) => Some(*id),
+            _ => None,
+        }
+    }
+
+    pub fn as_type(&self) -> Option<ValueType> {
+        match self {
+            Value::Type(typ) => Some(*typ),
+            _ => None,
+        }
+    }
+
+    pub fn try_into_rust<T: FromLox>(self, memory: &Memory) -> Option<T> {
+        T::from_lox(self, memory)
+    }
 }
+
+pub trait FromLox: Sized {
+    fn from_lox(value: Value, memory: &Memory) -> Option<Self>;
+}
+
+pub trait IntoLox {
+    fn into_lox(self, memory: &mut Memory) -> Value;
+}
+
+impl FromLox for Value {
+    fn from_lox(value: Value, _: &Memory) -> Option<Self> {
+        Some(value)
+    }
+}
+
+impl FromLox for () {
+    fn from_lox(value: Value, _: &Memory) -> Option<Self> {
+        matches!(value, Value::Nil).then_some(())
+    }
+}
+
+impl FromLox for bool {
+    fn from_lox(value: Value, _: &Memory) -> Option<Self> {
+        match value {
+            Value::Bool(b) => Some(b),
+            _ => None,
+        }
+    }
+}
+
+impl FromLox for f64 {
+    fn from_lox(value: Value, _: &Memory) -> Option<Self> {
+        value.as_number()
+    }
+}
+
+impl FromLox for String {
+    fn from_lox(value: Value, memory: &Memory) -> Option<Self> {
+        memory.string_value(value).map(str::to_owned)
+    }
+}
+
+impl<T: FromLox> FromLox for Option<T> {
+    fn from_lox(value: Value, memory: &Memory) -> Option<Self> {
+        match value {
+            Value::Nil => Some(None),
+            value => T::from_lox(value, memory).map(Some),
+        }
+    }
+}
+
+impl<T: FromLox> FromLox for Vec<T> {
+    fn from_lox(value: Value, memory: &Memory) -> Option<Self> {
+        let list = value.as_list()?;
+        memory
+            .list(list)
+            .items
+            .iter()
+            .map(|item| T::from_lox(*item, memory))
+            .collect()
+    }
+}
+
+impl IntoLox for Value {
+    fn into_lox(self, _: &mut Memory) -> Value {
+        self
+    }
+}
+
+impl IntoLox for () {
+    fn into_lox(self, _: &mut Memory) -> Value {
+        Value::Nil
+    }
+}
+
+impl IntoLox for bool {
+    fn into_lox(self, _: &mut Memory) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl IntoLox for f64 {
+    fn into_lox(self, _: &mut Memory) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl IntoLox for usize {
+    fn into_lox(self, _: &mut Memory) -> Value {
+        Value::Number(self as f64)
+    }
+}
+
+impl IntoLox for &str {
+    fn into_lox(self, memory: &mut Memory) -> Value {
+        Value::String(memory.string_intern(self))
+    }
+}
+
+impl IntoLox for String {
+    fn into_lox(self, memory: &mut Memory) -> Value {
+        self.as_str().into_lox(memory)
+    }
+}
+
+impl<T: IntoLox> IntoLox for Option<T> {
+    fn into_lox(self, memory: &mut Memory) -> Value {
+        match self {
+            Some(value) => value.into_lox(memory),
+            None => Value::Nil,
+        }
+    }
+}
+
+impl<T: IntoLox> IntoLox for Vec<T> {
+    fn into_lox(self, memory: &mut Memory) -> Value {
+        let items = self.into_iter().map(|item| item.into_lox(memory)).collect();
+        Value::List(memory.new_list(items))
+    }
+}
+
+pub trait FromArgs: Sized {
+    fn from_args(args: &[Value], memory: &Memory) -> Result<Self, String>;
+}
+
+macro_rules! impl_from_args {
+    ($count:literal; $($typ:ident $arg:ident),*) => {
+        impl<$($typ: FromLox),*> FromArgs for ($($typ,)*) {
+            #[allow(unused_variables)]
+            fn from_args(args: &[Value], memory: &Memory) -> Result<Self, String> {
+                let [$($arg),*] = args else {
+                    return Err(format!("Expected {} arguments but got {}", $count, args.len()));
+                };
+                Ok(($(
+                    $typ::from_lox(*$arg, memory)
+                        .ok_or_else(|| format!("Wrong type for argument '{}'", stringify!($arg)))?,
+                )*))
+            }
+        }
+    };
+}
+
+impl_from_args!(0;);
+impl_from_args!(1; A a);
+impl_from_args!(2; A a, B b);
+impl_from_args!(3; A a, B b, C c);
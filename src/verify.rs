@@ -0,0 +1,244 @@
+use std::{collections::HashSet, error::Error, fmt};
+
+use crate::{
+    chunk::{OpCode, Operand},
+    memory::{Function, Memory},
+    value::Value,
+};
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct VerifyError {
+    pub function: String,
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{} at {:04}] {}",
+            self.function, self.offset, self.message
+        )
+    }
+}
+
+impl Error for VerifyError {}
+
+pub fn verify(memory: &Memory) -> Result<(), VerifyError> {
+    let functions: Vec<_> = memory.functions().collect();
+    match functions.first() {
+        Some(script) if script.arity == 0 && !script.variadic => (),
+        _ => {
+            return Err(VerifyError {
+                function: "<script>".into(),
+                offset: 0,
+                message: "Expect a script function with no parameters".into(),
+            })
+        }
+    }
+
+    for function in functions.iter() {
+        verify_function(function, functions.len(), memory)?;
+    }
+    Ok(())
+}
+
+fn verify_function(
+    function: &Function,
+    function_count: usize,
+    memory: &Memory,
+) -> Result<(), VerifyError> {
+    let name = memory.get_string(function.name);
+    let chunk = &function.chunk;
+    let code = &chunk.code;
+    let constants = chunk.constants();
+    let err = |offset: usize, message: String| VerifyError {
+        function: name.into(),
+        offset,
+        message,
+    };
+
+    if function.min_arity > function.arity {
+        return Err(err(0, "Minimum arity exceeds arity".into()));
+    }
+    if function.variadic && function.arity == 0 {
+        return Err(err(0, "Variadic function has no rest parameter".into()));
+    }
+
+    if chunk.lines.len() != code.len() {
+        return Err(err(0, "Line table does not match code length".into()));
+    }
+
+    let string_count = memory.strings().count();
+    for (i, constant) in constants.iter().enumerate() {
+        let valid = match constant {
+            Value::StringId(id) => id.0 < string_count,
+            Value::Function(id) => id.0 < function_count,
+            Value::Closure(_)
+            | Value::NativeFunction(_)
+            | Value::Class(_)
+            | Value::Instance(_)
+            | Value::BoundMethod(_)
+            | Value::List(_)
+            | Value::Tuple(_)
+            | Value::Module(_)
+            | Value::Type(_)
+            | Value::Foreign(_) => false,
+            _ => true,
+        };
+        if !valid {
+            return Err(err(0, format!("Invalid constant {i}")));
+        }
+    }
+
+    let mut boundaries = HashSet::new();
+    let mut jumps = Vec::new();
+    let mut offset = 0;
+    let mut last = None;
+    while offset < code.len() {
+        boundaries.insert(offset);
+        let op_code = OpCode::try_from(code[offset])
+            .map_err(|_| err(offset, format!("Unknown opcode {}", code[offset])))?;
+        let operand = op_code.operand();
+        let next = offset + 1 + operand.size();
+        if next > code.len() {
+            return Err(err(offset, format!("{op_code:?} is missing its operand")));
+        }
+
+        match operand {
+            Operand::None | Operand::Byte | Operand::Short | Operand::Locals => (),
+            Operand::Constant
+            | Operand::ConstantLong
+            | Operand::Invoke
+            | Operand::InvokeLong
+            | Operand::LocalConstant => {
+                let index = match operand {
+                    Operand::ConstantLong | Operand::InvokeLong => {
+                        let bytes = &code[offset + 1..offset + 4];
+                        (bytes[0] as usize) << 16 | (bytes[1] as usize) << 8 | bytes[2] as usize
+                    }
+                    Operand::LocalConstant => code[offset + 2] as usize,
+                    _ => code[offset + 1] as usize,
+                };
+                let Some(constant) = constants.get(index) else {
+                    return Err(err(offset, format!("Constant {index} out of range")));
+                };
+                let valid = match op_code {
+                    OpCode::DefineGlobal
+                    | OpCode::GetGlobal
+                    | OpCode::SetGlobal
+                    | OpCode::DefineGlobalLong
+                    | OpCode::DefineConst
+                    | OpCode::DefineConstLong
+                    | OpCode::GetGlobalLong
+                    | OpCode::SetGlobalLong
+                    | OpCode::Class
+                    | OpCode::GetProperty
+                    | OpCode::SetProperty
+                    | OpCode::Method
+                    | OpCode::GetSuper
+                    | OpCode::Invoke
+                    | OpCode::SuperInvoke
+                    | OpCode::ClassLong
+                    | OpCode::GetPropertyLong
+                    | OpCode::SetPropertyLong
+                    | OpCode::MethodLong
+                    | OpCode::GetSuperLong
+                    | OpCode::InvokeLong
+                    | OpCode::SuperInvokeLong => {
+                        matches!(constant, Value::StringId(_))
+                    }
+                    OpCode::Closure | OpCode::Import | OpCode::ClosureLong | OpCode::ImportLong => {
+                        matches!(constant, Value::Function(_))
+                    }
+                    _ => true,
+                };
+                if !valid {
+                    return Err(err(
+                        offset,
+                        format!("{op_code:?} has an invalid constant {index}"),
+                    ));
+                }
+            }
+            Operand::Jump | Operand::Loop => {
+                let jump = u16::from_be_bytes([code[offset + 1], code[offset + 2]]) as usize;
+                let target = if operand == Operand::Jump {
+                    Some(next + jump)
+                } else {
+                    next.checked_sub(jump)
+                };
+                match target {
+                    Some(target) if target < code.len() => jumps.push((offset, target)),
+                    _ => return Err(err(offset, format!("{op_code:?} target out of range"))),
+                }
+            }
+        }
+
+        last = Some((offset, op_code));
+        offset = next;
+    }
+
+    for (offset, target) in jumps {
+        if !boundaries.contains(&target) {
+            return Err(err(
+                offset,
+                format!("Jump target {target} is inside an instruction"),
+            ));
+        }
+    }
+
+    match last {
+        Some((_, OpCode::Return)) => Ok(()),
+        Some((offset, _)) => Err(err(offset, "Function must end with Return".into())),
+        None => Err(err(0, "Function has no code".into())),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{asm::assemble, compiler::compile, memory::FunctionId};
+
+    fn verify_asm(source: &str) -> Result<(), VerifyError> {
+        verify(&assemble(source).unwrap())
+    }
+
+    #[test]
+    fn accepts_compiled_programs() {
+        let program = compile(
+            "fun f(n) { while (n > 0) n = n - 1; return n; } print f(3);".into(),
+            Default::default(),
+        )
+        .unwrap();
+        assert_eq!(verify(&program.to_memory()), Ok(()));
+    }
+
+    #[test]
+    fn rejects_bad_operands() {
+        let error =
+            verify_asm(".function <script>\n.constants\nnumber 1\n.code\nGetGlobal 0\nReturn\n")
+                .unwrap_err();
+        assert_eq!(error.message, "GetGlobal has an invalid constant 0");
+    }
+
+    #[test]
+    fn rejects_falling_off_the_end() {
+        let error = verify_asm(".function <script>\n.code\nNil\n").unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "[<script> at 0000] Function must end with Return"
+        );
+    }
+
+    #[test]
+    fn rejects_jumps_into_operands() {
+        let mut memory = assemble(
+            ".function <script>\n.constants\nnil\n.code\nJump done\nConstant 0\ndone:\nReturn\n",
+        )
+        .unwrap();
+        memory.function_mut(FunctionId(0)).chunk.code[2] = 1;
+        let error = verify(&memory).unwrap_err();
+        assert_eq!(error.message, "Jump target 4 is inside an instruction");
+    }
+}
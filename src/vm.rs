@@ -1,26 +1,102 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    any::Any,
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet},
+    error::Error,
     fmt::{self, Write},
+    ops::Deref,
+    panic::{self, AssertUnwindSafe},
     rc::Rc,
-    time::{SystemTime, UNIX_EPOCH},
+    time::{Duration, Instant},
 };
 
 use crate::{
-    chunk::{Chunk, ConstantId, OpCode},
-    compiler::compile,
-    config::Config,
-    debug::{disassemble_instruction, print_value},
-    memory::{ClosureId, FunctionId, Memory},
+    chunk::location,
+    chunk::{Chunk, ConstantId, Decoded, LocalInfo, OpCode},
+    compiler::{compile_script, try_compile},
+    config::{Config, DivisionByZero, PrintOutput},
+    debug::{disassemble_instruction, display_value, print_value},
+    diagnostic::write_excerpt,
+    foreign::{ForeignType, LoxBindings},
+    memory::{
+        Arity, ClassId, ClosureId, CompiledProgram, Function, FunctionId, HeapSnapshot, Memory,
+        ModuleId, NativeError, NativeFn, NativeFunctionId,
+    },
+    messages::Message,
+    observer::Instruction,
+    serialize::deserialize,
     string_intern::StrId,
-    value::Value,
+    trace::TraceEvent,
+    value::{IntoLox, Value, ValueType},
 };
 
-pub fn interpret(source: &str, config: Config) -> InterpretResult {
-    if let Some(mut vm) = compile(Rc::from(source), config) {
-        vm.run()
-    } else {
-        return InterpretResult::CompileError;
+const MAX_REPEAT_LEN: usize = 1 << 30;
+
+pub fn interpret(source: &str, config: Config) -> Result<(), LoxError> {
+    interpret_named(source, None, config)
+}
+
+pub fn interpret_named(source: &str, name: Option<&str>, config: Config) -> Result<(), LoxError> {
+    try_compile(Rc::from(source), name, config)?.run()
+}
+
+pub fn bench_interpret(source: &str) -> Result<(), LoxError> {
+    let config = Config {
+        vm_error: PrintOutput::Null,
+        compiler_error: PrintOutput::Null,
+        print_output: PrintOutput::Null,
+        eprint_output: PrintOutput::Null,
+        ..Default::default()
+    };
+    interpret(source, config)
+}
+
+pub fn run_precompiled(bytes: &[u8], config: Config) -> Result<(), LoxError> {
+    let memory = deserialize(bytes).map_err(|e| LoxError {
+        kind: ErrorKind::Compile,
+        message: format!("Could not load bytecode: {e}"),
+        line: 0,
+        trace: StackTrace::default(),
+        value: None,
+    })?;
+    VM::with_script(memory, config).run()
+}
+
+pub struct Repl {
+    pub vm: VM,
+}
+
+impl Repl {
+    pub fn new(config: Config) -> Repl {
+        Repl {
+            vm: VM::new(Memory::new(), config),
+        }
     }
+
+    pub fn interpret(&mut self, source: &str) -> Result<(), LoxError> {
+        let function = compile_script(Rc::from(source), &mut self.vm)?;
+
+        let closure = self.vm.new_closure(function);
+        self.vm.push(Value::Closure(closure));
+        self.vm.call(closure, 0);
+        let result = self.vm.run();
+        if result.is_err() {
+            self.vm.frames.clear();
+            self.vm.reset_stack();
+        }
+        result
+    }
+}
+
+#[derive(Clone)]
+pub struct VmSnapshot {
+    frames: Vec<CallFrame>,
+    stack: Vec<Value>,
+    globals: HashMap<StrId, Value>,
+    constants: HashSet<StrId>,
+    modules: HashMap<FunctionId, ModuleId>,
+    heap: HeapSnapshot,
+    next_gc: usize,
+    instructions: usize,
 }
 
 pub struct VM {
@@ -28,25 +104,301 @@ pub struct VM {
     pub frames: Vec<CallFrame>,
     pub stack: Vec<Value>,
     pub globals: HashMap<StrId, Value>,
+    pub constants: HashSet<StrId>,
+    pub modules: HashMap<FunctionId, ModuleId>,
     pub memory: Memory,
+    init_string: StrId,
+    next_gc: usize,
+    instructions: usize,
+    error: Option<LoxError>,
+    breakpoints: HashSet<(FunctionId, usize)>,
+    paused: bool,
+    hits: HashMap<FunctionId, Vec<usize>>,
+    inline_caches: Vec<Vec<Option<InlineCache>>>,
+    native_calls: Vec<(usize, NativeFunctionId)>,
+    #[cfg(feature = "predecode")]
+    decoded: HashMap<FunctionId, Rc<[Option<Decoded>]>>,
+    #[cfg(not(feature = "predecode"))]
+    code: HashMap<FunctionId, Rc<[u8]>>,
+}
+
+#[derive(Clone, Copy)]
+enum InlineCache {
+    Method { class: ClassId, method: ClosureId },
+    Field { class: ClassId, slot: usize },
 }
 
 impl VM {
-    pub fn new(memory: Memory, config: Config) -> Self {
+    pub fn new(mut memory: Memory, config: Config) -> Self {
+        let init_string = memory.string_id("init");
+        memory.set_number_format(config.number_format);
         let mut vm = Self {
+            next_gc: config.gc.initial_heap,
+            instructions: 0,
             config,
             frames: Vec::new(),
             stack: Vec::new(),
             globals: HashMap::new(),
+            constants: HashSet::new(),
+            modules: HashMap::new(),
             memory,
+            init_string,
+            error: None,
+            breakpoints: HashSet::new(),
+            paused: false,
+            hits: HashMap::new(),
+            inline_caches: Vec::new(),
+            native_calls: Vec::new(),
+            #[cfg(feature = "predecode")]
+            decoded: HashMap::new(),
+            #[cfg(not(feature = "predecode"))]
+            code: HashMap::new(),
         };
+        for typ in ValueType::ALL {
+            let name = vm.memory.string_id(typ.global());
+            vm.globals.insert(name, Value::Type(typ));
+        }
+        let start = Instant::now();
         vm.define_native("clock", move |_args| {
-            let t = SystemTime::now()
-                .duration_since(UNIX_EPOCH)
-                .expect("Time went backwards")
-                .as_secs();
-            Value::Number(t as f64)
+            Value::Number(start.elapsed().as_secs_f64())
+        });
+        if vm.config.capabilities.timers {
+            vm.define_native("clockMillis", move |_args| {
+                Value::Number(start.elapsed().as_millis() as f64)
+            });
+            let max_sleep = vm.config.limits.max_sleep_ms;
+            vm.define_fallible_native("sleep", move |_memory, args| {
+                let ms = match args {
+                    [ms] => ms.as_number().ok_or("Sleep duration must be a number")?,
+                    _ => return Err("Expected a duration in milliseconds".into()),
+                };
+                if ms > max_sleep as f64 {
+                    return Err(
+                        format!("Sleep duration exceeds the limit of {max_sleep} ms").into(),
+                    );
+                }
+                let duration = Duration::try_from_secs_f64(ms / 1000.0)
+                    .map_err(|_| "Sleep duration must be a non-negative number")?;
+                std::thread::sleep(duration);
+                Ok(Value::Nil)
+            });
+        }
+        vm.define_memory_native("len", |memory, args| {
+            let len = match args {
+                [Value::List(list) | Value::Tuple(list)] => Some(memory.list(*list).items.len()),
+                [value] => memory.string_value(*value).map(|s| s.chars().count()),
+                _ => None,
+            };
+            len.into_lox(memory)
+        });
+        vm.define_memory_native("str", |memory, args| match args {
+            [value] => {
+                let mut text = String::new();
+                display_value(value, memory, &mut text);
+                text.as_str().into_lox(memory)
+            }
+            _ => Value::Nil,
+        });
+        vm.define_memory_native("typeof", |memory, args| match args {
+            [value] => Value::String(memory.type_name(*value)),
+            _ => Value::Nil,
+        });
+        vm.define_memory_native("push", |memory, args| match args {
+            [Value::List(list), value] => {
+                let items = &mut memory.list_mut(*list).items;
+                items.push(*value);
+                Value::Number(items.len() as f64)
+            }
+            _ => Value::Nil,
         });
+        vm.define_memory_native("pop", |memory, args| match args {
+            [Value::List(list)] => memory.list_mut(*list).items.pop().unwrap_or(Value::Nil),
+            _ => Value::Nil,
+        });
+        vm.define_list_natives();
+        let args = vm.config.args.clone();
+        vm.define_memory_native("args", move |memory, _args| args.clone().into_lox(memory));
+        if vm.config.allow_io {
+            vm.define_io_natives();
+        }
+        if vm.config.allow_env {
+            vm.define_fallible_native("env", |memory, args| match args {
+                [name] => {
+                    let name = memory.string_value(*name).ok_or("Name must be a string")?;
+                    Ok(std::env::var(name).ok().into_lox(memory))
+                }
+                _ => Err("Expected a variable name".into()),
+            });
+        }
+        let arities = [
+            ("clock", Arity::Fixed(0)),
+            ("clockMillis", Arity::Fixed(0)),
+            ("sleep", Arity::Fixed(1)),
+            ("len", Arity::Fixed(1)),
+            ("str", Arity::Fixed(1)),
+            ("typeof", Arity::Fixed(1)),
+            ("push", Arity::Fixed(2)),
+            ("pop", Arity::Fixed(1)),
+            ("sort", Arity::Fixed(2)),
+            ("map", Arity::Fixed(2)),
+            ("filter", Arity::Fixed(2)),
+            ("reduce", Arity::Fixed(3)),
+            ("args", Arity::Fixed(0)),
+            ("env", Arity::Fixed(1)),
+            ("readFile", Arity::Fixed(1)),
+            ("writeFile", Arity::Fixed(2)),
+            ("readLine", Arity::Fixed(0)),
+        ];
+        for (name, arity) in arities {
+            vm.set_native_arity(name, arity);
+        }
+        vm
+    }
+
+    fn define_list_natives(&mut self) {
+        self.define_context_native("sort", |ctx, args| match args {
+            [Value::List(list), cmp] => {
+                let items = ctx.memory().list(*list).items.clone();
+                // The comparator may empty the list, so keep the items rooted
+                let rooted = ctx.memory().new_list(items.clone());
+                ctx.root(Value::List(rooted));
+                let items = merge_sort(items, &mut |a, b| {
+                    let order = ctx.call(*cmp, &[a, b])?;
+                    order
+                        .as_number()
+                        .map(|n| n <= 0.0)
+                        .ok_or_else(|| "Comparator must return a number".into())
+                })?;
+                ctx.memory().list_mut(*list).items = items;
+                Ok(Value::List(*list))
+            }
+            _ => Err("Expected a list and a comparator".into()),
+        });
+        self.define_context_native("map", |ctx, args| match args {
+            [Value::List(list), f] => {
+                let mapped = ctx.memory().new_list(Vec::new());
+                ctx.root(Value::List(mapped));
+                let mut i = 0;
+                while let Some(item) = ctx.memory().list(*list).items.get(i).copied() {
+                    let value = ctx.call(*f, &[item])?;
+                    ctx.memory().list_mut(mapped).items.push(value);
+                    i += 1;
+                }
+                Ok(Value::List(mapped))
+            }
+            _ => Err("Expected a list and a function".into()),
+        });
+        self.define_context_native("filter", |ctx, args| match args {
+            [Value::List(list), f] => {
+                let filtered = ctx.memory().new_list(Vec::new());
+                ctx.root(Value::List(filtered));
+                let mut i = 0;
+                while let Some(item) = ctx.memory().list(*list).items.get(i).copied() {
+                    if !is_falsey(ctx.call(*f, &[item])?) {
+                        ctx.memory().list_mut(filtered).items.push(item);
+                    }
+                    i += 1;
+                }
+                Ok(Value::List(filtered))
+            }
+            _ => Err("Expected a list and a function".into()),
+        });
+        self.define_context_native("reduce", |ctx, args| match args {
+            [Value::List(list), f, init] => {
+                let mut acc = *init;
+                let mut i = 0;
+                while let Some(item) = ctx.memory().list(*list).items.get(i).copied() {
+                    acc = ctx.call(*f, &[acc, item])?;
+                    i += 1;
+                }
+                Ok(acc)
+            }
+            _ => Err("Expected a list, a function and an initial value".into()),
+        });
+    }
+
+    fn define_io_natives(&mut self) {
+        self.define_fallible_native("readFile", |memory, args| match args {
+            [path] => {
+                let path = memory.string_value(*path).ok_or("Path must be a string")?;
+                let text = std::fs::read_to_string(path)
+                    .map_err(|e| format!("Could not read '{path}': {e}"))?;
+                Ok(text.as_str().into_lox(memory))
+            }
+            _ => Err("Expected a path".into()),
+        });
+        self.define_fallible_native("writeFile", |memory, args| match args {
+            [path, text] => {
+                let path = memory.string_value(*path).ok_or("Path must be a string")?;
+                let mut contents = String::new();
+                display_value(text, memory, &mut contents);
+                std::fs::write(path, contents)
+                    .map_err(|e| format!("Could not write '{path}': {e}"))?;
+                Ok(Value::Nil)
+            }
+            _ => Err("Expected a path and text".into()),
+        });
+        self.define_fallible_native("readLine", |memory, _args| {
+            let mut line = String::new();
+            let read = std::io::stdin()
+                .read_line(&mut line)
+                .map_err(|e| format!("Could not read line: {e}"))?;
+            if read == 0 {
+                return Ok(Value::Nil);
+            }
+            let line = line.strip_suffix('\n').unwrap_or(&line);
+            let line = line.strip_suffix('\r').unwrap_or(line);
+            Ok(line.into_lox(memory))
+        });
+        self.define_context_native("eprint", |ctx, args| {
+            let mut text = String::new();
+            for arg in args {
+                display_value(arg, &ctx.vm.memory, &mut text);
+            }
+            writeln!(ctx.vm.config.eprint_output, "{text}").map_err(|_| "Could not write")?;
+            Ok(Value::Nil)
+        });
+    }
+
+    pub fn snapshot(&self) -> VmSnapshot {
+        VmSnapshot {
+            frames: self.frames.clone(),
+            stack: self.stack.clone(),
+            globals: self.globals.clone(),
+            constants: self.constants.clone(),
+            modules: self.modules.clone(),
+            heap: self.memory.snapshot(),
+            next_gc: self.next_gc,
+            instructions: self.instructions,
+        }
+    }
+
+    pub fn restore(&mut self, snapshot: &VmSnapshot) {
+        self.frames = snapshot.frames.clone();
+        self.stack = snapshot.stack.clone();
+        self.globals = snapshot.globals.clone();
+        self.constants = snapshot.constants.clone();
+        self.modules = snapshot.modules.clone();
+        self.memory.restore(&snapshot.heap);
+        self.next_gc = snapshot.next_gc;
+        self.instructions = snapshot.instructions;
+        self.error = None;
+        self.inline_caches.clear();
+    }
+
+    pub fn load(program: &CompiledProgram, config: Config) -> Self {
+        VM::with_script(program.to_memory(), config)
+    }
+
+    pub fn run_program(program: &CompiledProgram, config: Config) -> Result<(), LoxError> {
+        VM::load(program, config).run()
+    }
+
+    pub fn with_script(memory: Memory, config: Config) -> Self {
+        let mut vm = VM::new(memory, config);
+        let closure = vm.new_closure(FunctionId(0));
+        vm.push(Value::Closure(closure));
+        vm.call(closure, 0);
         vm
     }
 
@@ -71,288 +423,1498 @@ impl VM {
         self.chunk().constant_value(constant)
     }
 
-    fn binary_op<F: Fn(f64, f64) -> Value>(&mut self, f: F) -> bool {
-        let b = self.pop();
-        let a = self.pop();
+    pub fn read_constant_long(&mut self) -> Value {
+        let ip = self.frame().instruction_pointer;
+        let constant = self.chunk().constant_long(ip);
+        self.frame_mut().instruction_pointer.increment(3);
+        self.chunk().constant_value(constant)
+    }
+
+    fn fetch(&mut self) -> Option<Decoded> {
+        let frame = self.frames.last_mut()?;
+        let ip = frame.instruction_pointer;
+        #[cfg(feature = "predecode")]
+        let decoded = frame.decoded.get(ip.0).copied().flatten();
+        #[cfg(not(feature = "predecode"))]
+        let decoded = crate::chunk::decode(&frame.code, ip);
+        if let Some(decoded) = decoded {
+            frame.instruction_pointer.increment(decoded.size);
+        }
+        decoded
+    }
 
-        match (a, b) {
-            (Value::Number(a), Value::Number(b)) => {
-                self.push(f(a, b));
-                true
+    fn constant(&self, index: usize) -> Result<Value, Message> {
+        let constants = self.chunk().constants();
+        constants
+            .get(index)
+            .copied()
+            .ok_or(Message::MalformedBytecode)
+    }
+
+    fn module(&self) -> Option<ModuleId> {
+        let module = self.memory.function(self.frame().function).module?;
+        self.modules.get(&module).copied()
+    }
+
+    fn scope(&self, module: Option<ModuleId>) -> (&HashMap<StrId, Value>, &HashSet<StrId>) {
+        match module {
+            Some(module) => {
+                let module = self.memory.module(module);
+                (&module.globals, &module.constants)
+            }
+            None => (&self.globals, &self.constants),
+        }
+    }
+
+    fn scope_mut(
+        &mut self,
+        module: Option<ModuleId>,
+    ) -> (&mut HashMap<StrId, Value>, &mut HashSet<StrId>) {
+        match module {
+            Some(module) => {
+                let module = self.memory.module_mut(module);
+                (&mut module.globals, &mut module.constants)
+            }
+            None => (&mut self.globals, &mut self.constants),
+        }
+    }
+
+    fn name(&self, index: usize) -> Result<StrId, Message> {
+        self.constant(index)?
+            .as_string_id()
+            .ok_or(Message::MalformedBytecode)
+    }
+
+    fn assign_to_constant(&mut self, name: StrId) -> StepResult {
+        let name = self.memory.get_string(name).to_owned();
+        self.runtime_error(Message::AssignToConstantGlobal, &[("name", &name)]);
+        StepResult::Finished(InterpretResult::RuntimeError)
+    }
+
+    fn add(&mut self, a: Value, b: Value) -> Option<Value> {
+        if let (Some(a), Some(b)) = (a.as_string(), b.as_string()) {
+            if b.is_empty() {
+                return Some(Value::String(a));
+            }
+            return Some(Value::String(self.memory.string_concat(a, b)));
+        }
+
+        if let (Value::Int(a), Value::Int(b)) = (a, b) {
+            if let Some(sum) = a.checked_add(b) {
+                return Some(Value::Int(sum));
+            }
+        }
+
+        if let (Some(a), Some(b)) = (a.as_number(), b.as_number()) {
+            return Some(Value::Number(a + b));
+        }
+
+        if a.as_string().is_none() && b.as_string().is_none() {
+            self.runtime_error(Message::OperandsMustBeStringsOrNumbers, &[]);
+            return None;
+        }
+
+        if self.config.strict_concat {
+            self.runtime_error(Message::ConcatenateNonString, &[]);
+            return None;
+        }
+
+        let mut concat = String::new();
+        display_value(&a, &self.memory, &mut concat);
+        display_value(&b, &self.memory, &mut concat);
+        Some(Value::String(self.memory.string_intern(&concat)))
+    }
+
+    fn repeat(&mut self, s: &'static str, count: f64) -> Option<Value> {
+        if count < 0.0 || count.fract() != 0.0 {
+            self.runtime_error(Message::InvalidRepeatCount, &[]);
+            return None;
+        }
+        if s.is_empty() {
+            return Some(Value::String(s));
+        }
+        let limit = self.config.limits.max_string_len.min(MAX_REPEAT_LEN);
+        let len = (s.len() as f64) * count;
+        let mut repeated = String::new();
+        if len > limit as f64 || repeated.try_reserve_exact(len as usize).is_err() {
+            self.runtime_error(Message::RepeatTooLong, &[("limit", &limit.to_string())]);
+            return None;
+        }
+        for _ in 0..count as usize {
+            repeated.push_str(s);
+        }
+        Some(Value::String(self.memory.string_intern(&repeated)))
+    }
+
+    fn division_by_zero(&mut self, op_code: OpCode) -> Result<Option<StepResult>, Message> {
+        let (a, b) = (self.peek(1)?, self.peek(0)?);
+        if a.as_number().is_none() || b.as_number() != Some(0.0) {
+            return Ok(None);
+        }
+        let int = matches!((a, b), (Value::Int(_), Value::Int(_))) && op_code != OpCode::Divide;
+        let message = match self.config.division_by_zero {
+            DivisionByZero::Nil => {
+                self.pop()?;
+                self.pop()?;
+                self.push(Value::Nil);
+                return Ok(Some(StepResult::Running));
+            }
+            _ if int => Message::IntegerDivisionByZero,
+            DivisionByZero::Infinity => return Ok(None),
+            DivisionByZero::RuntimeError => Message::DivisionByZero,
+        };
+        self.runtime_error(message, &[]);
+        Ok(Some(StepResult::Finished(InterpretResult::RuntimeError)))
+    }
+
+    fn binary_op<I, F>(&mut self, int: I, float: F) -> Result<bool, Message>
+    where
+        I: Fn(i64, i64) -> Option<Value>,
+        F: Fn(f64, f64) -> Value,
+    {
+        let b = self.pop()?;
+        let a = self.pop()?;
+
+        if let (Value::Int(a), Value::Int(b)) = (a, b) {
+            if let Some(value) = int(a, b) {
+                self.push(value);
+                return Ok(true);
+            }
+        }
+
+        match (a.as_number(), b.as_number()) {
+            (Some(a), Some(b)) => {
+                self.push(float(a, b));
+                Ok(true)
             }
             _ => {
-                self.runtime_error("Operands must be numbers");
-                false
+                self.runtime_error(Message::OperandsMustBeNumbers, &[]);
+                Ok(false)
+            }
+        }
+    }
+
+    pub fn run(&mut self) -> Result<(), LoxError> {
+        self.instructions = 0;
+        loop {
+            match self.step() {
+                StepResult::Running => (),
+                StepResult::Finished(InterpretResult::OK) => {
+                    self.stack.clear();
+                    return Ok(());
+                }
+                StepResult::Finished(_) => return Err(self.take_error()),
             }
         }
     }
 
-    pub fn run(&mut self) -> InterpretResult {
+    pub fn run_until_break(&mut self) -> Result<InterpretResult, LoxError> {
+        let mut resuming = std::mem::take(&mut self.paused);
         loop {
-            #[cfg(debug_assertions)]
-            {
-                let c = self.frame().closure;
-                let f = self.memory.closure(c).function;
-                let ip = self.frame().instruction_pointer;
-                let chunk = &self.memory.function(f).chunk;
+            if !resuming && self.at_breakpoint() {
+                self.paused = true;
+                return Ok(InterpretResult::Paused);
+            }
+            resuming = false;
+            match self.step() {
+                StepResult::Running => (),
+                StepResult::Finished(InterpretResult::OK) => {
+                    self.stack.clear();
+                    return Ok(InterpretResult::OK);
+                }
+                StepResult::Finished(_) => return Err(self.take_error()),
+            }
+        }
+    }
+
+    pub fn set_breakpoint(&mut self, script: Option<&str>, line: usize) -> bool {
+        let locations = self.breakpoint_locations(script, line);
+        let found = !locations.is_empty();
+        self.breakpoints.extend(locations);
+        found
+    }
 
-                let output = &mut self.config.vm_debug;
+    pub fn clear_breakpoint(&mut self, script: Option<&str>, line: usize) -> bool {
+        let locations = self.breakpoint_locations(script, line);
+        let before = self.breakpoints.len();
+        self.breakpoints
+            .retain(|location| !locations.contains(location));
+        self.breakpoints.len() != before
+    }
+
+    fn breakpoint_locations(&self, script: Option<&str>, line: usize) -> Vec<(FunctionId, usize)> {
+        let mut locations = Vec::new();
+        for (id, function) in self.memory.functions().enumerate() {
+            let chunk = &function.chunk;
+            let offsets = chunk.line_index().remove(&line).unwrap_or_default();
+            locations.extend(
+                offsets
+                    .into_iter()
+                    .filter(|offset| chunk.file(InstructionPointer(*offset)) == script)
+                    .map(|offset| (FunctionId(id), offset)),
+            );
+        }
+        locations
+    }
 
-                write!(output, "          ").unwrap();
-                for value in self.stack.iter() {
-                    write!(output, "[ ").unwrap();
-                    print_value(value, &self.memory, output);
-                    write!(output, " ]").unwrap();
+    pub fn coverage(&self, script: Option<&str>) -> BTreeMap<usize, usize> {
+        let mut coverage = BTreeMap::new();
+        for (id, function) in self.memory.functions().enumerate() {
+            let chunk = &function.chunk;
+            let hits = self.hits.get(&FunctionId(id));
+            for (offset, decoded) in chunk.decode_all().iter().enumerate() {
+                let ip = InstructionPointer(offset);
+                if decoded.is_none() || chunk.file(ip) != script {
+                    continue;
                 }
-                write!(output, "\n").unwrap();
+                let count = hits.map(|hits| hits[offset]).unwrap_or_default();
+                let line = coverage.entry(chunk.line(ip)).or_default();
+                *line = count.max(*line);
+            }
+        }
+        coverage
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        match self.frames.last() {
+            Some(frame) if !self.breakpoints.is_empty() => self
+                .breakpoints
+                .contains(&(frame.function, frame.instruction_pointer.0)),
+            _ => false,
+        }
+    }
+
+    pub fn call_function(&mut self, callee: Value, args: &[Value]) -> Result<Value, LoxError> {
+        self.reenter(callee, args).map_err(|_| self.take_error())
+    }
+
+    fn reenter(&mut self, callee: Value, args: &[Value]) -> Result<Value, ()> {
+        let depth = self.frames.len();
+        self.push(callee);
+        self.stack.extend_from_slice(args);
+        if !self.call_value(callee, args.len()) {
+            return Err(());
+        }
+        while self.frames.len() > depth {
+            match self.step() {
+                StepResult::Running => (),
+                StepResult::Finished(InterpretResult::OK) => break,
+                StepResult::Finished(_) => return Err(()),
+            }
+        }
+        Ok(self.stack.pop().unwrap_or(Value::Nil))
+    }
+
+    pub fn global(&mut self, name: &str) -> Option<Value> {
+        let name = self.memory.string_id(name);
+        self.globals.get(&name).copied()
+    }
+
+    pub fn take_error(&mut self) -> LoxError {
+        self.error.take().unwrap_or_else(|| LoxError {
+            kind: ErrorKind::Compile,
+            message: "Unknown opcode".into(),
+            line: self.current_line().unwrap_or(0),
+            trace: StackTrace::default(),
+            value: None,
+        })
+    }
+
+    pub fn step_line(&mut self) -> StepResult {
+        if self.is_finished() {
+            return StepResult::Finished(InterpretResult::OK);
+        }
+        let line = self.current_line();
+        loop {
+            let result = self.step();
+            if matches!(result, StepResult::Finished(_)) || self.current_line() != line {
+                return result;
+            }
+        }
+    }
 
-                disassemble_instruction(&chunk, ip, &self.memory, output);
+    pub fn step(&mut self) -> StepResult {
+        if self.is_finished() {
+            // Either the program returned or its initial call was rejected
+            return StepResult::Finished(match self.error {
+                Some(_) => InterpretResult::RuntimeError,
+                None => InterpretResult::OK,
+            });
+        }
+        if self.config.vm_debug.is_enabled() {
+            let f = self.frame().function;
+            let ip = self.frame().instruction_pointer;
+            let chunk = &self.memory.function(f).chunk;
+
+            let output = &mut self.config.vm_debug;
+
+            write!(output, "          ").unwrap();
+            for value in self.stack.iter() {
+                write!(output, "[ ").unwrap();
+                print_value(value, &self.memory, output);
+                write!(output, " ]").unwrap();
             }
+            writeln!(output).unwrap();
+
+            disassemble_instruction(chunk, ip, &self.memory, output);
+        }
 
-            let op_code = match self.read_op_code() {
-                Some(x) => x,
-                None => return InterpretResult::CompileError,
+        let ip = self.frame().instruction_pointer;
+        let Some(Decoded { op_code, a, b, .. }) = self.fetch() else {
+            return StepResult::Finished(InterpretResult::CompileError);
+        };
+
+        if let Some(max) = self.config.max_instructions {
+            if self.instructions >= max {
+                self.runtime_error(
+                    Message::InstructionBudgetExceeded,
+                    &[("max", &max.to_string())],
+                );
+                if let Some(error) = self.error.as_mut() {
+                    error.kind = ErrorKind::Budget;
+                }
+                return StepResult::Finished(InterpretResult::Cancelled);
+            }
+            self.instructions += 1;
+        }
+
+        if self.config.coverage {
+            let f = self.frame().function;
+            let len = self.memory.function(f).chunk.code.len();
+            let hits = self.hits.entry(f).or_insert_with(|| vec![0; len]);
+            hits[ip.0] += 1;
+        }
+
+        if !self.config.observers.is_empty() {
+            let instruction = Instruction {
+                function: self.frame().function,
+                op_code,
+                instruction_pointer: ip,
+                line: self.chunk().line(ip),
+                stack_depth: self.stack.len(),
+                frame_depth: self.frames.len(),
             };
+            for observer in self.config.observers.iter() {
+                observer.borrow_mut().on_instruction(&instruction);
+            }
+        }
+
+        if !self.config.trace_sinks.is_empty() {
+            let function = self.frame().function;
+            let frame = self.frames.len() - 1;
+            let line = self.chunk().line(ip);
+            let before = self.stack.clone();
+            let result = self.execute(op_code, a, b);
+            if result.is_ok() {
+                let event =
+                    TraceEvent::new(function, frame, op_code, ip, line, &before, &self.stack);
+                for sink in self.config.trace_sinks.iter() {
+                    sink.borrow_mut().on_event(&event, &self.memory);
+                }
+            }
+            return self.finish_step(result);
+        }
+
+        let result = self.execute(op_code, a, b);
+        self.finish_step(result)
+    }
+
+    fn finish_step(&mut self, result: Result<StepResult, Message>) -> StepResult {
+        match result {
+            Ok(StepResult::Running) if self.stack.len() > self.config.max_stack => {
+                self.runtime_error(Message::StackOverflow, &[]);
+                StepResult::Finished(InterpretResult::RuntimeError)
+            }
+            Ok(result) => result,
+            Err(message) => {
+                self.runtime_error(message, &[]);
+                StepResult::Finished(InterpretResult::RuntimeError)
+            }
+        }
+    }
 
-            match op_code {
-                OpCode::Return => {
-                    let result = self.pop();
-                    let frame = self.frames.pop().unwrap();
-                    if self.frames.is_empty() {
-                        self.pop();
-                        return InterpretResult::OK;
+    fn execute(&mut self, op_code: OpCode, a: usize, b: usize) -> Result<StepResult, Message> {
+        match op_code {
+            OpCode::Return => {
+                let result = self.pop()?;
+                let frame = self.frames.pop().unwrap();
+                if !self.config.observers.is_empty() {
+                    for observer in self.config.observers.iter() {
+                        observer
+                            .borrow_mut()
+                            .on_return(frame.function, &self.memory);
                     }
+                }
+                self.stack.truncate(frame.slot_start);
+                self.push(result);
+                if self.frames.is_empty() {
+                    return Ok(StepResult::Finished(InterpretResult::OK));
+                }
+            }
+
+            OpCode::Pop => {
+                self.pop()?;
+            }
+
+            OpCode::Equal => {
+                let a = self.pop()?;
+                let b = self.pop()?;
+                let equal = self.memory.values_equal(a, b);
+                self.push(Value::Bool(equal));
+            }
 
-                    self.stack.truncate(frame.slot_start);
-                    self.push(result);
+            OpCode::Greater => {
+                if !self.binary_op(|a, b| Some(Value::Bool(a > b)), |a, b| Value::Bool(a > b))? {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                 }
+            }
 
-                OpCode::Pop => {
-                    self.pop();
+            OpCode::Less => {
+                if !self.binary_op(|a, b| Some(Value::Bool(a < b)), |a, b| Value::Bool(a < b))? {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                 }
+            }
 
-                OpCode::Equal => {
-                    let a = self.pop();
-                    let b = self.pop();
-                    self.push(Value::Bool(a == b));
+            OpCode::Add => {
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let Some(sum) = self.add(a, b) else {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                self.push(sum);
+            }
+            OpCode::AddLocals => {
+                let (a, b) = (self.stack[self.slot(a)?], self.stack[self.slot(b)?]);
+                let Some(sum) = self.add(a, b) else {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                self.push(sum);
+            }
+            OpCode::IncrementLocal => {
+                let slot = self.slot(a)?;
+                let constant = self.constant(b)?;
+                let Some(sum) = self.add(self.stack[slot], constant) else {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                self.stack[slot] = sum;
+                self.push(sum);
+            }
+            OpCode::Subtract => {
+                let int = |a: i64, b| a.checked_sub(b).map(Value::Int);
+                if !self.binary_op(int, |a, b| Value::Number(a - b))? {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                }
+            }
+            OpCode::Multiply => {
+                let (a, b) = (self.peek(1)?, self.peek(0)?);
+                let repeat = match (a.as_string(), b.as_string()) {
+                    (Some(s), None) => b.as_number().map(|n| (s, n)),
+                    (None, Some(s)) => a.as_number().map(|n| (s, n)),
+                    _ => None,
+                };
+                if let Some((s, count)) = repeat {
+                    let Some(repeated) = self.repeat(s, count) else {
+                        return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                    };
+                    self.pop()?;
+                    self.pop()?;
+                    self.push(repeated);
+                    return Ok(StepResult::Running);
                 }
+                let int = |a: i64, b| a.checked_mul(b).map(Value::Int);
+                if !self.binary_op(int, |a, b| Value::Number(a * b))? {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                }
+            }
+            OpCode::Divide => {
+                if let Some(result) = self.division_by_zero(op_code)? {
+                    return Ok(result);
+                }
+                if !self.binary_op(|_, _| None, |a, b| Value::Number(a / b))? {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                }
+            }
+            OpCode::FloorDivide | OpCode::Modulo => {
+                if let Some(result) = self.division_by_zero(op_code)? {
+                    return Ok(result);
+                }
+                let ok = if op_code == OpCode::FloorDivide {
+                    self.binary_op(
+                        |a, b| floor_div(a, b).map(Value::Int),
+                        |a, b| Value::Number((a / b).floor()),
+                    )?
+                } else {
+                    self.binary_op(
+                        |a, b| floor_mod(a, b).map(Value::Int),
+                        |a, b| Value::Number(float_mod(a, b)),
+                    )?
+                };
+                if !ok {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                }
+            }
 
-                OpCode::Greater => {
-                    if !self.binary_op(|a, b| Value::Bool(a > b)) {
-                        return InterpretResult::RuntimeError;
+            OpCode::ArgMissing => {
+                let missing = self.frame().arg_count <= a;
+                self.push(Value::Bool(missing));
+            }
+
+            OpCode::TypeCheck => {
+                let typ = self.pop()?;
+                let value = self.pop()?;
+                let is = match typ {
+                    Value::Type(typ) => value.value_type() == typ,
+                    Value::Class(class) => self.is_instance_of(value, class),
+                    _ => {
+                        self.runtime_error(Message::TypeCheckNeedsType, &[]);
+                        return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                     }
-                }
+                };
+                self.push(Value::Bool(is));
+            }
 
-                OpCode::Less => {
-                    if !self.binary_op(|a, b| Value::Bool(a < b)) {
-                        return InterpretResult::RuntimeError;
+            OpCode::Not => {
+                let value = self.pop()?;
+                self.push(Value::Bool(is_falsey(value)));
+            }
+
+            OpCode::Negate => {
+                let value = self.pop()?;
+
+                match value {
+                    Value::Number(n) => self.push(Value::Number(-n)),
+                    Value::Int(n) => self.push(match n.checked_neg() {
+                        Some(n) => Value::Int(n),
+                        None => Value::Number(-(n as f64)),
+                    }),
+                    _ => {
+                        self.runtime_error(Message::OperandMustBeNumber, &[]);
+                        return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                     }
                 }
+            }
 
-                OpCode::Add => {
-                    let b = self.pop();
-                    let a = self.pop();
-                    match (a.as_string(), b.as_string()) {
-                        (Some(a), Some(b)) => {
-                            let concat = {
-                                let mut concat = a.to_owned();
-                                concat.push_str(b);
-                                self.memory.string_intern(&concat)
-                            };
-                            self.push(Value::String(concat));
-                            continue;
-                        }
-                        _ => (),
-                    }
+            OpCode::Constant | OpCode::ConstantLong => {
+                let constant = self.constant(a)?;
+                self.push(constant);
+            }
 
-                    match (a.as_number(), b.as_number()) {
-                        (Some(a), Some(b)) => {
-                            self.push(Value::Number(a + b));
-                            continue;
-                        }
-                        _ => (),
+            OpCode::Nil => self.push(Value::Nil),
+
+            OpCode::True => self.push(Value::Bool(true)),
+
+            OpCode::False => self.push(Value::Bool(false)),
+
+            OpCode::Print => {
+                let val = self.pop()?;
+                if self.config.print_hook.is_some() {
+                    let mut text = String::new();
+                    display_value(&val, &self.memory, &mut text);
+                    let ip = self.frame().instruction_pointer.minus(1);
+                    let file = self.chunk().file(ip).map(str::to_owned);
+                    let line = self.chunk().line(ip);
+                    if let Some(hook) = self.config.print_hook.as_mut() {
+                        hook(file.as_deref(), line, &text);
                     }
+                } else {
+                    display_value(&val, &self.memory, &mut self.config.print_output);
+                    writeln!(&mut self.config.print_output).unwrap();
+                }
+            }
 
-                    self.runtime_error("Operands must be strings or numbers");
-                    return InterpretResult::RuntimeError;
+            OpCode::DefineGlobal
+            | OpCode::DefineGlobalLong
+            | OpCode::DefineConst
+            | OpCode::DefineConstLong => {
+                let global_name = self.name(a)?;
+                let module = self.module();
+                if self.scope(module).1.contains(&global_name) {
+                    return Ok(self.assign_to_constant(global_name));
                 }
-                OpCode::Subtract => {
-                    if !self.binary_op(|a, b| Value::Number(a - b)) {
-                        return InterpretResult::RuntimeError;
-                    }
+                let val = self.pop()?;
+                let (globals, constants) = self.scope_mut(module);
+                globals.insert(global_name, val);
+                if matches!(op_code, OpCode::DefineConst | OpCode::DefineConstLong) {
+                    constants.insert(global_name);
                 }
-                OpCode::Multiply => {
-                    if !self.binary_op(|a, b| Value::Number(a * b)) {
-                        return InterpretResult::RuntimeError;
+            }
+
+            OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                let global_name = self.name(a)?;
+                let module = self.module();
+                let value = self.scope(module).0.get(&global_name);
+                match value.or_else(|| self.globals.get(&global_name)) {
+                    Some(value) => self.push(*value),
+                    None => {
+                        let name = self.memory.get_string(global_name).to_owned();
+                        self.runtime_error(Message::UndefinedVariable, &[("name", &name)]);
+                        return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                     }
                 }
-                OpCode::Divide => {
-                    if !self.binary_op(|a, b| Value::Number(a / b)) {
-                        return InterpretResult::RuntimeError;
+            }
+
+            OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                let global_name = self.name(a)?;
+                let module = self.module();
+                if self.scope(module).1.contains(&global_name) {
+                    return Ok(self.assign_to_constant(global_name));
+                }
+                let val = self.peek(0)?;
+                match self.scope_mut(module).0.entry(global_name) {
+                    Entry::Occupied(mut e) => {
+                        e.insert(val);
+                    }
+                    Entry::Vacant(_) => {
+                        let name = self.memory.get_string(global_name).to_owned();
+                        self.runtime_error(Message::UndefinedVariable, &[("name", &name)]);
+                        return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                     }
                 }
+            }
+
+            OpCode::GetLocal | OpCode::GetLocalLong => {
+                let value = self.stack[self.slot(a)?];
+                self.push(value);
+            }
+
+            OpCode::SetLocal | OpCode::SetLocalLong => {
+                let slot = self.slot(a)?;
+                let value = self.peek(0)?;
+                self.stack[slot] = value;
+            }
 
-                OpCode::Not => {
-                    let value = self.pop();
-                    self.push(Value::Bool(is_falsey(value)));
+            OpCode::JumpIfFalse => {
+                if is_falsey(self.peek(0)?) {
+                    self.frame_mut().instruction_pointer.increment(a);
                 }
+            }
 
-                OpCode::Negate => {
-                    let value = self.pop();
+            OpCode::JumpIfNil => {
+                if self.peek(0)? == Value::Nil {
+                    self.frame_mut().instruction_pointer.increment(a);
+                }
+            }
 
-                    match value {
-                        Value::Number(n) => self.push(Value::Number(-n)),
+            OpCode::JumpIfLess
+            | OpCode::JumpIfNotLess
+            | OpCode::JumpIfGreater
+            | OpCode::JumpIfNotGreater => {
+                let offset = a;
+                let b = self.pop()?;
+                let a = self.pop()?;
+                let (less, greater) = match (a, b) {
+                    (Value::Int(a), Value::Int(b)) => (a < b, a > b),
+                    _ => match (a.as_number(), b.as_number()) {
+                        (Some(a), Some(b)) => (a < b, a > b),
                         _ => {
-                            self.runtime_error("Operand must be a number");
-                            return InterpretResult::RuntimeError;
+                            self.runtime_error(Message::OperandsMustBeNumbers, &[]);
+                            return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                         }
-                    }
+                    },
+                };
+                let jump = match op_code {
+                    OpCode::JumpIfLess => less,
+                    OpCode::JumpIfNotLess => !less,
+                    OpCode::JumpIfGreater => greater,
+                    _ => !greater,
+                };
+                if jump {
+                    self.frame_mut().instruction_pointer.increment(offset);
                 }
+            }
 
-                OpCode::Constant => {
-                    let constant = self.read_constant();
-                    self.push(constant);
-                }
+            OpCode::Jump => {
+                self.frame_mut().instruction_pointer.increment(a);
+            }
 
-                OpCode::Nil => self.push(Value::Nil),
+            OpCode::Loop => {
+                self.frame_mut().instruction_pointer.decrement(a);
+            }
 
-                OpCode::True => self.push(Value::Bool(true)),
+            OpCode::Call => {
+                let arg_count = a;
+                if !self.call_value(self.peek(arg_count)?, arg_count) {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                }
+            }
 
-                OpCode::False => self.push(Value::Bool(false)),
+            OpCode::TailCall => {
+                let arg_count = a;
+                let callee = self.peek(arg_count)?;
+                let called = match callee.as_closure() {
+                    Some(closure) => self.tail_call(closure, arg_count),
+                    None => self.call_value(callee, arg_count),
+                };
+                if !called {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                }
+            }
 
-                OpCode::Print => {
-                    let val = self.pop();
-                    print_value(&val, &self.memory, &mut self.config.print_output);
-                    write!(&mut self.config.print_output, "\n").unwrap();
+            OpCode::Closure | OpCode::ClosureLong => {
+                if let Some(function) = self.constant(a)?.as_function() {
+                    let closure = self.new_closure(function);
+                    self.push(Value::Closure(closure));
+                } else {
+                    self.runtime_error(Message::ExpectedClosure, &[]);
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                 }
+            }
 
-                OpCode::DefineGlobal => {
-                    let global_name = self.read_constant().as_string_id().unwrap();
-                    let val = self.pop();
-                    self.globals.insert(global_name, val);
+            OpCode::Class | OpCode::ClassLong => {
+                let name = self.name(a)?;
+                self.maybe_collect();
+                let class = self.memory.new_class(name);
+                self.push(Value::Class(class));
+            }
+
+            OpCode::GetProperty | OpCode::GetPropertyLong => {
+                let name = self.name(a)?;
+                if let Some(module) = self.peek(0)?.as_module() {
+                    let Some(value) = self.memory.module(module).globals.get(&name).copied() else {
+                        let name = self.memory.get_string(name).to_owned();
+                        self.runtime_error(Message::UndefinedProperty, &[("name", &name)]);
+                        return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                    };
+                    self.pop()?;
+                    self.push(value);
+                    return Ok(StepResult::Running);
+                }
+                let receiver = self.peek(0)?;
+                if receiver.as_foreign().is_some() {
+                    let args_start = self.stack.len() - 1;
+                    let getter = self.foreign_accessor(receiver, name, false);
+                    let Some(value) =
+                        getter.and_then(|getter| self.call_native(getter, args_start))
+                    else {
+                        return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                    };
+                    self.pop()?;
+                    self.push(value);
+                    return Ok(StepResult::Running);
+                }
+                let Some(instance) = receiver.as_instance() else {
+                    self.runtime_error(Message::OnlyInstancesHaveProperties, &[]);
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                let class = self.memory.instance(instance).class;
+                let value = self
+                    .cached_field_slot(class, name, false)
+                    .and_then(|slot| self.memory.instance(instance).slot(slot));
+                if let Some(value) = value {
+                    self.pop()?;
+                    self.push(value);
+                } else if !self.bind_method(Some(self.memory.instance(instance).class), name)? {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                 }
+            }
 
-                OpCode::GetGlobal => {
-                    let global_name = self.read_constant().as_string_id().unwrap();
-                    match self.globals.get(&global_name) {
-                        Some(value) => self.push(value.clone()),
-                        None => {
-                            let name = self.memory.get_string(global_name);
-                            self.runtime_error(&format!("Undefined variable '{name}'"));
-                            return InterpretResult::RuntimeError;
-                        }
+            OpCode::SetProperty | OpCode::SetPropertyLong => {
+                let name = self.name(a)?;
+                let receiver = self.peek(1)?;
+                if receiver.as_foreign().is_some() {
+                    let args_start = self.stack.len() - 2;
+                    let setter = self.foreign_accessor(receiver, name, true);
+                    if setter
+                        .and_then(|setter| self.call_native(setter, args_start))
+                        .is_none()
+                    {
+                        return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                     }
+                    let value = self.pop()?;
+                    self.pop()?;
+                    self.push(value);
+                    return Ok(StepResult::Running);
                 }
+                let Some(instance) = receiver.as_instance() else {
+                    self.runtime_error(Message::OnlyInstancesHaveFields, &[]);
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                let class = self.memory.instance(instance).class;
+                let slot = self.cached_field_slot(class, name, true);
+                let value = self.pop()?;
+                if let Some(slot) = slot {
+                    self.memory.instance_mut(instance).set_slot(slot, value);
+                }
+                self.pop()?;
+                self.push(value);
+            }
 
-                OpCode::SetGlobal => {
-                    let global_name = self.read_constant().as_string_id().unwrap();
-                    let val = self.peek(0);
-                    match self.globals.entry(global_name) {
-                        Entry::Occupied(mut e) => {
-                            e.insert(val);
-                        }
-                        Entry::Vacant(_) => {
-                            let name = self.memory.get_string(global_name);
-                            self.runtime_error(&format!("Undefined variable' {name}'"));
-                            return InterpretResult::RuntimeError;
-                        }
-                    }
+            OpCode::Method | OpCode::MethodLong => {
+                let name = self.name(a)?;
+                let method = self.peek(0)?.as_closure();
+                let class = self.peek(1)?.as_class();
+                let (Some(method), Some(class)) = (method, class) else {
+                    return Err(Message::MalformedBytecode);
+                };
+                self.memory.closure_mut(method).class = Some(class);
+                self.memory.class_mut(class).methods.insert(name, method);
+                self.inline_caches.clear();
+                self.pop()?;
+            }
+
+            OpCode::Inherit => {
+                let Some(superclass) = self.peek(1)?.as_class() else {
+                    self.runtime_error(Message::SuperclassMustBeClass, &[]);
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                let subclass = self.peek(0)?.as_class().ok_or(Message::MalformedBytecode)?;
+                let methods = self.memory.class(superclass).methods.clone();
+                let subclass = self.memory.class_mut(subclass);
+                subclass.superclass = Some(superclass);
+                subclass.methods.extend(methods);
+                self.inline_caches.clear();
+                self.pop()?;
+                self.pop()?;
+            }
+
+            OpCode::GetSuper | OpCode::GetSuperLong => {
+                let name = self.name(a)?;
+                let superclass = self.superclass();
+                if !self.bind_method(superclass, name)? {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                 }
+            }
 
-                OpCode::GetLocal => {
-                    let slot = self.read_byte() as usize;
-                    let slot = self.frame().slot_start + slot;
-                    let value = self.stack[slot].clone();
-                    self.push(value);
+            OpCode::Invoke | OpCode::InvokeLong => {
+                let name = self.name(a)?;
+                let arg_count = b;
+                if !self.invoke(name, arg_count)? {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                 }
+            }
 
-                OpCode::SetLocal => {
-                    let slot = self.read_byte() as usize;
-                    let slot = self.frame().slot_start + slot;
-                    let value = self.peek(0);
-                    self.stack[slot] = value;
+            OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                let name = self.name(a)?;
+                let arg_count = b;
+                let superclass = self.superclass();
+                let Some(method) = superclass.and_then(|class| self.cached_method(class, name))
+                else {
+                    let name = self.memory.get_string(name).to_owned();
+                    self.runtime_error(Message::UndefinedProperty, &[("name", &name)]);
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                if !self.call(method, arg_count) {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                 }
+            }
 
-                OpCode::JumpIfFalse => {
-                    let offset = self.read_short();
-                    if is_falsey(self.peek(0)) {
-                        self.frame_mut().instruction_pointer.increment(offset);
-                    }
+            OpCode::BuildList => {
+                let count = a;
+                if count > self.stack.len() {
+                    return Err(Message::StackUnderflow);
                 }
+                self.maybe_collect();
+                let items = self.stack.split_off(self.stack.len() - count);
+                let list = self.memory.new_list(items);
+                self.push(Value::List(list));
+            }
 
-                OpCode::Jump => {
-                    let offset = self.read_short();
-                    self.frame_mut().instruction_pointer.increment(offset);
+            OpCode::BuildTuple => {
+                let count = a;
+                if count > self.stack.len() {
+                    return Err(Message::StackUnderflow);
+                }
+                self.maybe_collect();
+                let items = self.stack.split_off(self.stack.len() - count);
+                let tuple = self.memory.new_list(items);
+                self.push(Value::Tuple(tuple));
+            }
+
+            OpCode::Unpack => {
+                let value = self.peek(0)?;
+                let Some(items) = value.as_tuple().or(value.as_list()) else {
+                    self.runtime_error(Message::NotDestructurable, &[]);
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                let items = &self.memory.list(items).items;
+                if items.len() != a {
+                    let got = items.len().to_string();
+                    self.runtime_error(
+                        Message::DestructureCountMismatch,
+                        &[("expected", &a.to_string()), ("got", &got)],
+                    );
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                 }
+                let items = items.clone();
+                self.pop()?;
+                self.stack.extend(items);
+            }
 
-                OpCode::Loop => {
-                    let offset = self.read_short();
-                    self.frame_mut().instruction_pointer.decrement(offset);
+            OpCode::Dup => {
+                if a > self.stack.len() {
+                    return Err(Message::StackUnderflow);
                 }
+                self.stack.extend_from_within(self.stack.len() - a..);
+            }
 
-                OpCode::Call => {
-                    let arg_count = self.read_byte() as usize;
-                    if !self.call_value(self.peek(arg_count), arg_count) {
-                        return InterpretResult::RuntimeError;
-                    }
+            OpCode::IndexGet => {
+                let chars = self.string_value(self.peek(1)?).map(|s| s.chars().count());
+                let value = if let Some(len) = chars {
+                    let Some(index) = self.index(self.peek(0)?, len) else {
+                        return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                    };
+                    let string = self.string_value(self.peek(1)?).unwrap();
+                    let c = string.chars().nth(index).unwrap();
+                    Value::String(self.memory.string_intern(c.encode_utf8(&mut [0; 4])))
+                } else if let Some(list) = self.peek(1)?.as_list().or(self.peek(1)?.as_tuple()) {
+                    let len = self.memory.list(list).items.len();
+                    let Some(index) = self.index(self.peek(0)?, len) else {
+                        return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                    };
+                    self.memory.list(list).items[index]
+                } else {
+                    self.runtime_error(Message::NotIndexable, &[]);
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                self.pop()?;
+                self.pop()?;
+                self.push(value);
+            }
+
+            OpCode::IndexSet => {
+                let Some(list) = self.peek(2)?.as_list() else {
+                    self.runtime_error(Message::OnlyListsSupportIndexAssignment, &[]);
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                let len = self.memory.list(list).items.len();
+                let Some(index) = self.index(self.peek(1)?, len) else {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                let value = self.pop()?;
+                self.memory.list_mut(list).items[index] = value;
+                self.pop()?;
+                self.pop()?;
+                self.push(value);
+            }
+
+            OpCode::Import | OpCode::ImportLong => {
+                let function = self.constant(a)?.as_function();
+                let function = function.ok_or(Message::MalformedBytecode)?;
+                if let Some(module) = self.modules.get(&function) {
+                    self.push(Value::Module(*module));
+                    self.push(Value::Nil);
+                    return Ok(StepResult::Running);
+                }
+                let module = self.memory.new_module(function);
+                self.modules.insert(function, module);
+                self.push(Value::Module(module));
+                let closure = self.new_closure(function);
+                self.push(Value::Closure(closure));
+                if !self.call(closure, 0) {
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
                 }
+            }
 
-                OpCode::Closure => {
-                    if let Some(function) = self.read_constant().as_function() {
-                        let closure = self.new_closure(function);
-                        self.push(Value::Closure(closure));
-                    } else {
-                        self.runtime_error("Expected closure");
-                        return InterpretResult::RuntimeError;
-                    }
+            OpCode::Throw => {
+                let value = self.pop()?;
+                let mut text = String::new();
+                display_value(&value, &self.memory, &mut text);
+                self.runtime_error(Message::UncaughtException, &[("value", &text)]);
+                if let Some(error) = self.error.as_mut() {
+                    error.kind = ErrorKind::Uncaught;
+                    error.value = Some(value);
                 }
+                return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+            }
+
+            OpCode::Assert => {
+                let message = self.pop()?;
+                if is_falsey(self.pop()?) {
+                    let mut text = String::new();
+                    display_value(&message, &self.memory, &mut text);
+                    let line = self.chunk().line(self.frame().instruction_pointer.minus(1));
+                    self.runtime_error(
+                        Message::AssertionFailed,
+                        &[("line", &line.to_string()), ("message", &text)],
+                    );
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                }
+            }
+
+            OpCode::Len => {
+                let value = self.pop()?;
+                let len = if let Some(len) = self.string_value(value).map(|s| s.chars().count()) {
+                    len
+                } else if let Some(list) = value.as_list().or(value.as_tuple()) {
+                    self.memory.list(list).items.len()
+                } else {
+                    self.runtime_error(Message::NotIterable, &[]);
+                    return Ok(StepResult::Finished(InterpretResult::RuntimeError));
+                };
+                self.push(Value::Number(len as f64));
             }
         }
+
+        Ok(StepResult::Running)
     }
 
     pub fn new_closure(&mut self, function: FunctionId) -> ClosureId {
+        self.maybe_collect();
         self.memory.new_closure(function)
     }
 
+    fn maybe_collect(&mut self) {
+        if self.config.gc.stress || self.memory.objects() >= self.next_gc {
+            self.collect_garbage();
+        }
+    }
+
+    pub fn collect_garbage(&mut self) -> usize {
+        let roots = self
+            .stack
+            .iter()
+            .copied()
+            .chain(self.globals.values().copied())
+            .chain(
+                self.frames
+                    .iter()
+                    .map(|frame| Value::Closure(frame.closure)),
+            );
+        let freed = self.memory.collect(roots);
+        if freed > 0 {
+            self.inline_caches.clear();
+        }
+
+        let gc = self.config.gc;
+        self.next_gc = (self.memory.objects() * gc.heap_grow_factor).max(gc.initial_heap);
+        freed
+    }
+
     fn call_value(&mut self, value: Value, arg_count: usize) -> bool {
         if let Some(c_id) = value.as_closure() {
             self.call(c_id, arg_count)
+        } else if let Some(class) = value.as_class() {
+            self.maybe_collect();
+            let instance = self.memory.new_instance(class);
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = Value::Instance(instance);
+            let initializer = self.memory.class(class).methods.get(&self.init_string);
+            if let Some(initializer) = initializer.copied() {
+                self.call(initializer, arg_count)
+            } else if arg_count != 0 {
+                self.runtime_error(
+                    Message::WrongArgumentCount,
+                    &[("expected", "0"), ("got", &arg_count.to_string())],
+                );
+                false
+            } else {
+                true
+            }
+        } else if let Some(b_id) = value.as_bound_method() {
+            let bound = self.memory.bound_method(b_id);
+            let (receiver, method) = (bound.receiver, bound.method);
+            let slot = self.stack.len() - arg_count - 1;
+            self.stack[slot] = receiver;
+            self.call(method, arg_count)
         } else if let Some(f_id) = value.as_native_function() {
-            let native = &self.memory.native(f_id);
+            let native = self.memory.native(f_id);
+            if !native.arity.accepts(arg_count) {
+                let expected = native.arity.to_string();
+                let name = self.memory.get_string(native.name).to_owned();
+                self.runtime_error(
+                    Message::NativeWrongArgumentCount,
+                    &[
+                        ("expected", &expected),
+                        ("got", &arg_count.to_string()),
+                        ("name", &name),
+                    ],
+                );
+                return false;
+            }
             let init_stack = self.stack.len() - arg_count;
-            let args = &self.stack[init_stack..];
-            let res = (native.callable)(args);
-            self.stack.truncate(init_stack);
+            let Some(res) = self.call_native(f_id, init_stack) else {
+                return false;
+            };
+            self.stack.truncate(init_stack - 1);
             self.push(res);
             true
         } else {
-            self.runtime_error("Can only call functions and classes");
+            self.runtime_error(Message::NotCallable, &[]);
             false
         }
     }
 
+    fn call_native(&mut self, f_id: NativeFunctionId, args_start: usize) -> Option<Value> {
+        let native_depth = self.native_calls.len();
+        self.native_calls.push((self.frames.len(), f_id));
+        let callable = self.memory.native(f_id).callable.clone();
+        let result = panic::catch_unwind(AssertUnwindSafe(|| match callable {
+            NativeFn::Memory(callable) => callable(&mut self.memory, &self.stack[args_start..]),
+            NativeFn::Context(callable) => {
+                let args = self.stack[args_start..].to_vec();
+                callable(&mut NativeContext { vm: self }, &args)
+            }
+        }));
+        let result = match result {
+            _ if self.error.is_some() => None,
+            Ok(Ok(res)) => Some(res),
+            Ok(Err(error)) => {
+                self.native_failed(f_id, &error.message);
+                None
+            }
+            Err(payload) => {
+                let message = payload
+                    .downcast_ref::<&str>()
+                    .map(|s| s.to_string())
+                    .or_else(|| payload.downcast_ref::<String>().cloned())
+                    .unwrap_or_else(|| "native function panicked".into());
+                self.native_failed(f_id, &message);
+                None
+            }
+        };
+        self.native_calls.truncate(native_depth);
+        result
+    }
+
+    fn native_failed(&mut self, f_id: NativeFunctionId, message: &str) {
+        let name = self.memory.native(f_id).name;
+        let name = self.memory.get_string(name).to_owned();
+        self.runtime_error(
+            Message::NativeFailed,
+            &[("name", &name), ("message", message)],
+        );
+    }
+
+    fn foreign_accessor(
+        &mut self,
+        receiver: Value,
+        name: StrId,
+        setter: bool,
+    ) -> Option<NativeFunctionId> {
+        let class = self.memory.foreign_class(receiver.as_foreign()?);
+        let accessors = class.map(|class| match setter {
+            true => &class.setters,
+            false => &class.getters,
+        });
+        let accessor = accessors.and_then(|accessors| accessors.get(&name).copied());
+        if accessor.is_none() {
+            let name = self.memory.get_string(name).to_owned();
+            self.runtime_error(Message::UndefinedProperty, &[("name", &name)]);
+        }
+        accessor
+    }
+
+    fn string_value(&self, value: Value) -> Option<&str> {
+        self.memory.string_value(value)
+    }
+
+    fn index(&mut self, index: Value, len: usize) -> Option<usize> {
+        let Some(index) = index.as_number().filter(|n| n.fract() == 0.0) else {
+            self.runtime_error(Message::IndexMustBeInteger, &[]);
+            return None;
+        };
+        if index < 0.0 || index as usize >= len {
+            self.runtime_error(
+                Message::IndexOutOfRange,
+                &[("index", &index.to_string()), ("len", &len.to_string())],
+            );
+            return None;
+        }
+        Some(index as usize)
+    }
+
+    fn is_instance_of(&self, value: Value, class: ClassId) -> bool {
+        let mut current = value.as_instance().map(|id| self.memory.instance(id).class);
+        while let Some(id) = current {
+            if id == class {
+                return true;
+            }
+            current = self.memory.class(id).superclass;
+        }
+        false
+    }
+
+    fn superclass(&self) -> Option<ClassId> {
+        let class = self.memory.closure(self.frame().closure).class?;
+        self.memory.class(class).superclass
+    }
+
+    fn invoke(&mut self, name: StrId, arg_count: usize) -> Result<bool, Message> {
+        let receiver = self.peek(arg_count)?;
+        let slot = self.stack.len() - arg_count - 1;
+        if let Some(module) = receiver.as_module() {
+            let Some(value) = self.memory.module(module).globals.get(&name).copied() else {
+                let name = self.memory.get_string(name).to_owned();
+                self.runtime_error(Message::UndefinedProperty, &[("name", &name)]);
+                return Ok(false);
+            };
+            self.stack[slot] = value;
+            return Ok(self.call_value(value, arg_count));
+        }
+        if let Some(foreign) = receiver.as_foreign() {
+            let method = self.memory.foreign_class(foreign);
+            let Some(method) = method.and_then(|class| class.methods.get(&name).copied()) else {
+                let name = self.memory.get_string(name).to_owned();
+                self.runtime_error(Message::UndefinedProperty, &[("name", &name)]);
+                return Ok(false);
+            };
+            self.stack.insert(slot, Value::NativeFunction(method));
+            return Ok(self.call_value(Value::NativeFunction(method), arg_count + 1));
+        }
+        let Some(instance) = receiver.as_instance() else {
+            self.runtime_error(Message::OnlyInstancesHaveProperties, &[]);
+            return Ok(false);
+        };
+        if let Some(value) = self.memory.field(instance, name) {
+            self.stack[slot] = value;
+            return Ok(self.call_value(value, arg_count));
+        }
+        let class = self.memory.instance(instance).class;
+        let Some(method) = self.cached_method(class, name) else {
+            let name = self.memory.get_string(name).to_owned();
+            self.runtime_error(Message::UndefinedProperty, &[("name", &name)]);
+            return Ok(false);
+        };
+        Ok(self.call(method, arg_count))
+    }
+
+    fn inline_cache(&self) -> Option<InlineCache> {
+        let frame = self.frame();
+        let caches = self.inline_caches.get(frame.function.0)?;
+        caches.get(frame.instruction_pointer.0).copied().flatten()
+    }
+
+    fn set_inline_cache(&mut self, cache: InlineCache) {
+        let frame = self.frame();
+        let (function, site) = (frame.function, frame.instruction_pointer.0);
+        if self.inline_caches.len() <= function.0 {
+            self.inline_caches.resize(function.0 + 1, Vec::new());
+        }
+        let len = self.memory.function(function).chunk.code.len();
+        let caches = &mut self.inline_caches[function.0];
+        if caches.len() <= site {
+            caches.resize(len.max(site + 1), None);
+        }
+        caches[site] = Some(cache);
+    }
+
+    fn cached_method(&mut self, class: ClassId, name: StrId) -> Option<ClosureId> {
+        if let Some(InlineCache::Method {
+            class: cached,
+            method,
+        }) = self.inline_cache()
+        {
+            if cached == class {
+                return Some(method);
+            }
+        }
+
+        let method = self.find_method(Some(class), name)?;
+        self.set_inline_cache(InlineCache::Method { class, method });
+        Some(method)
+    }
+
+    fn cached_field_slot(&mut self, class: ClassId, name: StrId, add: bool) -> Option<usize> {
+        if let Some(InlineCache::Field {
+            class: cached,
+            slot,
+        }) = self.inline_cache()
+        {
+            if cached == class {
+                return Some(slot);
+            }
+        }
+
+        let slot = match add {
+            true => self.memory.add_field_slot(class, name),
+            false => self.memory.field_slot(class, name)?,
+        };
+        self.set_inline_cache(InlineCache::Field { class, slot });
+        Some(slot)
+    }
+
+    fn find_method(&self, class: Option<ClassId>, name: StrId) -> Option<ClosureId> {
+        self.memory.class(class?).methods.get(&name).copied()
+    }
+
+    fn bind_method(&mut self, class: Option<ClassId>, name: StrId) -> Result<bool, Message> {
+        let Some(method) = self.find_method(class, name) else {
+            let name = self.memory.get_string(name).to_owned();
+            self.runtime_error(Message::UndefinedProperty, &[("name", &name)]);
+            return Ok(false);
+        };
+        self.maybe_collect();
+        let receiver = self.pop()?;
+        let bound = self.memory.new_bound_method(receiver, method);
+        self.push(Value::BoundMethod(bound));
+        Ok(true)
+    }
+
+    fn check_arity(&mut self, c_id: ClosureId, arg_count: usize) -> bool {
+        let f_id = self.memory.closure(c_id).function;
+        let function = self.memory.function(f_id);
+        let (min, max) = (function.min_arity, function.arity);
+        if arg_count < min || (arg_count > max && !function.variadic) {
+            let expected = match (min == max, function.variadic) {
+                (_, true) => format!("at least {min}"),
+                (true, false) => max.to_string(),
+                (false, false) => format!("{min} to {max}"),
+            };
+            self.runtime_error(
+                Message::WrongArgumentCount,
+                &[("expected", &expected), ("got", &arg_count.to_string())],
+            );
+            return false;
+        }
+        true
+    }
+
+    fn tail_call(&mut self, c_id: ClosureId, arg_count: usize) -> bool {
+        if !self.check_arity(c_id, arg_count) {
+            return false;
+        }
+
+        let frame = self.frames.pop().unwrap();
+        if !self.config.observers.is_empty() {
+            for observer in self.config.observers.iter() {
+                observer
+                    .borrow_mut()
+                    .on_return(frame.function, &self.memory);
+            }
+        }
+        let callee = self.stack.len() - arg_count - 1;
+        self.stack.drain(frame.slot_start..callee);
+        self.call(c_id, arg_count)
+    }
+
     pub fn call(&mut self, c_id: ClosureId, arg_count: usize) -> bool {
-        let closure = &self.memory.closure(c_id);
-        let f_id = closure.function;
-        let arity = self.memory.function(f_id).arity;
-        if arg_count != arity {
-            self.runtime_error(&format!("Expected {arity} arguments but got {arg_count}"));
+        if !self.check_arity(c_id, arg_count) {
             return false;
         }
+        let f_id = self.memory.closure(c_id).function;
+        let function = self.memory.function(f_id);
+        let variadic = function.variadic;
+        let Some(positional) = function.arity.checked_sub(variadic as usize) else {
+            self.runtime_error(Message::MalformedBytecode, &[]);
+            return false;
+        };
 
-        if self.frames.len() == 64 {
-            self.runtime_error("Stack overflow");
+        if self.frames.len() >= self.config.max_frames || self.stack.len() > self.config.max_stack {
+            self.runtime_error(Message::StackOverflow, &[]);
             return false;
         }
 
-        self.frames.push(CallFrame {
+        for observer in self.config.observers.iter() {
+            observer.borrow_mut().on_call(f_id, &self.memory);
+        }
+
+        let frame = CallFrame {
             closure: c_id,
+            function: f_id,
             instruction_pointer: InstructionPointer(0),
             slot_start: self.stack.len() - arg_count - 1,
+            arg_count,
+            #[cfg(feature = "predecode")]
+            decoded: self.decoded(f_id),
+            #[cfg(not(feature = "predecode"))]
+            code: self.code(f_id),
+        };
+        self.frames.push(frame);
+        if variadic {
+            self.maybe_collect();
+        }
+        let rest = variadic.then(|| {
+            let extra = arg_count.saturating_sub(positional);
+            self.stack.split_off(self.stack.len() - extra)
         });
+        let passed = arg_count.min(positional);
+        self.stack
+            .resize(self.stack.len() + positional - passed, Value::Nil);
+        if let Some(rest) = rest {
+            let list = self.memory.new_list(rest);
+            self.push(Value::List(list));
+        }
+        self.frame_mut().arg_count = passed;
         true
     }
 
+    #[cfg(feature = "predecode")]
+    fn decoded(&mut self, f_id: FunctionId) -> Rc<[Option<Decoded>]> {
+        let memory = &self.memory;
+        self.decoded
+            .entry(f_id)
+            .or_insert_with(|| memory.function(f_id).chunk.decode_all().into())
+            .clone()
+    }
+
+    #[cfg(not(feature = "predecode"))]
+    fn code(&mut self, f_id: FunctionId) -> Rc<[u8]> {
+        let memory = &self.memory;
+        self.code
+            .entry(f_id)
+            .or_insert_with(|| memory.function(f_id).chunk.code.as_slice().into())
+            .clone()
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    pub fn current_chunk(&self) -> Option<&Chunk> {
+        if self.is_finished() {
+            None
+        } else {
+            Some(self.chunk())
+        }
+    }
+
+    pub fn current_line(&self) -> Option<usize> {
+        let chunk = self.current_chunk()?;
+        Some(chunk.line(self.frame().instruction_pointer))
+    }
+
+    pub fn current_frame(&self) -> Option<&CallFrame> {
+        self.frames.last()
+    }
+
+    pub fn locals(&self) -> &[Value] {
+        match self.frames.len() {
+            0 => &[],
+            len => self.frame_slots(len - 1),
+        }
+    }
+
+    pub fn stack(&self) -> &[Value] {
+        &self.stack
+    }
+
+    pub fn read_local(&self, frame: usize, name: &str) -> Option<Value> {
+        let slot = self
+            .live_locals(frame)
+            .find(|local| self.memory.get_string(local.name) == name)?
+            .slot;
+        self.frame_slots(frame).get(slot).copied()
+    }
+
+    pub fn local_name(&self, frame: usize, slot: usize) -> Option<&str> {
+        self.live_locals(frame)
+            .find(|local| local.slot == slot)
+            .map(|local| self.memory.get_string(local.name))
+    }
+
+    fn live_locals(&self, frame: usize) -> impl Iterator<Item = &LocalInfo> {
+        let frame = self.frames.get(frame);
+        let ip = frame.map_or(0, |frame| frame.instruction_pointer.0);
+        frame
+            .into_iter()
+            .flat_map(|frame| self.frame_function(frame).chunk.locals().iter().rev())
+            .filter(move |local| local.start <= ip && ip <= local.end)
+    }
+
+    pub fn frame_function(&self, frame: &CallFrame) -> &Function {
+        self.memory.function(frame.function)
+    }
+
+    pub fn frame_slots(&self, index: usize) -> &[Value] {
+        let start = self.frames[index].slot_start;
+        let end = self
+            .frames
+            .get(index + 1)
+            .map(|next| next.slot_start)
+            .unwrap_or(self.stack.len());
+        &self.stack[start..end]
+    }
+
     fn frame(&self) -> &CallFrame {
         self.frames.last().unwrap()
     }
@@ -362,9 +1924,7 @@ impl VM {
     }
 
     fn chunk(&self) -> &Chunk {
-        let c_id = self.frame().closure;
-        let f = self.memory.closure(c_id).function;
-        &self.memory.function(f).chunk
+        &self.memory.function(self.frame().function).chunk
     }
 
     pub fn reset_stack(&mut self) {
@@ -375,45 +1935,154 @@ impl VM {
         self.stack.push(value);
     }
 
-    pub fn pop(&mut self) -> Value {
-        self.stack.pop().unwrap()
+    pub fn pop(&mut self) -> Result<Value, Message> {
+        self.stack.pop().ok_or(Message::StackUnderflow)
     }
 
-    pub fn peek(&self, i: usize) -> Value {
-        self.stack.iter().rev().nth(i).unwrap().clone()
+    pub fn peek(&self, i: usize) -> Result<Value, Message> {
+        self.stack
+            .iter()
+            .rev()
+            .nth(i)
+            .copied()
+            .ok_or(Message::StackUnderflow)
     }
 
-    fn runtime_error(&mut self, error: &str) {
-        write!(self.config.vm_error, "{error}").unwrap();
-
-        let ins = self.chunk().byte(self.frame().instruction_pointer.minus(1));
-        let line = self.chunk().lines[ins as usize];
-        write!(self.config.vm_error, "[line {line}] in script").unwrap();
+    fn slot(&self, index: usize) -> Result<usize, Message> {
+        let slot = self.frame().slot_start + index;
+        if slot < self.stack.len() {
+            Ok(slot)
+        } else {
+            Err(Message::StackUnderflow)
+        }
+    }
 
-        for frame in self.frames.iter().rev() {
-            let f_id = self.memory.closure(frame.closure).function;
-            let function = &self.memory.function(f_id);
-            let name = self.memory.get_string(function.name);
-            writeln!(
-                self.config.vm_error,
-                "[line {} in {}]",
-                function.chunk.line(frame.instruction_pointer),
-                name
-            )
-            .unwrap();
+    fn runtime_error(&mut self, message: Message, args: &[(&str, &str)]) {
+        let error = self.config.messages.format(message, args);
+        writeln!(self.config.vm_error, "{error}").unwrap();
+
+        // A host call can fail before its frame is pushed, with none below it
+        let mut line = 0;
+        if !self.is_finished() {
+            let ip = self.frame().instruction_pointer.minus(1);
+            line = self.chunk().line(ip);
+            let (column, width) = self.chunk().column(ip);
+            if let Some(excerpt) = self.memory.source_line(self.chunk().file(ip), line) {
+                write_excerpt(&mut self.config.vm_error, line, excerpt, column, width);
+            }
         }
 
+        let trace = self.stack_trace();
+        write!(self.config.vm_error, "{trace}").unwrap();
+
+        self.error = Some(LoxError {
+            kind: ErrorKind::Runtime,
+            message: error,
+            line,
+            trace,
+            value: None,
+        });
         self.reset_stack();
     }
 
-    fn define_native<F: Fn(&[Value]) -> Value + 'static>(&mut self, name: &str, function: F) {
+    pub fn stack_trace(&self) -> StackTrace {
+        let mut natives = self.native_calls.iter().rev().peekable();
+        let mut frames = Vec::new();
+        for (depth, frame) in self.frames.iter().enumerate().rev() {
+            let function = self.frame_function(frame);
+            let ip = frame.instruction_pointer.minus(1);
+            let file = function.chunk.file(ip).map(str::to_owned);
+            let line = function.chunk.line(ip);
+            while let Some((_, native)) = natives.next_if(|(frames, _)| *frames == depth + 1) {
+                let name = self.memory.get_string(self.memory.native(*native).name);
+                frames.push(TraceFrame {
+                    function: format!("<native fn {name}>"),
+                    file: file.clone(),
+                    line,
+                });
+            }
+            frames.push(TraceFrame {
+                function: self.memory.get_string(function.name).to_owned(),
+                file,
+                line,
+            });
+        }
+        StackTrace { frames }
+    }
+
+    pub fn register_foreign_type<T: Any>(&mut self) -> ForeignType<'_, T> {
+        ForeignType::new(&mut self.memory)
+    }
+
+    pub fn bind<T: Any>(&mut self, name: &str) -> LoxBindings<'_, T> {
+        LoxBindings::new(self, name)
+    }
+
+    pub fn define_native<F: Fn(&[Value]) -> Value + 'static>(&mut self, name: &str, function: F) {
+        self.define_memory_native(name, move |_, args| function(args));
+    }
+
+    pub fn define_memory_native<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&mut Memory, &[Value]) -> Value + 'static,
+    {
+        self.define_fallible_native(name, move |memory, args| Ok(function(memory, args)));
+    }
+
+    pub fn set_native_arity(&mut self, name: &str, arity: Arity) -> bool {
+        let name = self.memory.string_id(name);
+        let native = self
+            .globals
+            .get(&name)
+            .and_then(|value| value.as_native_function());
+        if let Some(native) = native {
+            self.memory.native_mut(native).arity = arity;
+        }
+        native.is_some()
+    }
+
+    pub fn define_context_native<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&mut NativeContext, &[Value]) -> Result<Value, NativeError> + 'static,
+    {
+        let id = self.memory.new_context_native(name, function);
+        let name = self.memory.string_id(name);
+        self.globals.insert(name, Value::NativeFunction(id));
+    }
+
+    pub fn define_fallible_native<F>(&mut self, name: &str, function: F)
+    where
+        F: Fn(&mut Memory, &[Value]) -> Result<Value, NativeError> + 'static,
+    {
         let id = self.memory.new_native(name, function);
         let name = self.memory.string_id(name);
         self.globals.insert(name, Value::NativeFunction(id));
     }
 }
 
-#[derive(Copy, Clone, PartialEq, Eq)]
+pub struct NativeContext<'a> {
+    vm: &'a mut VM,
+}
+
+impl NativeContext<'_> {
+    pub fn memory(&mut self) -> &mut Memory {
+        &mut self.vm.memory
+    }
+
+    pub fn call(&mut self, callee: Value, args: &[Value]) -> Result<Value, NativeError> {
+        let result = self.vm.reenter(callee, args);
+        result.map_err(|_| match &self.vm.error {
+            Some(error) => error.message.clone().into(),
+            None => "Callback failed".into(),
+        })
+    }
+
+    pub fn root(&mut self, value: Value) {
+        self.vm.push(value);
+    }
+}
+
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct InstructionPointer(pub usize);
 
 impl InstructionPointer {
@@ -440,10 +2109,129 @@ impl fmt::Display for InstructionPointer {
     }
 }
 
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum StepResult {
+    Running,
+    Finished(InterpretResult),
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ErrorKind {
+    Compile,
+    Runtime,
+    Budget,
+    Uncaught,
+}
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct TraceFrame {
+    pub function: String,
+    pub file: Option<String>,
+    pub line: usize,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let location = location(self.file.as_deref(), self.line);
+        write!(f, "[{location} in {}]", self.function)
+    }
+}
+
+#[derive(Clone, PartialEq, Eq, Debug, Default)]
+pub struct StackTrace {
+    pub frames: Vec<TraceFrame>,
+}
+
+impl Deref for StackTrace {
+    type Target = [TraceFrame];
+
+    fn deref(&self) -> &Self::Target {
+        &self.frames
+    }
+}
+
+impl fmt::Display for StackTrace {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for frame in self.frames.iter() {
+            writeln!(f, "{frame}")?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Clone, PartialEq, Debug)]
+pub struct LoxError {
+    pub kind: ErrorKind,
+    pub message: String,
+    pub line: usize,
+    pub trace: StackTrace,
+    pub value: Option<Value>,
+}
+
+impl fmt::Display for LoxError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "[line {}] {}", self.line, self.message)
+    }
+}
+
+impl Error for LoxError {}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum InterpretResult {
     OK,
     CompileError,
     RuntimeError,
+    Cancelled,
+    Paused,
+}
+
+fn floor_div(a: i64, b: i64) -> Option<i64> {
+    let quotient = a.checked_div(b)?;
+    if a % b != 0 && (a < 0) != (b < 0) {
+        Some(quotient - 1)
+    } else {
+        Some(quotient)
+    }
+}
+
+fn floor_mod(a: i64, b: i64) -> Option<i64> {
+    let remainder = a.checked_rem(b)?;
+    if remainder != 0 && (remainder < 0) != (b < 0) {
+        Some(remainder + b)
+    } else {
+        Some(remainder)
+    }
+}
+
+fn float_mod(a: f64, b: f64) -> f64 {
+    let remainder = a % b;
+    if remainder != 0.0 && (remainder < 0.0) != (b < 0.0) {
+        remainder + b
+    } else {
+        remainder
+    }
+}
+
+fn merge_sort<F>(mut items: Vec<Value>, before: &mut F) -> Result<Vec<Value>, NativeError>
+where
+    F: FnMut(Value, Value) -> Result<bool, NativeError>,
+{
+    if items.len() < 2 {
+        return Ok(items);
+    }
+    let right = merge_sort(items.split_off(items.len() / 2), before)?;
+    let left = merge_sort(items, before)?;
+    let mut merged = Vec::with_capacity(left.len() + right.len());
+    let (mut left, mut right) = (left.into_iter().peekable(), right.into_iter().peekable());
+    while let (Some(a), Some(b)) = (left.peek(), right.peek()) {
+        if before(*a, *b)? {
+            merged.extend(left.next());
+        } else {
+            merged.extend(right.next());
+        }
+    }
+    merged.extend(left.chain(right));
+    Ok(merged)
 }
 
 fn is_falsey(value: Value) -> bool {
@@ -454,8 +2242,15 @@ fn is_falsey(value: Value) -> bool {
     }
 }
 
+#[derive(Clone)]
 pub struct CallFrame {
     pub closure: ClosureId,
+    pub function: FunctionId,
     pub instruction_pointer: InstructionPointer,
     pub slot_start: usize,
+    pub arg_count: usize,
+    #[cfg(feature = "predecode")]
+    decoded: Rc<[Option<Decoded>]>,
+    #[cfg(not(feature = "predecode"))]
+    code: Rc<[u8]>,
 }
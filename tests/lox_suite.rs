@@ -0,0 +1,117 @@
+//! Runs a craftinginterpreters-style test corpus through the VM.
+//!
+//! By default this runs the cases vendored under `tests/lox`, which follow the layout and
+//! `// expect:` conventions of the upstream `test/` directory. Point `LOX_TEST_SUITE` at the
+//! `test/` directory of a craftinginterpreters checkout to run the full corpus instead:
+//!
+//!     LOX_TEST_SUITE=../craftinginterpreters/test cargo test --test lox_suite -- --nocapture
+//!
+//! Cases listed in `tests/lox_suite_baseline.txt` that exist in the corpus must keep passing.
+//! Set `LOX_SUITE_BLESS=1` to rewrite the baseline with the current set of passing cases.
+
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use rlox::test_runner;
+
+const BASELINE: &str = "tests/lox_suite_baseline.txt";
+const VENDORED: &str = "tests/lox";
+
+// Directories exercising the tree-walk interpreter chapters only.
+const SKIPPED: &[&str] = &["benchmark", "expressions", "scanning"];
+
+#[test]
+fn lox_suite() {
+    let root = env::var_os("LOX_TEST_SUITE")
+        .map(PathBuf::from)
+        .unwrap_or_else(|| PathBuf::from(VENDORED));
+
+    let outcomes = test_runner::run_dir(&root).expect("could not read the test suite");
+
+    let mut chapters: BTreeMap<String, (usize, usize)> = BTreeMap::new();
+    let mut passing = BTreeSet::new();
+    let mut discovered = BTreeSet::new();
+    for outcome in outcomes.iter() {
+        let name = relative_name(&root, &outcome.path);
+        let chapter = chapter(&name);
+        if SKIPPED.contains(&chapter.as_str()) {
+            continue;
+        }
+        discovered.insert(name.clone());
+
+        let entry = chapters.entry(chapter).or_default();
+        entry.1 += 1;
+        if outcome.passed() {
+            entry.0 += 1;
+            passing.insert(name);
+        }
+    }
+
+    println!("{:<24} {:>6} {:>6}", "chapter", "passed", "total");
+    for (chapter, (passed, total)) in chapters.iter() {
+        println!("{chapter:<24} {passed:>6} {total:>6}");
+    }
+    let total: usize = chapters.values().map(|(_, total)| total).sum();
+    println!("{:<24} {:>6} {:>6}", "all", passing.len(), total);
+
+    let baseline = read_baseline();
+    for name in passing.difference(&baseline) {
+        println!("newly passing: {name}");
+    }
+    let regressions: Vec<_> = baseline
+        .intersection(&discovered)
+        .filter(|name| !passing.contains(*name))
+        .collect();
+    for name in regressions.iter() {
+        println!("regressed: {name}");
+    }
+
+    if env::var_os("LOX_SUITE_BLESS").is_some() {
+        write_baseline(&passing);
+        return;
+    }
+
+    assert!(
+        regressions.is_empty(),
+        "{} previously passing cases now fail",
+        regressions.len()
+    );
+}
+
+fn relative_name(root: &Path, path: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn chapter(name: &str) -> String {
+    match name.split_once('/') {
+        Some((dir, _)) => dir.into(),
+        None => "root".into(),
+    }
+}
+
+fn read_baseline() -> BTreeSet<String> {
+    fs::read_to_string(BASELINE)
+        .unwrap_or_default()
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(String::from)
+        .collect()
+}
+
+fn write_baseline(passing: &BTreeSet<String>) {
+    let mut contents = String::from("# Generated by LOX_SUITE_BLESS=1; see tests/lox_suite.rs\n");
+    for name in passing {
+        contents.push_str(name);
+        contents.push('\n');
+    }
+    fs::write(BASELINE, contents).expect("could not write the baseline");
+}
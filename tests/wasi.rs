@@ -0,0 +1,71 @@
+//! End-to-end checks that only use what a WASI sandbox provides.
+//!
+//! Runs on the host like any other test. To run it inside a WASM runtime, install
+//! `wasmtime` and the `wasm32-wasip1` target, then:
+//!
+//!     cargo test --target wasm32-wasip1 --test wasi
+//!
+//! The runner is configured in `.cargo/config.toml`.
+
+use std::{cell::RefCell, rc::Rc};
+
+use rlox::{
+    compiler::compile,
+    config::{Config, PrintOutput},
+    serialize::{deserialize, serialize},
+    vm::{interpret, VM},
+};
+
+fn config(output: &Rc<RefCell<String>>) -> Config {
+    Config {
+        print_output: PrintOutput::Str(output.clone()),
+        vm_error: PrintOutput::Str(output.clone()),
+        ..Default::default()
+    }
+}
+
+#[test]
+fn runs_scripts() {
+    let output = Rc::new(RefCell::new(String::new()));
+    let result = interpret(
+        r#"
+        fun fib(n) {
+            if (n < 2) return n;
+            return fib(n - 2) + fib(n - 1);
+        }
+        print "fib " + "10";
+        print fib(10);
+        "#,
+        config(&output),
+    );
+
+    assert_eq!(result, Ok(()));
+    assert_eq!(*output.borrow(), "fib 10\n55\n");
+}
+
+#[test]
+fn clock_reads_the_wasi_clock() {
+    let output = Rc::new(RefCell::new(String::new()));
+    let result = interpret("print clock() > 0;", config(&output));
+
+    assert_eq!(result, Ok(()));
+    assert_eq!(*output.borrow(), "true\n");
+}
+
+#[test]
+fn runs_precompiled_bytecode() {
+    let program = compile(Rc::from("var a = 1; print a + 2;"), Config::default()).unwrap();
+    let bytes = serialize(&program.to_memory(), false).unwrap();
+
+    let output = Rc::new(RefCell::new(String::new()));
+    let memory = deserialize(&bytes).unwrap();
+    let result = VM::with_script(memory, config(&output)).run();
+
+    assert_eq!(result, Ok(()));
+    assert_eq!(*output.borrow(), "3\n");
+}
+
+#[test]
+fn golden_output_macro() {
+    rlox::assert_lox! { "var a = \"lox\"; print a + a;" => "loxlox\n" };
+}